@@ -15,6 +15,7 @@ use esp_hal::gpio::{Level, Output, OutputConfig};
 use esp_hal::timer::timg::TimerGroup;
 use panic_rtt_target as _;
 use esp_println::println;
+use wifi_core::heap_layout::{ESP32_RECLAIMED_RAM, HeapConfig};
 
 
 esp_bootloader_esp_idf::esp_app_desc!();
@@ -30,7 +31,7 @@ async fn main(_spawner: Spawner) -> ! {
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
 
-    esp_alloc::heap_allocator!(#[esp_hal::ram(reclaimed)] size: 98768);
+    wifi_core::init_heap!(HeapConfig::new().with_reclaimed(ESP32_RECLAIMED_RAM));
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     esp_rtos::start(timg0.timer0);
 
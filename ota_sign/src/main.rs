@@ -0,0 +1,149 @@
+//! Host tool that signs firmware images for over-the-air updates.
+//!
+//! The firmware only installs images whose Ed25519 signature checks out
+//! against the public key it was built with (see `wifi::firmware`). This
+//! tool makes the key pair and the signatures:
+//!
+//! ```text
+//! # In ota_sign/: make a key pair once
+//! cargo run --release -- keygen ~/ota.key
+//! # In wifi/: build the firmware with the printed public key
+//! OTA_PUBLIC_KEY=<public key> cargo build --release
+//! espflash save-image --chip esp32 target/xtensa-esp32-none-elf/release/wifi wifi.bin
+//! # In ota_sign/: sign each image to be installed
+//! cargo run --release -- sign ~/ota.key ../wifi/wifi.bin ../wifi/wifi.signed.bin
+//! ```
+//!
+//! The key file holds the secret seed as 64 hex digits; keep it out of the
+//! repository. The signature format is described in
+//! [`wifi_core::app_image`].
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::process::ExitCode;
+
+use ed25519_dalek::SigningKey;
+use wifi_core::app_image::{
+    CHIP_ID_ESP32, ImageInfo, ImageVerifier, SignedImageVerifier, signature_block,
+};
+use wifi_core::ed25519::{SEED_LEN, VerifyingKey, parse_hex_key};
+
+/// Source of the random key seeds
+const RANDOM_SOURCE: &str = "/dev/urandom";
+
+const USAGE: &str = "\
+Usage:
+  ota_sign keygen <key-file>                      Make a key pair, print the public key
+  ota_sign public-key <key-file>                  Print the public key of a key file
+  ota_sign sign <key-file> <image> <signed-image> Append a signature to an ESP32 image
+  ota_sign verify <public-key> <signed-image>     Check a signed image";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["keygen", key_file] => keygen(key_file),
+        ["public-key", key_file] => {
+            read_key(key_file).map(|key| println!("{}", verifying_key(&key)))
+        }
+        ["sign", key_file, image, signed] => sign(key_file, image, signed),
+        ["verify", public_key, signed] => verify(public_key, signed),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Writes a new random seed to `key_file`, which must not exist yet, and
+/// prints the public key.
+fn keygen(key_file: &str) -> Result<(), String> {
+    let mut seed = [0; SEED_LEN];
+    File::open(RANDOM_SOURCE)
+        .and_then(|mut random| random.read_exact(&mut seed))
+        .map_err(|e| format!("cannot read {RANDOM_SOURCE}: {e}"))?;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(key_file)
+        .map_err(|e| format!("cannot create {key_file}: {e}"))?;
+    let hex: String = seed.iter().map(|b| format!("{b:02x}")).collect();
+    writeln!(file, "{hex}").map_err(|e| format!("cannot write {key_file}: {e}"))?;
+
+    println!("{}", verifying_key(&SigningKey::from_bytes(&seed)));
+    Ok(())
+}
+
+/// Reads the secret key from a key file made by [`keygen`].
+fn read_key(key_file: &str) -> Result<SigningKey, String> {
+    let text = fs::read_to_string(key_file).map_err(|e| format!("cannot read {key_file}: {e}"))?;
+    let seed = parse_hex_key(text.trim())
+        .ok_or_else(|| format!("{key_file} does not hold a key of 64 hex digits"))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// The public key of `key`, printed as 64 hex digits
+fn verifying_key(key: &SigningKey) -> VerifyingKey {
+    key.verifying_key().into()
+}
+
+/// Checks the ESP32 image in `image`, and writes it with a signature block
+/// to `signed`.
+fn sign(key_file: &str, image: &str, signed: &str) -> Result<(), String> {
+    let key = read_key(key_file)?;
+    let mut data = fs::read(image).map_err(|e| format!("cannot read {image}: {e}"))?;
+    let len = u32::try_from(data.len()).map_err(|_| format!("{image} is too large"))?;
+
+    let mut verifier = ImageVerifier::new(CHIP_ID_ESP32, len);
+    let info = verifier
+        .update(&data)
+        .and_then(|()| verifier.finish())
+        .map_err(|e| format!("{image}: {e}"))?;
+    print_info(&info);
+    data.extend_from_slice(&signature_block(&info, &key));
+
+    fs::write(signed, &data).map_err(|e| format!("cannot write {signed}: {e}"))?;
+    println!("Signed with {}", verifying_key(&key));
+    Ok(())
+}
+
+/// Checks that the image in `signed` is signed with `public_key`, given as
+/// 64 hex digits.
+fn verify(public_key: &str, signed: &str) -> Result<(), String> {
+    let key = parse_hex_key(public_key)
+        .ok_or("the public key must be 64 hex digits")
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).map_err(|_| "invalid public key"))?;
+    let data = fs::read(signed).map_err(|e| format!("cannot read {signed}: {e}"))?;
+    let len = u32::try_from(data.len()).map_err(|_| format!("{signed} is too large"))?;
+
+    let mut verifier = SignedImageVerifier::new(CHIP_ID_ESP32, len, key);
+    let info = verifier
+        .update(&data)
+        .and_then(|_| verifier.finish())
+        .map_err(|e| format!("{signed}: {e}"))?;
+    print_info(&info);
+    println!("Signature is valid");
+    Ok(())
+}
+
+/// Prints what an image holds
+fn print_info(info: &ImageInfo) {
+    let sha256: String = info.sha256.iter().map(|b| format!("{b:02x}")).collect();
+    println!(
+        "Image {:?}: {} bytes, {} segments, SHA-256 {}",
+        info.version.as_str(),
+        info.len,
+        info.segments,
+        sha256
+    );
+}
@@ -2,23 +2,206 @@
 //!
 //! This module handles heap memory setup required for WiFi functionality.
 //! ESP32 WiFi operations require significant heap memory for buffers and internal state.
+//! The regions of the heap are set by [`HEAP_CONFIG`]; with the `psram` feature
+//! the external PSRAM of WROVER-class modules is added after internal RAM.
+//!
+//! [`heap_stats`] reports how much of each heap region is in use, the
+//! high-water marks and the largest block that can still be allocated.
+//! [`start_heap_monitor`] samples the heap in the background, warns when
+//! free memory runs low and can log a report now and then, so the region
+//! sizes below can be chosen from what the device actually uses:
+//!
+//! ```text
+//! Heap: 61024 of 229840 bytes used, peak 83112, 168816 free, largest free block 98400; regions 12/98768 (peak 1880), 61012/131072 (peak 81232)
+//! ```
+//!
+//! Failed allocations are not counted: `esp-alloc` registers the global
+//! allocator itself and reports no failures, so the crate never sees one.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::RefCell;
+
+use embassy_executor::Spawner;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant, Timer};
+use esp_alloc::HEAP;
+use esp_println::println;
+use wifi_core::heap_layout::{ESP32_RECLAIMED_RAM, HeapConfig};
+use wifi_core::heap_stats::{self, HeapMonitor, HeapUsage, LowMemory, RegionUsage};
 
-/// Reclaimed RAM heap size (from bootloader sections)
-const RECLAIMED_HEAP_SIZE: usize = 98768; // 72 KB
+use crate::error::Error;
+use crate::supervisor::{self, Watchdog};
 
 /// Main heap size for WiFi operations
 const MAIN_HEAP_SIZE: usize = 128 * 1024; // 128 KB
 
+/// Heap regions: all of the reclaimed RAM (96.5 KiB), the main heap, and
+/// PSRAM with the `psram` feature
+pub const HEAP_CONFIG: HeapConfig = HeapConfig::new()
+    .with_reclaimed(ESP32_RECLAIMED_RAM)
+    .with_dram(MAIN_HEAP_SIZE)
+    .with_psram(cfg!(feature = "psram"));
+
+/// High-water marks and low-memory state, updated by every [`heap_stats`].
+static MONITOR: BlockingMutex<CriticalSectionRawMutex, RefCell<HeapMonitor>> =
+    BlockingMutex::new(RefCell::new(HeapMonitor::new(None)));
+
+/// Heap sampling and reporting of [`start_heap_monitor`]
+#[derive(Clone, Copy, Debug)]
+pub struct HeapMonitorConfig {
+    /// Time between two samples of the heap
+    pub sample_interval: Duration,
+    /// Time between two logged reports, or `None` for no reports
+    pub report_interval: Option<Duration>,
+    /// Free bytes below which a warning is logged, or `None` for no warning
+    pub low_memory_threshold: Option<usize>,
+}
+
+impl Default for HeapMonitorConfig {
+    /// Samples every 5 s and warns below 16 KiB free, without reports
+    fn default() -> Self {
+        Self {
+            sample_interval: Duration::from_secs(5),
+            report_interval: None,
+            low_memory_threshold: Some(16 * 1024),
+        }
+    }
+}
+
 /// Initialize heap allocators for WiFi operations.
 ///
-/// This function sets up two heap allocators:
+/// This function sets up the regions of [`HEAP_CONFIG`]:
 /// - Reclaimed RAM: Memory reclaimed from bootloader sections
 /// - Main heap: Additional memory for WiFi buffers and operations
 ///
 /// # Panics
 ///
 /// Panics if heap allocation fails or insufficient memory is available.
+#[cfg(not(feature = "psram"))]
 pub fn init_heap() {
-    esp_alloc::heap_allocator!(#[esp_hal::ram(reclaimed)] size: RECLAIMED_HEAP_SIZE);
-    esp_alloc::heap_allocator!(size: MAIN_HEAP_SIZE);
+    wifi_core::init_heap!(HEAP_CONFIG);
+}
+
+/// Initialize heap allocators for WiFi operations.
+///
+/// This function sets up the regions of [`HEAP_CONFIG`]:
+/// - Reclaimed RAM: Memory reclaimed from bootloader sections
+/// - Main heap: Additional memory for WiFi buffers and operations
+/// - PSRAM: External RAM, added last so internal RAM is used first
+///
+/// # Arguments
+///
+/// * `psram` - PSRAM peripheral; PSRAM must have been enabled through
+///   `esp_hal::Config::with_psram`
+///
+/// # Panics
+///
+/// Panics if heap allocation fails or insufficient memory is available.
+#[cfg(feature = "psram")]
+pub fn init_heap(psram: esp_hal::peripherals::PSRAM<'static>) {
+    wifi_core::init_heap!(HEAP_CONFIG, psram: psram);
+}
+
+/// Returns the current use of each heap region, the peaks and the largest
+/// free block, and logs a change of the low-memory state.
+///
+/// The peaks of the regions are the highest this function has seen; the
+/// peak of the whole heap is exact. Finding the largest free block takes a
+/// few allocations with interrupts disabled, so call this every few seconds
+/// at most, not in a tight loop.
+pub fn heap_stats() -> HeapUsage {
+    let stats = HEAP.stats();
+    let mut usage = HeapUsage::default();
+    for region in stats.region_stats.iter().flatten() {
+        let _ = usage.regions.push(RegionUsage {
+            size: region.size,
+            used: region.used,
+            free: region.free,
+            peak: 0,
+        });
+    }
+    let limit = usage.regions.iter().map(|region| region.free).max();
+
+    let change = MONITOR.lock(|monitor| {
+        // In the critical section, so no other task allocates while a
+        // probe holds the memory
+        usage.largest_free_block = heap_stats::largest_block(limit.unwrap_or(0), try_allocate);
+        monitor
+            .borrow_mut()
+            .sample(&mut usage, Some(stats.max_usage))
+    });
+    match change {
+        Some(LowMemory::Entered { free }) => println!("Heap low: {} bytes free", free),
+        Some(LowMemory::Cleared { free }) => println!("Heap recovered: {} bytes free", free),
+        None => {}
+    }
+    usage
+}
+
+/// Allocates and frees `size` bytes, telling whether the allocation worked.
+fn try_allocate(size: usize) -> bool {
+    let Ok(layout) = Layout::from_size_align(size.max(1), 4) else {
+        return false;
+    };
+    // SAFETY: the layout has a non-zero size, and the block is freed with the
+    // same layout it was allocated with
+    unsafe {
+        let block = HEAP.alloc(layout);
+        if block.is_null() {
+            return false;
+        }
+        HEAP.dealloc(block, layout);
+    }
+    true
+}
+
+/// Embassy task that samples the heap and logs reports.
+#[embassy_executor::task]
+async fn heap_monitor_task(config: HeapMonitorConfig, watchdog: Watchdog) {
+    let mut last_report = Instant::now();
+    loop {
+        watchdog.heartbeat();
+        let usage = heap_stats();
+        if let Some(interval) = config.report_interval
+            && last_report.elapsed() >= interval
+        {
+            println!("{}", usage);
+            last_report = Instant::now();
+        }
+        Timer::after(config.sample_interval).await;
+    }
+}
+
+/// Starts sampling the heap in the background.
+///
+/// Every sample updates the high-water marks that [`heap_stats`] reports and
+/// checks for low memory: a warning is logged when fewer than
+/// [`HeapMonitorConfig::low_memory_threshold`] bytes are free, and a notice
+/// once an eighth more is free again.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the monitor task
+/// * `config` - Sampling, reporting and the low-memory threshold
+///
+/// The monitor checks in with the supervisor on every sample.
+///
+/// # Errors
+///
+/// This function will return:
+/// - [`Error::Supervisor`] if the monitor task cannot be supervised
+/// - [`Error::Spawn`] if the monitor task is already running
+pub fn start_heap_monitor(spawner: Spawner, config: HeapMonitorConfig) -> Result<(), Error> {
+    MONITOR.lock(|monitor| {
+        monitor
+            .borrow_mut()
+            .set_threshold(config.low_memory_threshold)
+    });
+    // Room for a sample that is late by a whole interval
+    let watchdog = supervisor::register("heap monitor", config.sample_interval * 3)?;
+    spawner
+        .spawn(heap_monitor_task(config, watchdog))
+        .inspect_err(|_| watchdog.idle())?;
+    Ok(())
 }
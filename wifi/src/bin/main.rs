@@ -8,18 +8,56 @@
 #![deny(clippy::large_stack_frames)]
 
 use embassy_executor::Spawner;
+#[cfg(feature = "ble")]
+use embassy_futures::select::{Either, select};
+use embassy_net::StackResources;
 use embassy_time::{Duration, Timer};
 use esp_hal::clock::CpuClock;
 use esp_hal::timer::timg::TimerGroup;
+use esp_hal::uart::{self, Uart};
 use esp_println::println;
 use panic_rtt_target as _;
-use wifi::allocator;
+use static_cell::StaticCell;
+use wifi::allocator::{self, HeapMonitorConfig};
+use wifi::ed25519::{self, PUBLIC_KEY_LEN, VerifyingKey};
+use wifi::net::Ipv4Config;
+use wifi::rogue::Allowlist;
+use wifi::scan_config::{ChannelSet, ScannerConfig};
+use wifi::station::{BackoffConfig, Credentials, NetworkProfile, ProfileStore};
+use wifi::supervisor::{self, SupervisorConfig};
+use wifi::telemetry::TelemetryConfig;
+use wifi::time_sync::SntpConfig;
+use wifi::tracker::TrackerConfig;
 
 extern crate alloc;
 
 // ESP-IDF application descriptor
 esp_bootloader_esp_idf::esp_app_desc!();
 
+/// Number of sockets on the station network stack: DHCP, DNS, SNTP, mDNS,
+/// the MQTT broker connection, the firmware download and the HTTP server
+/// connections
+const STATION_SOCKETS: usize = 6 + wifi::http_server::MAX_CONNECTIONS;
+
+/// Key firmware updates must be signed with, from `OTA_PUBLIC_KEY` at build
+/// time; without it, the device does not take updates
+const OTA_PUBLIC_KEY: Option<[u8; PUBLIC_KEY_LEN]> = match option_env!("OTA_PUBLIC_KEY") {
+    Some(hex) => match ed25519::parse_hex_key(hex) {
+        Some(key) => Some(key),
+        None => panic!("OTA_PUBLIC_KEY must be 64 hex digits"),
+    },
+    None => None,
+};
+
+/// Time the station network gets to come up after boot
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time between two runs of the main loop
+const MAIN_LOOP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Socket storage of the station network stack
+static STATION_RESOURCES: StaticCell<StackResources<STATION_SOCKETS>> = StaticCell::new();
+
 #[allow(
     clippy::large_stack_frames,
     reason = "it's not unusual to allocate larger buffers etc. in main"
@@ -29,23 +67,349 @@ async fn main(_spawner: Spawner) -> ! {
     rtt_target::rtt_init_defmt!();
 
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
+    #[cfg(feature = "psram")]
+    let config = config.with_psram(esp_hal::psram::PsramConfig::default());
     let peripherals = esp_hal::init(config);
 
     // Initialize heap memory for WiFi operations
+    #[cfg(not(feature = "psram"))]
     allocator::init_heap();
+    #[cfg(feature = "psram")]
+    allocator::init_heap(peripherals.PSRAM);
 
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     esp_rtos::start(timg0.timer0);
 
     println!("Embassy initialized!");
 
-    match wifi::scanner::wifi_scanner(_spawner, peripherals.WIFI).await {
-        Ok(_) => println!("WiFi scanner task spawned successfully."),
-        Err(e) => println!("Failed to initialize WiFi scanner: {}", e),
+    // A supervisor reset into safe mode leaves the radio off
+    let safe_mode = supervisor::take_safe_mode();
+    if safe_mode {
+        println!("Safe mode: WiFi is disabled until the next reset");
+    }
+
+    // Log heap use every HEAP_REPORT_SECS seconds, if given at build time
+    let mut heap_config = HeapMonitorConfig::default();
+    if let Some(secs) = option_env!("HEAP_REPORT_SECS") {
+        match secs.parse() {
+            Ok(secs) => heap_config.report_interval = Some(Duration::from_secs(secs)),
+            Err(_) => println!("Invalid HEAP_REPORT_SECS: {}", secs),
+        }
+    }
+    if let Err(e) = allocator::start_heap_monitor(_spawner, heap_config) {
+        println!("Failed to start heap monitor: {}", e);
+    }
+
+    // Console on the pins of the USB serial bridge
+    let console_uart = match Uart::new(peripherals.UART0, uart::Config::default()) {
+        Ok(uart) => Some(
+            uart.with_rx(peripherals.GPIO3)
+                .with_tx(peripherals.GPIO1)
+                .into_async(),
+        ),
+        Err(e) => {
+            println!("Failed to configure console UART: {:?}", e);
+            None
+        }
+    };
+
+    // Settings saved by earlier runs
+    let flash = wifi::storage::init_flash(peripherals.FLASH);
+    let store = match wifi::storage::init_storage(flash).await {
+        Ok(store) => Some(store),
+        Err(e) => {
+            println!("Failed to open config store: {}", e);
+            None
+        }
+    };
+    // Roll back an update that never confirmed itself
+    if let Err(e) = wifi::firmware::check_boot(_spawner, flash).await {
+        println!("Failed to check firmware slot: {}", e);
+    }
+
+    let mut scanner_config = ScannerConfig::default();
+    let mut profiles = ProfileStore::new();
+    let mut ipv4_config = Ipv4Config::default();
+    let mut allowlist = Allowlist::new();
+    if let Some(store) = store {
+        match store.lock().await {
+            Ok(mut store) => {
+                match store.load() {
+                    Ok(Some(config)) => scanner_config = config,
+                    Ok(None) => {}
+                    Err(e) => println!("Failed to load scanner config: {}", e),
+                }
+                match store.load() {
+                    Ok(Some(saved)) => profiles = saved,
+                    Ok(None) => {}
+                    Err(e) => println!("Failed to load network profiles: {}", e),
+                }
+                match store.load() {
+                    Ok(Some(config)) => ipv4_config = config,
+                    Ok(None) => {}
+                    Err(e) => println!("Failed to load IP configuration: {}", e),
+                }
+                match store.load() {
+                    Ok(Some(saved)) => allowlist = saved,
+                    Ok(None) => {}
+                    Err(e) => println!("Failed to load rogue AP allowlist: {}", e),
+                }
+            }
+            Err(e) => println!("Failed to mount config store: {}", e),
+        }
+    }
+
+    let radio = if safe_mode {
+        None
+    } else {
+        match wifi::radio::init_radio(peripherals.WIFI).await {
+            Ok(radio) => Some(radio),
+            Err(e) => {
+                println!("Failed to initialize WiFi: {}", e);
+                None
+            }
+        }
+    };
+
+    if let Err(e) = supervisor::start_supervisor(
+        _spawner,
+        peripherals.LPWR,
+        radio.as_ref().map(|radio| radio.controller),
+        SupervisorConfig::default(),
+    ) {
+        println!("Failed to start task supervisor: {}", e);
+    }
+
+    let mut scanner = None;
+    if let Some(radio) = &radio {
+        match wifi::scanner::start_scanner(_spawner, radio.controller, scanner_config) {
+            Ok(handle) => {
+                println!("WiFi scanner task spawned successfully.");
+                scanner = Some(handle);
+            }
+            Err(e) => println!("Failed to start WiFi scanner: {}", e),
+        }
+    }
+
+    if let Err(e) = wifi::tracking::start_tracker(_spawner, TrackerConfig::default()) {
+        println!("Failed to start AP tracker: {}", e);
+    }
+
+    if let Err(e) = wifi::survey::start_survey(_spawner, ChannelSet::ALL) {
+        println!("Failed to start channel survey: {}", e);
+    }
+
+    // Expected networks given at build time replace the saved ones
+    if let Some(text) = option_env!("ROGUE_ALLOWLIST") {
+        match Allowlist::parse(text) {
+            Some(parsed) => {
+                allowlist = parsed;
+                if let Some(store) = store
+                    && let Err(e) = store
+                        .lock()
+                        .await
+                        .and_then(|mut store| store.save(&allowlist))
+                {
+                    println!("Failed to save rogue AP allowlist: {}", e);
+                }
+            }
+            None => println!("Invalid ROGUE_ALLOWLIST: {}", text),
+        }
+    }
+    if let Err(e) = wifi::rogue_detection::start_rogue_detector(_spawner, allowlist) {
+        println!("Failed to start rogue AP detector: {}", e);
+    }
+
+    let mut station = None;
+    let mut station_stack = None;
+    if let Some(radio) = radio {
+        // Remember the network given at build time, if any
+        if let Some(ssid) = option_env!("WIFI_SSID") {
+            let password = option_env!("WIFI_PASSWORD").unwrap_or("");
+            match Credentials::new(ssid, password) {
+                Ok(credentials) => {
+                    if profiles.insert(NetworkProfile::new(credentials)).is_err() {
+                        println!("No room for network profile {}", ssid);
+                    } else if let Some(store) = store
+                        && let Err(e) = store
+                            .lock()
+                            .await
+                            .and_then(|mut store| store.save(&profiles))
+                    {
+                        println!("Failed to save network profiles: {}", e);
+                    }
+                }
+                Err(e) => println!("Invalid WiFi credentials: {}", e),
+            }
+        }
+
+        #[cfg(feature = "ble")]
+        let ble =
+            match wifi::ble::start_ble_provisioning(_spawner, radio.init, peripherals.BT, store) {
+                Ok(ble) => Some(ble),
+                Err(e) => {
+                    println!("Failed to start BLE provisioning: {}", e);
+                    None
+                }
+            };
+
+        // Without a known network, ask for one through the captive portal
+        if profiles.is_empty() {
+            match wifi::provisioning::start_provisioning(
+                _spawner,
+                radio.controller,
+                radio.interfaces.ap,
+                store,
+                scanner,
+            )
+            .await
+            {
+                Ok(portal) => {
+                    // ... or over BLE, whichever answers first
+                    #[cfg(feature = "ble")]
+                    let credentials = match ble {
+                        Some(ble) => match select(portal.wait(), ble.wait()).await {
+                            Either::First(credentials) | Either::Second(credentials) => credentials,
+                        },
+                        None => portal.wait().await,
+                    };
+                    #[cfg(not(feature = "ble"))]
+                    let credentials = portal.wait().await;
+                    let _ = profiles.insert(NetworkProfile::new(credentials));
+                }
+                Err(e) => println!("Failed to start provisioning: {}", e),
+            }
+            // Provisioning paused the scanner; the access point closes as
+            // the station starts
+            if let Some(scanner) = scanner {
+                scanner.resume().await;
+            }
+        }
+
+        if !profiles.is_empty() {
+            match wifi::station::start_station(
+                _spawner,
+                radio.controller,
+                profiles,
+                BackoffConfig::default(),
+            ) {
+                Ok(handle) => {
+                    println!("Station task spawned successfully.");
+                    #[cfg(feature = "ble")]
+                    if let Some(ble) = ble {
+                        ble.attach_station(handle);
+                    }
+                    station = Some(handle);
+                }
+                Err(e) => println!("Failed to start station: {}", e),
+            }
+        }
+
+        let mac = radio.interfaces.sta.mac_address();
+        match wifi::net::start_network(
+            _spawner,
+            radio.interfaces.sta,
+            &ipv4_config,
+            STATION_RESOURCES.init(StackResources::new()),
+        ) {
+            Ok(stack) => {
+                let updater = match OTA_PUBLIC_KEY.map(|key| VerifyingKey::from_bytes(&key)) {
+                    Some(Ok(key)) => {
+                        match wifi::firmware::start_updater(_spawner, stack, flash, key) {
+                            Ok(updater) => Some(updater),
+                            Err(e) => {
+                                println!("Failed to start firmware updater: {}", e);
+                                None
+                            }
+                        }
+                    }
+                    Some(Err(e)) => {
+                        println!("OTA_PUBLIC_KEY is not usable: {}", e);
+                        None
+                    }
+                    None => {
+                        println!("No OTA_PUBLIC_KEY built in, firmware updates are disabled");
+                        None
+                    }
+                };
+                // The API also serves while the address is still pending
+                if let Err(e) = wifi::http_server::start_http_server(
+                    _spawner, stack, scanner, station, store, updater,
+                ) {
+                    println!("Failed to start HTTP server: {}", e);
+                }
+                let mut sntp_config = SntpConfig::default();
+                if let Some(server) = option_env!("SNTP_SERVER") {
+                    sntp_config.server = server;
+                }
+                if let Err(e) = wifi::time_sync::start_time_sync(_spawner, stack, sntp_config) {
+                    println!("Failed to start time synchronization: {}", e);
+                }
+                if let Err(e) = wifi::discovery::start_discovery(
+                    _spawner,
+                    stack,
+                    option_env!("DEVICE_NAME"),
+                    mac,
+                ) {
+                    println!("Failed to start mDNS responder: {}", e);
+                }
+                // Publish to the broker given at build time, if any
+                if let Some(host) = option_env!("MQTT_HOST") {
+                    let mut config = TelemetryConfig::new(host);
+                    if let Some(username) = option_env!("MQTT_USERNAME") {
+                        config.credentials =
+                            Some((username, option_env!("MQTT_PASSWORD").unwrap_or("")));
+                    }
+                    config.config_topic = option_env!("MQTT_CONFIG_TOPIC");
+                    if let Err(e) = wifi::telemetry::start_telemetry(
+                        _spawner, stack, config, scanner, station, store,
+                    ) {
+                        println!("Failed to start MQTT publisher: {}", e);
+                    }
+                }
+                station_stack = Some(stack);
+            }
+            Err(e) => println!("Failed to start station network: {}", e),
+        }
+    }
+
+    if let Some(uart) = console_uart
+        && let Err(e) = wifi::console::start_console(
+            _spawner,
+            uart,
+            station_stack,
+            scanner,
+            station,
+            store,
+            &[],
+        )
+    {
+        println!("Failed to start console: {}", e);
+    }
+
+    if let Some(stack) = station_stack {
+        match wifi::net::wait_config_up(stack, NETWORK_TIMEOUT).await {
+            Ok(config) => {
+                println!("Station address {}", config.address);
+                // Reaching the network is what an update must not break
+                wifi::firmware::mark_healthy();
+            }
+            Err(e) => println!("Station network not up: {}", e),
+        }
     }
 
+    let watchdog = match supervisor::register("main", MAIN_LOOP_INTERVAL * 3) {
+        Ok(watchdog) => Some(watchdog),
+        Err(e) => {
+            println!("Failed to supervise main loop: {}", e);
+            None
+        }
+    };
     loop {
         println!("Main loop running...");
-        Timer::after(Duration::from_secs(30)).await;
+        if let Some(watchdog) = watchdog {
+            watchdog.heartbeat();
+        }
+        Timer::after(MAIN_LOOP_INTERVAL).await;
     }
 }
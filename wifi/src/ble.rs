@@ -0,0 +1,311 @@
+//! BLE provisioning service.
+//!
+//! [`start_ble_provisioning`] brings up BLE next to WiFi and advertises the
+//! device under its [`device_name`]. A phone app connects, reads the nearby
+//! networks from the latest scan, writes an SSID and password and follows
+//! the connection attempt on the status characteristic. The GATT service
+//! and its encoding are described in [`wifi_core::ble_provisioning`].
+//!
+//! Received credentials are saved as a [`NetworkProfile`] in the
+//! configuration store. Once a station is attached with
+//! [`BleProvisioningHandle::attach_station`], they are added to it and it
+//! connects right away; before that, they are handed out by
+//! [`BleProvisioningHandle::wait`].
+//!
+//! Only built with the `ble` feature, which also turns on WiFi/BLE
+//! coexistence in the driver. Coexistence needs more heap than WiFi alone.
+
+use core::cell::{Cell, RefCell};
+
+use bleps::ad_structure::{
+    AdStructure, BR_EDR_NOT_SUPPORTED, LE_GENERAL_DISCOVERABLE, create_advertising_data,
+};
+use bleps::async_attribute_server::AttributeServer;
+use bleps::asynch::Ble;
+use bleps::attribute_server::NotificationData;
+use bleps::gatt;
+use embassy_executor::Spawner;
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::peripherals::BT;
+use esp_println::println;
+use esp_radio::ble::controller::BleConnector;
+use wifi_core::ble_provisioning::{
+    MAX_NETWORKS_LEN, ProvisioningStatus, Session, WriteError, encode_networks,
+};
+use wifi_core::profiles::{NetworkProfile, ProfileStore};
+use wifi_core::report::Ssid;
+use wifi_core::station::Credentials;
+
+use crate::error::Error;
+use crate::provisioning::device_name;
+use crate::scanner;
+use crate::station::{self, StationHandle};
+use crate::storage::SharedStore;
+
+/// How often the status characteristic checks the station state
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Wait before advertising again after the controller failed
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Credentials written by the client, not yet saved
+static RECEIVED: Signal<CriticalSectionRawMutex, Credentials> = Signal::new();
+
+/// Saved credentials for [`BleProvisioningHandle::wait`]
+static SUBMITTED: Signal<CriticalSectionRawMutex, Credentials> = Signal::new();
+
+/// Raised when the status may have changed outside the station
+static STATUS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Error of the last rejected write, cleared by the next accepted one
+static REJECTED: BlockingMutex<CriticalSectionRawMutex, Cell<Option<WriteError>>> =
+    BlockingMutex::new(Cell::new(None));
+
+/// Station the credentials are handed to, once attached
+static STATION: BlockingMutex<CriticalSectionRawMutex, Cell<Option<StationHandle>>> =
+    BlockingMutex::new(Cell::new(None));
+
+/// Handle to the running BLE provisioning service
+#[derive(Clone, Copy, Debug)]
+pub struct BleProvisioningHandle {
+    _private: (),
+}
+
+impl BleProvisioningHandle {
+    /// Waits until credentials have been received and saved.
+    ///
+    /// Only returns for credentials received while no station is attached.
+    pub async fn wait(&self) -> Credentials {
+        SUBMITTED.wait().await
+    }
+
+    /// Hands all credentials received from now on to `station`, which
+    /// connects to them right away.
+    pub fn attach_station(&self, station: StationHandle) {
+        STATION.lock(|cell| cell.set(Some(station)));
+        STATUS_CHANGED.signal(());
+    }
+}
+
+/// Status currently shown to the client
+fn status() -> ProvisioningStatus {
+    if let Some(e) = REJECTED.lock(Cell::get) {
+        return ProvisioningStatus::Rejected(e);
+    }
+    let last_reason = STATION
+        .lock(Cell::get)
+        .and_then(|station| station.last_disconnect_reason());
+    ProvisioningStatus::from_state(station::state(), last_reason)
+}
+
+/// Records the outcome of a write for the status characteristic.
+fn record_write(result: Result<(), WriteError>) {
+    if let Err(e) = result {
+        println!("BLE provisioning write rejected: {}", e);
+    }
+    REJECTED.lock(|cell| cell.set(result.err()));
+    STATUS_CHANGED.signal(());
+}
+
+/// Embassy task that advertises the device and serves the provisioning service.
+///
+/// # Arguments
+///
+/// * `connector` - HCI connection to the BLE controller
+/// * `name` - Advertised device name
+#[embassy_executor::task]
+async fn ble_task(connector: BleConnector<'static>, name: Ssid) {
+    let mut ble = Ble::new(connector, || Instant::now().as_millis());
+    loop {
+        if let Err(e) = advertise(&mut ble, &name).await {
+            println!("BLE advertising failed: {:?}", e);
+            Timer::after(RETRY_DELAY).await;
+            continue;
+        }
+        serve(&mut ble).await;
+    }
+}
+
+/// Resets the controller and starts advertising.
+async fn advertise(ble: &mut Ble<BleConnector<'static>>, name: &str) -> Result<(), bleps::Error> {
+    ble.init().await?;
+    ble.cmd_set_le_advertising_parameters().await?;
+    let data = create_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        AdStructure::CompleteLocalName(name),
+    ])
+    .expect("device name fits into the advertising data");
+    ble.cmd_set_le_advertising_data(data).await?;
+    ble.cmd_set_le_advertise_enable(true).await?;
+    Ok(())
+}
+
+/// Serves one connection until the client disconnects.
+async fn serve(ble: &mut Ble<BleConnector<'static>>) {
+    let session = RefCell::new(Session::new());
+    let networks = RefCell::new(([0u8; MAX_NETWORKS_LEN], 0usize));
+    REJECTED.lock(|cell| cell.set(None));
+
+    let mut networks_read = |offset: usize, data: &mut [u8]| {
+        let mut networks = networks.borrow_mut();
+        let (encoded, len) = &mut *networks;
+        // Take a fresh snapshot for every read that starts at the beginning,
+        // so that the reads of a long value see the same list
+        if offset == 0 {
+            let report = scanner::latest_scan();
+            *len = encode_networks(report.iter().flat_map(|report| report.iter()), encoded);
+        }
+        let rest = encoded[..*len].get(offset..).unwrap_or_default();
+        let n = rest.len().min(data.len());
+        data[..n].copy_from_slice(&rest[..n]);
+        n
+    };
+    let mut ssid_write = |offset: usize, data: &[u8]| {
+        record_write(session.borrow_mut().write_ssid(offset, data));
+    };
+    let mut password_write = |offset: usize, data: &[u8]| {
+        record_write(session.borrow_mut().write_password(offset, data));
+    };
+    let mut control_write = |_offset: usize, data: &[u8]| {
+        let result = session.borrow_mut().command(data);
+        if let Ok(Some(credentials)) = &result {
+            RECEIVED.signal(credentials.clone());
+        }
+        record_write(result.map(|_| ()));
+    };
+    let mut status_read = |_offset: usize, data: &mut [u8]| {
+        let encoded = status().encode();
+        let n = encoded.len().min(data.len());
+        data[..n].copy_from_slice(&encoded[..n]);
+        n
+    };
+
+    gatt!([service {
+        uuid: "5e1a0001-7c3b-4f52-9a4e-1c2d3e4f5a6b",
+        characteristics: [
+            characteristic {
+                uuid: "5e1a0002-7c3b-4f52-9a4e-1c2d3e4f5a6b",
+                read: networks_read,
+            },
+            characteristic {
+                uuid: "5e1a0003-7c3b-4f52-9a4e-1c2d3e4f5a6b",
+                write: ssid_write,
+            },
+            characteristic {
+                uuid: "5e1a0004-7c3b-4f52-9a4e-1c2d3e4f5a6b",
+                write: password_write,
+            },
+            characteristic {
+                uuid: "5e1a0005-7c3b-4f52-9a4e-1c2d3e4f5a6b",
+                write: control_write,
+            },
+            characteristic {
+                name: "status",
+                uuid: "5e1a0006-7c3b-4f52-9a4e-1c2d3e4f5a6b",
+                notify: true,
+                read: status_read,
+            },
+        ],
+    },]);
+
+    let mut rng = bleps::no_rng::NoRng;
+    let mut server = AttributeServer::new(ble, &mut gatt_attributes, &mut rng);
+
+    let last_status = Cell::new(status());
+    let mut notifier = || async move {
+        loop {
+            select(Timer::after(STATUS_POLL_INTERVAL), STATUS_CHANGED.wait()).await;
+            let current = status();
+            if last_status.replace(current) != current {
+                return NotificationData::new(status_handle, &current.encode());
+            }
+        }
+    };
+
+    match server.run(&mut notifier).await {
+        Ok(()) => println!("BLE client disconnected"),
+        Err(e) => println!("BLE connection failed: {:?}", e),
+    }
+}
+
+/// Embassy task that saves received credentials and hands them on.
+///
+/// # Arguments
+///
+/// * `store` - Configuration store the received network is saved to, if any
+#[embassy_executor::task]
+async fn credentials_task(store: Option<&'static SharedStore>) {
+    loop {
+        let credentials = RECEIVED.wait().await;
+        println!(
+            "BLE provisioning received credentials for {}",
+            credentials.ssid
+        );
+        let profile = NetworkProfile::new(credentials.clone());
+
+        if let Some(store) = store {
+            match store.lock().await {
+                Ok(mut store) => {
+                    let mut profiles = match store.load::<ProfileStore>() {
+                        Ok(profiles) => profiles.unwrap_or_default(),
+                        Err(e) => {
+                            println!("Failed to load network profiles: {}", e);
+                            ProfileStore::new()
+                        }
+                    };
+                    if profiles.insert(profile.clone()).is_err() {
+                        println!("No room for network profile {}", credentials.ssid);
+                    } else if let Err(e) = store.save(&profiles) {
+                        println!("Failed to save network profiles: {}", e);
+                    }
+                }
+                Err(e) => println!("Failed to mount config store: {}", e),
+            }
+        }
+
+        match STATION.lock(Cell::get) {
+            Some(station) => {
+                if station.add_profile(profile).is_err() {
+                    println!("No room for network profile {}", credentials.ssid);
+                }
+                station.connect().await;
+            }
+            None => SUBMITTED.signal(credentials),
+        }
+    }
+}
+
+/// Starts the BLE provisioning service.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the BLE tasks
+/// * `radio` - Radio driver from [`crate::radio::Radio::init`]
+/// * `device` - Bluetooth peripheral
+/// * `store` - Configuration store received networks are saved to, if any
+///
+/// # Errors
+///
+/// This function will return:
+/// - [`Error::BleInit`] if the BLE controller cannot be brought up
+/// - [`Error::Spawn`] if the service is already running
+pub fn start_ble_provisioning(
+    spawner: Spawner,
+    radio: &'static esp_radio::Controller<'static>,
+    device: BT<'static>,
+    store: Option<&'static SharedStore>,
+) -> Result<BleProvisioningHandle, Error> {
+    let connector =
+        BleConnector::new(radio, device, Default::default()).map_err(|_| Error::BleInit)?;
+    let name = device_name();
+
+    spawner.spawn(credentials_task(store))?;
+    spawner.spawn(ble_task(connector, name.clone()))?;
+
+    println!("BLE provisioning advertising as {}", name);
+    Ok(BleProvisioningHandle { _private: () })
+}
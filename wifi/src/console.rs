@@ -0,0 +1,481 @@
+//! Serial shell on UART0.
+//!
+//! [`start_console`] spawns a task that reads commands from the serial
+//! port, the one `espflash monitor` and other terminals connect to at
+//! 115200 baud:
+//!
+//! ```text
+//! wifi> scan interval 30
+//! Scanning every 30 s
+//! wifi> connect "Office WiFi" secret
+//! Joining Office WiFi
+//! wifi> config set channels [1,6,11]
+//! ```
+//!
+//! | Command                        | Effect                                        |
+//! |--------------------------------|-----------------------------------------------|
+//! | `help`                         | Lists the commands                            |
+//! | `scan`                         | Scans now and prints the access points        |
+//! | `scan interval <secs>`         | Sets the time between scans                   |
+//! | `connect <ssid> [password]`    | Joins a known network, or a new one           |
+//! | `disconnect`                   | Leaves the network and stops reconnecting     |
+//! | `ifconfig`                     | Prints the link, addresses and connection     |
+//! | `heap`                         | Prints heap usage, peaks and largest block    |
+//! | `tasks`                        | Prints the supervised tasks and their misses  |
+//! | `reboot`                       | Restarts the device                           |
+//! | `config get`                   | Prints the scanner configuration as JSON      |
+//! | `config set <key> <value>`     | Sets a field of the scanner configuration     |
+//!
+//! Configuration changes are saved to the configuration store, like those
+//! made through the HTTP API; `config set` takes the fields and values of
+//! `PUT /api/config`. The line editor keeps a history for the up and down
+//! keys. Log output shares the port and can land in the middle of a line
+//! being typed; Ctrl-U clears the line.
+//!
+//! Applications add commands of their own, or replace built-in ones, by
+//! passing them to [`start_console`]:
+//!
+//! ```no_run
+//! use core::fmt::Write as _;
+//! use wifi::console::{Command, Handler};
+//! use wifi::shell::{Args, ShellError};
+//!
+//! fn uptime(_args: &Args<'_>, out: &mut dyn core::fmt::Write) -> Result<(), ShellError> {
+//!     let _ = write!(out, "{} s\r\n", embassy_time::Instant::now().as_secs());
+//!     Ok(())
+//! }
+//!
+//! const COMMANDS: [Command<Handler>; 1] = [Command::new("uptime", "Prints the uptime", uptime)];
+//! ```
+
+use core::fmt::{self, Write as _};
+
+use embassy_executor::Spawner;
+use embassy_net::Stack;
+use embedded_io_async::Write as _;
+use esp_hal::Async;
+use esp_hal::system::software_reset;
+use esp_hal::uart::{Uart, UartTx};
+use esp_println::println;
+use wifi_core::api;
+pub use wifi_core::shell::Command;
+use wifi_core::shell::{self, Args, Edit, LineEditor, MAX_LINE_LEN, NEWLINE, Registry, ShellError};
+
+use crate::allocator;
+use crate::control::ScannerHandle;
+use crate::error::Error;
+use crate::http_server::{save_config, update_config};
+use crate::station::{Credentials, NetworkProfile, StationHandle};
+use crate::storage::SharedStore;
+use crate::supervisor;
+
+/// Maximum number of commands, built-in ones included
+pub const MAX_COMMANDS: usize = 24;
+
+/// Prompt shown before each line
+const PROMPT: &str = "wifi> ";
+
+/// Capacity of the output sent at once; a command's output beyond it is cut
+/// short
+const OUTPUT_CAPACITY: usize = 2048;
+
+/// Number of lines the line editor remembers
+const HISTORY_LEN: usize = 8;
+
+/// An application command: runs with the arguments after the command name
+/// and writes its output, with [`NEWLINE`] line ends, to the writer
+pub type Handler = fn(&Args<'_>, &mut dyn fmt::Write) -> Result<(), ShellError>;
+
+/// UART the console runs on
+pub type ConsoleUart = Uart<'static, Async>;
+
+/// What a command does
+#[derive(Clone, Copy)]
+enum Action {
+    Help,
+    Scan,
+    ScanInterval,
+    Connect,
+    Disconnect,
+    Ifconfig,
+    Heap,
+    Tasks,
+    Reboot,
+    ConfigGet,
+    ConfigSet,
+    Custom(Handler),
+}
+
+/// Commands every console has
+const BUILTIN_COMMANDS: [Command<Action>; 11] = [
+    Command::new("help", "List the commands", Action::Help),
+    Command::new("scan", "Scan now and print the access points", Action::Scan),
+    Command::new(
+        "scan interval",
+        "Set the time between scans",
+        Action::ScanInterval,
+    )
+    .with_args("<secs>", 1, 1),
+    Command::new(
+        "connect",
+        "Join a known network, or a new one",
+        Action::Connect,
+    )
+    .with_args("<ssid> [password]", 1, 2),
+    Command::new("disconnect", "Leave the network", Action::Disconnect),
+    Command::new("ifconfig", "Print the network interface", Action::Ifconfig),
+    Command::new("heap", "Print heap usage", Action::Heap),
+    Command::new("tasks", "Print the supervised tasks", Action::Tasks),
+    Command::new("reboot", "Restart the device", Action::Reboot),
+    Command::new(
+        "config get",
+        "Print the scanner configuration",
+        Action::ConfigGet,
+    ),
+    Command::new(
+        "config set",
+        "Set a scanner configuration field",
+        Action::ConfigSet,
+    )
+    .with_args("<key> <value>", 2, 2),
+];
+
+/// What the console reports on and controls
+#[derive(Clone, Copy)]
+struct ConsoleContext {
+    stack: Option<Stack<'static>>,
+    scanner: Option<ScannerHandle>,
+    station: Option<StationHandle>,
+    store: Option<&'static SharedStore>,
+}
+
+/// Output of a command, sent to the UART in pieces
+type Output = heapless::String<OUTPUT_CAPACITY>;
+
+/// Embassy task that reads lines from the UART and runs them.
+#[embassy_executor::task]
+async fn console_task(
+    uart: ConsoleUart,
+    registry: Registry<Action, MAX_COMMANDS>,
+    context: ConsoleContext,
+) {
+    let (mut rx, mut tx) = uart.split();
+    let mut editor = LineEditor::<MAX_LINE_LEN, HISTORY_LEN>::new(PROMPT);
+    let mut out = Output::new();
+    let mut input = [0u8; 32];
+
+    let _ = write!(out, "{NEWLINE}Type `help` for commands{NEWLINE}");
+    let _ = editor.write_prompt(&mut out);
+    loop {
+        send(&mut tx, &mut out).await;
+        let len = match rx.read_async(&mut input).await {
+            Ok(len) => len,
+            Err(e) => {
+                println!("Console input lost: {:?}", e);
+                continue;
+            }
+        };
+        for &byte in &input[..len] {
+            match editor.feed(byte, &mut out) {
+                Edit::Pending => {}
+                Edit::Cancelled => {
+                    let _ = editor.write_prompt(&mut out);
+                }
+                Edit::Line(line) => {
+                    send(&mut tx, &mut out).await;
+                    run(line, &registry, &context, &mut tx, &mut out).await;
+                    let _ = editor.write_prompt(&mut out);
+                }
+            }
+        }
+    }
+}
+
+/// Runs one line, writing its output to `out`.
+async fn run(
+    line: &str,
+    registry: &Registry<Action, MAX_COMMANDS>,
+    context: &ConsoleContext,
+    tx: &mut UartTx<'static, Async>,
+    out: &mut Output,
+) {
+    let result = match registry.parse(line) {
+        Ok(Some(invocation)) => {
+            let args = &invocation.args;
+            match invocation.command.action {
+                Action::Help => {
+                    let _ = registry.write_help(out);
+                    Ok(())
+                }
+                Action::Scan => scan(context, tx, out).await,
+                Action::ScanInterval => scan_interval(context, args, out).await,
+                Action::Connect => connect(context, args, out).await,
+                Action::Disconnect => disconnect(context, out).await,
+                Action::Ifconfig => ifconfig(context, out),
+                Action::Heap => {
+                    let _ = write!(out, "{}{NEWLINE}", allocator::heap_stats());
+                    Ok(())
+                }
+                Action::Tasks => {
+                    for task in supervisor::tasks() {
+                        let _ = write!(out, "{}{NEWLINE}", task);
+                    }
+                    Ok(())
+                }
+                Action::Reboot => {
+                    let _ = write!(out, "Restarting{NEWLINE}");
+                    send(tx, out).await;
+                    let _ = tx.flush_async().await;
+                    software_reset()
+                }
+                Action::ConfigGet => config_get(context, out),
+                Action::ConfigSet => config_set(context, args, out).await,
+                Action::Custom(handler) => handler(args, out),
+            }
+        }
+        Ok(None) => Ok(()),
+        Err(ShellError::Usage) => {
+            let command = Args::split(line)
+                .ok()
+                .and_then(|words| registry.lookup(&words));
+            if let Some(command) = command {
+                let _ = shell::write_usage(out, command);
+            }
+            Ok(())
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        let _ = write!(out, "error: {}{NEWLINE}", e);
+    }
+    send(tx, out).await;
+}
+
+/// Sends and clears `out`
+async fn send(tx: &mut UartTx<'static, Async>, out: &mut Output) {
+    if !out.is_empty() {
+        let _ = tx.write_all(out.as_bytes()).await;
+        out.clear();
+    }
+}
+
+/// Returns the scan task, or why it cannot be used
+fn scanner(context: &ConsoleContext) -> Result<ScannerHandle, ShellError> {
+    context
+        .scanner
+        .ok_or(ShellError::Failed("scanner is not running"))
+}
+
+/// Returns the station, or why it cannot be used
+fn station(context: &ConsoleContext) -> Result<StationHandle, ShellError> {
+    context
+        .station
+        .ok_or(ShellError::Failed("station is not running"))
+}
+
+/// `scan`: scans now and prints the access points, a line at a time
+async fn scan(
+    context: &ConsoleContext,
+    tx: &mut UartTx<'static, Async>,
+    out: &mut Output,
+) -> Result<(), ShellError> {
+    match scanner(context)?.scan_now().await {
+        Ok(report) => {
+            let _ = write!(out, "{}{NEWLINE}", report);
+            for ap in &report {
+                let _ = write!(out, "  {}{NEWLINE}", ap);
+                send(tx, out).await;
+            }
+        }
+        Err(e) => {
+            let _ = write!(out, "Scan failed: {}{NEWLINE}", e);
+        }
+    }
+    Ok(())
+}
+
+/// `scan interval <secs>`: sets the time between scans
+async fn scan_interval(
+    context: &ConsoleContext,
+    args: &Args<'_>,
+    out: &mut Output,
+) -> Result<(), ShellError> {
+    let scanner = scanner(context)?;
+    let interval_secs = args.parse(0, "interval")?;
+    match scanner.set_interval(interval_secs) {
+        Ok(()) => {
+            save_config(context.store, &scanner.config()).await;
+            let _ = write!(out, "Scanning every {} s{NEWLINE}", interval_secs);
+        }
+        Err(e) => {
+            let _ = write!(out, "Interval rejected: {}{NEWLINE}", e);
+        }
+    }
+    Ok(())
+}
+
+/// `connect <ssid> [password]`: joins a network, remembering it first if a
+/// password is given
+async fn connect(
+    context: &ConsoleContext,
+    args: &Args<'_>,
+    out: &mut Output,
+) -> Result<(), ShellError> {
+    let station = station(context)?;
+    let ssid = args.get(0).unwrap_or_default();
+    if let Some(password) = args.get(1) {
+        let credentials = Credentials::new(ssid, password)
+            .map_err(|_| ShellError::InvalidArgument("SSID or password"))?;
+        station
+            .add_profile(NetworkProfile::new(credentials))
+            .map_err(|_| ShellError::Failed("no room for another network"))?;
+        if let Some(store) = context.store
+            && let Err(e) = store
+                .lock()
+                .await
+                .and_then(|mut store| store.save(&station.profiles()))
+        {
+            let _ = write!(out, "Failed to save network profiles: {}{NEWLINE}", e);
+        }
+    }
+    let profile = station
+        .profiles()
+        .get(ssid)
+        .cloned()
+        .ok_or(ShellError::Failed(
+            "unknown network, give its password to add it",
+        ))?;
+    let _ = write!(out, "Joining {}{NEWLINE}", profile.credentials.ssid);
+    station.join(profile.credentials.ssid).await;
+    Ok(())
+}
+
+/// `disconnect`: leaves the network
+async fn disconnect(context: &ConsoleContext, out: &mut Output) -> Result<(), ShellError> {
+    station(context)?.disconnect().await;
+    let _ = write!(out, "Disconnecting{NEWLINE}");
+    Ok(())
+}
+
+/// `ifconfig`: prints the link, addresses and connection of the station
+fn ifconfig(context: &ConsoleContext, out: &mut Output) -> Result<(), ShellError> {
+    let stack = context
+        .stack
+        .ok_or(ShellError::Failed("network is not running"))?;
+    let _ = write!(
+        out,
+        "sta: link {}, MAC {}{NEWLINE}",
+        if stack.is_link_up() { "up" } else { "down" },
+        stack.hardware_address()
+    );
+    match stack.config_v4() {
+        Some(config) => {
+            let _ = write!(out, "  inet {}", config.address);
+            if let Some(gateway) = config.gateway {
+                let _ = write!(out, ", gateway {}", gateway);
+            }
+            for server in &config.dns_servers {
+                let _ = write!(out, ", dns {}", server);
+            }
+            let _ = out.write_str(NEWLINE);
+        }
+        None => {
+            let _ = write!(out, "  no address{NEWLINE}");
+        }
+    }
+    if let Some(station) = context.station {
+        let _ = write!(out, "  {}", station.state());
+        if let Some(ssid) = station.network() {
+            let _ = write!(out, " ({})", ssid);
+        }
+        let _ = out.write_str(NEWLINE);
+    }
+    Ok(())
+}
+
+/// `config get`: prints the scanner configuration
+fn config_get(context: &ConsoleContext, out: &mut Output) -> Result<(), ShellError> {
+    let _ = api::write_config(out, &scanner(context)?.config());
+    let _ = out.write_str(NEWLINE);
+    Ok(())
+}
+
+/// `config set <key> <value>`: sets one scanner configuration field
+async fn config_set(
+    context: &ConsoleContext,
+    args: &Args<'_>,
+    out: &mut Output,
+) -> Result<(), ShellError> {
+    let scanner = scanner(context)?;
+    let (key, value) = (
+        args.get(0).unwrap_or_default(),
+        args.get(1).unwrap_or_default(),
+    );
+    let mut body = heapless::String::<{ MAX_LINE_LEN + 16 }>::new();
+    shell::write_setting(&mut body, key, value)
+        .map_err(|_| ShellError::InvalidArgument("value"))?;
+    match update_config(scanner, body.as_bytes()) {
+        Ok(config) => {
+            save_config(context.store, &config).await;
+            let _ = api::write_config(out, &config);
+            let _ = out.write_str(NEWLINE);
+        }
+        Err(e) => {
+            let _ = write!(out, "Configuration rejected: {}{NEWLINE}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Starts the serial shell on `uart`.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the console task
+/// * `uart` - UART0 in async mode, on the pins of the USB serial bridge
+/// * `stack` - Station network stack `ifconfig` reports on, if running
+/// * `scanner` - Scan task the scan and configuration commands use, if running
+/// * `station` - Station `connect` and `disconnect` control, if running
+/// * `store` - Configuration store changes are saved to, if any
+/// * `commands` - Commands of the application, added to or replacing the
+///   built-in ones
+///
+/// Commands beyond [`MAX_COMMANDS`] are left out with a warning.
+///
+/// # Errors
+///
+/// Returns [`Error::Spawn`] if the console is already running.
+pub fn start_console(
+    spawner: Spawner,
+    uart: ConsoleUart,
+    stack: Option<Stack<'static>>,
+    scanner: Option<ScannerHandle>,
+    station: Option<StationHandle>,
+    store: Option<&'static SharedStore>,
+    commands: &[Command<Handler>],
+) -> Result<(), Error> {
+    let mut registry = Registry::new();
+    let custom = commands.iter().map(|command| Command {
+        name: command.name,
+        usage: command.usage,
+        help: command.help,
+        min_args: command.min_args,
+        max_args: command.max_args,
+        action: Action::Custom(command.action),
+    });
+    for command in BUILTIN_COMMANDS.into_iter().chain(custom) {
+        if let Err(command) = registry.register(command) {
+            println!("No room for console command {}", command.name);
+        }
+    }
+
+    let context = ConsoleContext {
+        stack,
+        scanner,
+        station,
+        store,
+    };
+    spawner.spawn(console_task(uart, registry, context))?;
+    println!("Console running on UART0");
+    Ok(())
+}
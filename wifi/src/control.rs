@@ -0,0 +1,207 @@
+//! Runtime control of the scan task.
+//!
+//! [`wifi_scanner`](crate::scanner::wifi_scanner) returns a [`ScannerHandle`]
+//! that changes the scan task's behavior while it runs. Configuration updates
+//! are delivered through a [`Watch`], so the task always picks up the newest
+//! configuration, even if several updates arrive while it is scanning.
+//! Pause, resume, stop and on-demand scans are sent as commands over a
+//! bounded channel and handled in order.
+//!
+//! ```no_run
+//! # async fn example(scanner: wifi::control::ScannerHandle) {
+//! // Only scan when asked to
+//! scanner.pause().await;
+//! if let Ok(report) = scanner.scan_now().await {
+//!     esp_println::println!("{}", report);
+//! }
+//! # }
+//! ```
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::{Receiver, Watch};
+use wifi_core::report::ScanReport;
+use wifi_core::scan_config::{ConfigError, ScannerConfig};
+
+use crate::error::Error;
+
+/// Only the scan task receives configuration updates
+const MAX_CONFIG_RECEIVERS: usize = 1;
+
+/// Number of commands that can be queued before senders wait
+const COMMAND_QUEUE_DEPTH: usize = 4;
+
+/// Current scanner configuration, written by [`ScannerHandle`] and read by the scan task.
+static SCANNER_CONFIG: Watch<CriticalSectionRawMutex, ScannerConfig, MAX_CONFIG_RECEIVERS> =
+    Watch::new();
+
+/// Commands from [`ScannerHandle`] to the scan task.
+static COMMANDS: Channel<CriticalSectionRawMutex, ScannerCommand, COMMAND_QUEUE_DEPTH> =
+    Channel::new();
+
+/// Result of the on-demand scan currently in flight.
+static SCAN_RESPONSE: Signal<CriticalSectionRawMutex, Result<ScanReport, Error>> =
+    Signal::new();
+
+/// Serializes on-demand scans, since there is a single response slot.
+static SCAN_REQUEST_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+/// Lifecycle state of the scan task.
+static STATE: BlockingMutex<CriticalSectionRawMutex, Cell<ScannerState>> =
+    BlockingMutex::new(Cell::new(ScannerState::Running));
+
+/// Receiving end of the configuration updates, owned by the scan task.
+pub(crate) type ConfigReceiver =
+    Receiver<'static, CriticalSectionRawMutex, ScannerConfig, MAX_CONFIG_RECEIVERS>;
+
+/// Lifecycle state of the scan task
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ScannerState {
+    /// Scanning periodically
+    Running,
+    /// Periodic scans are suspended; on-demand scans still run
+    Paused,
+    /// The scan task has exited
+    Stopped,
+}
+
+/// Command sent to the scan task
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub(crate) enum ScannerCommand {
+    /// Suspend periodic scans
+    Pause,
+    /// Resume periodic scans, starting with an immediate scan
+    Resume,
+    /// Exit the scan task
+    Stop,
+    /// Run one scan now and answer through [`SCAN_RESPONSE`]
+    ScanNow,
+}
+
+/// Handle for controlling a running scan task.
+///
+/// The handle is cheap to copy and can be passed to any task.
+#[derive(Clone, Copy, Debug)]
+pub struct ScannerHandle {
+    _private: (),
+}
+
+impl ScannerHandle {
+    /// Stores the initial configuration and takes the task's receiver.
+    ///
+    /// Returns `None` if the receiver has already been taken.
+    pub(crate) fn init(config: ScannerConfig) -> Option<(Self, ConfigReceiver)> {
+        let receiver = SCANNER_CONFIG.receiver()?;
+        SCANNER_CONFIG.sender().send(config);
+        Some((Self { _private: () }, receiver))
+    }
+
+    /// Returns the configuration currently in use.
+    pub fn config(&self) -> ScannerConfig {
+        SCANNER_CONFIG.try_get().unwrap_or_default()
+    }
+
+    /// Replaces the scanner configuration.
+    ///
+    /// The new configuration is used from the next scan on. A changed
+    /// interval also applies to the wait that is in progress.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ConfigError`] if the configuration is invalid; the current
+    /// configuration is then left unchanged.
+    pub fn set_config(&self, config: ScannerConfig) -> Result<(), ConfigError> {
+        config.validate()?;
+        SCANNER_CONFIG.sender().send(config);
+        Ok(())
+    }
+
+    /// Modifies the current configuration in place.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ConfigError`] if the modified configuration is invalid.
+    pub fn update_config(&self, f: impl FnOnce(&mut ScannerConfig)) -> Result<(), ConfigError> {
+        let mut config = self.config();
+        f(&mut config);
+        self.set_config(config)
+    }
+
+    /// Changes the time between periodic scans.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ConfigError::ZeroInterval`] if `interval_secs` is zero.
+    pub fn set_interval(&self, interval_secs: u32) -> Result<(), ConfigError> {
+        self.update_config(|config| config.interval_secs = interval_secs)
+    }
+
+    /// Returns the lifecycle state of the scan task.
+    pub fn state(&self) -> ScannerState {
+        state()
+    }
+
+    /// Suspends periodic scans. On-demand scans keep working.
+    pub async fn pause(&self) {
+        COMMANDS.send(ScannerCommand::Pause).await;
+    }
+
+    /// Resumes periodic scans, starting with an immediate scan.
+    pub async fn resume(&self) {
+        COMMANDS.send(ScannerCommand::Resume).await;
+    }
+
+    /// Stops the scan task. It cannot be restarted.
+    pub async fn stop(&self) {
+        COMMANDS.send(ScannerCommand::Stop).await;
+    }
+
+    /// Runs a scan right away and waits for its report.
+    ///
+    /// The report is also published to subscribers. Commands are handled in
+    /// order, so a scan that is already running completes first.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::ScannerStopped`] if the scan task has exited, or
+    /// [`Error::Scan`] if the driver failed.
+    pub async fn scan_now(&self) -> Result<ScanReport, Error> {
+        let _guard = SCAN_REQUEST_LOCK.lock().await;
+
+        SCAN_RESPONSE.reset();
+        if state() == ScannerState::Stopped {
+            return Err(Error::ScannerStopped);
+        }
+        COMMANDS.send(ScannerCommand::ScanNow).await;
+        SCAN_RESPONSE.wait().await
+    }
+}
+
+/// Waits for the next command from a [`ScannerHandle`].
+pub(crate) async fn next_command() -> ScannerCommand {
+    COMMANDS.receive().await
+}
+
+/// Answers the pending [`ScannerHandle::scan_now`] call.
+pub(crate) fn respond(result: Result<ScanReport, Error>) {
+    SCAN_RESPONSE.signal(result);
+}
+
+/// Records the lifecycle state of the scan task.
+///
+/// Entering [`ScannerState::Stopped`] also fails any on-demand scan still waiting.
+pub(crate) fn set_state(state: ScannerState) {
+    STATE.lock(|cell| cell.set(state));
+    if state == ScannerState::Stopped {
+        respond(Err(Error::ScannerStopped));
+    }
+}
+
+fn state() -> ScannerState {
+    STATE.lock(|cell| cell.get())
+}
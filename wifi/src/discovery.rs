@@ -0,0 +1,175 @@
+//! mDNS responder for finding devices on the network.
+//!
+//! [`start_discovery`] spawns a task that answers mDNS queries for
+//! `<device-name>.local` and advertises two DNS-SD services on port 80:
+//!
+//! - `_http._tcp`, so browsers and generic tools list the device
+//! - `_espscan._tcp`, so host tools can find scanners only, with the
+//!   firmware version, chip and MAC address in its `TXT` record
+//!
+//! ```text
+//! avahi-browse -rt _espscan._tcp
+//! dns-sd -B _espscan._tcp
+//! curl http://espscan-a1b2c3.local/api/status
+//! ```
+//!
+//! Each time the station gets an address, the task announces the device
+//! twice, one second apart. The packets are built by the host-tested
+//! responder in `wifi_core`.
+
+use core::fmt::Write as _;
+
+use embassy_executor::Spawner;
+use embassy_futures::select::select;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address, Stack};
+use embassy_time::{Duration, Timer};
+use esp_println::println;
+use wifi_core::mdns::{MDNS_GROUP, MDNS_PORT, Responder, Service};
+use wifi_core::report::Bssid;
+
+use crate::error::Error;
+use crate::http_client::HTTP_PORT;
+
+/// Prefix of the device name when none is configured; the last three bytes
+/// of the MAC address follow it
+pub const DEFAULT_NAME_PREFIX: &str = "espscan";
+
+/// Chip reported in the `TXT` record
+const CHIP: &str = "esp32";
+
+/// Time between the two announcements after the address changes
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest mDNS packet handled; a full announcement takes about 600 bytes
+const PACKET_CAPACITY: usize = 1024;
+
+/// Embassy task that answers mDNS queries on `stack`.
+///
+/// # Arguments
+///
+/// * `stack` - Station network stack
+/// * `name` - Device name, or `None` for one made from the MAC address
+/// * `mac` - MAC address of the station interface
+#[embassy_executor::task]
+async fn mdns_task(stack: Stack<'static>, name: Option<&'static str>, mac: [u8; 6]) {
+    let mut default_name = heapless::String::<32>::new();
+    let name = match name {
+        Some(name) => name,
+        None => {
+            let _ = write!(
+                default_name,
+                "{}-{:02x}{:02x}{:02x}",
+                DEFAULT_NAME_PREFIX, mac[3], mac[4], mac[5]
+            );
+            default_name.as_str()
+        }
+    };
+    let mut mac_text = heapless::String::<17>::new();
+    let _ = write!(mac_text, "{}", Bssid(mac));
+    let txt = [
+        ("version", env!("CARGO_PKG_VERSION")),
+        ("chip", CHIP),
+        ("mac", mac_text.as_str()),
+    ];
+    let services = [
+        Service {
+            service_type: "_http._tcp",
+            port: HTTP_PORT,
+            txt: &[("path", "/api/status")],
+        },
+        Service {
+            service_type: "_espscan._tcp",
+            port: HTTP_PORT,
+            txt: &txt,
+        },
+    ];
+    let responder = Responder::new(name, &services);
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0u8; 2 * PACKET_CAPACITY];
+    let mut tx_buffer = [0u8; 2 * PACKET_CAPACITY];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(MDNS_PORT) {
+        println!("mDNS responder failed to bind: {:?}", e);
+        return;
+    }
+    if let Err(e) = stack.join_multicast_group(Ipv4Address::from(MDNS_GROUP)) {
+        println!("mDNS responder failed to join the multicast group: {:?}", e);
+        return;
+    }
+
+    let group = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::from(MDNS_GROUP)), MDNS_PORT);
+    let mut query = [0u8; PACKET_CAPACITY];
+    let mut response = [0u8; PACKET_CAPACITY];
+    loop {
+        stack.wait_config_up().await;
+        let Some(config) = stack.config_v4() else {
+            stack.wait_config_down().await;
+            continue;
+        };
+        let address = config.address.address().octets();
+        println!("mDNS: answering for {}.local", name);
+
+        for _ in 0..2 {
+            if let Some(len) = responder.announce(address, &mut response)
+                && let Err(e) = socket.send_to(&response[..len], group).await
+            {
+                println!("mDNS announcement failed: {:?}", e);
+            }
+            Timer::after(ANNOUNCE_INTERVAL).await;
+        }
+
+        let serve = async {
+            loop {
+                let Ok((len, meta)) = socket.recv_from(&mut query).await else {
+                    continue;
+                };
+                let legacy_unicast = meta.endpoint.port != MDNS_PORT;
+                let Some(answer) =
+                    responder.answer(&query[..len], address, legacy_unicast, &mut response)
+                else {
+                    continue;
+                };
+                let destination = if answer.unicast { meta.endpoint } else { group };
+                if let Err(e) = socket.send_to(&response[..answer.len], destination).await {
+                    println!("mDNS response failed: {:?}", e);
+                }
+            }
+        };
+        // Start over with the new address when the current one goes away
+        select(serve, stack.wait_config_down()).await;
+    }
+}
+
+/// Starts answering mDNS queries on `stack`.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the responder task
+/// * `stack` - Station network stack, from [`crate::net::start_network`]
+/// * `name` - Device name, a single DNS label; `None` makes one from
+///   [`DEFAULT_NAME_PREFIX`] and the MAC address
+/// * `mac` - MAC address of the station interface, reported in the `TXT` record
+///
+/// The stack needs a free socket for the task.
+///
+/// # Errors
+///
+/// Returns [`Error::Spawn`] if the responder is already running.
+pub fn start_discovery(
+    spawner: Spawner,
+    stack: Stack<'static>,
+    name: Option<&'static str>,
+    mac: [u8; 6],
+) -> Result<(), Error> {
+    spawner.spawn(mdns_task(stack, name, mac))?;
+    Ok(())
+}
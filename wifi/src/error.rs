@@ -0,0 +1,188 @@
+//! Error type for the WiFi library.
+//!
+//! Each stage of bringing up and running the scanner has its own [`Error`]
+//! variant, carrying the underlying driver or executor error where there is one.
+
+use core::fmt;
+
+use embassy_executor::SpawnError;
+use embassy_net::dns;
+use embassy_net::tcp::{self, ConnectError};
+use embassy_net::udp::SendError;
+use esp_bootloader_esp_idf::partitions;
+use esp_radio::InitializationError;
+use esp_radio::wifi::WifiError;
+use wifi_core::flash_kv::StoreError;
+use wifi_core::http_client::ClientError;
+use wifi_core::mqtt::MqttError;
+use wifi_core::net_config::NetConfigError;
+use wifi_core::ota::OtaError;
+use wifi_core::scan_config::ConfigError;
+use wifi_core::sntp::SntpError;
+use wifi_core::task_watchdog::WatchdogError;
+
+use crate::events::SubscribeError;
+
+/// Errors reported by the WiFi library
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub enum Error {
+    /// The scanner configuration was rejected
+    InvalidConfig(ConfigError),
+    /// The scanner has already been started
+    AlreadyInitialized,
+    /// The radio could not be initialized
+    RadioInit(InitializationError),
+    /// The WiFi controller could not be created
+    ControllerCreation(WifiError),
+    /// The WiFi mode could not be set
+    SetMode(WifiError),
+    /// The WiFi controller could not be started
+    Start(WifiError),
+    /// A background task could not be spawned
+    Spawn(SpawnError),
+    /// No subscriber slot was free on a channel
+    Subscribe(SubscribeError),
+    /// A scan failed
+    Scan(WifiError),
+    /// The scan task has been stopped
+    ScannerStopped,
+    /// The partition table could not be read or lacks a needed partition
+    PartitionTable(partitions::Error),
+    /// The configuration store failed
+    Storage(StoreError),
+    /// The provisioning access point could not be configured
+    AccessPoint(WifiError),
+    /// The BLE controller could not be initialized
+    BleInit,
+    /// A static IP configuration was rejected
+    NetConfig(NetConfigError),
+    /// A network operation did not finish in time
+    Timeout,
+    /// A host name could not be resolved
+    Dns(dns::Error),
+    /// A TCP connection could not be opened
+    Connect(ConnectError),
+    /// An HTTP request failed
+    Http(ClientError<tcp::Error>),
+    /// An MQTT session failed
+    Mqtt(MqttError<tcp::Error>),
+    /// A UDP datagram could not be sent
+    Udp(SendError),
+    /// A time server sent an unusable answer
+    Sntp(SntpError),
+    /// A URL is not a plain `http://` URL
+    InvalidUrl,
+    /// A server answered an HTTP request with this error status
+    HttpStatus(u16),
+    /// A firmware update or the OTA data partition failed
+    Ota(OtaError),
+    /// A task could not be registered with the supervisor
+    Supervisor(WatchdogError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidConfig(e) => write!(f, "invalid scanner configuration: {}", e),
+            Error::AlreadyInitialized => f.write_str("WiFi scanner is already running"),
+            Error::RadioInit(e) => write!(f, "failed to initialize radio controller: {}", e),
+            Error::ControllerCreation(e) => write!(f, "failed to create WiFi controller: {}", e),
+            Error::SetMode(e) => write!(f, "failed to set Wi-Fi mode: {}", e),
+            Error::Start(e) => write!(f, "failed to start Wi-Fi controller: {}", e),
+            Error::Spawn(e) => write!(f, "failed to spawn task: {}", e),
+            Error::Subscribe(e) => write!(f, "failed to subscribe: {:?}", e),
+            Error::Scan(e) => write!(f, "WiFi scan failed: {}", e),
+            Error::ScannerStopped => f.write_str("WiFi scanner is stopped"),
+            Error::PartitionTable(e) => write!(f, "partition table error: {}", e),
+            Error::Storage(e) => write!(f, "configuration store error: {}", e),
+            Error::AccessPoint(e) => write!(f, "failed to configure access point: {}", e),
+            Error::BleInit => f.write_str("failed to initialize BLE controller"),
+            Error::NetConfig(e) => write!(f, "invalid network configuration: {}", e),
+            Error::Timeout => f.write_str("network operation timed out"),
+            Error::Dns(e) => write!(f, "DNS lookup failed: {:?}", e),
+            Error::Connect(e) => write!(f, "TCP connection failed: {:?}", e),
+            Error::Http(e) => write!(f, "HTTP request failed: {}", e),
+            Error::Mqtt(e) => write!(f, "MQTT session failed: {}", e),
+            Error::Udp(e) => write!(f, "UDP send failed: {:?}", e),
+            Error::Sntp(e) => write!(f, "time synchronization failed: {}", e),
+            Error::InvalidUrl => f.write_str("invalid URL"),
+            Error::HttpStatus(status) => write!(f, "server answered with status {}", status),
+            Error::Ota(e) => write!(f, "OTA update failed: {}", e),
+            Error::Supervisor(e) => write!(f, "cannot supervise task: {}", e),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        Error::InvalidConfig(e)
+    }
+}
+
+impl From<InitializationError> for Error {
+    fn from(e: InitializationError) -> Self {
+        Error::RadioInit(e)
+    }
+}
+
+impl From<SpawnError> for Error {
+    fn from(e: SpawnError) -> Self {
+        Error::Spawn(e)
+    }
+}
+
+impl From<StoreError> for Error {
+    fn from(e: StoreError) -> Self {
+        Error::Storage(e)
+    }
+}
+
+impl From<NetConfigError> for Error {
+    fn from(e: NetConfigError) -> Self {
+        Error::NetConfig(e)
+    }
+}
+
+impl From<dns::Error> for Error {
+    fn from(e: dns::Error) -> Self {
+        Error::Dns(e)
+    }
+}
+
+impl From<ConnectError> for Error {
+    fn from(e: ConnectError) -> Self {
+        Error::Connect(e)
+    }
+}
+
+impl From<ClientError<tcp::Error>> for Error {
+    fn from(e: ClientError<tcp::Error>) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<MqttError<tcp::Error>> for Error {
+    fn from(e: MqttError<tcp::Error>) -> Self {
+        Error::Mqtt(e)
+    }
+}
+
+impl From<SntpError> for Error {
+    fn from(e: SntpError) -> Self {
+        Error::Sntp(e)
+    }
+}
+
+impl From<OtaError> for Error {
+    fn from(e: OtaError) -> Self {
+        Error::Ota(e)
+    }
+}
+
+impl From<WatchdogError> for Error {
+    fn from(e: WatchdogError) -> Self {
+        Error::Supervisor(e)
+    }
+}
@@ -0,0 +1,175 @@
+//! Distribution of scan results to other tasks.
+//!
+//! Every completed scan is published to [`SCAN_RESULTS`], a bounded
+//! publish/subscribe channel, and stored in [`LATEST_SCAN`]. Any number of
+//! tasks up to [`MAX_SCAN_SUBSCRIBERS`] can [`subscribe`] without the scanner
+//! knowing about them.
+//!
+//! The firmware's own tasks take [`BUILTIN_SCAN_SUBSCRIBERS`] of the slots:
+//! the access point tracker, the channel survey, the rogue access point
+//! detector and the MQTT publisher, one each. The remaining
+//! [`APP_SCAN_SUBSCRIBERS`] are free for the application, e.g. a display or
+//! a logger.
+//!
+//! The channel only holds [`SCAN_QUEUE_DEPTH`] reports. The scanner never waits
+//! for slow subscribers: when the queue is full the oldest report is dropped,
+//! and a subscriber that had not read it yet gets a [`ScanUpdate::Missed`] with
+//! the number of reports it lost. Subscribers that only care about the newest
+//! scan can use [`ScanSubscriber::latest`], which skips over old reports.
+//!
+//! Failed scans are published too, as [`ScanUpdate::Failed`], so subscribers
+//! can tell a broken radio from an empty neighborhood.
+//!
+//! ```no_run
+//! use wifi::events::{self, ScanUpdate};
+//!
+//! #[embassy_executor::task]
+//! async fn logger_task() {
+//!     let mut scans = events::subscribe().unwrap();
+//!     loop {
+//!         match scans.next().await {
+//!             ScanUpdate::Report(report) => esp_println::println!("{}", report),
+//!             ScanUpdate::Missed(n) => esp_println::println!("missed {} scans", n),
+//!             ScanUpdate::Failed(e) => esp_println::println!("{}", e),
+//!         }
+//!     }
+//! }
+//! ```
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber, WaitResult};
+use embassy_sync::watch::Watch;
+use wifi_core::report::ScanReport;
+
+use crate::error::Error;
+
+pub use embassy_sync::pubsub::Error as SubscribeError;
+
+/// Number of reports buffered for subscribers before the oldest is dropped
+pub const SCAN_QUEUE_DEPTH: usize = 2;
+
+/// Scan subscribers used by the firmware's own tasks
+pub const BUILTIN_SCAN_SUBSCRIBERS: usize = 4;
+
+/// Scan subscribers left for the application
+pub const APP_SCAN_SUBSCRIBERS: usize = 4;
+
+/// Maximum number of concurrent scan subscribers
+pub const MAX_SCAN_SUBSCRIBERS: usize = BUILTIN_SCAN_SUBSCRIBERS + APP_SCAN_SUBSCRIBERS;
+
+/// Maximum number of receivers on [`LATEST_SCAN`]
+pub const MAX_LATEST_SCAN_RECEIVERS: usize = 2;
+
+/// Only the scan task publishes, and it uses the immediate publisher
+const MAX_SCAN_PUBLISHERS: usize = 1;
+
+/// Channel carrying the outcome of every scan.
+pub static SCAN_RESULTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    Result<ScanReport, Error>,
+    SCAN_QUEUE_DEPTH,
+    MAX_SCAN_SUBSCRIBERS,
+    MAX_SCAN_PUBLISHERS,
+> = PubSubChannel::new();
+
+/// Most recent scan report.
+///
+/// Receivers can await changes; [`Watch::try_get`] reads the current value.
+pub static LATEST_SCAN: Watch<CriticalSectionRawMutex, ScanReport, MAX_LATEST_SCAN_RECEIVERS> =
+    Watch::new();
+
+/// Update delivered to a [`ScanSubscriber`]
+#[allow(
+    clippy::large_enum_variant,
+    reason = "reports are passed by value so subscribers need no heap"
+)]
+#[derive(Clone, Debug, defmt::Format)]
+pub enum ScanUpdate {
+    /// A completed scan
+    Report(ScanReport),
+    /// The subscriber fell behind and this many scans were dropped
+    Missed(u64),
+    /// A scan failed
+    Failed(Error),
+}
+
+/// Subscription to the scan results channel.
+///
+/// Dropping the subscriber frees its slot.
+pub struct ScanSubscriber {
+    inner: Subscriber<
+        'static,
+        CriticalSectionRawMutex,
+        Result<ScanReport, Error>,
+        SCAN_QUEUE_DEPTH,
+        MAX_SCAN_SUBSCRIBERS,
+        MAX_SCAN_PUBLISHERS,
+    >,
+}
+
+impl ScanSubscriber {
+    /// Waits for the next update, reporting lost scans explicitly.
+    pub async fn next(&mut self) -> ScanUpdate {
+        self.inner.next_message().await.into()
+    }
+
+    /// Returns the next update if one is already queued.
+    pub fn try_next(&mut self) -> Option<ScanUpdate> {
+        self.inner.try_next_message().map(Into::into)
+    }
+
+    /// Waits for a report and returns the newest one queued.
+    ///
+    /// Older queued reports, failures and lag notifications are discarded.
+    pub async fn latest(&mut self) -> ScanReport {
+        let mut latest = None;
+        loop {
+            while let Some(message) = self.inner.try_next_message_pure() {
+                if let Ok(report) = message {
+                    latest = Some(report);
+                }
+            }
+            if let Some(report) = latest {
+                return report;
+            }
+            if let Ok(report) = self.inner.next_message_pure().await {
+                latest = Some(report);
+            }
+        }
+    }
+}
+
+impl From<WaitResult<Result<ScanReport, Error>>> for ScanUpdate {
+    fn from(result: WaitResult<Result<ScanReport, Error>>) -> Self {
+        match result {
+            WaitResult::Message(Ok(report)) => ScanUpdate::Report(report),
+            WaitResult::Message(Err(error)) => ScanUpdate::Failed(error),
+            WaitResult::Lagged(missed) => ScanUpdate::Missed(missed),
+        }
+    }
+}
+
+/// Subscribes to scan results.
+///
+/// Only scans completed after this call are delivered.
+///
+/// # Errors
+///
+/// Returns [`SubscribeError::MaximumSubscribersReached`] if all
+/// [`MAX_SCAN_SUBSCRIBERS`] slots are in use.
+pub fn subscribe() -> Result<ScanSubscriber, SubscribeError> {
+    SCAN_RESULTS
+        .subscriber()
+        .map(|inner| ScanSubscriber { inner })
+}
+
+/// Publishes a completed scan to all subscribers without waiting.
+pub(crate) fn publish(report: ScanReport) {
+    LATEST_SCAN.sender().send(report.clone());
+    SCAN_RESULTS.immediate_publisher().publish_immediate(Ok(report));
+}
+
+/// Publishes a failed scan to all subscribers without waiting.
+pub(crate) fn publish_error(error: Error) {
+    SCAN_RESULTS.immediate_publisher().publish_immediate(Err(error));
+}
@@ -0,0 +1,329 @@
+//! Over-the-air firmware updates.
+//!
+//! The flash holds two application slots, `ota_0` and `ota_1` (see
+//! `partitions.csv`). [`start_updater`] spawns a task that downloads a new
+//! image over HTTP into the slot that is not running, checks its size,
+//! checksums and Ed25519 signature while it streams in, switches the
+//! bootloader over to it and restarts. Images are signed with the
+//! `ota_sign` tool, and the firmware is built with its public key in
+//! `OTA_PUBLIC_KEY`. An update is started through the API:
+//!
+//! ```text
+//! espflash save-image --chip esp32 target/xtensa-esp32-none-elf/release/wifi wifi.bin
+//! (cd ../ota_sign && cargo run --release -- sign ~/ota.key ../wifi/wifi.bin ../wifi/wifi.signed.bin)
+//! python3 -m http.server 8000
+//! curl -X POST -d '{"url": "http://192.168.1.10:8000/wifi.signed.bin"}' http://192.168.1.50/api/ota
+//! ```
+//!
+//! Nothing from the download is selected for booting unless the signature
+//! matches; an image signed with another key, or not at all, is rejected
+//! once its end arrives.
+//!
+//! A new image boots on trial. [`check_boot`] runs early on every boot and
+//! counts the trial boots; the image has [`HEALTH_TIMEOUT`] to call
+//! [`mark_healthy`], otherwise it is marked invalid and the device restarts
+//! into the previous image. So does an image that resets before it gets
+//! there, after [`MAX_TRIAL_BOOTS`] boots.
+//!
+//! The updater shares the SPI flash with the configuration store, see
+//! [`crate::storage::init_flash`]. It holds the flash while an image is
+//! downloaded, so settings saved meanwhile wait for the update to finish.
+//!
+//! Flashing with `probe-rs run` writes `ota_0` but leaves the OTA data alone;
+//! erase it (`espflash erase-parts --partition-table partitions.csv otadata`)
+//! if a flashed image should win over an earlier update in `ota_1`.
+
+use embassy_executor::Spawner;
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer, with_timeout};
+use esp_bootloader_esp_idf::partitions::{self, PARTITION_TABLE_MAX_LEN};
+use esp_hal::system::software_reset;
+use esp_println::println;
+use esp_storage::FlashStorage;
+use wifi_core::api::MAX_URL_LEN;
+use wifi_core::app_image::{CHIP_ID_ESP32, ImageError, ImageInfo, SIGNATURE_BLOCK_LEN};
+use wifi_core::ed25519::VerifyingKey;
+use wifi_core::flash_kv::Partition;
+use wifi_core::http_client::Url;
+use wifi_core::ota::{
+    BootCheck, MAX_TRIAL_BOOTS, OtaData, OtaError, PartitionInfo, PartitionLayout, Region, Slot,
+    SlotWriter,
+};
+
+use crate::error::Error;
+use crate::http_client::{HttpClient, Request};
+use crate::storage::SharedFlash;
+
+/// Time a new image gets to call [`mark_healthy`] before it is rolled back
+pub const HEALTH_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Time the image server gets for each step of the download
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes between two progress messages
+const PROGRESS_STEP: u32 = 128 * 1024;
+
+/// Time the last log messages get before a restart
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+
+/// A partition of the locked SPI flash
+type FlashPartition<'a> = Partition<&'a mut FlashStorage<'static>>;
+
+/// URL of the image to install next
+static UPDATE_REQUEST: Signal<CriticalSectionRawMutex, heapless::String<MAX_URL_LEN>> =
+    Signal::new();
+
+/// Signalled once the running image is healthy
+static HEALTHY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Reads where the OTA partitions are and which slot is running.
+///
+/// The slot is `None` if the running image was not booted from one.
+fn read_layout(
+    flash: &mut FlashStorage<'static>,
+) -> Result<(PartitionLayout, Option<Slot>), Error> {
+    let mut table = [0u8; PARTITION_TABLE_MAX_LEN];
+    let partitions =
+        partitions::read_partition_table(flash, &mut table).map_err(Error::PartitionTable)?;
+    let layout = PartitionLayout::find(partitions.iter().map(|entry| PartitionInfo {
+        kind: entry.raw_type(),
+        subtype: entry.raw_subtype(),
+        region: Region {
+            offset: entry.offset(),
+            size: entry.len(),
+        },
+    }))?;
+    let booted = partitions
+        .booted_partition()
+        .map_err(Error::PartitionTable)?
+        .and_then(|entry| layout.slot_at(entry.offset()));
+    Ok((layout, booted))
+}
+
+/// Opens a region of the flash.
+fn open_partition<'a>(
+    flash: &'a mut FlashStorage<'static>,
+    region: Region,
+) -> Result<FlashPartition<'a>, Error> {
+    Partition::new(flash, region.offset, region.size).map_err(|e| Error::Ota(OtaError::Flash(e)))
+}
+
+/// Opens the OTA data partition and reads which slot is running.
+fn open_otadata<'a>(
+    flash: &'a mut FlashStorage<'static>,
+) -> Result<(OtaData<FlashPartition<'a>>, Option<Slot>), Error> {
+    let (layout, booted) = read_layout(flash)?;
+    let otadata = OtaData::new(open_partition(flash, layout.otadata)?)?;
+    Ok((otadata, booted))
+}
+
+/// Checks whether the running image is on trial, and rolls it back if it
+/// used up its trial boots.
+///
+/// Call this early on every boot; it spawns the task that waits for
+/// [`mark_healthy`] when the image is on trial, and does not return if the
+/// image is rolled back.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the health check task
+/// * `flash` - Shared SPI flash, from [`crate::storage::init_flash`]
+///
+/// # Errors
+///
+/// This function will return:
+/// - [`Error::PartitionTable`] if the partition table cannot be read
+/// - [`Error::Ota`] if the OTA partitions are missing or the flash fails
+/// - [`Error::Spawn`] if the health check task is already running
+pub async fn check_boot(spawner: Spawner, flash: &'static SharedFlash) -> Result<(), Error> {
+    let mut locked = flash.lock().await;
+    let (mut otadata, booted) = open_otadata(&mut locked)?;
+    let Some(booted) = booted else {
+        println!("Running image is not in an OTA slot");
+        return Ok(());
+    };
+    match otadata.check_boot(booted)? {
+        BootCheck::Settled => println!("Running firmware from {}", booted),
+        BootCheck::Trial { boot } => {
+            println!(
+                "Firmware in {} on trial, boot {} of {}",
+                booted, boot, MAX_TRIAL_BOOTS
+            );
+            spawner.spawn(health_task(flash, booted))?;
+        }
+        BootCheck::RolledBack => {
+            println!(
+                "Firmware in {} failed {} trial boots, rolling back",
+                booted, MAX_TRIAL_BOOTS
+            );
+            software_reset();
+        }
+    }
+    Ok(())
+}
+
+/// Confirms the running image once it works, e.g. after the network came
+/// up. Does nothing unless the image is on trial.
+pub fn mark_healthy() {
+    HEALTHY.signal(());
+}
+
+/// Embassy task that confirms the image in `booted`, or rolls it back if
+/// [`mark_healthy`] is not called within [`HEALTH_TIMEOUT`].
+#[embassy_executor::task]
+async fn health_task(flash: &'static SharedFlash, booted: Slot) {
+    let healthy = with_timeout(HEALTH_TIMEOUT, HEALTHY.wait()).await.is_ok();
+    let result = open_otadata(&mut *flash.lock().await).and_then(|(mut otadata, _)| {
+        if healthy {
+            otadata.confirm()?;
+        } else {
+            otadata.roll_back()?;
+        }
+        Ok(())
+    });
+    match (healthy, result) {
+        (true, Ok(())) => println!("Firmware in {} confirmed", booted),
+        (true, Err(e)) => println!("Failed to confirm firmware in {}: {}", booted, e),
+        (false, result) => {
+            if let Err(e) = result {
+                println!("Failed to mark firmware in {} invalid: {}", booted, e);
+            }
+            // Without the mark, the restart still uses up a trial boot
+            println!(
+                "Firmware in {} not healthy after {} s, rolling back",
+                booted,
+                HEALTH_TIMEOUT.as_secs()
+            );
+            Timer::after(RESTART_DELAY).await;
+            software_reset();
+        }
+    }
+}
+
+/// Handle for starting firmware updates.
+///
+/// The handle is cheap to copy and can be passed to any task.
+#[derive(Clone, Copy, Debug)]
+pub struct UpdaterHandle {
+    _private: (),
+}
+
+impl UpdaterHandle {
+    /// Asks the updater to install the image at `url`, a URL accepted by
+    /// [`Url::parse`].
+    ///
+    /// An update already running finishes first; a request still waiting is
+    /// replaced.
+    pub fn request(&self, url: heapless::String<MAX_URL_LEN>) {
+        UPDATE_REQUEST.signal(url);
+    }
+}
+
+/// Embassy task that installs the images asked for and restarts into them.
+///
+/// # Arguments
+///
+/// * `stack` - Network stack images are downloaded over
+/// * `flash` - Shared SPI flash the images are written to
+/// * `key` - Key the images must be signed with
+#[embassy_executor::task]
+async fn updater_task(stack: Stack<'static>, flash: &'static SharedFlash, key: VerifyingKey) {
+    loop {
+        let url = UPDATE_REQUEST.wait().await;
+        println!("Firmware update from {}", url);
+        match update(stack, flash, &url, key).await {
+            Ok((slot, info)) => {
+                println!(
+                    "Firmware {} ({} bytes) installed in {}, restarting",
+                    info.version, info.len, slot
+                );
+                Timer::after(RESTART_DELAY).await;
+                software_reset();
+            }
+            Err(e) => println!("Firmware update failed: {}", e),
+        }
+    }
+}
+
+/// Downloads the image at `url` into the slot that is not running and, if
+/// it is signed with `key`, selects it for the next boot.
+///
+/// The flash stays locked until the update is done.
+async fn update(
+    stack: Stack<'_>,
+    flash: &SharedFlash,
+    url: &str,
+    key: VerifyingKey,
+) -> Result<(Slot, ImageInfo), Error> {
+    let url = Url::parse(url).ok_or(Error::InvalidUrl)?;
+
+    let mut flash = flash.lock().await;
+    let (layout, booted) = read_layout(&mut flash)?;
+    let slot = booted.map_or(Slot::Ota0, Slot::other);
+    let region = layout.slot(slot);
+    let mut writer = SlotWriter::new(open_partition(&mut flash, region)?, CHIP_ID_ESP32, key)?;
+
+    let (mut rx, mut tx, mut head) = ([0u8; 2048], [0u8; 512], [0u8; 1024]);
+    let client = HttpClient::new(stack).with_timeout(DOWNLOAD_TIMEOUT);
+    let mut socket = client.connect(url.host, url.port, &mut rx, &mut tx).await?;
+    let request = Request::get(url.authority, url.path);
+    let mut response = client.send(&mut socket, &request, &mut head).await?;
+    if !response.is_success() {
+        return Err(Error::HttpStatus(response.status()));
+    }
+    if let Some(len) = response.header("Content-Length")
+        && len
+            .parse::<u32>()
+            .is_ok_and(|len| len > region.size + SIGNATURE_BLOCK_LEN as u32)
+    {
+        return Err(Error::Ota(OtaError::Image(ImageError::TooLarge)));
+    }
+
+    println!("Writing firmware to {} at {:#x}", slot, region.offset);
+    let mut buf = [0u8; 1024];
+    let mut next_progress = PROGRESS_STEP;
+    loop {
+        let len = response.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        writer.write(&buf[..len])?;
+        if writer.len() >= next_progress {
+            println!("Firmware update: {} KiB written", writer.len() / 1024);
+            next_progress += PROGRESS_STEP;
+        }
+    }
+    let (info, partition) = writer.finish()?;
+
+    let (mut otadata, _) = open_otadata(partition.into_inner())?;
+    otadata.select(slot)?;
+    Ok((slot, info))
+}
+
+/// Starts the task that installs firmware updates.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the updater task
+/// * `stack` - Network stack images are downloaded over, from [`crate::net::start_network`]
+/// * `flash` - Shared SPI flash, from [`crate::storage::init_flash`]
+/// * `key` - Public key the images must be signed with, e.g. from
+///   [`wifi_core::ed25519::parse_hex_key`] on a key given at build time
+///
+/// The stack needs a free socket for the download.
+///
+/// # Errors
+///
+/// Returns [`Error::Spawn`] if the updater is already running.
+pub fn start_updater(
+    spawner: Spawner,
+    stack: Stack<'static>,
+    flash: &'static SharedFlash,
+    key: VerifyingKey,
+) -> Result<UpdaterHandle, Error> {
+    println!("Firmware updates must be signed with {}", key);
+    spawner.spawn(updater_task(stack, flash, key))?;
+    Ok(UpdaterHandle { _private: () })
+}
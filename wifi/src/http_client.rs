@@ -0,0 +1,126 @@
+//! HTTP client over the network stack.
+//!
+//! An [`HttpClient`] resolves host names and opens TCP connections on a
+//! [`Stack`], and sends requests over them with the host-tested client in
+//! `wifi_core`. Every step that waits on the network is bounded by the
+//! client's timeout: the DNS lookup, the connection, the response head, and
+//! the gaps between body packets.
+//!
+//! ```no_run
+//! # async fn example(stack: embassy_net::Stack<'_>) -> Result<(), wifi::Error> {
+//! use wifi::http_client::{HttpClient, Request};
+//!
+//! let (mut rx, mut tx, mut buf) = ([0; 1024], [0; 1024], [0; 1024]);
+//! let client = HttpClient::new(stack);
+//! let mut socket = client.connect("192.168.1.200", 8080, &mut rx, &mut tx).await?;
+//! let request = Request::get("192.168.1.200:8080", "/health");
+//! let mut response = client.send(&mut socket, &request, &mut buf).await?;
+//!
+//! let mut body = [0; 256];
+//! let len = response.read_to_end(&mut body).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{IpAddress, Stack};
+use embassy_time::{Duration, with_timeout};
+pub use wifi_core::http_client::{ClientError, Request, Response};
+
+use crate::error::Error;
+use crate::net;
+
+/// Port of plain HTTP
+pub const HTTP_PORT: u16 = 80;
+
+/// Timeout of a client created with [`HttpClient::new`]
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A response read from a TCP socket
+pub type TcpResponse<'a, 's> = Response<'a, TcpSocket<'s>>;
+
+/// HTTP client on a network stack
+#[derive(Clone, Copy)]
+pub struct HttpClient<'d> {
+    stack: Stack<'d>,
+    timeout: Duration,
+}
+
+impl<'d> HttpClient<'d> {
+    /// Creates a client with the [`DEFAULT_TIMEOUT`]
+    pub fn new(stack: Stack<'d>) -> Self {
+        Self {
+            stack,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Sets how long each network step may take
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Looks up the IPv4 address of `host`, which may also be an address in
+    /// dotted decimal notation.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Dns`] if the lookup fails or [`Error::Timeout`].
+    pub async fn resolve(&self, host: &str) -> Result<IpAddress, Error> {
+        net::resolve(self.stack, host, self.timeout).await
+    }
+
+    /// Opens a connection to `host`.
+    ///
+    /// The socket closes itself when the server sends nothing for the
+    /// client's timeout, so a stalled body read fails instead of hanging.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - Host name or IPv4 address of the server
+    /// * `port` - TCP port of the server, usually [`HTTP_PORT`]
+    /// * `rx_buffer` - Receive buffer of the socket
+    /// * `tx_buffer` - Transmit buffer of the socket
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Dns`], [`Error::Connect`] or [`Error::Timeout`].
+    pub async fn connect<'s>(
+        &self,
+        host: &str,
+        port: u16,
+        rx_buffer: &'s mut [u8],
+        tx_buffer: &'s mut [u8],
+    ) -> Result<TcpSocket<'s>, Error>
+    where
+        'd: 's,
+    {
+        net::connect(self.stack, host, port, rx_buffer, tx_buffer, self.timeout).await
+    }
+
+    /// Sends `request` on a connection from [`HttpClient::connect`] and
+    /// waits for the response head.
+    ///
+    /// The body is read from the returned [`Response`]; see
+    /// [`wifi_core::http_client::send`] for the use of `buf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Http`] or [`Error::Timeout`].
+    pub async fn send<'a, 's>(
+        &self,
+        socket: &'a mut TcpSocket<'s>,
+        request: &Request<'_>,
+        buf: &'a mut [u8],
+    ) -> Result<TcpResponse<'a, 's>, Error> {
+        let response = with_timeout(
+            self.timeout,
+            wifi_core::http_client::send(socket, request, buf),
+        )
+        .await
+        .map_err(|_| Error::Timeout)??;
+        Ok(response)
+    }
+}
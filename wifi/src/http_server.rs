@@ -0,0 +1,321 @@
+//! HTTP server with the device's JSON API.
+//!
+//! [`start_http_server`] serves the API of [`wifi_core::api`] on port 80 of
+//! a network stack, so a deployed scanner can be looked at from a browser:
+//!
+//! ```text
+//! curl http://192.168.1.50/api/status
+//! curl http://192.168.1.50/api/scan
+//! curl -X PUT -d '{"interval_secs": 30}' http://192.168.1.50/api/config
+//! curl -X POST -d '{"url": "http://192.168.1.10:8000/wifi.bin"}' http://192.168.1.50/api/ota
+//! ```
+//!
+//! Each of the [`MAX_CONNECTIONS`] server tasks handles one connection at a
+//! time, which bounds the memory the server can take; further clients wait
+//! in the TCP backlog until a task is free. Every response closes the
+//! connection.
+
+use core::fmt::Write as _;
+
+use embassy_executor::Spawner;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{IpListenEndpoint, Stack};
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::println;
+use wifi_core::api::{self, ApiError, ApiRoute, DeviceStatus};
+use wifi_core::http::{ParseError, Request, ResponseHead, Status};
+use wifi_core::scan_config::ScannerConfig;
+
+use crate::control::ScannerHandle;
+use crate::error::Error;
+use crate::firmware::UpdaterHandle;
+use crate::http_client::HTTP_PORT;
+use crate::net::write_all;
+use crate::scanner;
+use crate::station::{self, StationHandle};
+use crate::storage::SharedStore;
+use crate::time_sync;
+
+/// Number of connections served at the same time
+pub const MAX_CONNECTIONS: usize = 2;
+
+/// Largest request the server accepts, head and body
+const REQUEST_CAPACITY: usize = 1024;
+
+/// Capacity of a response body; a full scan report takes about 5 KiB
+pub(crate) const BODY_CAPACITY: usize = 6 * 1024;
+
+/// Time a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the server reports on and controls
+#[derive(Clone, Copy)]
+struct ServerContext {
+    stack: Stack<'static>,
+    scanner: Option<ScannerHandle>,
+    station: Option<StationHandle>,
+    store: Option<&'static SharedStore>,
+    updater: Option<UpdaterHandle>,
+}
+
+/// Embassy task that serves the API, one connection at a time.
+#[embassy_executor::task(pool_size = MAX_CONNECTIONS)]
+async fn server_task(context: ServerContext) {
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 2048];
+    let mut request = [0u8; REQUEST_CAPACITY];
+    let mut body = heapless::String::<BODY_CAPACITY>::new();
+
+    loop {
+        let mut socket = TcpSocket::new(context.stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(REQUEST_TIMEOUT));
+        if let Err(e) = socket
+            .accept(IpListenEndpoint {
+                addr: None,
+                port: HTTP_PORT,
+            })
+            .await
+        {
+            println!("HTTP server accept failed: {:?}", e);
+            continue;
+        }
+
+        serve(&mut socket, &mut request, &mut body, &context).await;
+        let _ = socket.flush().await;
+        socket.close();
+        // Let the close reach the client before the socket is dropped
+        Timer::after(Duration::from_millis(50)).await;
+        socket.abort();
+    }
+}
+
+/// Reads one request from `socket` and answers it.
+async fn serve(
+    socket: &mut TcpSocket<'_>,
+    buf: &mut [u8],
+    body: &mut heapless::String<BODY_CAPACITY>,
+    context: &ServerContext,
+) {
+    let mut len = 0;
+    let request = loop {
+        match Request::parse(&buf[..len]) {
+            Err(ParseError::Incomplete) if len == buf.len() => {
+                respond(socket, Status::ContentTooLarge, body, |out| {
+                    api::write_error(out, "request too large")
+                })
+                .await;
+                return;
+            }
+            Err(ParseError::Incomplete) => match socket.read(&mut buf[len..]).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => len += n,
+            },
+            Err(ParseError::Malformed) => {
+                respond(socket, Status::BadRequest, body, |out| {
+                    api::write_error(out, "malformed request")
+                })
+                .await;
+                return;
+            }
+            Ok(request) => break request,
+        }
+    };
+
+    match (api::route(&request), context.scanner) {
+        (ApiRoute::Scan, _) => match scanner::latest_scan() {
+            Some(report) => {
+                respond(socket, Status::Ok, body, |out| {
+                    api::write_scan(out, &report)
+                })
+                .await
+            }
+            None => {
+                respond(socket, Status::ServiceUnavailable, body, |out| {
+                    api::write_error(out, "no scan completed yet")
+                })
+                .await
+            }
+        },
+        (ApiRoute::Status, _) => {
+            let status = device_status(context.stack, context.station);
+            respond(socket, Status::Ok, body, |out| {
+                api::write_status(out, &status)
+            })
+            .await;
+        }
+        (ApiRoute::Config | ApiRoute::UpdateConfig(_), None) => {
+            respond(socket, Status::ServiceUnavailable, body, |out| {
+                api::write_error(out, "scanner is not running")
+            })
+            .await
+        }
+        (ApiRoute::Config, Some(scanner)) => {
+            let config = scanner.config();
+            respond(socket, Status::Ok, body, |out| {
+                api::write_config(out, &config)
+            })
+            .await;
+        }
+        (ApiRoute::UpdateConfig(update), Some(scanner)) => match update_config(scanner, update) {
+            Ok(config) => {
+                println!("Scanner configuration updated over HTTP");
+                save_config(context.store, &config).await;
+                respond(socket, Status::Ok, body, |out| {
+                    api::write_config(out, &config)
+                })
+                .await;
+            }
+            Err(e) => {
+                respond(socket, Status::BadRequest, body, |out| {
+                    api::write_error(out, e)
+                })
+                .await
+            }
+        },
+        (ApiRoute::StartUpdate(update), _) => match (api::parse_update(update), context.updater) {
+            (_, None) => {
+                respond(socket, Status::ServiceUnavailable, body, |out| {
+                    api::write_error(out, "updater is not running")
+                })
+                .await
+            }
+            (Ok(url), Some(updater)) => {
+                println!("Firmware update requested over HTTP");
+                respond(socket, Status::Accepted, body, |out| {
+                    api::write_update(out, &url)
+                })
+                .await;
+                updater.request(url);
+            }
+            (Err(e), Some(_)) => {
+                respond(socket, Status::BadRequest, body, |out| {
+                    api::write_error(out, e)
+                })
+                .await
+            }
+        },
+        (ApiRoute::NotFound, _) => {
+            respond(socket, Status::NotFound, body, |out| {
+                api::write_error(out, "not found")
+            })
+            .await
+        }
+        (ApiRoute::MethodNotAllowed, _) => {
+            respond(socket, Status::MethodNotAllowed, body, |out| {
+                api::write_error(out, "method not allowed")
+            })
+            .await
+        }
+    }
+}
+
+/// Applies a configuration update to the scan task
+pub(crate) fn update_config(
+    scanner: ScannerHandle,
+    update: &[u8],
+) -> Result<ScannerConfig, ApiError> {
+    let config = api::update_config(&scanner.config(), update)?;
+    scanner
+        .set_config(config.clone())
+        .map_err(ApiError::Config)?;
+    Ok(config)
+}
+
+/// Collects the status of the device
+pub(crate) fn device_status(stack: Stack<'_>, station: Option<StationHandle>) -> DeviceStatus {
+    DeviceStatus {
+        uptime_ms: Instant::now().as_millis(),
+        utc: time_sync::now_utc(),
+        heap_used: esp_alloc::HEAP.used(),
+        heap_free: esp_alloc::HEAP.free(),
+        connection: station::state(),
+        network: station.and_then(|station| station.network()),
+        ipv4: stack.config_v4().map(|config| {
+            (
+                config.address.address().octets(),
+                config.address.prefix_len(),
+            )
+        }),
+    }
+}
+
+/// Renders a JSON body with `render` and sends it with `status`, logging
+/// failures.
+async fn respond(
+    socket: &mut TcpSocket<'_>,
+    status: Status,
+    body: &mut heapless::String<BODY_CAPACITY>,
+    render: impl FnOnce(&mut heapless::String<BODY_CAPACITY>) -> core::fmt::Result,
+) {
+    body.clear();
+    let status = match render(body) {
+        Ok(()) => status,
+        Err(_) => {
+            println!("HTTP response exceeds {} bytes", BODY_CAPACITY);
+            body.clear();
+            let _ = api::write_error(body, "response too large");
+            Status::InternalServerError
+        }
+    };
+
+    let mut head = heapless::String::<256>::new();
+    let head_fields = ResponseHead::new(status).with_content(api::CONTENT_TYPE, body.len());
+    if write!(head, "{}", head_fields).is_err() {
+        return;
+    }
+    let result = match write_all(socket, head.as_bytes()).await {
+        Ok(()) => write_all(socket, body.as_bytes()).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        println!("HTTP response failed: {:?}", e);
+    }
+}
+
+/// Saves an updated scanner configuration, so it survives a restart.
+pub(crate) async fn save_config(store: Option<&SharedStore>, config: &ScannerConfig) {
+    if let Some(store) = store
+        && let Err(e) = store.lock().await.and_then(|mut store| store.save(config))
+    {
+        println!("Failed to save scanner configuration: {}", e);
+    }
+}
+
+/// Starts the HTTP server on `stack`.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the server tasks
+/// * `stack` - Network stack to serve on, from [`crate::net::start_network`]
+/// * `scanner` - Scan task whose results and configuration are served, if running
+/// * `station` - Station whose network is reported, if running
+/// * `store` - Configuration store updated configurations are saved to, if any
+/// * `updater` - Firmware updater that `POST /api/ota` starts, if running
+///
+/// The stack needs a free socket for each of the [`MAX_CONNECTIONS`] tasks.
+///
+/// # Errors
+///
+/// Returns [`Error::Spawn`] if the server tasks cannot be spawned, e.g. when
+/// the server is already running.
+pub fn start_http_server(
+    spawner: Spawner,
+    stack: Stack<'static>,
+    scanner: Option<ScannerHandle>,
+    station: Option<StationHandle>,
+    store: Option<&'static SharedStore>,
+    updater: Option<UpdaterHandle>,
+) -> Result<(), Error> {
+    let context = ServerContext {
+        stack,
+        scanner,
+        station,
+        store,
+        updater,
+    };
+    for _ in 0..MAX_CONNECTIONS {
+        spawner.spawn(server_task(context))?;
+    }
+    println!("HTTP server listening on port {}", HTTP_PORT);
+    Ok(())
+}
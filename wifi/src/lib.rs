@@ -3,11 +3,40 @@
 //! This library provides an async WiFi scanning implementation for ESP32 microcontrollers
 //! using the Embassy async runtime and esp-hal ecosystem.
 //!
+//! The logic that does not need the hardware, such as access point tracking,
+//! the station state machine and the network protocols, lives in the
+//! `wifi_core` crate and is tested on the host; the modules here run it on
+//! the ESP32.
+//!
 //! ## Features
 //!
 //! - Async WiFi network scanning
+//! - Typed, heapless scan reports (see [`report`])
+//! - Runtime-configurable scanning (see [`scan_config`])
+//! - Access point tracking with appear/disappear events (see [`tracking`])
+//! - Channel survey and best-channel recommendation (see [`survey`])
+//! - Evil-twin and rogue access point alerts (see [`rogue_detection`])
+//! - Station connection manager with reconnect backoff (see [`station`])
+//! - Multiple known networks with priority and automatic selection (see [`profiles`])
+//! - Network stack bring-up with DHCP or static IPv4 (see [`net`])
+//! - HTTP/1.1 client with chunked bodies and timeouts (see [`http_client`])
+//! - JSON API for scan results, device status and configuration (see [`http_server`])
+//! - MQTT publishing of scan results and device telemetry (see [`telemetry`])
+//! - SNTP time synchronization and UTC timestamps on scans (see [`time_sync`])
+//! - mDNS/DNS-SD discovery as `<device-name>.local` (see [`discovery`])
+//! - Signed over-the-air firmware updates with A/B slots and rollback (see [`firmware`])
+//! - Serial shell on UART0 with line editing, history and application commands (see [`console`])
+//! - Persistent settings in a wear-levelled flash key-value store (see [`storage`])
+//! - SoftAP provisioning with a captive-portal web page (see [`provisioning`])
+//! - BLE GATT provisioning next to WiFi, with the `ble` feature (see `ble`)
+//! - Per-stage errors through [`Error`]
+//! - Pause, resume, stop and on-demand scans through a [`control::ScannerHandle`]
+//! - Scan results published to any number of subscriber tasks (see [`events`])
 //! - Embassy executor integration
 //! - Optimized heap memory allocation for WiFi operations
+//! - Configurable heap regions, with external PSRAM through the `psram` feature (see [`allocator`])
+//! - Heap statistics, high-water marks and low-memory warnings (see [`allocator`])
+//! - Task watchdog with heartbeats, radio restart, chip reset and safe mode (see [`supervisor`])
 //! - Clean module organization for embedded Rust projects
 //!
 //! ## Example
@@ -32,8 +61,149 @@
 /// Memory allocation configuration
 pub mod allocator;
 
+/// Heap regions and sizes, re-exported from `wifi_core`
+pub use wifi_core::heap_layout;
+
+/// Heap statistics, re-exported from `wifi_core`
+pub use wifi_core::heap_stats;
+
+/// Error type for the WiFi library
+pub mod error;
+
+pub use error::Error;
+
 /// WiFi driver and scanning tasks
 pub mod scanner;
 
+/// Radio and WiFi controller bring-up
+pub mod radio;
+
+/// Station connection manager
+pub mod station;
+
+/// Known network profiles and selection, re-exported from `wifi_core`
+pub use wifi_core::profiles;
+
 /// Global static storage for WiFi components
-pub mod types;
\ No newline at end of file
+pub mod types;
+
+/// Scan result publish/subscribe channel
+pub mod events;
+
+/// Runtime control of the scan task
+pub mod control;
+
+/// Scanner configuration and profiles, re-exported from `wifi_core`
+pub use wifi_core::scan_config;
+
+/// Access point tracking task
+pub mod tracking;
+
+/// Access point tracker, re-exported from `wifi_core`
+pub use wifi_core::tracker;
+
+/// Channel survey task
+pub mod survey;
+
+/// Channel survey scoring, re-exported from `wifi_core`
+pub use wifi_core::channel_survey;
+
+/// Rogue access point detection task
+pub mod rogue_detection;
+
+/// Rogue access point detection, re-exported from `wifi_core`
+pub use wifi_core::rogue;
+
+/// Network stack bring-up
+pub mod net;
+
+/// HTTP client over the network stack
+pub mod http_client;
+
+/// HTTP server with the JSON API
+pub mod http_server;
+
+/// JSON API resources, re-exported from `wifi_core`
+pub use wifi_core::api;
+
+/// Minimal JSON support, re-exported from `wifi_core`
+pub use wifi_core::json;
+
+/// MQTT publisher for scan results and telemetry
+pub mod telemetry;
+
+/// MQTT client, re-exported from `wifi_core`
+pub use wifi_core::mqtt;
+
+/// SNTP time synchronization task
+pub mod time_sync;
+
+/// Wall clock, re-exported from `wifi_core`
+pub use wifi_core::clock;
+
+/// SNTP packets, re-exported from `wifi_core`
+pub use wifi_core::sntp;
+
+/// mDNS responder task
+pub mod discovery;
+
+/// mDNS responder, re-exported from `wifi_core`
+pub use wifi_core::mdns;
+
+/// Over-the-air firmware updates
+pub mod firmware;
+
+/// A/B firmware slots and OTA data, re-exported from `wifi_core`
+pub use wifi_core::ota;
+
+/// Application image checks, re-exported from `wifi_core`
+pub use wifi_core::app_image;
+
+/// Ed25519 signatures, re-exported from `wifi_core`
+pub use wifi_core::ed25519;
+
+/// Serial shell on UART0
+pub mod console;
+
+/// Line editing and command registry, re-exported from `wifi_core`
+pub use wifi_core::shell;
+
+/// Task watchdog and supervisor
+pub mod supervisor;
+
+/// Heartbeat deadlines and escalation, re-exported from `wifi_core`
+pub use wifi_core::task_watchdog;
+
+/// Persistent configuration storage
+pub mod storage;
+
+/// Flash key-value store, re-exported from `wifi_core`
+pub use wifi_core::flash_kv;
+
+/// Typed settings, re-exported from `wifi_core`
+pub use wifi_core::settings;
+
+/// SoftAP provisioning portal
+pub mod provisioning;
+
+/// HTTP message handling, re-exported from `wifi_core`
+pub use wifi_core::http;
+
+/// DHCP server, re-exported from `wifi_core`
+pub use wifi_core::dhcp_server;
+
+/// Captive portal DNS responder, re-exported from `wifi_core`
+pub use wifi_core::captive_dns;
+
+/// Provisioning portal page and form, re-exported from `wifi_core`
+pub use wifi_core::portal;
+
+/// BLE provisioning service
+#[cfg(feature = "ble")]
+pub mod ble;
+
+/// BLE provisioning protocol, re-exported from `wifi_core`
+pub use wifi_core::ble_provisioning;
+
+/// Typed scan results, re-exported from `wifi_core`
+pub use wifi_core::report;
@@ -0,0 +1,193 @@
+//! Network stack bring-up.
+//!
+//! [`start_network`] creates an embassy-net stack on a WiFi interface, with
+//! an address from DHCP or a static [`Ipv4Config`], and spawns the task that
+//! runs it. The returned [`Stack`] is ready for sockets right away; use
+//! [`wait_link_up`] and [`wait_config_up`] to wait until it can reach the
+//! network.
+//!
+//! ```no_run
+//! # async fn example(spawner: embassy_executor::Spawner, radio: wifi::radio::Radio) -> Result<(), wifi::Error> {
+//! use embassy_net::StackResources;
+//! use embassy_time::Duration;
+//! use static_cell::StaticCell;
+//! use wifi::net::{self, Ipv4Config};
+//!
+//! static RESOURCES: StaticCell<StackResources<4>> = StaticCell::new();
+//!
+//! let stack = net::start_network(
+//!     spawner,
+//!     radio.interfaces.sta,
+//!     &Ipv4Config::Dhcp,
+//!     RESOURCES.init(StackResources::new()),
+//! )?;
+//! let config = net::wait_config_up(stack, Duration::from_secs(30)).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use embassy_executor::Spawner;
+use embassy_net::dns::{self, DnsQueryType};
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_net::{
+    Config, IpAddress, Ipv4Address, Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4,
+};
+use embassy_time::{Duration, with_timeout};
+use esp_hal::rng::Rng;
+use esp_println::println;
+use esp_radio::wifi::WifiDevice;
+pub use wifi_core::net_config::{Ipv4Config, NetConfigError, StaticIpv4};
+
+use crate::error::Error;
+
+/// Embassy task that runs a network stack.
+///
+/// One instance runs per stack: the station and the provisioning access
+/// point.
+#[embassy_executor::task(pool_size = 2)]
+async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) -> ! {
+    runner.run().await
+}
+
+/// Translates an [`Ipv4Config`] into the stack configuration.
+fn stack_config(config: &Ipv4Config) -> Config {
+    match config {
+        Ipv4Config::Dhcp => Config::dhcpv4(Default::default()),
+        Ipv4Config::Static(config) => Config::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(config.address().into(), config.prefix_len()),
+            gateway: config.gateway().map(Ipv4Address::from),
+            dns_servers: config
+                .dns_servers()
+                .iter()
+                .map(|&server| Ipv4Address::from(server))
+                .collect(),
+        }),
+    }
+}
+
+/// Creates a network stack on a WiFi interface and starts running it.
+///
+/// The stack holds up to `SOCKETS` sockets at a time; its DNS resolver
+/// takes one of them, and so does a DHCP configuration.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the stack task
+/// * `device` - Station or access point interface from [`crate::radio::Radio::interfaces`]
+/// * `config` - How the interface gets its IPv4 address
+/// * `resources` - Socket storage for the stack
+///
+/// # Errors
+///
+/// Returns [`Error::Spawn`] if both stacks are already running.
+pub fn start_network<const SOCKETS: usize>(
+    spawner: Spawner,
+    device: WifiDevice<'static>,
+    config: &Ipv4Config,
+    resources: &'static mut StackResources<SOCKETS>,
+) -> Result<Stack<'static>, Error> {
+    let rng = Rng::new();
+    let seed = u64::from(rng.random()) << 32 | u64::from(rng.random());
+    let (stack, runner) = embassy_net::new(device, stack_config(config), resources, seed);
+    spawner.spawn(net_task(runner))?;
+    println!("Network stack started ({})", config);
+    Ok(stack)
+}
+
+/// Waits until the link of `stack` is up.
+///
+/// # Errors
+///
+/// Returns [`Error::Timeout`] if the link is still down after `timeout`.
+pub async fn wait_link_up(stack: Stack<'_>, timeout: Duration) -> Result<(), Error> {
+    with_timeout(timeout, stack.wait_link_up())
+        .await
+        .map_err(|_| Error::Timeout)
+}
+
+/// Waits until `stack` has an IPv4 address, e.g. from DHCP, and returns its
+/// configuration.
+///
+/// # Errors
+///
+/// Returns [`Error::Timeout`] if the stack has no address after `timeout`.
+pub async fn wait_config_up(stack: Stack<'_>, timeout: Duration) -> Result<StaticConfigV4, Error> {
+    with_timeout(timeout, async {
+        loop {
+            stack.wait_config_up().await;
+            if let Some(config) = stack.config_v4() {
+                return config;
+            }
+            // Configured for another protocol only; wait for a change
+            stack.wait_config_down().await;
+        }
+    })
+    .await
+    .map_err(|_| Error::Timeout)
+}
+
+/// Looks up the IPv4 address of `host`, which may also be an address in
+/// dotted decimal notation.
+///
+/// # Errors
+///
+/// Returns [`Error::Dns`] if the lookup fails, or [`Error::Timeout`] if it
+/// takes longer than `timeout`.
+pub async fn resolve(stack: Stack<'_>, host: &str, timeout: Duration) -> Result<IpAddress, Error> {
+    let addresses = with_timeout(timeout, stack.dns_query(host, DnsQueryType::A))
+        .await
+        .map_err(|_| Error::Timeout)??;
+    addresses
+        .first()
+        .copied()
+        .ok_or(Error::Dns(dns::Error::Failed))
+}
+
+/// Opens a TCP connection to `host`.
+///
+/// The lookup and the connection may each take up to `timeout`, which also
+/// becomes the socket's timeout: the socket closes itself when the peer sends
+/// nothing for that long.
+///
+/// # Arguments
+///
+/// * `stack` - Network stack to connect on
+/// * `host` - Host name or IPv4 address of the peer
+/// * `port` - TCP port of the peer
+/// * `rx_buffer` - Receive buffer of the socket
+/// * `tx_buffer` - Transmit buffer of the socket
+/// * `timeout` - Time each step may take
+///
+/// # Errors
+///
+/// Returns [`Error::Dns`], [`Error::Connect`] or [`Error::Timeout`].
+pub async fn connect<'s>(
+    stack: Stack<'s>,
+    host: &str,
+    port: u16,
+    rx_buffer: &'s mut [u8],
+    tx_buffer: &'s mut [u8],
+    timeout: Duration,
+) -> Result<TcpSocket<'s>, Error> {
+    let address = resolve(stack, host, timeout).await?;
+    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    socket.set_timeout(Some(timeout));
+    with_timeout(timeout, socket.connect((address, port)))
+        .await
+        .map_err(|_| Error::Timeout)??;
+    Ok(socket)
+}
+
+/// Writes all of `data` to `socket`.
+pub(crate) async fn write_all(
+    socket: &mut TcpSocket<'_>,
+    mut data: &[u8],
+) -> Result<(), tcp::Error> {
+    while !data.is_empty() {
+        match socket.write(data).await? {
+            0 => return Err(tcp::Error::ConnectionReset),
+            n => data = &data[n..],
+        }
+    }
+    Ok(())
+}
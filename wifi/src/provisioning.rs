@@ -0,0 +1,405 @@
+//! SoftAP provisioning with a captive portal.
+//!
+//! When the device knows no network, [`start_provisioning`] opens an access
+//! point named after the device (see [`device_name`]) next to the station
+//! interface and runs a small network on it:
+//!
+//! - a [`dhcp_server`](crate::dhcp_server) handing out addresses from
+//!   192.168.4.100,
+//! - a [`captive_dns`] responder resolving every name to the device,
+//! - a web server on port 80 serving the [`portal`] page over
+//!   [`http`](crate::http).
+//!
+//! Phones and laptops detect the captive portal and open the page, which
+//! lists the networks from the latest scan. Submitted credentials are saved
+//! as a [`NetworkProfile`] in the configuration store and handed to the
+//! caller through [`ProvisioningHandle::wait`]. Starting the station then
+//! switches the controller back to client mode, which closes the access
+//! point.
+//!
+//! Scans hop across all channels and would take the radio away from the
+//! access point's clients, so the scanner is paused while the portal is open
+//! and the page lists the networks of the last scan before it opened.
+
+use core::fmt::Write as _;
+
+use embassy_executor::Spawner;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpListenEndpoint, Ipv4Address, Stack, StackResources};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::efuse::Efuse;
+use esp_println::println;
+use esp_radio::wifi::{AccessPointConfig, ClientConfig, ModeConfig, WifiDevice};
+use static_cell::StaticCell;
+use wifi_core::captive_dns::{self, DNS_PORT};
+use wifi_core::dhcp_server::{CLIENT_PORT, DhcpConfig, DhcpServer, SERVER_PORT};
+use wifi_core::http::{Method, ParseError, Request, ResponseHead, Status};
+use wifi_core::portal::{self, Notice, PortalAction};
+use wifi_core::profiles::{NetworkProfile, ProfileStore};
+use wifi_core::report::Ssid;
+use wifi_core::station::Credentials;
+
+use crate::control::ScannerHandle;
+use crate::error::Error;
+use crate::net::{self, Ipv4Config, StaticIpv4, write_all};
+use crate::scanner;
+use crate::storage::SharedStore;
+use crate::types::SharedController;
+
+/// Prefix of the access point SSID, followed by the end of the MAC address
+pub const AP_SSID_PREFIX: &str = "esp-setup-";
+
+/// Address of the device on the access point network
+pub const AP_ADDRESS: [u8; 4] = [192, 168, 4, 1];
+
+/// Length of the access point subnet prefix
+const AP_PREFIX_LEN: u8 = 24;
+
+/// URL of the portal page, announced to DHCP clients
+pub const PORTAL_URL: &str = "http://192.168.4.1/";
+
+/// Maximum number of clients on the access point
+pub const MAX_CLIENTS: usize = 4;
+
+/// HTTP port of the portal
+const HTTP_PORT: u16 = 80;
+
+/// Sockets of the access point stack: DHCP, DNS and HTTP servers, and the
+/// stack's own DNS resolver
+const SOCKET_COUNT: usize = 4;
+
+/// Largest request the portal accepts, head and body
+const REQUEST_CAPACITY: usize = 1536;
+
+/// Capacity of the rendered portal page
+const PAGE_CAPACITY: usize = 12 * 1024;
+
+/// Time a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time for the confirmation page to reach the client before the access
+/// point goes away
+const HANDOVER_DELAY: Duration = Duration::from_secs(1);
+
+/// Static storage for the access point network stack
+static STACK_RESOURCES: StaticCell<StackResources<SOCKET_COUNT>> = StaticCell::new();
+
+/// Credentials submitted through the portal
+static SUBMITTED: Signal<CriticalSectionRawMutex, Credentials> = Signal::new();
+
+/// Name the device announces itself with while it is being provisioned,
+/// e.g. `esp-setup-AB0C`, from its base MAC address
+pub fn device_name() -> Ssid {
+    portal::access_point_ssid(AP_SSID_PREFIX, Efuse::mac_address())
+}
+
+/// Handle to a running provisioning portal
+#[derive(Clone, Copy, Debug)]
+pub struct ProvisioningHandle {
+    _private: (),
+}
+
+impl ProvisioningHandle {
+    /// Waits until credentials have been submitted and saved.
+    pub async fn wait(&self) -> Credentials {
+        SUBMITTED.wait().await
+    }
+}
+
+/// Embassy task that leases addresses to access point clients.
+///
+/// Replies are broadcast, as clients have no address yet.
+#[embassy_executor::task]
+async fn dhcp_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(SERVER_PORT) {
+        println!("DHCP server failed to bind: {:?}", e);
+        return;
+    }
+
+    let mut server =
+        DhcpServer::<MAX_CLIENTS>::new(DhcpConfig::default()).with_portal_url(PORTAL_URL);
+    let mut packet = [0u8; 576];
+    let mut reply = [0u8; 576];
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        let now_ms = Instant::now().as_millis();
+        if let Some(reply_len) = server.handle(&packet[..len], now_ms, &mut reply)
+            && let Err(e) = socket
+                .send_to(&reply[..reply_len], (Ipv4Address::BROADCAST, CLIENT_PORT))
+                .await
+        {
+            println!("DHCP reply failed: {:?}", e);
+        }
+    }
+}
+
+/// Embassy task that resolves every name to the device.
+#[embassy_executor::task]
+async fn dns_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(DNS_PORT) {
+        println!("DNS responder failed to bind: {:?}", e);
+        return;
+    }
+
+    let mut query = [0u8; 512];
+    let mut response = [0u8; 512];
+    loop {
+        let Ok((len, meta)) = socket.recv_from(&mut query).await else {
+            continue;
+        };
+        if let Some(response_len) = captive_dns::answer(&query[..len], AP_ADDRESS, &mut response)
+            && let Err(e) = socket
+                .send_to(&response[..response_len], meta.endpoint)
+                .await
+        {
+            println!("DNS response failed: {:?}", e);
+        }
+    }
+}
+
+/// Embassy task that serves the portal, one connection at a time.
+///
+/// # Arguments
+///
+/// * `stack` - Access point network stack
+/// * `store` - Configuration store the submitted network is saved to, if any
+#[embassy_executor::task]
+async fn portal_task(stack: Stack<'static>, store: Option<&'static SharedStore>) {
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 2048];
+    let mut request = [0u8; REQUEST_CAPACITY];
+    let mut page = heapless::String::<PAGE_CAPACITY>::new();
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(REQUEST_TIMEOUT));
+        if let Err(e) = socket
+            .accept(IpListenEndpoint {
+                addr: None,
+                port: HTTP_PORT,
+            })
+            .await
+        {
+            println!("Portal accept failed: {:?}", e);
+            continue;
+        }
+
+        let submitted = serve(&mut socket, &mut request, &mut page, store).await;
+        let _ = socket.flush().await;
+        socket.close();
+        // Let the close reach the client before the socket is dropped
+        Timer::after(Duration::from_millis(50)).await;
+        socket.abort();
+
+        if let Some(credentials) = submitted {
+            Timer::after(HANDOVER_DELAY).await;
+            SUBMITTED.signal(credentials);
+        }
+    }
+}
+
+/// Reads one request from `socket` and answers it.
+///
+/// Returns the credentials if a valid form was submitted.
+async fn serve(
+    socket: &mut TcpSocket<'_>,
+    buf: &mut [u8],
+    page: &mut heapless::String<PAGE_CAPACITY>,
+    store: Option<&SharedStore>,
+) -> Option<Credentials> {
+    let mut len = 0;
+    let request = loop {
+        match Request::parse(&buf[..len]) {
+            Err(ParseError::Incomplete) if len == buf.len() => {
+                respond(socket, ResponseHead::new(Status::ContentTooLarge), "").await;
+                return None;
+            }
+            Err(ParseError::Incomplete) => match socket.read(&mut buf[len..]).await {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => len += n,
+            },
+            Err(ParseError::Malformed) => {
+                respond(socket, ResponseHead::new(Status::BadRequest), "").await;
+                return None;
+            }
+            Ok(request) => break request,
+        }
+    };
+
+    let (status, notice, submitted) = match portal::route(&request) {
+        PortalAction::Page => (Status::Ok, None, None),
+        PortalAction::Redirect => {
+            let head = ResponseHead::new(Status::Found).with_location(PORTAL_URL);
+            respond(socket, head, "").await;
+            return None;
+        }
+        PortalAction::MethodNotAllowed => {
+            respond(socket, ResponseHead::new(Status::MethodNotAllowed), "").await;
+            return None;
+        }
+        PortalAction::Rejected(e) => {
+            println!("Portal form rejected: {}", e);
+            (Status::BadRequest, Some(Notice::Error(e)), None)
+        }
+        PortalAction::Submitted(credentials) => {
+            println!("Portal received credentials for {}", credentials.ssid);
+            save_profile(store, &credentials).await;
+            (Status::Ok, None, Some(credentials))
+        }
+    };
+
+    page.clear();
+    let notice = match &submitted {
+        Some(credentials) => Some(Notice::Saved(credentials.ssid.as_str())),
+        None => notice,
+    };
+    let report = scanner::latest_scan();
+    let networks = report.iter().flat_map(|report| report.iter());
+    if portal::render_page(page, networks, notice).is_err() {
+        println!("Portal page exceeds {} bytes", PAGE_CAPACITY);
+        respond(socket, ResponseHead::new(Status::InternalServerError), "").await;
+        return submitted;
+    }
+
+    let head = ResponseHead::new(status).with_content("text/html; charset=utf-8", page.len());
+    let body = if request.method == Method::Head {
+        ""
+    } else {
+        page.as_str()
+    };
+    respond(socket, head, body).await;
+    submitted
+}
+
+/// Writes a response, logging failures.
+async fn respond(socket: &mut TcpSocket<'_>, head: ResponseHead<'_>, body: &str) {
+    let mut head_buf = heapless::String::<256>::new();
+    if write!(head_buf, "{}", head).is_err() {
+        return;
+    }
+    let result = match write_all(socket, head_buf.as_bytes()).await {
+        Ok(()) => write_all(socket, body.as_bytes()).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        println!("Portal response failed: {:?}", e);
+    }
+}
+
+/// Adds the submitted network to the profiles in the configuration store.
+async fn save_profile(store: Option<&SharedStore>, credentials: &Credentials) {
+    let Some(store) = store else {
+        return;
+    };
+    let mut store = match store.lock().await {
+        Ok(store) => store,
+        Err(e) => {
+            println!("Failed to mount config store: {}", e);
+            return;
+        }
+    };
+    let mut profiles = match store.load::<ProfileStore>() {
+        Ok(profiles) => profiles.unwrap_or_default(),
+        Err(e) => {
+            println!("Failed to load network profiles: {}", e);
+            ProfileStore::new()
+        }
+    };
+    if profiles
+        .insert(NetworkProfile::new(credentials.clone()))
+        .is_err()
+    {
+        println!("No room for network profile {}", credentials.ssid);
+    } else if let Err(e) = store.save(&profiles) {
+        println!("Failed to save network profiles: {}", e);
+    }
+}
+
+/// Opens the provisioning access point and starts the portal.
+///
+/// The scanner is paused, after one scan if none has completed yet, so the
+/// portal has networks to list and the radio stays on the access point's
+/// channel; resume it once the station has taken over. The controller is
+/// then switched to access point and station mode. The access point is open
+/// and uses channel 1.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the portal tasks
+/// * `wifi_controller` - Controller returned by [`crate::radio::init_radio`]
+/// * `device` - Access point interface from [`crate::radio::Radio::interfaces`]
+/// * `store` - Configuration store the submitted network is saved to, if any
+/// * `scanner` - Scan task to pause, if it runs
+///
+/// # Errors
+///
+/// This function will return:
+/// - [`Error::AccessPoint`] if the access point configuration is rejected
+/// - [`Error::AlreadyInitialized`] if provisioning has already been started
+/// - [`Error::Spawn`] if a portal task or the network stack cannot be spawned
+pub async fn start_provisioning(
+    spawner: Spawner,
+    wifi_controller: &'static SharedController,
+    device: WifiDevice<'static>,
+    store: Option<&'static SharedStore>,
+    scanner: Option<ScannerHandle>,
+) -> Result<ProvisioningHandle, Error> {
+    if let Some(scanner) = scanner {
+        if scanner::latest_scan().is_none()
+            && let Err(e) = scanner.scan_now().await
+        {
+            println!("Portal scan failed: {}", e);
+        }
+        scanner.pause().await;
+    }
+
+    let ssid = device_name();
+    let ap_config = AccessPointConfig::default()
+        .with_ssid(ssid.as_str().into())
+        .with_max_connections(MAX_CLIENTS as u16);
+    wifi_controller
+        .lock()
+        .await
+        .set_config(&ModeConfig::ApSta(ClientConfig::default(), ap_config))
+        .map_err(Error::AccessPoint)?;
+
+    let resources = STACK_RESOURCES
+        .try_init(StackResources::new())
+        .ok_or(Error::AlreadyInitialized)?;
+    let config = Ipv4Config::Static(StaticIpv4::new(AP_ADDRESS, AP_PREFIX_LEN)?);
+    let stack = net::start_network(spawner, device, &config, resources)?;
+
+    spawner.spawn(dhcp_task(stack))?;
+    spawner.spawn(dns_task(stack))?;
+    spawner.spawn(portal_task(stack, store))?;
+
+    println!("Provisioning portal open on {} at {}", ssid, PORTAL_URL);
+    Ok(ProvisioningHandle { _private: () })
+}
@@ -0,0 +1,76 @@
+//! Radio and WiFi controller bring-up.
+//!
+//! [`init_radio`] initializes the radio once and returns the WiFi controller
+//! as a [`SharedController`], so that the scanner and the station can both
+//! use it, together with the network interfaces for the network stack.
+
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use esp_hal::peripherals::WIFI;
+use esp_println::println;
+use esp_radio::wifi::Interfaces;
+
+use crate::error::Error;
+use crate::types::{RADIO_INIT, SharedController, WIFI_CONTROLLER};
+
+/// The started WiFi controller and its network interfaces
+pub struct Radio {
+    /// Radio driver, needed to bring up BLE next to WiFi
+    pub init: &'static esp_radio::Controller<'static>,
+    /// WiFi controller, shared by the scan and station tasks
+    pub controller: &'static SharedController,
+    /// Network interfaces for station and access point mode
+    pub interfaces: Interfaces<'static>,
+}
+
+/// Initializes the radio and starts the WiFi controller in station mode.
+///
+/// # Arguments
+///
+/// * `device` - WiFi peripheral device with static lifetime
+///
+/// # Errors
+///
+/// This function will return:
+/// - [`Error::RadioInit`] if radio initialization fails
+/// - [`Error::AlreadyInitialized`] if the radio has already been initialized
+/// - [`Error::ControllerCreation`] if WiFi controller creation fails
+/// - [`Error::SetMode`] if setting WiFi mode fails
+/// - [`Error::Start`] if starting the WiFi controller fails
+pub async fn init_radio(device: WIFI<'static>) -> Result<Radio, Error> {
+    let radio_init = esp_radio::init()?;
+    let radio_init = &*RADIO_INIT
+        .try_init(radio_init)
+        .ok_or(Error::AlreadyInitialized)?;
+
+    println!("Radio initialized!");
+
+    println!("Creating WiFi controller...");
+    let (mut wifi_controller, interfaces) =
+        esp_radio::wifi::new(radio_init, device, Default::default())
+            .map_err(Error::ControllerCreation)?;
+    println!("WiFi controller created!");
+
+    wifi_controller
+        .set_mode(esp_radio::wifi::WifiMode::Sta)
+        .map_err(Error::SetMode)?;
+
+    Timer::after(Duration::from_millis(500)).await;
+
+    println!("Starting WiFi controller...");
+    wifi_controller.start_async().await.map_err(Error::Start)?;
+    println!("WiFi controller started!");
+
+    // Give WiFi some time to initialize
+    Timer::after(Duration::from_millis(500)).await;
+
+    let controller = WIFI_CONTROLLER
+        .try_init(Mutex::new(wifi_controller))
+        .ok_or(Error::AlreadyInitialized)?;
+
+    Ok(Radio {
+        init: radio_init,
+        controller,
+        interfaces,
+    })
+}
@@ -0,0 +1,91 @@
+//! Rogue access point detection task.
+//!
+//! [`start_rogue_detector`] spawns a task that checks every published scan
+//! with a [`RogueDetector`] and publishes the resulting [`RogueAlert`]s on
+//! [`ROGUE_ALERTS`].
+//!
+//! The networks expected in the area are an [`Allowlist`], which `main`
+//! loads from the configuration store. Building with `ROGUE_ALLOWLIST` set,
+//! in the format of [`Allowlist::parse`], replaces the saved allowlist:
+//!
+//! ```text
+//! ROGUE_ALLOWLIST="corp=WPA2-Enterprise+aa:bb:cc:00:11:22+aa:bb:cc:00:11:23,guest=WPA2"
+//! ```
+
+use embassy_executor::Spawner;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+use esp_println::println;
+use wifi_core::rogue::{Allowlist, RogueAlert, RogueDetector};
+
+use crate::error::Error;
+use crate::events::{self, ScanSubscriber, ScanUpdate, SubscribeError};
+
+/// Number of alerts buffered for subscribers before the oldest is dropped
+pub const ROGUE_ALERT_QUEUE_DEPTH: usize = 8;
+
+/// Maximum number of concurrent alert subscribers
+pub const MAX_ROGUE_ALERT_SUBSCRIBERS: usize = 2;
+
+/// Only the detector task publishes, and it uses the immediate publisher
+const MAX_ROGUE_ALERT_PUBLISHERS: usize = 1;
+
+/// Channel carrying rogue access point alerts.
+pub static ROGUE_ALERTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    RogueAlert,
+    ROGUE_ALERT_QUEUE_DEPTH,
+    MAX_ROGUE_ALERT_SUBSCRIBERS,
+    MAX_ROGUE_ALERT_PUBLISHERS,
+> = PubSubChannel::new();
+
+/// Subscription to [`ROGUE_ALERTS`]
+pub type RogueAlertSubscriber = Subscriber<
+    'static,
+    CriticalSectionRawMutex,
+    RogueAlert,
+    ROGUE_ALERT_QUEUE_DEPTH,
+    MAX_ROGUE_ALERT_SUBSCRIBERS,
+    MAX_ROGUE_ALERT_PUBLISHERS,
+>;
+
+/// Subscribes to rogue access point alerts.
+///
+/// # Errors
+///
+/// Returns [`SubscribeError::MaximumSubscribersReached`] if all
+/// [`MAX_ROGUE_ALERT_SUBSCRIBERS`] slots are in use.
+pub fn subscribe() -> Result<RogueAlertSubscriber, SubscribeError> {
+    ROGUE_ALERTS.subscriber()
+}
+
+/// Embassy task that checks published scans for rogue access points.
+#[embassy_executor::task]
+async fn rogue_detector_task(mut scans: ScanSubscriber, allowlist: Allowlist) {
+    let mut detector = RogueDetector::new(allowlist);
+    let publisher = ROGUE_ALERTS.immediate_publisher();
+
+    loop {
+        if let ScanUpdate::Report(report) = scans.next().await {
+            detector.check(&report, |alert| {
+                println!("{}", alert);
+                publisher.publish_immediate(alert);
+            });
+        }
+    }
+}
+
+/// Spawns the rogue access point detection task.
+///
+/// Downgraded twins of any network are detected even with an empty
+/// `allowlist`; the other checks only apply to allowlisted networks.
+///
+/// # Errors
+///
+/// Returns [`Error::Subscribe`] if no scan subscriber slot is free, or
+/// [`Error::Spawn`] if the detector is already running.
+pub fn start_rogue_detector(spawner: Spawner, allowlist: Allowlist) -> Result<(), Error> {
+    let scans = events::subscribe().map_err(Error::Subscribe)?;
+    spawner.spawn(rogue_detector_task(scans, allowlist))?;
+    Ok(())
+}
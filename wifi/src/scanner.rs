@@ -1,136 +1,302 @@
 //! WiFi driver functionality for ESP32.
 //!
 //! This module provides async tasks for WiFi scanning and network operations.
+//! Every completed scan is turned into a [`ScanReport`] and published through
+//! [`crate::events`].
 
-use core::fmt::Error;
-
-use embassy_time::{Duration, Timer};
+use embassy_futures::select::{select3, Either3};
+use embassy_time::{Duration, Instant, Timer};
 use embassy_executor::Spawner;
 use esp_hal::peripherals::WIFI;
 use esp_println::println;
-use esp_radio::wifi::WifiController;
-use crate::types::{RADIO_INIT, WIFI_CONTROLLER};
+use esp_radio::wifi::{AccessPointInfo, ScanConfig, ScanTypeConfig, WifiError};
+use wifi_core::report::{AccessPointRecord, AuthMethod, ScanReport, SecondaryChannel};
+use wifi_core::scan_config::{ScanType, ScannerConfig};
+use crate::control::{self, ConfigReceiver, ScannerCommand, ScannerHandle, ScannerState};
+use crate::error::Error;
+use crate::events::{self, LATEST_SCAN};
+use crate::radio;
+use crate::supervisor::{self, Watchdog};
+use crate::time_sync;
+use crate::types::SharedController;
 
-/// Interval between WiFi scans in seconds
-const SCAN_INTERVAL_SECS: u64 = 10;
+/// Longest time a scan may take, waiting for the controller included,
+/// before the supervisor steps in
+const SCAN_DEADLINE: Duration = Duration::from_secs(60);
 
 /// Embassy task that continuously scans for WiFi networks.
 ///
-/// This task runs indefinitely, performing WiFi scans at regular intervals
-/// and printing discovered networks with their signal strength.
-///
-/// # Arguments
+/// This task performs WiFi scans as described by the current
+/// [`ScannerConfig`] until it is stopped through the [`ScannerHandle`].
+/// Each scan is converted into a [`ScanReport`], printed, and published to
+/// subscribers; failed scans are published as errors.
+/// Commands are checked before the timer, so a pause sent right after
+/// spawning takes effect before the first scan.
+/// The task is idle for the supervisor between scans, so only a scan that
+/// hangs misses a deadline.
 ///
-/// * `wifi_controller` - Mutable reference to the WiFi controller with static lifetime
+/// The task leaves the WiFi mode as it finds it, station mode after
+/// [`radio::init_radio`], so an access point opened next to it stays up.
 ///
-/// # Panics
+/// # Arguments
 ///
-/// Panics if WiFi mode cannot be set to Station mode.
+/// * `wifi_controller` - WiFi controller shared with the station task
+/// * `config_updates` - Receiver for configuration changes made through the [`ScannerHandle`]
+/// * `watchdog` - Check-ins with the supervisor
 #[embassy_executor::task]
-pub async fn wifi_scan_task(wifi_controller: &'static mut WifiController<'static>) {
-    // Set WiFi mode once
-    wifi_controller
-        .set_mode(esp_radio::wifi::WifiMode::Sta)
-        .unwrap();
+pub async fn wifi_scan_task(
+    wifi_controller: &'static SharedController,
+    mut config_updates: ConfigReceiver,
+    watchdog: Watchdog,
+) {
+    let mut config = config_updates.get().await;
+    let mut sequence: u32 = 0;
+    let mut paused = false;
+    let mut last_scan = Instant::now();
+    let mut next_scan = last_scan;
 
     loop {
+        // A paused scanner never wakes up on its own
+        let deadline = if paused { Instant::MAX } else { next_scan };
+        watchdog.idle();
+        let event = select3(
+            control::next_command(),
+            config_updates.changed(),
+            Timer::at(deadline),
+        )
+        .await;
+
+        let on_demand = match event {
+            Either3::First(ScannerCommand::Pause) => {
+                println!("WiFi scanner paused");
+                paused = true;
+                control::set_state(ScannerState::Paused);
+                continue;
+            }
+            Either3::First(ScannerCommand::Resume) => {
+                println!("WiFi scanner resumed");
+                paused = false;
+                next_scan = Instant::now();
+                control::set_state(ScannerState::Running);
+                continue;
+            }
+            Either3::First(ScannerCommand::Stop) => {
+                println!("WiFi scanner stopped");
+                control::set_state(ScannerState::Stopped);
+                return;
+            }
+            Either3::First(ScannerCommand::ScanNow) => true,
+            Either3::Second(new_config) => {
+                println!("Scanner configuration updated");
+                next_scan = last_scan + Duration::from_secs(new_config.interval_secs.into());
+                config = new_config;
+                continue;
+            }
+            Either3::Third(()) => false,
+        };
+
         println!("Starting Wi-Fi scan...");
+        watchdog.heartbeat();
+
+        let result = scan(wifi_controller, &config, sequence.wrapping_add(1))
+            .await
+            .map_err(Error::Scan);
+        match &result {
+            Ok(report) => {
+                sequence = report.sequence;
+                println!("{}", report);
 
-        let scan_config = esp_radio::wifi::ScanConfig::default();
-        match wifi_controller.scan_with_config_async(scan_config).await {
-            Ok(scan_results) => {
-                println!("Found {} networks:", scan_results.len());
-
-                for (i, ap) in scan_results.iter().enumerate() {
-                    println!(
-                        "  {}: SSID: {}, Channel: {}, RSSI: {}",
-                        i + 1,
-                        ap.ssid.as_str(),
-                        ap.channel,
-                        ap.signal_strength
-                    );
+                for (i, ap) in report.iter().enumerate() {
+                    println!("  {}: {}", i + 1, ap);
                 }
+
+                events::publish(report.clone());
             }
             Err(e) => {
-                println!("WiFi scan failed: {}", e);
+                println!("{}", e);
+                events::publish_error(*e);
             }
         }
-        println!("Waiting before next scan...");
-        Timer::after(Duration::from_secs(SCAN_INTERVAL_SECS)).await;
+        if on_demand {
+            control::respond(result);
+        }
+
+        last_scan = Instant::now();
+        next_scan = last_scan + Duration::from_secs(config.interval_secs.into());
+        if !paused {
+            println!("Waiting before next scan...");
+        }
+    }
+}
+
+/// Runs one scan as described by `config`.
+///
+/// The driver can only restrict a scan to a single channel, so a channel
+/// subset is scanned one channel at a time and merged into one report. The
+/// controller stays locked until all channels are done.
+pub(crate) async fn scan(
+    wifi_controller: &SharedController,
+    config: &ScannerConfig,
+    sequence: u32,
+) -> Result<ScanReport, WifiError> {
+    let mut wifi_controller = wifi_controller.lock().await;
+    let mut report = ScanReport::new(sequence, 0);
+
+    if config.channels.is_all() {
+        let scan_results = wifi_controller
+            .scan_with_config_async(driver_scan_config(config, None))
+            .await?;
+        append_results(&mut report, &scan_results);
+    } else {
+        for channel in config.channels.iter() {
+            let scan_results = wifi_controller
+                .scan_with_config_async(driver_scan_config(config, Some(channel)))
+                .await?;
+            append_results(&mut report, &scan_results);
+        }
+    }
+
+    report.timestamp_ms = Instant::now().as_millis();
+    report.utc = time_sync::now_utc();
+    Ok(report)
+}
+
+/// Translates the scanner configuration into driver scan settings.
+fn driver_scan_config(config: &ScannerConfig, channel: Option<u8>) -> ScanConfig<'_> {
+    let scan_type = match config.scan_type {
+        ScanType::Active => ScanTypeConfig::Active {
+            min: core::time::Duration::from_millis(config.dwell_min_ms.into()),
+            max: core::time::Duration::from_millis(config.dwell_max_ms.into()),
+        },
+        ScanType::Passive => {
+            ScanTypeConfig::Passive(core::time::Duration::from_millis(config.dwell_max_ms.into()))
+        }
+    };
+
+    let mut scan_config = ScanConfig::default()
+        .with_scan_type(scan_type)
+        .with_show_hidden(config.show_hidden);
+    if let Some(channel) = channel {
+        scan_config = scan_config.with_channel(channel);
     }
+    if let Some(ssid) = &config.ssid_filter {
+        scan_config = scan_config.with_ssid(ssid.as_str());
+    }
+    if let Some(bssid) = config.bssid_filter {
+        scan_config = scan_config.with_bssid(bssid.0);
+    }
+    scan_config
+}
+
+/// Returns a copy of the most recent scan report, if a scan has completed yet.
+pub fn latest_scan() -> Option<ScanReport> {
+    LATEST_SCAN.try_get()
+}
+
+/// Adds the raw driver results to a [`ScanReport`].
+fn append_results(report: &mut ScanReport, scan_results: &[AccessPointInfo]) {
+    for ap in scan_results {
+        report.push(AccessPointRecord::new(
+            ap.ssid.as_str(),
+            ap.bssid,
+            ap.channel,
+            secondary_channel(&ap.secondary_channel),
+            ap.signal_strength,
+            ap.auth_method.and_then(auth_method),
+        ));
+    }
+}
+
+/// Maps the driver's secondary channel onto the report type.
+fn secondary_channel(channel: &esp_radio::wifi::SecondaryChannel) -> SecondaryChannel {
+    match channel {
+        esp_radio::wifi::SecondaryChannel::None => SecondaryChannel::None,
+        esp_radio::wifi::SecondaryChannel::Above => SecondaryChannel::Above,
+        esp_radio::wifi::SecondaryChannel::Below => SecondaryChannel::Below,
+    }
+}
+
+/// Maps the driver's authentication method onto the report type.
+///
+/// Returns `None` for methods the report does not know about.
+fn auth_method(method: esp_radio::wifi::AuthMethod) -> Option<AuthMethod> {
+    use esp_radio::wifi::AuthMethod as Driver;
+
+    Some(match method {
+        Driver::None => AuthMethod::Open,
+        Driver::Wep => AuthMethod::Wep,
+        Driver::Wpa => AuthMethod::Wpa,
+        Driver::Wpa2Personal => AuthMethod::Wpa2Personal,
+        Driver::WpaWpa2Personal => AuthMethod::WpaWpa2Personal,
+        Driver::Wpa2Enterprise => AuthMethod::Wpa2Enterprise,
+        Driver::Wpa3Personal => AuthMethod::Wpa3Personal,
+        Driver::Wpa2Wpa3Personal => AuthMethod::Wpa2Wpa3Personal,
+        Driver::WapiPersonal => AuthMethod::WapiPersonal,
+        _ => return None,
+    })
 }
 
 /// Initializes the WiFi subsystem and spawns a background scanning task.
 ///
-/// This function sets up the radio and WiFi controller, then spawns
-/// an async task that continuously scans for available WiFi networks.
+/// This function sets up the radio and WiFi controller with
+/// [`radio::init_radio`], then spawns an async task that continuously scans
+/// for available WiFi networks. Use [`start_scanner`] instead to share the
+/// controller with the station.
 ///
 /// # Arguments
 ///
 /// * `spawner` - Embassy task spawner for creating the background scan task
 /// * `device` - WiFi peripheral device with static lifetime
+/// * `config` - Initial scanner configuration
 ///
 /// # Returns
 ///
-/// Returns `Ok(())` on successful initialization, or an `Error` if any step fails.
+/// Returns a [`ScannerHandle`] for changing the configuration at runtime,
+/// or an [`Error`] describing the step that failed.
 ///
 /// # Errors
 ///
-/// This function will return an error if:
-/// - Radio initialization fails
-/// - WiFi controller creation fails
-/// - Setting WiFi mode fails
-/// - Starting the WiFi controller fails
-/// - Spawning the scan task fails
+/// This function will return:
+/// - [`Error::InvalidConfig`] if the configuration is invalid
+/// - any error of [`radio::init_radio`]
+/// - any error of [`start_scanner`]
 pub async fn wifi_scanner(
     spawner: Spawner, 
     device: WIFI<'static>,
-) -> Result<(), Error> {
-    let radio_init = esp_radio::init()
-        .map_err(|e| {
-            println!("Failed to initialize radio controller: {}", e);
-            Error
-        })?;
-    let radio_init = RADIO_INIT.init(radio_init);
-    
-    println!("Radio initialized!");
-    
-    println!("Creating WiFi controller...");
-    let (wifi_controller, _interfaces) = esp_radio::wifi::new(
-        radio_init,
-        device,
-        Default::default(),
-    ).map_err(|e| {
-        println!("Failed to create WiFi controller: {}", e);
-        Error
-    })?;
-    println!("WiFi controller created!");
-    
-    let wifi_controller = WIFI_CONTROLLER.init(wifi_controller);
-
-    wifi_controller
-        .set_mode(esp_radio::wifi::WifiMode::Sta).map_err(|e| {
-            println!("Failed to set Wi-Fi mode: {}", e);
-            Error
-        })?;
-
-    Timer::after(Duration::from_millis(500)).await;
-    
-    println!("Starting WiFi controller...");
-    wifi_controller.start_async().await.map_err(|e|{
-        println!("Failed to start Wi-Fi controller: {}", e);
-        Error
-    })?;
-    println!("WiFi controller started!");
-    
-    // Give WiFi some time to initialize
-    Timer::after(Duration::from_millis(500)).await;
-
-    spawner.spawn(wifi_scan_task(wifi_controller)).map_err(|e| {
-        println!("Failed to spawn WiFi scan task: {}", e);
-        Error
-    })?;
-
-    Ok(())
-
-}
\ No newline at end of file
+    config: ScannerConfig,
+) -> Result<ScannerHandle, Error> {
+    config.validate()?;
+
+    let radio = radio::init_radio(device).await?;
+    start_scanner(spawner, radio.controller, config)
+}
+
+/// Spawns the background scanning task on an initialized controller.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the background scan task
+/// * `wifi_controller` - Controller returned by [`radio::init_radio`]
+/// * `config` - Initial scanner configuration
+///
+/// # Errors
+///
+/// This function will return:
+/// - [`Error::InvalidConfig`] if the configuration is invalid
+/// - [`Error::AlreadyInitialized`] if the scanner has already been started
+/// - [`Error::Supervisor`] if the scan task cannot be supervised
+/// - [`Error::Spawn`] if spawning the scan task fails
+pub fn start_scanner(
+    spawner: Spawner,
+    wifi_controller: &'static SharedController,
+    config: ScannerConfig,
+) -> Result<ScannerHandle, Error> {
+    config.validate()?;
+
+    let (handle, config_updates) =
+        ScannerHandle::init(config).ok_or(Error::AlreadyInitialized)?;
+    let watchdog = supervisor::register("scanner", SCAN_DEADLINE)?;
+    spawner.spawn(wifi_scan_task(wifi_controller, config_updates, watchdog))?;
+
+    Ok(handle)
+}
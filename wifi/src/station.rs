@@ -0,0 +1,385 @@
+//! Station connection manager.
+//!
+//! [`start_station`] spawns a task that joins the best known network and
+//! keeps the connection up. What to do next is decided by a
+//! [`StationMachine`]; the task carries its decisions out on the driver.
+//! Every state change is published on [`STATION_STATE`], and the reasons for
+//! failed attempts and lost links are decoded from the driver's disconnect
+//! events.
+//!
+//! Each connection attempt starts with a scan. The networks of the
+//! [`ProfileStore`] found in range are tried best first, as ranked by
+//! [`ProfileStore::select`], and only when all of them fail does the station
+//! back off.
+//!
+//! ```no_run
+//! # async fn example() {
+//! use wifi::station::ConnectionState;
+//!
+//! // Wait until the station is connected
+//! if let Some(mut states) = wifi::station::subscribe() {
+//!     states.get_and(|state| *state == ConnectionState::Connected).await;
+//! }
+//! # }
+//! ```
+
+use core::cell::{Cell, RefCell};
+
+use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::{Receiver, Watch};
+use embassy_time::{Duration, Timer};
+use esp_hal::rng::Rng;
+use esp_println::println;
+use esp_radio::wifi::event::{self, EventExt};
+use esp_radio::wifi::{AuthMethod, ClientConfig, ModeConfig};
+use wifi_core::profiles::Candidate;
+pub use wifi_core::profiles::{NetworkProfile, ProfileStore};
+use wifi_core::report::Ssid;
+use wifi_core::scan_config::ScannerConfig;
+use wifi_core::station::StationMachine;
+pub use wifi_core::station::{
+    BackoffConfig, ConnectionState, Credentials, CredentialsError, DisconnectReason,
+};
+
+use crate::error::Error;
+use crate::scanner;
+use crate::supervisor::{self, Watchdog};
+use crate::types::SharedController;
+
+/// Maximum number of receivers on [`STATION_STATE`]
+pub const MAX_STATE_RECEIVERS: usize = 4;
+
+/// Number of commands that can be queued before senders wait
+const COMMAND_QUEUE_DEPTH: usize = 2;
+
+/// Longest time the scan for known networks, or an attempt to join one, may
+/// take before the supervisor steps in
+const CONNECT_DEADLINE: Duration = Duration::from_secs(60);
+
+/// Connection state of the station, updated on every change.
+pub static STATION_STATE: Watch<CriticalSectionRawMutex, ConnectionState, MAX_STATE_RECEIVERS> =
+    Watch::new();
+
+/// Commands from [`StationHandle`] to the station task.
+static COMMANDS: Channel<CriticalSectionRawMutex, StationCommand, COMMAND_QUEUE_DEPTH> =
+    Channel::new();
+
+/// Reason code of the latest disconnect event from the driver.
+static DISCONNECTED: Signal<CriticalSectionRawMutex, u8> = Signal::new();
+
+/// Why the connection was last lost.
+static LAST_REASON: BlockingMutex<CriticalSectionRawMutex, Cell<Option<DisconnectReason>>> =
+    BlockingMutex::new(Cell::new(None));
+
+/// Known networks, read at the start of every connection attempt.
+static PROFILES: BlockingMutex<CriticalSectionRawMutex, RefCell<ProfileStore>> =
+    BlockingMutex::new(RefCell::new(ProfileStore::new()));
+
+/// Network being joined or joined.
+static NETWORK: BlockingMutex<CriticalSectionRawMutex, RefCell<Option<Ssid>>> =
+    BlockingMutex::new(RefCell::new(None));
+
+/// Network to try before all others, chosen with [`StationHandle::join`].
+static PREFERRED: BlockingMutex<CriticalSectionRawMutex, RefCell<Option<Ssid>>> =
+    BlockingMutex::new(RefCell::new(None));
+
+/// Receiver of station state changes
+pub type StationStateReceiver =
+    Receiver<'static, CriticalSectionRawMutex, ConnectionState, MAX_STATE_RECEIVERS>;
+
+/// Command sent to the station task
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+enum StationCommand {
+    /// Start connecting, cutting a pending backoff short
+    Connect,
+    /// Disconnect and stay idle
+    Disconnect,
+}
+
+/// Returns the current connection state of the station.
+pub fn state() -> ConnectionState {
+    STATION_STATE.try_get().unwrap_or(ConnectionState::Idle)
+}
+
+/// Subscribes to station state changes.
+///
+/// Returns `None` if all [`MAX_STATE_RECEIVERS`] receivers are in use. The
+/// watch only keeps the newest state, so a slow receiver can miss short-lived
+/// states such as [`ConnectionState::Disconnected`]; use
+/// [`StationHandle::last_disconnect_reason`] for the reason.
+pub fn subscribe() -> Option<StationStateReceiver> {
+    STATION_STATE.receiver()
+}
+
+/// Handle for controlling the station task.
+///
+/// The handle is cheap to copy and can be passed to any task.
+#[derive(Clone, Copy, Debug)]
+pub struct StationHandle {
+    _private: (),
+}
+
+impl StationHandle {
+    /// Returns the current connection state.
+    pub fn state(&self) -> ConnectionState {
+        state()
+    }
+
+    /// Returns why the connection was last lost or could not be established.
+    pub fn last_disconnect_reason(&self) -> Option<DisconnectReason> {
+        LAST_REASON.lock(Cell::get)
+    }
+
+    /// Returns the SSID of the network being joined or joined, if any.
+    pub fn network(&self) -> Option<Ssid> {
+        NETWORK.lock(|network| network.borrow().clone())
+    }
+
+    /// Returns a copy of the known networks.
+    pub fn profiles(&self) -> ProfileStore {
+        PROFILES.lock(|profiles| profiles.borrow().clone())
+    }
+
+    /// Adds a known network, replacing any profile for the same SSID.
+    ///
+    /// Changes apply from the next connection attempt on.
+    ///
+    /// # Errors
+    ///
+    /// Returns the profile back if the store is full.
+    pub fn add_profile(&self, profile: NetworkProfile) -> Result<(), NetworkProfile> {
+        PROFILES.lock(|profiles| profiles.borrow_mut().insert(profile))
+    }
+
+    /// Forgets a known network.
+    ///
+    /// A connection to it that is already up is kept.
+    pub fn remove_profile(&self, ssid: &str) -> Option<NetworkProfile> {
+        PROFILES.lock(|profiles| profiles.borrow_mut().remove(ssid))
+    }
+
+    /// Starts connecting again after [`disconnect`](Self::disconnect) or
+    /// after the station gave up. A pending backoff is cut short.
+    pub async fn connect(&self) {
+        COMMANDS.send(StationCommand::Connect).await;
+    }
+
+    /// Leaves the current network and joins the known network `ssid`.
+    ///
+    /// From then on, `ssid` is tried first whenever the station connects,
+    /// ahead of networks of higher priority, until another network is
+    /// joined this way. If it is not in range, the station falls back to the
+    /// other known networks.
+    pub async fn join(&self, ssid: Ssid) {
+        PREFERRED.lock(|preferred| *preferred.borrow_mut() = Some(ssid));
+        COMMANDS.send(StationCommand::Disconnect).await;
+        COMMANDS.send(StationCommand::Connect).await;
+    }
+
+    /// Disconnects from the network and stops reconnecting.
+    pub async fn disconnect(&self) {
+        COMMANDS.send(StationCommand::Disconnect).await;
+    }
+}
+
+/// Embassy task that connects the station and reconnects it when the link is lost.
+///
+/// # Arguments
+///
+/// * `wifi_controller` - WiFi controller shared with the scan task
+/// * `backoff` - Reconnect timing
+/// * `watchdog` - Check-ins with the supervisor; the task is idle while it
+///   waits for a command, a lost link or the end of a backoff
+#[embassy_executor::task]
+async fn station_task(
+    wifi_controller: &'static SharedController,
+    backoff: BackoffConfig,
+    watchdog: Watchdog,
+) {
+    event::StaDisconnected::update_handler(|event| DISCONNECTED.signal(event.reason()));
+
+    let rng = Rng::new();
+    let mut machine = StationMachine::new(backoff);
+    let sender = STATION_STATE.sender();
+    machine.start();
+
+    loop {
+        let state = machine.state();
+        if STATION_STATE.try_get() != Some(state) {
+            println!("Station {}", state);
+            LAST_REASON.lock(|cell| cell.set(machine.last_reason()));
+            sender.send(state);
+        }
+
+        watchdog.idle();
+        match state {
+            ConnectionState::Idle => {
+                if COMMANDS.receive().await == StationCommand::Connect {
+                    machine.start();
+                }
+            }
+            ConnectionState::Connecting { .. } => {
+                match connect_best(wifi_controller, &watchdog).await {
+                    Ok(()) => machine.connected(),
+                    Err(reason) => machine.failed(reason),
+                }
+            }
+            ConnectionState::Connected => {
+                match select(DISCONNECTED.wait(), COMMANDS.receive()).await {
+                    Either::First(code) => machine.link_lost(DisconnectReason::from_code(code)),
+                    Either::Second(StationCommand::Disconnect) => {
+                        watchdog.heartbeat();
+                        disconnect(wifi_controller).await;
+                        set_network(None);
+                        machine.stop();
+                    }
+                    Either::Second(StationCommand::Connect) => {}
+                }
+            }
+            ConnectionState::Disconnected { .. } => {
+                if !machine.retry(rng.random()) {
+                    println!("Station giving up after {} attempts", machine.failures());
+                    match COMMANDS.receive().await {
+                        StationCommand::Connect => machine.start(),
+                        StationCommand::Disconnect => machine.stop(),
+                    }
+                }
+            }
+            ConnectionState::Backoff { delay_ms, .. } => {
+                match select(Timer::after_millis(delay_ms.into()), COMMANDS.receive()).await {
+                    Either::First(()) => machine.backoff_elapsed(),
+                    Either::Second(StationCommand::Connect) => machine.start(),
+                    Either::Second(StationCommand::Disconnect) => machine.stop(),
+                }
+            }
+        }
+    }
+}
+
+/// Scans for known networks and tries them best first.
+///
+/// Sends a heartbeat before the scan and before each attempt. Returns the
+/// reason of the last failure if none of them could be joined.
+async fn connect_best(
+    wifi_controller: &SharedController,
+    watchdog: &Watchdog,
+) -> Result<(), DisconnectReason> {
+    watchdog.heartbeat();
+    let report = scanner::scan(wifi_controller, &ScannerConfig::default(), 0)
+        .await
+        .map_err(|e| {
+            println!("Station scan failed: {}", e);
+            DisconnectReason::NoApFound
+        })?;
+    let mut candidates = PROFILES.lock(|profiles| profiles.borrow().select(&report));
+    let preferred = PREFERRED.lock(|preferred| {
+        let preferred = preferred.borrow();
+        candidates
+            .iter()
+            .position(|candidate| Some(&candidate.credentials.ssid) == preferred.as_ref())
+    });
+    if let Some(index) = preferred {
+        candidates[..=index].rotate_right(1);
+    }
+    if candidates.is_empty() {
+        println!("No known network in range");
+    }
+
+    let mut reason = DisconnectReason::NoApFound;
+    for candidate in &candidates {
+        println!(
+            "Joining {} via {} ({} dBm)",
+            candidate.credentials.ssid, candidate.bssid, candidate.rssi
+        );
+        set_network(Some(candidate.credentials.ssid.clone()));
+        watchdog.heartbeat();
+        match connect(wifi_controller, candidate).await {
+            Ok(()) => return Ok(()),
+            Err(e) => reason = e,
+        }
+    }
+    set_network(None);
+    Err(reason)
+}
+
+/// Tries to join one candidate network.
+///
+/// Returns the reason from the driver's disconnect event if it failed.
+async fn connect(
+    wifi_controller: &SharedController,
+    candidate: &Candidate,
+) -> Result<(), DisconnectReason> {
+    let credentials = &candidate.credentials;
+    let mut client_config = ClientConfig::default()
+        .with_ssid(credentials.ssid.as_str().into())
+        .with_password(credentials.password.as_str().into())
+        .with_bssid(candidate.bssid.0)
+        .with_channel(candidate.channel);
+    if credentials.is_open() {
+        client_config = client_config.with_auth_method(AuthMethod::None);
+    }
+
+    let mut wifi_controller = wifi_controller.lock().await;
+    if let Err(e) = wifi_controller.set_config(&ModeConfig::Client(client_config)) {
+        println!("Station configuration rejected: {}", e);
+        return Err(DisconnectReason::ConnectionFailed);
+    }
+
+    DISCONNECTED.reset();
+    wifi_controller.connect_async().await.map_err(|e| {
+        println!("Connection attempt failed: {}", e);
+        DISCONNECTED
+            .try_take()
+            .map_or(DisconnectReason::Unspecified, DisconnectReason::from_code)
+    })
+}
+
+/// Records the network being joined.
+fn set_network(ssid: Option<Ssid>) {
+    NETWORK.lock(|network| *network.borrow_mut() = ssid);
+}
+
+/// Leaves the network, discarding the disconnect event it causes.
+async fn disconnect(wifi_controller: &SharedController) {
+    if let Err(e) = wifi_controller.lock().await.disconnect_async().await {
+        println!("Failed to disconnect: {}", e);
+    }
+    DISCONNECTED.reset();
+}
+
+/// Spawns the connection task for the given known networks.
+///
+/// The task starts connecting right away and keeps reconnecting with
+/// exponential backoff, as described by `backoff`. While no known network
+/// is in range, it keeps scanning at the backoff pace.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the station task
+/// * `wifi_controller` - Controller returned by [`crate::radio::init_radio`]
+/// * `profiles` - Known networks
+/// * `backoff` - Reconnect timing
+///
+/// # Errors
+///
+/// This function will return:
+/// - [`Error::Supervisor`] if the station task cannot be supervised
+/// - [`Error::Spawn`] if the station task is already running
+pub fn start_station(
+    spawner: Spawner,
+    wifi_controller: &'static SharedController,
+    profiles: ProfileStore,
+    backoff: BackoffConfig,
+) -> Result<StationHandle, Error> {
+    PROFILES.lock(|store| *store.borrow_mut() = profiles);
+    let watchdog = supervisor::register("station", CONNECT_DEADLINE)?;
+    spawner
+        .spawn(station_task(wifi_controller, backoff, watchdog))
+        .inspect_err(|_| watchdog.idle())?;
+    Ok(StationHandle { _private: () })
+}
@@ -0,0 +1,172 @@
+//! Persistent configuration storage.
+//!
+//! [`init_flash`] puts the SPI flash behind an async mutex, shared by the
+//! configuration store and the [firmware updater](crate::firmware), so their
+//! erases and writes never interleave. [`init_storage`] finds the
+//! [`CONFIG_PARTITION_LABEL`] data partition on it, and each
+//! [`SharedStore::lock`] mounts a [`KvStore`] there, so any task can load and
+//! save [`settings`](wifi_core::settings).
+//!
+//! The partition must be listed in the partition table the application is
+//! flashed with, see `partitions.csv`.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::{Mutex, MutexGuard};
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use esp_bootloader_esp_idf::partitions::{self, PARTITION_TABLE_MAX_LEN};
+use esp_hal::peripherals::FLASH;
+use esp_println::println;
+use esp_storage::{FlashStorage, FlashStorageError};
+use static_cell::StaticCell;
+use wifi_core::flash_kv::{KvStore, Partition, StoreError};
+
+use crate::error::Error;
+
+/// Label of the data partition holding the configuration store
+pub const CONFIG_PARTITION_LABEL: &str = "config";
+
+/// The SPI flash, shared between tasks.
+///
+/// Flash operations block the executor while the flash is locked; keep the
+/// lock no longer than needed.
+pub type SharedFlash = Mutex<CriticalSectionRawMutex, FlashStorage<'static>>;
+
+/// The SPI flash while a task holds its lock
+pub struct LockedFlash(MutexGuard<'static, CriticalSectionRawMutex, FlashStorage<'static>>);
+
+impl ErrorType for LockedFlash {
+    type Error = FlashStorageError;
+}
+
+impl ReadNorFlash for LockedFlash {
+    const READ_SIZE: usize = FlashStorage::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
+
+impl NorFlash for LockedFlash {
+    const WRITE_SIZE: usize = FlashStorage::WRITE_SIZE;
+    const ERASE_SIZE: usize = FlashStorage::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.0.erase(from, to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(offset, bytes)
+    }
+}
+
+/// The configuration partition of the locked SPI flash
+pub type ConfigFlash = Partition<LockedFlash>;
+
+/// The configuration store, mounted while the flash is locked
+pub type ConfigStore = KvStore<ConfigFlash>;
+
+/// Configuration store shared between tasks
+#[derive(Debug)]
+pub struct SharedStore {
+    flash: &'static SharedFlash,
+    offset: u32,
+    size: u32,
+}
+
+impl SharedStore {
+    /// Locks the flash and mounts the store on it.
+    ///
+    /// The flash stays locked until the returned store is dropped; keep it
+    /// for one load or save at a time.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError`] if the store cannot be mounted.
+    pub async fn lock(&self) -> Result<ConfigStore, StoreError> {
+        let flash = LockedFlash(self.flash.lock().await);
+        let partition = Partition::new(flash, self.offset, self.size).map_err(StoreError::Flash)?;
+        let mut store = KvStore::new(partition)?;
+        store.mount()?;
+        Ok(store)
+    }
+}
+
+/// Static storage for the shared SPI flash
+static SHARED_FLASH: StaticCell<SharedFlash> = StaticCell::new();
+
+/// Static storage for the configuration store
+static CONFIG_STORE: StaticCell<SharedStore> = StaticCell::new();
+
+/// Puts the SPI flash behind an async mutex.
+///
+/// The peripheral can only be taken once, so neither can the flash.
+///
+/// # Arguments
+///
+/// * `flash` - SPI flash peripheral
+pub fn init_flash(flash: FLASH<'static>) -> &'static SharedFlash {
+    SHARED_FLASH.init(Mutex::new(FlashStorage::new(flash)))
+}
+
+/// Mounts the configuration store.
+///
+/// A store written in an unsupported format, e.g. by newer firmware, is
+/// formatted.
+///
+/// # Arguments
+///
+/// * `flash` - Shared SPI flash, from [`init_flash`]
+///
+/// # Errors
+///
+/// This function will return:
+/// - [`Error::PartitionTable`] if the partition table cannot be read or has no
+///   [`CONFIG_PARTITION_LABEL`] partition
+/// - [`Error::Storage`] if the partition cannot hold a store or the flash fails
+/// - [`Error::AlreadyInitialized`] if the store has already been mounted
+pub async fn init_storage(flash: &'static SharedFlash) -> Result<&'static SharedStore, Error> {
+    let mut locked = LockedFlash(flash.lock().await);
+
+    let mut table = [0u8; PARTITION_TABLE_MAX_LEN];
+    let (offset, size) = {
+        let partitions = partitions::read_partition_table(&mut *locked.0, &mut table)
+            .map_err(Error::PartitionTable)?;
+        let entry = partitions
+            .iter()
+            .find(|entry| entry.label_as_str() == CONFIG_PARTITION_LABEL)
+            .ok_or(Error::PartitionTable(partitions::Error::Invalid))?;
+        (entry.offset(), entry.len())
+    };
+
+    let partition = Partition::new(locked, offset, size).map_err(StoreError::Flash)?;
+    let mut store = KvStore::new(partition)?;
+    match store.mount() {
+        Err(StoreError::UnsupportedFormat(version)) => {
+            println!(
+                "Config store has unsupported format version {}, formatting",
+                version
+            );
+            store.format()?;
+        }
+        result => result?,
+    }
+    println!(
+        "Config store mounted: {} bytes at {:#x}, {} keys",
+        size,
+        offset,
+        store.len()
+    );
+
+    CONFIG_STORE
+        .try_init(SharedStore {
+            flash,
+            offset,
+            size,
+        })
+        .map(|store| &*store)
+        .ok_or(Error::AlreadyInitialized)
+}
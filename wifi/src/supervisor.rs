@@ -0,0 +1,255 @@
+//! Task watchdog and supervisor.
+//!
+//! Long-running tasks register with [`register`] and check in through the
+//! returned [`Watchdog`]: a heartbeat at least once per deadline while they
+//! work, and [`Watchdog::idle`] before waiting for something that may take
+//! any time, such as a command. The scan task, for example, sends a
+//! heartbeat before each scan and goes idle after it, so a scan that hangs
+//! in the driver is noticed:
+//!
+//! ```text
+//! Task scanner missed its deadline: no heartbeat for 60012 ms, 1 in a row, restarting the radio
+//! ```
+//!
+//! [`start_supervisor`] spawns the task that checks the deadlines and acts
+//! on missed ones as set by [`SupervisorConfig::escalation`]:
+//!
+//! - restarting the radio, which only works if the stuck task does not hold
+//!   the WiFi controller;
+//! - resetting the chip through the RTC watchdog;
+//! - resetting the chip into safe mode, in which `main` starts neither the
+//!   radio nor anything that needs it, leaving the console to look into the
+//!   problem. [`take_safe_mode`] tells `main` about it; the reset after that
+//!   boots normally again.
+//!
+//! The supervisor also keeps the RTC watchdog fed, so a task that blocks
+//! the executor, which no heartbeat can catch, resets the chip as well.
+//! Deadlines and misses are kept in `wifi_core`.
+
+use core::cell::RefCell;
+
+use embassy_executor::Spawner;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use esp_hal::peripherals::LPWR;
+use esp_hal::rtc_cntl::{Rtc, RwdtStage, RwdtStageAction};
+use esp_println::println;
+pub use wifi_core::task_watchdog::Escalation;
+use wifi_core::task_watchdog::{Action, MAX_TASKS, Miss, TaskId, TaskStatus, TaskWatchdog};
+
+use crate::error::Error;
+use crate::types::SharedController;
+
+/// Longest time a radio restart may take, waiting for the controller included
+const RADIO_RESTART_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time the RTC watchdog is given to reset the chip on purpose, in ms
+const RESET_DELAY_MS: u64 = 10;
+
+/// Value of [`SAFE_MODE`] that asks the next boot for safe mode; anything
+/// else, such as the random contents after power-up, does not
+const SAFE_MODE_REQUEST: u32 = 0x5afe_b007;
+
+/// Safe mode request, kept in RTC memory across the reset
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut SAFE_MODE: u32 = 0;
+
+/// Deadlines of the registered tasks.
+static WATCHDOG: BlockingMutex<CriticalSectionRawMutex, RefCell<TaskWatchdog>> =
+    BlockingMutex::new(RefCell::new(TaskWatchdog::new(Escalation::LOG_ONLY)));
+
+/// Checks and escalation of [`start_supervisor`]
+#[derive(Clone, Copy, Debug)]
+pub struct SupervisorConfig {
+    /// Time between two checks of the deadlines
+    pub check_interval: Duration,
+    /// What missed deadlines lead to
+    pub escalation: Escalation,
+    /// Time without a check after which the RTC watchdog resets the chip,
+    /// or `None` to leave the RTC watchdog off; must be longer than a radio
+    /// restart takes
+    pub hardware_timeout: Option<Duration>,
+}
+
+impl Default for SupervisorConfig {
+    /// Checks every second with the default escalation, and resets the chip
+    /// if the checks stop for 15 s
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(1),
+            escalation: Escalation::default(),
+            hardware_timeout: Some(Duration::from_secs(15)),
+        }
+    }
+}
+
+/// Check-ins of one supervised task
+#[derive(Clone, Copy, Debug)]
+pub struct Watchdog {
+    id: TaskId,
+}
+
+impl Watchdog {
+    /// Tells the supervisor the task is working; the next heartbeat is due
+    /// within the task's deadline.
+    pub fn heartbeat(&self) {
+        let now = Instant::now().as_millis();
+        WATCHDOG.lock(|watchdog| watchdog.borrow_mut().heartbeat(self.id, now));
+    }
+
+    /// Tells the supervisor the task is about to wait for something that may
+    /// take any time; it has no deadline until its next heartbeat.
+    pub fn idle(&self) {
+        let now = Instant::now().as_millis();
+        WATCHDOG.lock(|watchdog| watchdog.borrow_mut().idle(self.id, now));
+    }
+}
+
+/// Registers a task, whose first heartbeat is due within `deadline`.
+///
+/// # Arguments
+///
+/// * `name` - Name used in the log and by the console
+/// * `deadline` - Longest time allowed between two heartbeats
+///
+/// # Errors
+///
+/// Returns [`Error::Supervisor`] if [`MAX_TASKS`] tasks are already
+/// registered.
+pub fn register(name: &'static str, deadline: Duration) -> Result<Watchdog, Error> {
+    let now = Instant::now().as_millis();
+    let deadline_ms = u32::try_from(deadline.as_millis()).unwrap_or(u32::MAX);
+    let id = WATCHDOG.lock(|watchdog| watchdog.borrow_mut().register(name, deadline_ms, now))?;
+    Ok(Watchdog { id })
+}
+
+/// Returns the supervision state of each registered task.
+pub fn tasks() -> heapless::Vec<TaskStatus, MAX_TASKS> {
+    WATCHDOG.lock(|watchdog| watchdog.borrow().tasks().copied().collect())
+}
+
+/// Returns true if the supervisor reset the chip into safe mode, and clears
+/// the request so that the next reset boots normally.
+///
+/// Call it once, early in `main`.
+pub fn take_safe_mode() -> bool {
+    // SAFETY: only read and written here and by the supervisor task right
+    // before a reset, never at the same time
+    unsafe {
+        let requested = (&raw const SAFE_MODE).read_volatile() == SAFE_MODE_REQUEST;
+        (&raw mut SAFE_MODE).write_volatile(0);
+        requested
+    }
+}
+
+/// Embassy task that checks the deadlines and acts on missed ones.
+#[embassy_executor::task]
+async fn supervisor_task(
+    mut rtc: Rtc<'static>,
+    radio: Option<&'static SharedController>,
+    config: SupervisorConfig,
+) {
+    if let Some(timeout) = config.hardware_timeout {
+        rtc.rwdt
+            .set_stage_action(RwdtStage::Stage0, RwdtStageAction::ResetSystem);
+        rtc.rwdt.set_timeout(
+            RwdtStage::Stage0,
+            esp_hal::time::Duration::from_millis(timeout.as_millis()),
+        );
+        rtc.rwdt.enable();
+    }
+
+    loop {
+        rtc.rwdt.feed();
+
+        let mut misses = heapless::Vec::<Miss, MAX_TASKS>::new();
+        let now = Instant::now().as_millis();
+        let action = WATCHDOG.lock(|watchdog| {
+            watchdog.borrow_mut().check(now, |miss| {
+                let _ = misses.push(*miss);
+            })
+        });
+        for miss in &misses {
+            println!("{}", miss);
+        }
+
+        match action {
+            Some(Action::RestartRadio) => restart_radio(radio).await,
+            Some(Action::ResetChip) => reset_chip(&mut rtc),
+            Some(Action::SafeMode) => {
+                // SAFETY: see `take_safe_mode`
+                unsafe { (&raw mut SAFE_MODE).write_volatile(SAFE_MODE_REQUEST) };
+                reset_chip(&mut rtc)
+            }
+            None => {}
+        }
+        Timer::after(config.check_interval).await;
+    }
+}
+
+/// Stops and starts the WiFi controller.
+///
+/// A task stuck in a driver call usually holds the controller, in which case
+/// the restart times out and the next step of the escalation has to help.
+async fn restart_radio(radio: Option<&'static SharedController>) {
+    let Some(controller) = radio else {
+        println!("No radio to restart");
+        return;
+    };
+    let restart = async {
+        let mut controller = controller.lock().await;
+        controller.stop_async().await?;
+        controller.start_async().await
+    };
+    match with_timeout(RADIO_RESTART_TIMEOUT, restart).await {
+        Ok(Ok(())) => println!("Radio restarted"),
+        Ok(Err(e)) => println!("Radio restart failed: {}", e),
+        Err(_) => println!("Radio restart timed out, the WiFi controller is busy"),
+    }
+}
+
+/// Resets the chip through the RTC watchdog.
+///
+/// Resets the digital core only, so the RTC memory holding the safe mode
+/// request survives.
+fn reset_chip(rtc: &mut Rtc<'static>) -> ! {
+    rtc.rwdt
+        .set_stage_action(RwdtStage::Stage0, RwdtStageAction::ResetCore);
+    rtc.rwdt.set_timeout(
+        RwdtStage::Stage0,
+        esp_hal::time::Duration::from_millis(RESET_DELAY_MS),
+    );
+    rtc.rwdt.enable();
+    rtc.rwdt.feed();
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Starts checking the deadlines of the registered tasks.
+///
+/// Tasks may register before or after the supervisor starts. With a
+/// [`SupervisorConfig::hardware_timeout`], the RTC watchdog is enabled and
+/// resets the chip if the supervisor itself stops running.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the supervisor task
+/// * `lpwr` - Low-power peripheral, for the RTC watchdog
+/// * `radio` - WiFi controller to restart, if the radio is up
+/// * `config` - Check interval, escalation and RTC watchdog timeout
+///
+/// # Errors
+///
+/// Returns [`Error::Spawn`] if the supervisor task is already running.
+pub fn start_supervisor(
+    spawner: Spawner,
+    lpwr: LPWR<'static>,
+    radio: Option<&'static SharedController>,
+    config: SupervisorConfig,
+) -> Result<(), Error> {
+    WATCHDOG.lock(|watchdog| watchdog.borrow_mut().set_escalation(config.escalation));
+    spawner.spawn(supervisor_task(Rtc::new(lpwr), radio, config))?;
+    Ok(())
+}
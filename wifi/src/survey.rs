@@ -0,0 +1,72 @@
+//! Channel survey task.
+//!
+//! [`start_survey`] spawns a task that feeds every published scan into a
+//! [`ChannelSurvey`] and stores the resulting ranking in
+//! [`CHANNEL_RECOMMENDATION`].
+
+use embassy_executor::Spawner;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::Watch;
+use esp_println::println;
+use wifi_core::channel_survey::{ChannelSurvey, Recommendation};
+use wifi_core::scan_config::ChannelSet;
+
+use crate::error::Error;
+use crate::events::{self, ScanSubscriber, ScanUpdate};
+
+/// Number of scans the recommendation is averaged over
+pub const SURVEY_WINDOW: usize = 10;
+
+/// Maximum number of receivers on [`CHANNEL_RECOMMENDATION`]
+pub const MAX_RECOMMENDATION_RECEIVERS: usize = 2;
+
+/// Number of ranked channels printed after each scan
+const PRINTED_CHANNELS: usize = 3;
+
+/// Latest channel ranking, updated after every scan.
+pub static CHANNEL_RECOMMENDATION: Watch<
+    CriticalSectionRawMutex,
+    Recommendation,
+    MAX_RECOMMENDATION_RECEIVERS,
+> = Watch::new();
+
+/// Returns the latest channel ranking, if a scan has been surveyed yet.
+pub fn recommendation() -> Option<Recommendation> {
+    CHANNEL_RECOMMENDATION.try_get()
+}
+
+/// Embassy task that surveys channel usage across published scans.
+#[embassy_executor::task]
+async fn channel_survey_task(mut scans: ScanSubscriber, candidates: ChannelSet) {
+    let mut survey = ChannelSurvey::<SURVEY_WINDOW>::new();
+    let sender = CHANNEL_RECOMMENDATION.sender();
+
+    loop {
+        if let ScanUpdate::Report(report) = scans.next().await {
+            survey.add(&report);
+            let recommendation = survey.recommend(candidates);
+
+            println!("Channel ranking over {} scans:", recommendation.scans);
+            for score in recommendation.ranked.iter().take(PRINTED_CHANNELS) {
+                println!("  {}", score);
+            }
+
+            sender.send(recommendation);
+        }
+    }
+}
+
+/// Spawns the channel survey task.
+///
+/// Only channels in `candidates` are ranked, e.g. channels 1 to 11 for
+/// regions that do not allow 12 and 13.
+///
+/// # Errors
+///
+/// Returns [`Error::Subscribe`] if no scan subscriber slot is free, or
+/// [`Error::Spawn`] if the survey is already running.
+pub fn start_survey(spawner: Spawner, candidates: ChannelSet) -> Result<(), Error> {
+    let scans = events::subscribe().map_err(Error::Subscribe)?;
+    spawner.spawn(channel_survey_task(scans, candidates))?;
+    Ok(())
+}
@@ -0,0 +1,274 @@
+//! MQTT publisher for scan results and device telemetry.
+//!
+//! [`start_telemetry`] spawns a task that keeps an MQTT session open to a
+//! broker and publishes:
+//!
+//! - every scan report, as the JSON of `GET /api/scan`, to
+//!   [`TelemetryConfig::scan_topic`]
+//! - the device status, as the JSON of `GET /api/status`, to
+//!   [`TelemetryConfig::telemetry_topic`] every
+//!   [`TelemetryConfig::telemetry_interval`], retained so new subscribers see
+//!   the last one right away
+//!
+//! With a [`TelemetryConfig::config_topic`], the task also subscribes to it
+//! and applies each message like a `PUT /api/config` request.
+//!
+//! The task pings the broker every half keepalive interval. When the
+//! broker stops answering or the connection fails, the task waits
+//! [`RECONNECT_DELAY`] and connects again; scans published meanwhile are not
+//! sent.
+
+use embassy_executor::Spawner;
+use embassy_futures::select::{Either4, select4};
+use embassy_net::Stack;
+use embassy_time::{Duration, Ticker, Timer, with_timeout};
+use esp_println::println;
+use wifi_core::api;
+use wifi_core::mqtt::{self, Client, ConnectOptions, Event, QoS};
+
+use crate::control::ScannerHandle;
+use crate::error::Error;
+use crate::events::{self, ScanSubscriber, ScanUpdate};
+use crate::http_server::{self, BODY_CAPACITY};
+use crate::net;
+use crate::station::StationHandle;
+use crate::storage::SharedStore;
+
+/// Time the task waits before connecting again after a failure
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Time the broker gets to resolve, accept and acknowledge the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest message accepted on the configuration topic
+const RX_CAPACITY: usize = 1024;
+
+/// Room for a topic and the packet framing next to the largest body
+const TX_CAPACITY: usize = BODY_CAPACITY + 256;
+
+/// Broker and topics of the publisher
+#[derive(Clone, Copy, Debug)]
+pub struct TelemetryConfig {
+    /// Host name or IPv4 address of the broker
+    pub host: &'static str,
+    /// TCP port of the broker
+    pub port: u16,
+    /// Client identifier, unique per broker
+    pub client_id: &'static str,
+    /// User name and password, if the broker asks for them
+    pub credentials: Option<(&'static str, &'static str)>,
+    /// Keepalive interval in seconds
+    pub keep_alive_secs: u16,
+    /// Quality of service of published messages
+    pub qos: QoS,
+    /// Topic scan reports are published to
+    pub scan_topic: &'static str,
+    /// Topic the device status is published to
+    pub telemetry_topic: &'static str,
+    /// Time between two status messages
+    pub telemetry_interval: Duration,
+    /// Topic scanner configuration updates are received on, if any
+    pub config_topic: Option<&'static str>,
+}
+
+impl TelemetryConfig {
+    /// Publishes to `wifi-scanner/scan` and `wifi-scanner/telemetry` on the
+    /// standard port of `host`, with QoS 1, a status message every minute and
+    /// no configuration topic.
+    pub const fn new(host: &'static str) -> Self {
+        Self {
+            host,
+            port: mqtt::DEFAULT_PORT,
+            client_id: "wifi-scanner",
+            credentials: None,
+            keep_alive_secs: 60,
+            qos: QoS::AtLeastOnce,
+            scan_topic: "wifi-scanner/scan",
+            telemetry_topic: "wifi-scanner/telemetry",
+            telemetry_interval: Duration::from_secs(60),
+            config_topic: None,
+        }
+    }
+}
+
+/// What the publisher reports on and controls
+#[derive(Clone, Copy)]
+struct TelemetryContext {
+    stack: Stack<'static>,
+    scanner: Option<ScannerHandle>,
+    station: Option<StationHandle>,
+    store: Option<&'static SharedStore>,
+}
+
+/// Buffers of one MQTT session
+struct Buffers {
+    socket_rx: [u8; 1024],
+    socket_tx: [u8; 2048],
+    mqtt_rx: [u8; RX_CAPACITY],
+    mqtt_tx: [u8; TX_CAPACITY],
+    body: heapless::String<BODY_CAPACITY>,
+}
+
+/// Embassy task that publishes to the broker, reconnecting after failures.
+#[embassy_executor::task]
+async fn telemetry_task(
+    context: TelemetryContext,
+    config: TelemetryConfig,
+    mut scans: ScanSubscriber,
+) {
+    let mut buffers = Buffers {
+        socket_rx: [0; 1024],
+        socket_tx: [0; 2048],
+        mqtt_rx: [0; RX_CAPACITY],
+        mqtt_tx: [0; TX_CAPACITY],
+        body: heapless::String::new(),
+    };
+
+    loop {
+        context.stack.wait_config_up().await;
+        if let Err(e) = session(&context, &config, &mut scans, &mut buffers).await {
+            println!("MQTT session with {} ended: {}", config.host, e);
+        }
+        Timer::after(RECONNECT_DELAY).await;
+    }
+}
+
+/// Connects to the broker and publishes until the session fails.
+async fn session(
+    context: &TelemetryContext,
+    config: &TelemetryConfig,
+    scans: &mut ScanSubscriber,
+    buffers: &mut Buffers,
+) -> Result<(), Error> {
+    let mut socket = net::connect(
+        context.stack,
+        config.host,
+        config.port,
+        &mut buffers.socket_rx,
+        &mut buffers.socket_tx,
+        CONNECT_TIMEOUT,
+    )
+    .await?;
+    // The broker answers a ping at least every half keepalive interval, so a
+    // silent socket is a dead one
+    let keep_alive = Duration::from_secs(u64::from(config.keep_alive_secs.max(2)));
+    socket.set_timeout(Some(keep_alive));
+
+    let mut options =
+        ConnectOptions::new(config.client_id).with_keep_alive_secs(config.keep_alive_secs);
+    if let Some((username, password)) = config.credentials {
+        options = options.with_credentials(username, password.as_bytes());
+    }
+    let mut client = with_timeout(
+        CONNECT_TIMEOUT,
+        Client::connect(
+            &mut socket,
+            &options,
+            &mut buffers.mqtt_rx,
+            &mut buffers.mqtt_tx,
+        ),
+    )
+    .await
+    .map_err(|_| Error::Timeout)??;
+    println!("MQTT connected to {}:{}", config.host, config.port);
+
+    if let Some(topic) = config.config_topic {
+        client.subscribe(topic, QoS::AtLeastOnce).await?;
+    }
+
+    let body = &mut buffers.body;
+    let mut telemetry = Ticker::every(config.telemetry_interval);
+    let mut ping = Ticker::every(keep_alive / 2);
+    loop {
+        match select4(scans.next(), telemetry.next(), ping.next(), client.poll()).await {
+            Either4::First(ScanUpdate::Report(report)) => {
+                body.clear();
+                if api::write_scan(body, &report).is_err() {
+                    println!("Scan report exceeds {} bytes, not published", BODY_CAPACITY);
+                    continue;
+                }
+                client
+                    .publish(config.scan_topic, body.as_bytes(), config.qos, false)
+                    .await?;
+            }
+            Either4::First(ScanUpdate::Missed(n)) => println!("MQTT publisher skipped {} scans", n),
+            Either4::First(ScanUpdate::Failed(_)) => {}
+            Either4::Second(()) => {
+                let status = http_server::device_status(context.stack, context.station);
+                body.clear();
+                if api::write_status(body, &status).is_err() {
+                    continue;
+                }
+                client
+                    .publish(config.telemetry_topic, body.as_bytes(), config.qos, true)
+                    .await?;
+            }
+            Either4::Third(()) => {
+                if client.ping_outstanding() {
+                    return Err(Error::Timeout);
+                }
+                client.ping().await?;
+            }
+            Either4::Fourth(Ok(Event::Message(message))) => {
+                apply_config(context, message.topic, message.payload).await;
+            }
+            Either4::Fourth(Ok(Event::SubAck { granted: None, .. })) => {
+                println!("MQTT broker rejected the configuration subscription");
+            }
+            Either4::Fourth(Ok(_)) => {}
+            Either4::Fourth(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+/// Applies a scanner configuration update received on `topic`.
+async fn apply_config(context: &TelemetryContext, topic: &str, update: &[u8]) {
+    let Some(scanner) = context.scanner else {
+        println!("Ignoring MQTT configuration update: scanner is not running");
+        return;
+    };
+    match http_server::update_config(scanner, update) {
+        Ok(config) => {
+            println!("Scanner configuration updated over MQTT ({})", topic);
+            http_server::save_config(context.store, &config).await;
+        }
+        Err(e) => println!("Invalid MQTT configuration update: {}", e),
+    }
+}
+
+/// Starts publishing scan results and telemetry to an MQTT broker.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the publisher task
+/// * `stack` - Network stack to reach the broker on, from [`crate::net::start_network`]
+/// * `config` - Broker and topics to publish to
+/// * `scanner` - Scan task that configuration updates are applied to, if running
+/// * `station` - Station whose network is reported, if running
+/// * `store` - Configuration store updated configurations are saved to, if any
+///
+/// The stack needs a free socket for the broker connection.
+///
+/// # Errors
+///
+/// Returns [`Error::Subscribe`] if no scan subscriber slot is free, or
+/// [`Error::Spawn`] if the publisher is already running.
+pub fn start_telemetry(
+    spawner: Spawner,
+    stack: Stack<'static>,
+    config: TelemetryConfig,
+    scanner: Option<ScannerHandle>,
+    station: Option<StationHandle>,
+    store: Option<&'static SharedStore>,
+) -> Result<(), Error> {
+    let scans = events::subscribe().map_err(Error::Subscribe)?;
+    let context = TelemetryContext {
+        stack,
+        scanner,
+        station,
+        store,
+    };
+    spawner.spawn(telemetry_task(context, config, scans))?;
+    println!("MQTT publisher started for {}:{}", config.host, config.port);
+    Ok(())
+}
@@ -0,0 +1,195 @@
+//! SNTP time synchronization.
+//!
+//! [`start_time_sync`] spawns a task that asks a time server for the time
+//! once the network is up, and again every
+//! [`SntpConfig::resync_interval`]. Each answer updates a [`WallClock`],
+//! which also learns the drift of the local oscillator, so [`now_utc`] stays
+//! accurate between synchronizations.
+//!
+//! Until the first synchronization [`now_utc`] returns `None`; scan reports
+//! and the status API then only carry uptime.
+
+use embassy_executor::Spawner;
+use embassy_net::Stack;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::{Receiver, Watch};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use esp_println::println;
+use wifi_core::clock::{UtcTime, WallClock};
+use wifi_core::sntp::{self, Measurement, NTP_PORT, NtpTimestamp, PACKET_LEN, SntpError};
+
+use crate::error::Error;
+use crate::net;
+
+/// Time server used unless configured otherwise
+pub const DEFAULT_SNTP_SERVER: &str = "pool.ntp.org";
+
+/// Maximum number of receivers on [`CLOCK`]
+pub const MAX_CLOCK_RECEIVERS: usize = 2;
+
+/// Time the server gets to resolve and answer
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time the task waits before trying again after a failure
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Longest round trip accepted; a slow exchange makes a poor measurement
+const MAX_DELAY_US: i64 = 500_000;
+
+/// Wall clock from the last synchronization.
+///
+/// Receivers can await changes; [`Watch::try_get`] reads the current value.
+pub static CLOCK: Watch<CriticalSectionRawMutex, WallClock, MAX_CLOCK_RECEIVERS> = Watch::new();
+
+/// Receiver of [`CLOCK`]
+pub type ClockReceiver = Receiver<'static, CriticalSectionRawMutex, WallClock, MAX_CLOCK_RECEIVERS>;
+
+/// Time server and synchronization interval
+#[derive(Clone, Copy, Debug)]
+pub struct SntpConfig {
+    /// Host name or IPv4 address of the time server
+    pub server: &'static str,
+    /// Time between two synchronizations
+    pub resync_interval: Duration,
+}
+
+impl Default for SntpConfig {
+    /// Synchronizes with [`DEFAULT_SNTP_SERVER`] every hour
+    fn default() -> Self {
+        Self {
+            server: DEFAULT_SNTP_SERVER,
+            resync_interval: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// Returns the current time in UTC, or `None` before the first
+/// synchronization.
+pub fn now_utc() -> Option<UtcTime> {
+    CLOCK
+        .try_get()
+        .map(|clock| clock.now(Instant::now().as_micros()))
+}
+
+/// Subscribes to clock updates, e.g. to wait for the first synchronization.
+///
+/// Returns `None` if all [`MAX_CLOCK_RECEIVERS`] receivers are in use.
+pub fn subscribe() -> Option<ClockReceiver> {
+    CLOCK.receiver()
+}
+
+/// Embassy task that keeps [`CLOCK`] synchronized with a time server.
+#[embassy_executor::task]
+async fn sntp_task(stack: Stack<'static>, config: SntpConfig) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0u8; 256];
+    let mut tx_buffer = [0u8; 128];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(0) {
+        println!("SNTP client failed to bind: {:?}", e);
+        return;
+    }
+
+    let sender = CLOCK.sender();
+    loop {
+        stack.wait_config_up().await;
+        let delay = match synchronize(stack, &socket, config.server).await {
+            Ok(measurement) => {
+                let uptime_us = Instant::now().as_micros();
+                let mut clock = CLOCK.try_get();
+                match &mut clock {
+                    Some(clock) => clock.update(uptime_us, measurement.offset_us),
+                    None => clock = Some(WallClock::new(uptime_us, measurement.offset_us)),
+                }
+                if let Some(clock) = clock {
+                    println!(
+                        "Time synchronized: {} (delay {} us, drift {} ppb)",
+                        clock.now(uptime_us),
+                        measurement.delay_us,
+                        clock.drift_ppb()
+                    );
+                    sender.send(clock);
+                }
+                config.resync_interval
+            }
+            Err(e) => {
+                println!("Time synchronization with {} failed: {}", config.server, e);
+                RETRY_INTERVAL
+            }
+        };
+        Timer::after(delay).await;
+    }
+}
+
+/// Asks `server` for the time once and measures the offset of the uptime
+/// clock from UTC.
+async fn synchronize(
+    stack: Stack<'_>,
+    socket: &UdpSocket<'_>,
+    server: &str,
+) -> Result<Measurement, Error> {
+    let address = net::resolve(stack, server, RESPONSE_TIMEOUT).await?;
+
+    let mut packet = [0u8; PACKET_LEN];
+    let sent_us = Instant::now().as_micros();
+    // The uptime identifies the request; the server echoes it back
+    let origin = NtpTimestamp(sent_us);
+    sntp::write_request(&mut packet, origin);
+    socket
+        .send_to(&packet, (address, NTP_PORT))
+        .await
+        .map_err(Error::Udp)?;
+
+    with_timeout(RESPONSE_TIMEOUT, async {
+        loop {
+            let Ok((len, _)) = socket.recv_from(&mut packet).await else {
+                continue;
+            };
+            let received_us = Instant::now().as_micros();
+            match sntp::parse_response(&packet[..len], origin) {
+                // A late answer to an earlier request
+                Err(SntpError::OriginMismatch) => continue,
+                Err(e) => return Err(Error::Sntp(e)),
+                Ok(response) => {
+                    let measurement = response.measure(sent_us, received_us);
+                    if measurement.delay_us > MAX_DELAY_US {
+                        return Err(Error::Timeout);
+                    }
+                    return Ok(measurement);
+                }
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::Timeout)?
+}
+
+/// Starts synchronizing the wall clock on `stack`.
+///
+/// # Arguments
+///
+/// * `spawner` - Embassy task spawner for creating the synchronization task
+/// * `stack` - Network stack to reach the time server on, from [`crate::net::start_network`]
+/// * `config` - Time server and synchronization interval
+///
+/// The stack needs a free socket for the task.
+///
+/// # Errors
+///
+/// Returns [`Error::Spawn`] if synchronization is already running.
+pub fn start_time_sync(
+    spawner: Spawner,
+    stack: Stack<'static>,
+    config: SntpConfig,
+) -> Result<(), Error> {
+    spawner.spawn(sntp_task(stack, config))?;
+    Ok(())
+}
@@ -0,0 +1,85 @@
+//! Access point tracking task.
+//!
+//! [`start_tracker`] spawns a task that feeds every published scan into an
+//! [`ApTracker`] and publishes the resulting [`TrackerEvent`]s on
+//! [`AP_EVENTS`].
+
+use embassy_executor::Spawner;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+use esp_println::println;
+use wifi_core::tracker::{ApTracker, DEFAULT_TRACKER_CAPACITY, TrackerConfig, TrackerEvent};
+
+use crate::error::Error;
+use crate::events::{self, ScanSubscriber, ScanUpdate, SubscribeError};
+
+/// Number of events buffered for subscribers before the oldest is dropped
+pub const AP_EVENT_QUEUE_DEPTH: usize = 16;
+
+/// Maximum number of concurrent event subscribers
+pub const MAX_AP_EVENT_SUBSCRIBERS: usize = 4;
+
+/// Only the tracker task publishes, and it uses the immediate publisher
+const MAX_AP_EVENT_PUBLISHERS: usize = 1;
+
+/// Channel carrying appear, disappear and signal change events.
+pub static AP_EVENTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    TrackerEvent,
+    AP_EVENT_QUEUE_DEPTH,
+    MAX_AP_EVENT_SUBSCRIBERS,
+    MAX_AP_EVENT_PUBLISHERS,
+> = PubSubChannel::new();
+
+/// Subscription to [`AP_EVENTS`]
+pub type ApEventSubscriber = Subscriber<
+    'static,
+    CriticalSectionRawMutex,
+    TrackerEvent,
+    AP_EVENT_QUEUE_DEPTH,
+    MAX_AP_EVENT_SUBSCRIBERS,
+    MAX_AP_EVENT_PUBLISHERS,
+>;
+
+/// Subscribes to tracker events.
+///
+/// # Errors
+///
+/// Returns [`SubscribeError::MaximumSubscribersReached`] if all
+/// [`MAX_AP_EVENT_SUBSCRIBERS`] slots are in use.
+pub fn subscribe() -> Result<ApEventSubscriber, SubscribeError> {
+    AP_EVENTS.subscriber()
+}
+
+/// Embassy task that tracks access points across published scans.
+///
+/// Scans the task fell behind on are skipped rather than counted as
+/// missing, so a slow tracker does not report networks as gone.
+#[embassy_executor::task]
+async fn ap_tracker_task(mut scans: ScanSubscriber, config: TrackerConfig) {
+    let mut tracker = ApTracker::<DEFAULT_TRACKER_CAPACITY>::new(config);
+    let publisher = AP_EVENTS.immediate_publisher();
+
+    loop {
+        match scans.next().await {
+            ScanUpdate::Report(report) => tracker.update(&report, |event| {
+                println!("{}", event);
+                publisher.publish_immediate(event);
+            }),
+            ScanUpdate::Missed(n) => println!("AP tracker skipped {} scans", n),
+            ScanUpdate::Failed(_) => {}
+        }
+    }
+}
+
+/// Spawns the access point tracking task.
+///
+/// # Errors
+///
+/// Returns [`Error::Subscribe`] if no scan subscriber slot is free, or
+/// [`Error::Spawn`] if the tracker is already running.
+pub fn start_tracker(spawner: Spawner, config: TrackerConfig) -> Result<(), Error> {
+    let scans = events::subscribe().map_err(Error::Subscribe)?;
+    spawner.spawn(ap_tracker_task(scans, config))?;
+    Ok(())
+}
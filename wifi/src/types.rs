@@ -3,14 +3,21 @@
 //! This module provides static cells for WiFi controller and radio initialization,
 //! ensuring they have the 'static lifetime required by Embassy async tasks.
 
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
 use esp_radio::wifi::WifiController;
 use static_cell::StaticCell;
 
+/// WiFi controller shared between the scan and station tasks.
+///
+/// Each task locks it only for the duration of one driver operation.
+pub type SharedController = Mutex<CriticalSectionRawMutex, WifiController<'static>>;
+
 /// Static storage for WiFi controller.
 ///
 /// This static cell ensures the WiFi controller has a 'static lifetime,
 /// which is required for spawning async tasks with Embassy.
-pub static WIFI_CONTROLLER: StaticCell<WifiController<'static>> = StaticCell::new();
+pub static WIFI_CONTROLLER: StaticCell<SharedController> = StaticCell::new();
 
 /// Static storage for radio initialization controller.
 ///
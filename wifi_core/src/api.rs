@@ -0,0 +1,580 @@
+//! JSON API of the device's HTTP server.
+//!
+//! Four resources let a browser or script look at and manage a deployed
+//! scanner:
+//!
+//! | Path          | Methods    | Content                                        |
+//! |---------------|------------|------------------------------------------------|
+//! | `/api/scan`   | GET        | Latest [`ScanReport`]                          |
+//! | `/api/status` | GET        | [`DeviceStatus`]: time, heap, connection, IP   |
+//! | `/api/config` | GET, PUT   | [`ScannerConfig`]                              |
+//! | `/api/ota`    | POST       | Firmware update from `{"url":"http://..."}`    |
+//!
+//! [`route`] decides what to answer, and the `write_*` functions produce the
+//! bodies. A `PUT` to `/api/config` may leave fields out; [`update_config`]
+//! applies the given ones to the current configuration. [`parse_update`]
+//! reads the image URL of a `POST` to `/api/ota`.
+
+use core::fmt;
+
+use crate::clock::UtcTime;
+use crate::http::{Method, Request};
+use crate::http_client::Url;
+use crate::json::{JsonError, JsonStr, Value, parse_object};
+use crate::net_config::Ip;
+use crate::report::{Bssid, MAX_SSID_LEN, ScanReport, SecondaryChannel, Ssid};
+use crate::scan_config::{ChannelSet, ConfigError, ScanType, ScannerConfig};
+use crate::station::ConnectionState;
+
+/// Path of the latest scan
+pub const SCAN_PATH: &str = "/api/scan";
+
+/// Path of the device status
+pub const STATUS_PATH: &str = "/api/status";
+
+/// Path of the scanner configuration
+pub const CONFIG_PATH: &str = "/api/config";
+
+/// Path that starts firmware updates
+pub const UPDATE_PATH: &str = "/api/ota";
+
+/// Longest firmware image URL accepted
+pub const MAX_URL_LEN: usize = 128;
+
+/// Media type of all API bodies
+pub const CONTENT_TYPE: &str = "application/json";
+
+/// What to answer to a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApiRoute<'a> {
+    /// Serve the latest scan
+    Scan,
+    /// Serve the device status
+    Status,
+    /// Serve the scanner configuration
+    Config,
+    /// Update the scanner configuration from this body
+    UpdateConfig(&'a [u8]),
+    /// Start a firmware update from this body
+    StartUpdate(&'a [u8]),
+    /// The path is unknown
+    NotFound,
+    /// The path does not support the method
+    MethodNotAllowed,
+}
+
+/// Decides what to answer to `request`
+pub fn route<'a>(request: &Request<'a>) -> ApiRoute<'a> {
+    match (request.path, request.method) {
+        (SCAN_PATH, Method::Get) => ApiRoute::Scan,
+        (STATUS_PATH, Method::Get) => ApiRoute::Status,
+        (CONFIG_PATH, Method::Get) => ApiRoute::Config,
+        (CONFIG_PATH, Method::Put) => ApiRoute::UpdateConfig(request.body),
+        (UPDATE_PATH, Method::Post) => ApiRoute::StartUpdate(request.body),
+        (SCAN_PATH | STATUS_PATH | CONFIG_PATH | UPDATE_PATH, _) => ApiRoute::MethodNotAllowed,
+        _ => ApiRoute::NotFound,
+    }
+}
+
+/// Reasons why a configuration or firmware update was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ApiError {
+    /// The body is not a JSON object
+    Json(JsonError),
+    /// The object has a field the configuration does not have
+    UnknownField,
+    /// A field has a value of the wrong type or out of range
+    InvalidValue(&'static str),
+    /// The updated configuration is invalid
+    Config(ConfigError),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Json(e) => write!(f, "{e}"),
+            ApiError::UnknownField => f.write_str("unknown configuration field"),
+            ApiError::InvalidValue(field) => write!(f, "invalid value for {field}"),
+            ApiError::Config(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<JsonError> for ApiError {
+    fn from(e: JsonError) -> Self {
+        ApiError::Json(e)
+    }
+}
+
+/// Snapshot of the device for `/api/status`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceStatus {
+    /// Time since boot in milliseconds
+    pub uptime_ms: u64,
+    /// Wall-clock time, if the clock is synchronized
+    pub utc: Option<UtcTime>,
+    /// Heap bytes in use
+    pub heap_used: usize,
+    /// Heap bytes free
+    pub heap_free: usize,
+    /// Connection state of the station
+    pub connection: ConnectionState,
+    /// Network the station is joining or has joined
+    pub network: Option<Ssid>,
+    /// IPv4 address and prefix length of the station
+    pub ipv4: Option<([u8; 4], u8)>,
+}
+
+/// Writes `report` as a JSON object.
+///
+/// # Errors
+///
+/// Returns an error if `out` fails.
+pub fn write_scan(out: &mut impl fmt::Write, report: &ScanReport) -> fmt::Result {
+    write!(
+        out,
+        "{{\"sequence\":{},\"timestamp_ms\":{},\"utc\":",
+        report.sequence, report.timestamp_ms
+    )?;
+    write_utc(out, report.utc)?;
+    write!(out, ",\"dropped\":{},\"access_points\":[", report.dropped)?;
+    for (i, ap) in report.iter().enumerate() {
+        let secondary = match ap.secondary_channel {
+            SecondaryChannel::None => "none",
+            SecondaryChannel::Above => "above",
+            SecondaryChannel::Below => "below",
+        };
+        write!(
+            out,
+            "{}{{\"ssid\":{},\"bssid\":\"{}\",\"channel\":{},\"secondary_channel\":\"{}\",\
+            \"rssi\":{},\"auth\":",
+            if i == 0 { "" } else { "," },
+            JsonStr(&ap.ssid),
+            ap.bssid,
+            ap.channel,
+            secondary,
+            ap.signal_strength
+        )?;
+        match ap.auth_method {
+            Some(auth) => write!(out, "{}}}", JsonStr(auth.as_str()))?,
+            None => out.write_str("null}")?,
+        }
+    }
+    out.write_str("]}")
+}
+
+/// Writes `status` as a JSON object.
+///
+/// The connection is an object with a `state` field and, depending on the
+/// state, `attempt`, `delay_ms` or `reason`.
+///
+/// # Errors
+///
+/// Returns an error if `out` fails.
+pub fn write_status(out: &mut impl fmt::Write, status: &DeviceStatus) -> fmt::Result {
+    write!(out, "{{\"uptime_ms\":{},\"utc\":", status.uptime_ms)?;
+    write_utc(out, status.utc)?;
+    write!(
+        out,
+        ",\"heap\":{{\"used\":{},\"free\":{}}},\"connection\":",
+        status.heap_used, status.heap_free
+    )?;
+    match status.connection {
+        ConnectionState::Idle => out.write_str("{\"state\":\"idle\"}")?,
+        ConnectionState::Connecting { attempt } => {
+            write!(out, "{{\"state\":\"connecting\",\"attempt\":{attempt}}}")?
+        }
+        ConnectionState::Connected => out.write_str("{\"state\":\"connected\"}")?,
+        ConnectionState::Disconnected { reason } => write!(
+            out,
+            "{{\"state\":\"disconnected\",\"reason\":\"{}\"}}",
+            reason
+        )?,
+        ConnectionState::Backoff { attempt, delay_ms } => write!(
+            out,
+            "{{\"state\":\"backoff\",\"attempt\":{attempt},\"delay_ms\":{delay_ms}}}"
+        )?,
+    }
+    out.write_str(",\"network\":")?;
+    match &status.network {
+        Some(ssid) => write!(out, "{}", JsonStr(ssid))?,
+        None => out.write_str("null")?,
+    }
+    out.write_str(",\"ipv4\":")?;
+    match status.ipv4 {
+        Some((address, prefix_len)) => write!(out, "\"{}/{}\"", Ip(address), prefix_len)?,
+        None => out.write_str("null")?,
+    }
+    out.write_str("}")
+}
+
+/// Writes `config` as a JSON object, in the form [`update_config`] reads.
+///
+/// # Errors
+///
+/// Returns an error if `out` fails.
+pub fn write_config(out: &mut impl fmt::Write, config: &ScannerConfig) -> fmt::Result {
+    let scan_type = match config.scan_type {
+        ScanType::Active => "active",
+        ScanType::Passive => "passive",
+    };
+    write!(
+        out,
+        "{{\"interval_secs\":{},\"scan_type\":\"{}\",\"dwell_min_ms\":{},\"dwell_max_ms\":{},\
+        \"channels\":[",
+        config.interval_secs, scan_type, config.dwell_min_ms, config.dwell_max_ms
+    )?;
+    for (i, channel) in config.channels.iter().enumerate() {
+        write!(out, "{}{}", if i == 0 { "" } else { "," }, channel)?;
+    }
+    write!(
+        out,
+        "],\"show_hidden\":{},\"ssid_filter\":",
+        config.show_hidden
+    )?;
+    match &config.ssid_filter {
+        Some(ssid) => write!(out, "{}", JsonStr(ssid))?,
+        None => out.write_str("null")?,
+    }
+    out.write_str(",\"bssid_filter\":")?;
+    match config.bssid_filter {
+        Some(bssid) => write!(out, "\"{bssid}\"")?,
+        None => out.write_str("null")?,
+    }
+    out.write_str("}")
+}
+
+/// Writes the answer to a started firmware update, `{"url":"<url>"}`.
+///
+/// # Errors
+///
+/// Returns an error if `out` fails.
+pub fn write_update(out: &mut impl fmt::Write, url: &str) -> fmt::Result {
+    write!(out, "{{\"url\":{}}}", JsonStr(url))
+}
+
+/// Writes a wall-clock time as an RFC 3339 string, or `null` if unknown.
+fn write_utc(out: &mut impl fmt::Write, utc: Option<UtcTime>) -> fmt::Result {
+    match utc {
+        Some(utc) => write!(out, "\"{}\"", utc),
+        None => out.write_str("null"),
+    }
+}
+
+/// Writes an error body, `{"error":"<message>"}`.
+///
+/// # Errors
+///
+/// Returns an error if `out` fails.
+pub fn write_error(out: &mut impl fmt::Write, message: impl fmt::Display) -> fmt::Result {
+    let mut text = heapless::String::<128>::new();
+    // A message cut short is still worth sending
+    let _ = fmt::write(&mut text, format_args!("{message}"));
+    write!(out, "{{\"error\":{}}}", JsonStr(&text))
+}
+
+/// Applies the fields of the JSON object in `body` to `current`.
+///
+/// Fields left out keep their current value. The result is validated, so it
+/// can be handed to the scanner as it is.
+///
+/// # Errors
+///
+/// Returns an [`ApiError`] if the body is not an object of configuration
+/// fields or the result is invalid.
+pub fn update_config(current: &ScannerConfig, body: &[u8]) -> Result<ScannerConfig, ApiError> {
+    let body = core::str::from_utf8(body).map_err(|_| ApiError::Json(JsonError::Syntax))?;
+    let mut config = current.clone();
+    parse_object(body, |name, value| {
+        match name {
+            "interval_secs" => {
+                config.interval_secs = value
+                    .as_u32()
+                    .ok_or(ApiError::InvalidValue("interval_secs"))?
+            }
+            "scan_type" => {
+                config.scan_type = match value {
+                    Value::String(s) if s.raw() == "active" => ScanType::Active,
+                    Value::String(s) if s.raw() == "passive" => ScanType::Passive,
+                    _ => return Err(ApiError::InvalidValue("scan_type")),
+                }
+            }
+            "dwell_min_ms" => {
+                config.dwell_min_ms = value
+                    .as_u32()
+                    .ok_or(ApiError::InvalidValue("dwell_min_ms"))?
+            }
+            "dwell_max_ms" => {
+                config.dwell_max_ms = value
+                    .as_u32()
+                    .ok_or(ApiError::InvalidValue("dwell_max_ms"))?
+            }
+            "channels" => {
+                let Value::Array(channels) = value else {
+                    return Err(ApiError::InvalidValue("channels"));
+                };
+                let mut set = ChannelSet::EMPTY;
+                for channel in channels.iter() {
+                    match channel.as_u32().map(u8::try_from) {
+                        Some(Ok(channel)) if set.insert(channel) => {}
+                        _ => return Err(ApiError::InvalidValue("channels")),
+                    }
+                }
+                config.channels = set;
+            }
+            "show_hidden" => {
+                config.show_hidden = value
+                    .as_bool()
+                    .ok_or(ApiError::InvalidValue("show_hidden"))?
+            }
+            "ssid_filter" => {
+                config.ssid_filter = match value {
+                    Value::Null => None,
+                    Value::String(s) => Some(
+                        s.decode::<MAX_SSID_LEN>()
+                            .ok_or(ApiError::InvalidValue("ssid_filter"))?,
+                    ),
+                    _ => return Err(ApiError::InvalidValue("ssid_filter")),
+                }
+            }
+            "bssid_filter" => {
+                config.bssid_filter = match value {
+                    Value::Null => None,
+                    Value::String(s) => {
+                        Some(Bssid::parse(s.raw()).ok_or(ApiError::InvalidValue("bssid_filter"))?)
+                    }
+                    _ => return Err(ApiError::InvalidValue("bssid_filter")),
+                }
+            }
+            _ => return Err(ApiError::UnknownField),
+        }
+        Ok(())
+    })?;
+    config.validate().map_err(ApiError::Config)?;
+    Ok(config)
+}
+
+/// Reads the image URL from the body of a firmware update request,
+/// `{"url":"http://host[:port]/path"}`.
+///
+/// # Errors
+///
+/// Returns an [`ApiError`] if the body is not such an object, or the URL is
+/// not a plain `http://` URL of at most [`MAX_URL_LEN`] bytes.
+pub fn parse_update(body: &[u8]) -> Result<heapless::String<MAX_URL_LEN>, ApiError> {
+    let body = core::str::from_utf8(body).map_err(|_| ApiError::Json(JsonError::Syntax))?;
+    let mut url = None;
+    parse_object(body, |name, value| {
+        match (name, value) {
+            ("url", Value::String(s)) => {
+                url = Some(
+                    s.decode::<MAX_URL_LEN>()
+                        .ok_or(ApiError::InvalidValue("url"))?,
+                )
+            }
+            ("url", _) => return Err(ApiError::InvalidValue("url")),
+            _ => return Err(ApiError::UnknownField),
+        }
+        Ok(())
+    })?;
+    url.filter(|url| Url::parse(url).is_some())
+        .ok_or(ApiError::InvalidValue("url"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::{AccessPointRecord, AuthMethod};
+    use crate::station::DisconnectReason;
+    use std::string::String;
+
+    #[test]
+    fn routes_requests() {
+        let get = |raw: &'static [u8]| route(&Request::parse(raw).unwrap());
+        assert_eq!(get(b"GET /api/scan HTTP/1.1\r\n\r\n"), ApiRoute::Scan);
+        assert_eq!(get(b"GET /api/status HTTP/1.1\r\n\r\n"), ApiRoute::Status);
+        assert_eq!(get(b"GET /api/config HTTP/1.1\r\n\r\n"), ApiRoute::Config);
+        assert_eq!(get(b"GET /api/other HTTP/1.1\r\n\r\n"), ApiRoute::NotFound);
+
+        let raw = b"PUT /api/config HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}";
+        let put = Request::parse(raw).unwrap();
+        assert_eq!(route(&put), ApiRoute::UpdateConfig(b"{}"));
+        let raw = b"DELETE /api/scan HTTP/1.1\r\n\r\n";
+        let delete = Request::parse(raw).unwrap();
+        assert_eq!(route(&delete), ApiRoute::MethodNotAllowed);
+        let raw = b"POST /api/ota HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}";
+        let post = Request::parse(raw).unwrap();
+        assert_eq!(route(&post), ApiRoute::StartUpdate(b"{}"));
+        assert_eq!(
+            get(b"GET /api/ota HTTP/1.1\r\n\r\n"),
+            ApiRoute::MethodNotAllowed
+        );
+    }
+
+    #[test]
+    fn reads_update_requests() {
+        let url = parse_update(br#"{"url": "http:\/\/10.0.0.2:8000\/wifi.bin"}"#).unwrap();
+        assert_eq!(url, "http://10.0.0.2:8000/wifi.bin");
+        let mut out = String::new();
+        write_update(&mut out, &url).unwrap();
+        assert_eq!(out, r#"{"url":"http://10.0.0.2:8000/wifi.bin"}"#);
+
+        assert_eq!(parse_update(b"{}"), Err(ApiError::InvalidValue("url")));
+        assert_eq!(
+            parse_update(br#"{"url": "https://example.com/wifi.bin"}"#),
+            Err(ApiError::InvalidValue("url"))
+        );
+        assert_eq!(
+            parse_update(br#"{"url": 1}"#),
+            Err(ApiError::InvalidValue("url"))
+        );
+        assert_eq!(
+            parse_update(br#"{"url": "http://a/", "force": true}"#),
+            Err(ApiError::UnknownField)
+        );
+        let long = std::format!(r#"{{"url": "http://a/{}"}}"#, "x".repeat(MAX_URL_LEN));
+        assert_eq!(
+            parse_update(long.as_bytes()),
+            Err(ApiError::InvalidValue("url"))
+        );
+    }
+
+    #[test]
+    fn writes_scan_and_status() {
+        let mut report = ScanReport::new(3, 1500);
+        report.push(AccessPointRecord::new(
+            "caf\u{e9} \"1\"",
+            [0xaa, 0xbb, 0xcc, 0, 0x11, 0x22],
+            6,
+            SecondaryChannel::Above,
+            -48,
+            Some(AuthMethod::Wpa2Personal),
+        ));
+        report.push(AccessPointRecord::new(
+            "",
+            [0, 1, 2, 3, 4, 5],
+            11,
+            SecondaryChannel::None,
+            -80,
+            None,
+        ));
+        let mut out = String::new();
+        write_scan(&mut out, &report).unwrap();
+        assert_eq!(
+            out,
+            "{\"sequence\":3,\"timestamp_ms\":1500,\"utc\":null,\"dropped\":0,\"access_points\":[\
+            {\"ssid\":\"caf\u{e9} \\\"1\\\"\",\"bssid\":\"aa:bb:cc:00:11:22\",\"channel\":6,\
+            \"secondary_channel\":\"above\",\"rssi\":-48,\"auth\":\"WPA2\"},\
+            {\"ssid\":\"\",\"bssid\":\"00:01:02:03:04:05\",\"channel\":11,\
+            \"secondary_channel\":\"none\",\"rssi\":-80,\"auth\":null}]}"
+        );
+
+        let mut status = DeviceStatus {
+            uptime_ms: 61000,
+            utc: None,
+            heap_used: 1024,
+            heap_free: 2048,
+            connection: ConnectionState::Backoff {
+                attempt: 2,
+                delay_ms: 500,
+            },
+            network: None,
+            ipv4: None,
+        };
+        out.clear();
+        write_status(&mut out, &status).unwrap();
+        assert_eq!(
+            out,
+            "{\"uptime_ms\":61000,\"utc\":null,\"heap\":{\"used\":1024,\"free\":2048},\
+            \"connection\":{\"state\":\"backoff\",\"attempt\":2,\"delay_ms\":500},\
+            \"network\":null,\"ipv4\":null}"
+        );
+
+        status.connection = ConnectionState::Connected;
+        status.utc = Some(UtcTime::from_unix_micros(1_714_564_800_250_000));
+        status.network = Some(Ssid::try_from("office").unwrap());
+        status.ipv4 = Some(([192, 168, 1, 50], 24));
+        out.clear();
+        write_status(&mut out, &status).unwrap();
+        assert!(out.contains("\"utc\":\"2024-05-01T12:00:00.250Z\","));
+        assert!(out.ends_with(
+            "\"connection\":{\"state\":\"connected\"},\
+            \"network\":\"office\",\"ipv4\":\"192.168.1.50/24\"}"
+        ));
+
+        status.connection = ConnectionState::Disconnected {
+            reason: DisconnectReason::AuthExpired,
+        };
+        out.clear();
+        write_status(&mut out, &status).unwrap();
+        assert!(out.contains("{\"state\":\"disconnected\",\"reason\":\""));
+
+        out.clear();
+        write_error(&mut out, ApiError::InvalidValue("channels")).unwrap();
+        assert_eq!(out, "{\"error\":\"invalid value for channels\"}");
+    }
+
+    #[test]
+    fn updates_configuration() {
+        let current = ScannerConfig::default();
+        let mut out = String::new();
+        write_config(&mut out, &current).unwrap();
+        assert_eq!(
+            out,
+            "{\"interval_secs\":10,\"scan_type\":\"active\",\"dwell_min_ms\":10,\
+            \"dwell_max_ms\":20,\"channels\":[1,2,3,4,5,6,7,8,9,10,11,12,13,14],\
+            \"show_hidden\":false,\"ssid_filter\":null,\"bssid_filter\":null}"
+        );
+        // What is written can be read back
+        assert_eq!(update_config(&current, out.as_bytes()), Ok(current.clone()));
+
+        let updated = update_config(
+            &current,
+            br#"{"interval_secs": 30, "scan_type": "passive", "dwell_min_ms": 0,
+                "dwell_max_ms": 200, "channels": [1, 6, 11], "show_hidden": true,
+                "ssid_filter": "office", "bssid_filter": "AA:BB:CC:00:11:22"}"#,
+        )
+        .unwrap();
+        let expected = ScannerConfig::default()
+            .with_interval_secs(30)
+            .with_passive(200)
+            .with_channels(ChannelSet::from_channels(&[1, 6, 11]))
+            .with_show_hidden(true)
+            .with_ssid_filter("office")
+            .with_bssid_filter([0xaa, 0xbb, 0xcc, 0, 0x11, 0x22]);
+        assert_eq!(updated, expected);
+
+        let cleared = update_config(&updated, br#"{"ssid_filter":null}"#).unwrap();
+        assert_eq!(cleared.ssid_filter, None);
+        assert_eq!(cleared.interval_secs, 30);
+
+        for (body, error) in [
+            (&b"{\"interval\":5}"[..], ApiError::UnknownField),
+            (
+                b"{\"interval_secs\":-5}",
+                ApiError::InvalidValue("interval_secs"),
+            ),
+            (b"{\"channels\":[1,15]}", ApiError::InvalidValue("channels")),
+            (
+                b"{\"scan_type\":\"fast\"}",
+                ApiError::InvalidValue("scan_type"),
+            ),
+            (
+                b"{\"bssid_filter\":\"aa:bb\"}",
+                ApiError::InvalidValue("bssid_filter"),
+            ),
+            (
+                b"{\"interval_secs\":0}",
+                ApiError::Config(ConfigError::ZeroInterval),
+            ),
+            (
+                b"{\"channels\":[]}",
+                ApiError::Config(ConfigError::NoChannels),
+            ),
+            (b"interval_secs=5", ApiError::Json(JsonError::Syntax)),
+        ] {
+            assert_eq!(update_config(&current, body), Err(error));
+        }
+    }
+}
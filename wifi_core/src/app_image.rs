@@ -0,0 +1,672 @@
+//! ESP-IDF application image checks.
+//!
+//! An [`ImageVerifier`] follows an application image as it streams in, e.g.
+//! from an HTTP download, and checks it the way the bootloader will before
+//! it is ever booted: the header, the segment layout, the checksum byte and,
+//! if present, the SHA-256 digest appended to the image. It keeps no more
+//! than a few bytes of the image, so the image itself can go straight to
+//! flash.
+//!
+//! # Layout
+//!
+//! | Bytes | Content                                                 |
+//! |-------|---------------------------------------------------------|
+//! | 24    | Header: magic `0xE9`, segment count, entry, chip ID     |
+//! | 8 + n | Each segment: load address, length, data                |
+//! | 0..15 | Padding, so the checksum is the last byte of 16         |
+//! | 1     | Checksum: `0xEF` XOR every byte of segment data         |
+//! | 32    | SHA-256 of everything before, if the header asks for it |
+//!
+//! The first segment of an application starts with its `esp_app_desc_t`,
+//! from which [`ImageInfo::version`] is taken.
+//!
+//! # Signatures
+//!
+//! A signed image, as firmware updates are sent, carries a signature block
+//! after the image:
+//!
+//! | Bytes | Content                                                 |
+//! |-------|---------------------------------------------------------|
+//! | 4     | Magic `ESIG`                                            |
+//! | 64    | Ed25519 signature of [`ImageInfo::sha256`]              |
+//!
+//! The signature covers the SHA-256 digest of the image rather than the
+//! image itself, so a [`SignedImageVerifier`] can hash the image as it
+//! streams past and only needs the key and the block at the end. The block
+//! is made by [`signature_block`] and not written to flash.
+
+use core::fmt;
+
+use ed25519_dalek::{Signer, SigningKey};
+use sha2::{Digest, Sha256};
+
+use crate::ed25519::{SIGNATURE_LEN, VerifyingKey};
+
+/// Chip ID of the ESP32 in the image header
+pub const CHIP_ID_ESP32: u16 = 0x0000;
+
+/// First byte of every image
+const IMAGE_MAGIC: u8 = 0xE9;
+
+/// Length of the image header, including the extended header
+const HEADER_LEN: usize = 24;
+
+/// Length of a segment header: load address and data length
+const SEGMENT_HEADER_LEN: usize = 8;
+
+/// Most segments the bootloader loads
+const MAX_SEGMENTS: u8 = 16;
+
+/// Value the checksum starts from
+const CHECKSUM_SEED: u8 = 0xEF;
+
+/// Length of the appended SHA-256 digest
+const DIGEST_LEN: usize = 32;
+
+/// Magic number at the start of the application description
+const APP_DESC_MAGIC: u32 = 0xABCD_5432;
+
+/// Bytes of the application description read: magic, secure version,
+/// reserved words and the version string
+const APP_DESC_LEN: usize = 48;
+
+/// Offset of the version string in the application description
+const APP_DESC_VERSION: usize = 16;
+
+/// Longest version string of an application description
+pub const MAX_VERSION_LEN: usize = 32;
+
+/// First bytes of the signature block
+pub const SIGNATURE_MAGIC: [u8; 4] = *b"ESIG";
+
+/// Length of the signature block after a signed image
+pub const SIGNATURE_BLOCK_LEN: usize = SIGNATURE_MAGIC.len() + SIGNATURE_LEN;
+
+/// Reasons why an image was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImageError {
+    /// The data does not start with an image header
+    BadMagic,
+    /// The image is built for the chip with this ID
+    WrongChip(u16),
+    /// The header has no segments or more than the bootloader loads
+    BadSegmentCount(u8),
+    /// The image is larger than the space it goes to
+    TooLarge,
+    /// The data ends before the image does
+    Truncated,
+    /// More data follows the end of the image
+    TrailingData,
+    /// The checksum byte does not match the segment data
+    ChecksumMismatch,
+    /// The appended SHA-256 digest does not match the image
+    DigestMismatch,
+    /// No signature block follows the image
+    Unsigned,
+    /// The signature was not made with the expected key for this image
+    BadSignature,
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::BadMagic => f.write_str("not an application image"),
+            ImageError::WrongChip(id) => write!(f, "image is built for chip {id:#06x}"),
+            ImageError::BadSegmentCount(count) => write!(f, "image has {count} segments"),
+            ImageError::TooLarge => f.write_str("image does not fit in the slot"),
+            ImageError::Truncated => f.write_str("image is truncated"),
+            ImageError::TrailingData => f.write_str("data follows the end of the image"),
+            ImageError::ChecksumMismatch => f.write_str("image checksum does not match"),
+            ImageError::DigestMismatch => f.write_str("image SHA-256 digest does not match"),
+            ImageError::Unsigned => f.write_str("image is not signed"),
+            ImageError::BadSignature => f.write_str("image signature is not valid"),
+        }
+    }
+}
+
+/// What a verified image holds
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ImageInfo {
+    /// Length of the whole image in bytes
+    pub len: u32,
+    /// Address the bootloader jumps to
+    pub entry: u32,
+    /// Number of segments
+    pub segments: u8,
+    /// Version from the application description, empty if it has none
+    pub version: heapless::String<MAX_VERSION_LEN>,
+    /// SHA-256 digest appended to the image, if any
+    pub digest: Option<[u8; DIGEST_LEN]>,
+    /// SHA-256 digest of the whole image, including any appended digest
+    pub sha256: [u8; DIGEST_LEN],
+}
+
+/// Part of the image the verifier is in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Header,
+    SegmentHeader,
+    SegmentData(u32),
+    Padding(u32),
+    Checksum,
+    Digest,
+    Done,
+}
+
+/// Checks an application image streamed through it.
+pub struct ImageVerifier {
+    chip_id: u16,
+    max_len: u32,
+    state: State,
+    /// Bytes taken so far
+    len: u32,
+    /// Fixed-size field being collected: header, segment header or digest
+    field: [u8; DIGEST_LEN],
+    filled: usize,
+    segments: u8,
+    /// Segments whose header is still to come
+    segments_left: u8,
+    entry: u32,
+    digest_appended: bool,
+    checksum: u8,
+    /// Hash of all bytes taken
+    sha: Sha256,
+    /// Hash of the bytes before the appended digest, once they are complete
+    expected_digest: [u8; DIGEST_LEN],
+    app_desc: [u8; APP_DESC_LEN],
+    app_desc_len: usize,
+    digest: Option<[u8; DIGEST_LEN]>,
+}
+
+impl ImageVerifier {
+    /// Starts checking an image for the chip `chip_id`, e.g.
+    /// [`CHIP_ID_ESP32`], that may take up to `max_len` bytes.
+    pub fn new(chip_id: u16, max_len: u32) -> Self {
+        Self {
+            chip_id,
+            max_len,
+            state: State::Header,
+            len: 0,
+            field: [0; DIGEST_LEN],
+            filled: 0,
+            segments: 0,
+            segments_left: 0,
+            entry: 0,
+            digest_appended: false,
+            checksum: CHECKSUM_SEED,
+            sha: Sha256::new(),
+            expected_digest: [0; DIGEST_LEN],
+            app_desc: [0; APP_DESC_LEN],
+            app_desc_len: 0,
+            digest: None,
+        }
+    }
+
+    /// Number of bytes taken so far
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    /// Returns `true` before the first byte
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Takes the next part of the image.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`ImageError`] as soon as the data cannot be a valid image;
+    /// the verifier must not be used after that.
+    pub fn update(&mut self, data: &[u8]) -> Result<(), ImageError> {
+        if self.update_to_end(data)? < data.len() {
+            return Err(ImageError::TrailingData);
+        }
+        Ok(())
+    }
+
+    /// Takes the next part of the image, stopping where the image ends, and
+    /// returns how many bytes of `data` belong to the image.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`ImageError`] as soon as the data cannot be a valid image;
+    /// the verifier must not be used after that.
+    pub fn update_to_end(&mut self, data: &[u8]) -> Result<usize, ImageError> {
+        let mut rest = data;
+        while !rest.is_empty() && self.state != State::Done {
+            let take = match self.state {
+                State::Header => self.collect(rest, HEADER_LEN),
+                State::SegmentHeader => self.collect(rest, SEGMENT_HEADER_LEN),
+                State::Digest => self.collect(rest, DIGEST_LEN),
+                State::SegmentData(left) | State::Padding(left) => rest.len().min(left as usize),
+                State::Checksum => 1,
+                State::Done => unreachable!(),
+            };
+            if self.len as usize + take > self.max_len as usize {
+                return Err(ImageError::TooLarge);
+            }
+            let (part, tail) = rest.split_at(take);
+            self.take(part)?;
+            rest = tail;
+        }
+        Ok(data.len() - rest.len())
+    }
+
+    /// Checks that the image is complete and returns what it holds.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ImageError::Truncated`] if the image has not ended.
+    pub fn finish(self) -> Result<ImageInfo, ImageError> {
+        if self.state != State::Done {
+            return Err(ImageError::Truncated);
+        }
+        let mut version = heapless::String::new();
+        let desc = &self.app_desc;
+        if self.app_desc_len == APP_DESC_LEN
+            && u32::from_le_bytes([desc[0], desc[1], desc[2], desc[3]]) == APP_DESC_MAGIC
+        {
+            let raw = &desc[APP_DESC_VERSION..];
+            let end = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+            if let Ok(text) = core::str::from_utf8(&raw[..end]) {
+                // Both are MAX_VERSION_LEN long, so this always fits
+                let _ = version.push_str(text);
+            }
+        }
+        Ok(ImageInfo {
+            len: self.len,
+            entry: self.entry,
+            segments: self.segments,
+            version,
+            digest: self.digest,
+            sha256: self.sha.finalize().into(),
+        })
+    }
+
+    /// Copies up to the rest of a fixed-size field from `data` and returns
+    /// how many bytes it took
+    fn collect(&mut self, data: &[u8], field_len: usize) -> usize {
+        let take = data.len().min(field_len - self.filled);
+        self.field[self.filled..self.filled + take].copy_from_slice(&data[..take]);
+        self.filled += take;
+        take
+    }
+
+    /// Accounts for `part`, which lies within the current state, and moves
+    /// on once the state is complete
+    fn take(&mut self, part: &[u8]) -> Result<(), ImageError> {
+        self.len += part.len() as u32;
+        self.sha.update(part);
+        match self.state {
+            State::Header if self.filled == HEADER_LEN => {
+                let header = &self.field;
+                if header[0] != IMAGE_MAGIC {
+                    return Err(ImageError::BadMagic);
+                }
+                let chip_id = u16::from_le_bytes([header[12], header[13]]);
+                if chip_id != self.chip_id {
+                    return Err(ImageError::WrongChip(chip_id));
+                }
+                self.segments = header[1];
+                if self.segments == 0 || self.segments > MAX_SEGMENTS {
+                    return Err(ImageError::BadSegmentCount(self.segments));
+                }
+                self.segments_left = self.segments;
+                self.entry = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+                self.digest_appended = header[23] == 1;
+                self.filled = 0;
+                self.state = State::SegmentHeader;
+            }
+            State::SegmentHeader if self.filled == SEGMENT_HEADER_LEN => {
+                let header = &self.field;
+                let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+                if len > self.max_len {
+                    return Err(ImageError::TooLarge);
+                }
+                self.segments_left -= 1;
+                self.filled = 0;
+                self.state = State::SegmentData(len);
+                self.end_segment();
+            }
+            State::SegmentData(left) => {
+                if self.segments_left == self.segments - 1 {
+                    let take = part.len().min(APP_DESC_LEN - self.app_desc_len);
+                    self.app_desc[self.app_desc_len..self.app_desc_len + take]
+                        .copy_from_slice(&part[..take]);
+                    self.app_desc_len += take;
+                }
+                self.checksum = part.iter().fold(self.checksum, |sum, &b| sum ^ b);
+                self.state = State::SegmentData(left - part.len() as u32);
+                self.end_segment();
+            }
+            State::Padding(left) => {
+                self.state = match left - part.len() as u32 {
+                    0 => State::Checksum,
+                    left => State::Padding(left),
+                };
+            }
+            State::Checksum => {
+                if part[0] != self.checksum {
+                    return Err(ImageError::ChecksumMismatch);
+                }
+                self.state = if self.digest_appended {
+                    self.expected_digest = self.sha.clone().finalize().into();
+                    State::Digest
+                } else {
+                    State::Done
+                };
+            }
+            State::Digest if self.filled == DIGEST_LEN => {
+                let mut digest = [0; DIGEST_LEN];
+                digest.copy_from_slice(&self.field);
+                if digest != self.expected_digest {
+                    return Err(ImageError::DigestMismatch);
+                }
+                self.digest = Some(digest);
+                self.state = State::Done;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Moves past a segment whose data is complete
+    fn end_segment(&mut self) {
+        if self.state != State::SegmentData(0) {
+            return;
+        }
+        self.state = if self.segments_left > 0 {
+            State::SegmentHeader
+        } else {
+            // The checksum goes into the last byte of a 16 byte block
+            match 15 - self.len % 16 {
+                0 => State::Checksum,
+                padding => State::Padding(padding),
+            }
+        };
+    }
+}
+
+impl fmt::Debug for ImageVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImageVerifier")
+            .field("state", &self.state)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+/// Checks a signed image streamed through it: the image as an
+/// [`ImageVerifier`] does, and then the signature block after it against a
+/// public key.
+pub struct SignedImageVerifier {
+    image: ImageVerifier,
+    key: VerifyingKey,
+    block: [u8; SIGNATURE_BLOCK_LEN],
+    filled: usize,
+}
+
+impl SignedImageVerifier {
+    /// Starts checking an image for the chip `chip_id` that may take up to
+    /// `max_len` bytes, not counting the signature block, and must be signed
+    /// with the secret key of `key`.
+    pub fn new(chip_id: u16, max_len: u32, key: VerifyingKey) -> Self {
+        Self {
+            image: ImageVerifier::new(chip_id, max_len),
+            key,
+            block: [0; SIGNATURE_BLOCK_LEN],
+            filled: 0,
+        }
+    }
+
+    /// Number of bytes taken so far, including the signature block
+    pub fn len(&self) -> u32 {
+        self.image.len() + self.filled as u32
+    }
+
+    /// Returns `true` before the first byte
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Takes the next part of the signed image and returns how many bytes
+    /// of `data` belong to the image itself; the rest is signature block.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`ImageError`] as soon as the data cannot be a valid
+    /// signed image; the verifier must not be used after that.
+    pub fn update(&mut self, data: &[u8]) -> Result<usize, ImageError> {
+        let image_len = self.image.update_to_end(data)?;
+        let rest = &data[image_len..];
+        if rest.len() > SIGNATURE_BLOCK_LEN - self.filled {
+            return Err(ImageError::TrailingData);
+        }
+        self.block[self.filled..self.filled + rest.len()].copy_from_slice(rest);
+        self.filled += rest.len();
+        let magic = self.filled.min(SIGNATURE_MAGIC.len());
+        if self.block[..magic] != SIGNATURE_MAGIC[..magic] {
+            return Err(ImageError::Unsigned);
+        }
+        Ok(image_len)
+    }
+
+    /// Checks that the image is complete and signed, and returns what it
+    /// holds.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ImageError::Truncated`] if the image or its signature block
+    /// has not ended, [`ImageError::Unsigned`] if there is no signature
+    /// block, or [`ImageError::BadSignature`].
+    pub fn finish(self) -> Result<ImageInfo, ImageError> {
+        let info = self.image.finish()?;
+        match self.filled {
+            0 => return Err(ImageError::Unsigned),
+            SIGNATURE_BLOCK_LEN => {}
+            _ => return Err(ImageError::Truncated),
+        }
+        let mut signature = [0; SIGNATURE_LEN];
+        signature.copy_from_slice(&self.block[SIGNATURE_MAGIC.len()..]);
+        self.key
+            .verify(&info.sha256, &signature)
+            .map_err(|_| ImageError::BadSignature)?;
+        Ok(info)
+    }
+}
+
+impl fmt::Debug for SignedImageVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignedImageVerifier")
+            .field("image", &self.image)
+            .field("signature", &self.filled)
+            .finish()
+    }
+}
+
+/// Makes the signature block that follows the image `info` was taken from,
+/// signed with the `ed25519-dalek` key `key`.
+pub fn signature_block(info: &ImageInfo, key: &SigningKey) -> [u8; SIGNATURE_BLOCK_LEN] {
+    let mut block = [0; SIGNATURE_BLOCK_LEN];
+    block[..SIGNATURE_MAGIC.len()].copy_from_slice(&SIGNATURE_MAGIC);
+    block[SIGNATURE_MAGIC.len()..].copy_from_slice(&key.sign(&info.sha256).to_bytes());
+    block
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ed25519::SEED_LEN;
+    use crate::ed25519::tests::TEST_SEED;
+    use std::vec::Vec;
+
+    /// Builds an image with two segments, the first holding an application
+    /// description with `version`
+    pub(crate) fn image(version: &str, digest: bool) -> Vec<u8> {
+        let mut image = Vec::new();
+        image.push(IMAGE_MAGIC);
+        image.push(2);
+        image.extend_from_slice(&[2, 0x20]);
+        image.extend_from_slice(&0x4008_0400u32.to_le_bytes());
+        image.extend_from_slice(&[0xEE, 0, 0, 0]);
+        image.extend_from_slice(&CHIP_ID_ESP32.to_le_bytes());
+        image.extend_from_slice(&[0; 9]);
+        image.push(u8::from(digest));
+
+        let mut desc = Vec::new();
+        desc.extend_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+        desc.extend_from_slice(&[0; 12]);
+        let mut name = [0; 32];
+        name[..version.len()].copy_from_slice(version.as_bytes());
+        desc.extend_from_slice(&name);
+        desc.extend((0..52u8).map(|i| i.wrapping_mul(37)));
+        let code: Vec<u8> = (0..301u32).map(|i| (i * 13 + 5) as u8).collect();
+
+        let mut checksum = CHECKSUM_SEED;
+        for (addr, data) in [(0x3F40_0020u32, &desc), (0x4008_0000, &code)] {
+            image.extend_from_slice(&addr.to_le_bytes());
+            image.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            image.extend_from_slice(data);
+            checksum = data.iter().fold(checksum, |sum, &b| sum ^ b);
+        }
+        while image.len() % 16 != 15 {
+            image.push(0);
+        }
+        image.push(checksum);
+        if digest {
+            let digest = Sha256::digest(&image);
+            image.extend_from_slice(&digest);
+        }
+        image
+    }
+
+    /// Appends a signature block made with the key of `seed`
+    pub(crate) fn signed_image(mut image: Vec<u8>, seed: &[u8; SEED_LEN]) -> Vec<u8> {
+        let mut verifier = ImageVerifier::new(CHIP_ID_ESP32, image.len() as u32);
+        verifier.update(&image).unwrap();
+        let info = verifier.finish().unwrap();
+        image.extend_from_slice(&signature_block(&info, &SigningKey::from_bytes(seed)));
+        image
+    }
+
+    fn verify(image: &[u8], chunk: usize) -> Result<ImageInfo, ImageError> {
+        let mut verifier = ImageVerifier::new(CHIP_ID_ESP32, 4096);
+        for part in image.chunks(chunk) {
+            verifier.update(part)?;
+        }
+        verifier.finish()
+    }
+
+    #[test]
+    fn accepts_valid_images_in_any_chunks() {
+        for digest in [false, true] {
+            let image = image("1.2.3", digest);
+            for chunk in [1, 7, 64, image.len()] {
+                let info = verify(&image, chunk).unwrap();
+                assert_eq!(info.len as usize, image.len());
+                assert_eq!(info.entry, 0x4008_0400);
+                assert_eq!(info.segments, 2);
+                assert_eq!(info.version, "1.2.3");
+                assert_eq!(info.digest.is_some(), digest);
+                assert_eq!(info.sha256, *Sha256::digest(&image));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_damaged_images() {
+        let image = image("1.2.3", true);
+        let flipped = |at: usize| {
+            let mut image = image.clone();
+            image[at] ^= 0x40;
+            image
+        };
+
+        assert_eq!(verify(&flipped(0), 5), Err(ImageError::BadMagic));
+        assert_eq!(verify(&flipped(12), 5), Err(ImageError::WrongChip(0x40)));
+        let mut no_segments = image.clone();
+        no_segments[1] = 0;
+        assert_eq!(verify(&no_segments, 5), Err(ImageError::BadSegmentCount(0)));
+
+        // Code bytes are covered by the checksum, padding only by the digest
+        assert_eq!(verify(&flipped(200), 5), Err(ImageError::ChecksumMismatch));
+        let padding = image.len() - DIGEST_LEN - 2;
+        assert_eq!(
+            verify(&flipped(padding), 5),
+            Err(ImageError::DigestMismatch)
+        );
+        assert_eq!(
+            verify(&flipped(image.len() - 1), 5),
+            Err(ImageError::DigestMismatch)
+        );
+
+        assert_eq!(
+            verify(&image[..image.len() - 1], 5),
+            Err(ImageError::Truncated)
+        );
+        let mut longer = image.clone();
+        longer.push(0);
+        assert_eq!(verify(&longer, 5), Err(ImageError::TrailingData));
+
+        let mut verifier = ImageVerifier::new(CHIP_ID_ESP32, image.len() as u32 - 1);
+        assert_eq!(verifier.update(&image), Err(ImageError::TooLarge));
+    }
+
+    #[test]
+    fn checks_signatures() {
+        let key = VerifyingKey::from(SigningKey::from_bytes(&TEST_SEED).verifying_key());
+        let verify_signed = |data: &[u8], chunk: usize| {
+            let mut verifier = SignedImageVerifier::new(CHIP_ID_ESP32, 4096, key);
+            let mut image_len = 0;
+            for part in data.chunks(chunk) {
+                image_len += verifier.update(part)?;
+            }
+            assert_eq!(verifier.len() as usize, data.len());
+            let info = verifier.finish()?;
+            assert_eq!(info.len as usize, image_len);
+            Ok(info)
+        };
+
+        let plain = image("1.2.3", true);
+        let signed = signed_image(plain.clone(), &TEST_SEED);
+        assert_eq!(signed.len(), plain.len() + SIGNATURE_BLOCK_LEN);
+        for chunk in [1, 5, 64, signed.len()] {
+            let info = verify_signed(&signed, chunk).unwrap();
+            assert_eq!(info.len as usize, plain.len());
+            assert_eq!(info.version, "1.2.3");
+        }
+
+        assert_eq!(verify_signed(&plain, 64), Err(ImageError::Unsigned));
+        let mut other_trailer = plain.clone();
+        other_trailer.extend_from_slice(b"JUNK");
+        assert_eq!(verify_signed(&other_trailer, 64), Err(ImageError::Unsigned));
+        assert_eq!(
+            verify_signed(&signed[..signed.len() - 1], 64),
+            Err(ImageError::Truncated)
+        );
+        let mut longer = signed.clone();
+        longer.push(0);
+        assert_eq!(verify_signed(&longer, 64), Err(ImageError::TrailingData));
+
+        // Another key, a changed signature, or a changed image
+        let foreign = signed_image(plain.clone(), &[8; SEED_LEN]);
+        assert_eq!(verify_signed(&foreign, 64), Err(ImageError::BadSignature));
+        let mut bad_signature = signed.clone();
+        *bad_signature.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            verify_signed(&bad_signature, 64),
+            Err(ImageError::BadSignature)
+        );
+        // Without an appended digest, only the signature notices a change
+        // to the padding
+        let undigested = image("1.2.3", false);
+        let padding = undigested.len() - 2;
+        let mut tampered = signed_image(undigested.clone(), &TEST_SEED);
+        tampered[padding] ^= 0x40;
+        assert!(verify(&tampered[..undigested.len()], 5).is_ok());
+        assert_eq!(verify_signed(&tampered, 5), Err(ImageError::BadSignature));
+    }
+}
@@ -0,0 +1,405 @@
+//! BLE provisioning protocol.
+//!
+//! A phone app onboards the device through one GATT service with five
+//! characteristics:
+//!
+//! | Characteristic | UUID                                   | Access       | Contents                   |
+//! |----------------|----------------------------------------|--------------|----------------------------|
+//! | Service        | `5e1a0001-7c3b-4f52-9a4e-1c2d3e4f5a6b` |              |                            |
+//! | Networks       | `5e1a0002-7c3b-4f52-9a4e-1c2d3e4f5a6b` | read         | [`encode_networks`]        |
+//! | SSID           | `5e1a0003-7c3b-4f52-9a4e-1c2d3e4f5a6b` | write        | UTF-8, up to 32 bytes      |
+//! | Password       | `5e1a0004-7c3b-4f52-9a4e-1c2d3e4f5a6b` | write        | UTF-8, empty or 8 to 64 bytes |
+//! | Control        | `5e1a0005-7c3b-4f52-9a4e-1c2d3e4f5a6b` | write        | one [`Command`] byte       |
+//! | Status         | `5e1a0006-7c3b-4f52-9a4e-1c2d3e4f5a6b` | read, notify | [`ProvisioningStatus::encode`] |
+//!
+//! The app writes the SSID and the password, long values with prepared
+//! writes at increasing offsets, then writes [`Command::Connect`]. A
+//! [`Session`] collects the writes and hands out the [`Credentials`] on that
+//! command. The status characteristic follows the station until the device
+//! is connected or the attempt fails.
+
+use core::fmt;
+
+use crate::report::{AccessPointRecord, AuthMethod, MAX_ACCESS_POINTS, MAX_SSID_LEN};
+use crate::station::{
+    ConnectionState, Credentials, CredentialsError, DisconnectReason, MAX_PASSWORD_LEN,
+};
+
+/// Size of the longest [`encode_networks`] output
+pub const MAX_NETWORKS_LEN: usize = MAX_ACCESS_POINTS * (NETWORK_HEADER_LEN + MAX_SSID_LEN);
+
+/// Bytes in front of the SSID of each encoded network
+const NETWORK_HEADER_LEN: usize = 4;
+
+/// Authentication code of networks whose method the driver did not report
+const AUTH_UNKNOWN: u8 = 0xFF;
+
+/// Command written to the control characteristic
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    /// Join the network with the written SSID and password (`0x01`)
+    Connect,
+    /// Discard the written SSID and password (`0x02`)
+    Clear,
+}
+
+impl Command {
+    /// Decodes a command byte
+    pub const fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x01 => Some(Command::Connect),
+            0x02 => Some(Command::Clear),
+            _ => None,
+        }
+    }
+}
+
+/// Reasons why a write was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum WriteError {
+    /// A write did not continue where the previous one ended
+    InvalidOffset,
+    /// The value is longer than the characteristic allows
+    TooLong,
+    /// The control characteristic got an unknown command
+    UnknownCommand,
+    /// The SSID or password is not valid UTF-8
+    InvalidUtf8,
+    /// The credentials are invalid
+    Credentials(CredentialsError),
+}
+
+impl WriteError {
+    /// Code of the error in the status characteristic
+    pub const fn code(self) -> u8 {
+        match self {
+            WriteError::InvalidOffset => 1,
+            WriteError::TooLong => 2,
+            WriteError::UnknownCommand => 3,
+            WriteError::InvalidUtf8 => 4,
+            WriteError::Credentials(CredentialsError::EmptySsid) => 5,
+            WriteError::Credentials(CredentialsError::SsidTooLong) => 6,
+            WriteError::Credentials(CredentialsError::InvalidPasswordLength) => 7,
+        }
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::InvalidOffset => f.write_str("write at unexpected offset"),
+            WriteError::TooLong => f.write_str("value too long"),
+            WriteError::UnknownCommand => f.write_str("unknown command"),
+            WriteError::InvalidUtf8 => f.write_str("value is not valid UTF-8"),
+            WriteError::Credentials(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Credentials being written by a connected client
+///
+/// There is no `Debug` implementation, as it would show the password.
+#[derive(Clone, Default)]
+pub struct Session {
+    ssid: heapless::Vec<u8, MAX_SSID_LEN>,
+    password: heapless::Vec<u8, MAX_PASSWORD_LEN>,
+}
+
+impl Session {
+    /// Creates an empty session
+    pub const fn new() -> Self {
+        Self {
+            ssid: heapless::Vec::new(),
+            password: heapless::Vec::new(),
+        }
+    }
+
+    /// Handles a write of `data` at `offset` to the SSID characteristic.
+    ///
+    /// A write at offset 0 replaces the SSID; later parts of a long write
+    /// must follow on directly.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`WriteError::InvalidOffset`] or [`WriteError::TooLong`]; the
+    /// SSID is cleared then.
+    pub fn write_ssid(&mut self, offset: usize, data: &[u8]) -> Result<(), WriteError> {
+        write_at(&mut self.ssid, offset, data)
+    }
+
+    /// Handles a write of `data` at `offset` to the password characteristic.
+    ///
+    /// # Errors
+    ///
+    /// As for [`write_ssid`](Self::write_ssid).
+    pub fn write_password(&mut self, offset: usize, data: &[u8]) -> Result<(), WriteError> {
+        write_at(&mut self.password, offset, data)
+    }
+
+    /// Handles a write to the control characteristic.
+    ///
+    /// Returns the credentials on [`Command::Connect`]. Either command
+    /// clears the session.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`WriteError`] for unknown commands and invalid credentials.
+    pub fn command(&mut self, data: &[u8]) -> Result<Option<Credentials>, WriteError> {
+        let command = match data {
+            [byte] => Command::from_byte(*byte).ok_or(WriteError::UnknownCommand)?,
+            _ => return Err(WriteError::UnknownCommand),
+        };
+        let result = match command {
+            Command::Connect => self.credentials().map(Some),
+            Command::Clear => Ok(None),
+        };
+        self.ssid.clear();
+        self.password.clear();
+        result
+    }
+
+    fn credentials(&self) -> Result<Credentials, WriteError> {
+        let ssid = core::str::from_utf8(&self.ssid).map_err(|_| WriteError::InvalidUtf8)?;
+        let password = core::str::from_utf8(&self.password).map_err(|_| WriteError::InvalidUtf8)?;
+        Credentials::new(ssid, password).map_err(WriteError::Credentials)
+    }
+}
+
+/// Writes `data` at `offset`, clearing `value` on error
+fn write_at<const N: usize>(
+    value: &mut heapless::Vec<u8, N>,
+    offset: usize,
+    data: &[u8],
+) -> Result<(), WriteError> {
+    if offset == 0 {
+        value.clear();
+    } else if offset != value.len() {
+        value.clear();
+        return Err(WriteError::InvalidOffset);
+    }
+    value.extend_from_slice(data).map_err(|()| {
+        value.clear();
+        WriteError::TooLong
+    })
+}
+
+/// Provisioning progress shown in the status characteristic
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ProvisioningStatus {
+    /// Waiting for credentials
+    Idle,
+    /// The last write was rejected
+    Rejected(WriteError),
+    /// Joining the network
+    Connecting,
+    /// Connected to the network
+    Connected,
+    /// The last attempt failed; the station may still retry
+    Failed(DisconnectReason),
+}
+
+impl ProvisioningStatus {
+    /// Status matching the station's connection state
+    pub fn from_state(state: ConnectionState, last_reason: Option<DisconnectReason>) -> Self {
+        match state {
+            ConnectionState::Idle => ProvisioningStatus::Idle,
+            ConnectionState::Connecting { .. } => ProvisioningStatus::Connecting,
+            ConnectionState::Connected => ProvisioningStatus::Connected,
+            ConnectionState::Disconnected { reason } => ProvisioningStatus::Failed(reason),
+            ConnectionState::Backoff { .. } => {
+                ProvisioningStatus::Failed(last_reason.unwrap_or(DisconnectReason::Unspecified))
+            }
+        }
+    }
+
+    /// Encodes the status as `[state, detail]`.
+    ///
+    /// | State | Meaning    | Detail                   |
+    /// |-------|------------|--------------------------|
+    /// | 0     | idle       | 0                        |
+    /// | 1     | rejected   | [`WriteError::code`]     |
+    /// | 2     | connecting | 0                        |
+    /// | 3     | connected  | 0                        |
+    /// | 4     | failed     | [`DisconnectReason::code`] |
+    pub const fn encode(&self) -> [u8; 2] {
+        match self {
+            ProvisioningStatus::Idle => [0, 0],
+            ProvisioningStatus::Rejected(e) => [1, e.code()],
+            ProvisioningStatus::Connecting => [2, 0],
+            ProvisioningStatus::Connected => [3, 0],
+            ProvisioningStatus::Failed(reason) => [4, reason.code()],
+        }
+    }
+}
+
+/// Encodes the visible networks among `networks` for the networks characteristic.
+///
+/// Networks are listed once per SSID, strongest first, as by
+/// [`distinct_networks`](crate::report::distinct_networks). Each one takes
+/// `[rssi as i8, channel, auth, ssid length, ssid...]`, where `auth` is the
+/// position of the method in [`AuthMethod`] or `0xFF` if unknown.
+///
+/// Returns the length written, at most [`MAX_NETWORKS_LEN`]; networks that do
+/// not fit into `out` are left out.
+pub fn encode_networks<'a>(
+    networks: impl IntoIterator<Item = &'a AccessPointRecord>,
+    out: &mut [u8],
+) -> usize {
+    let mut len = 0;
+    for ap in crate::report::distinct_networks(networks) {
+        let ssid = ap.ssid.as_bytes();
+        let Some(entry) = out.get_mut(len..len + NETWORK_HEADER_LEN + ssid.len()) else {
+            break;
+        };
+        entry[0] = ap.signal_strength as u8;
+        entry[1] = ap.channel;
+        entry[2] = ap.auth_method.map_or(AUTH_UNKNOWN, auth_code);
+        entry[3] = ssid.len() as u8;
+        entry[NETWORK_HEADER_LEN..].copy_from_slice(ssid);
+        len += entry.len();
+    }
+    len
+}
+
+const fn auth_code(auth: AuthMethod) -> u8 {
+    match auth {
+        AuthMethod::Open => 0,
+        AuthMethod::Wep => 1,
+        AuthMethod::Wpa => 2,
+        AuthMethod::Wpa2Personal => 3,
+        AuthMethod::WpaWpa2Personal => 4,
+        AuthMethod::Wpa2Enterprise => 5,
+        AuthMethod::Wpa3Personal => 6,
+        AuthMethod::Wpa2Wpa3Personal => 7,
+        AuthMethod::WapiPersonal => 8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::SecondaryChannel;
+
+    #[test]
+    fn collects_long_writes_into_credentials() {
+        let mut session = Session::new();
+        session.write_ssid(0, b"Office ").unwrap();
+        session.write_ssid(7, b"5G").unwrap();
+        session.write_password(0, b"correct ").unwrap();
+        session.write_password(8, b"horse").unwrap();
+        assert_eq!(
+            session.command(&[0x01]),
+            Ok(Some(
+                Credentials::new("Office 5G", "correct horse").unwrap()
+            ))
+        );
+
+        // The session starts over after a command
+        assert_eq!(
+            session.command(&[0x01]),
+            Err(WriteError::Credentials(CredentialsError::EmptySsid))
+        );
+
+        session.write_ssid(0, b"cafe").unwrap();
+        assert_eq!(session.command(&[0x02]), Ok(None));
+        assert_eq!(session.command(&[0x07]), Err(WriteError::UnknownCommand));
+        assert_eq!(session.command(&[]), Err(WriteError::UnknownCommand));
+    }
+
+    #[test]
+    fn rejects_invalid_writes() {
+        let mut session = Session::new();
+        session.write_ssid(0, b"abc").unwrap();
+        assert_eq!(session.write_ssid(5, b"x"), Err(WriteError::InvalidOffset));
+        assert_eq!(
+            session.write_password(0, &[b'p'; MAX_PASSWORD_LEN + 1]),
+            Err(WriteError::TooLong)
+        );
+
+        session.write_ssid(0, &[0xC3]).unwrap();
+        assert_eq!(session.command(&[0x01]), Err(WriteError::InvalidUtf8));
+
+        session.write_ssid(0, b"home").unwrap();
+        session.write_password(0, b"short").unwrap();
+        let error = session.command(&[0x01]).unwrap_err();
+        assert_eq!(
+            ProvisioningStatus::Rejected(error).encode(),
+            [
+                1,
+                WriteError::Credentials(CredentialsError::InvalidPasswordLength).code()
+            ]
+        );
+    }
+
+    #[test]
+    fn follows_the_station_state() {
+        let status = |state| ProvisioningStatus::from_state(state, None).encode();
+        assert_eq!(status(ConnectionState::Idle), [0, 0]);
+        assert_eq!(status(ConnectionState::Connecting { attempt: 1 }), [2, 0]);
+        assert_eq!(status(ConnectionState::Connected), [3, 0]);
+        assert_eq!(
+            status(ConnectionState::Disconnected {
+                reason: DisconnectReason::AuthFailed
+            }),
+            [4, 202]
+        );
+        assert_eq!(
+            ProvisioningStatus::from_state(
+                ConnectionState::Backoff {
+                    attempt: 2,
+                    delay_ms: 1000
+                },
+                Some(DisconnectReason::HandshakeTimeout)
+            ),
+            ProvisioningStatus::Failed(DisconnectReason::HandshakeTimeout)
+        );
+    }
+
+    #[test]
+    fn encodes_distinct_networks() {
+        let ap = |ssid: &str, last: u8, rssi: i8, auth| {
+            AccessPointRecord::new(
+                ssid,
+                [0, 0, 0, 0, 0, last],
+                11,
+                SecondaryChannel::None,
+                rssi,
+                auth,
+            )
+        };
+        let networks = [
+            ap("lab", 1, -80, Some(AuthMethod::Wpa2Personal)),
+            ap("cafe", 2, -60, Some(AuthMethod::Open)),
+            ap("lab", 3, -50, Some(AuthMethod::Wpa2Personal)),
+            ap("", 4, -20, None),
+        ];
+        let mut out = [0u8; MAX_NETWORKS_LEN];
+        let len = encode_networks(&networks, &mut out);
+        assert_eq!(
+            out[..len],
+            [
+                -50i8 as u8,
+                11,
+                3,
+                3,
+                b'l',
+                b'a',
+                b'b', //
+                -60i8 as u8,
+                11,
+                0,
+                4,
+                b'c',
+                b'a',
+                b'f',
+                b'e',
+            ]
+        );
+
+        // Networks that do not fit are left out whole
+        assert_eq!(encode_networks(&networks, &mut out[..10]), 7);
+    }
+}
@@ -0,0 +1,135 @@
+//! Catch-all DNS responder for the captive portal.
+//!
+//! While the device runs its provisioning access point, every name resolves
+//! to the device itself, so that whatever page a client opens ends up on the
+//! portal. [`answer`] turns one DNS query (RFC 1035) into a response: `A` and
+//! `ANY` questions get the portal address, every other type an empty answer,
+//! which makes clients fall back to IPv4.
+
+/// UDP port the responder listens on
+pub const DNS_PORT: u16 = 53;
+
+/// Time-to-live of the answers in seconds; short, as the answers are only
+/// valid during provisioning
+pub const ANSWER_TTL_SECS: u32 = 60;
+
+const HEADER_LEN: usize = 12;
+
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+/// Flags of a response: QR and AA set, opcode QUERY, no error
+const RESPONSE_FLAGS: u16 = 0x8400;
+
+/// Recursion desired bit, echoed from the query
+const FLAG_RD: u16 = 0x0100;
+
+/// Answers a DNS `query` with `address` and writes the response to `out`.
+///
+/// Returns the length of the response, or `None` for anything that is not a
+/// standard query with exactly one question, or if `out` is too small.
+pub fn answer(query: &[u8], address: [u8; 4], out: &mut [u8]) -> Option<usize> {
+    let header = query.get(..HEADER_LEN)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    // QR must be clear and the opcode QUERY
+    if flags & 0xF800 != 0 || questions != 1 {
+        return None;
+    }
+
+    // Question name: labels up to the root label, without compression
+    let mut end = HEADER_LEN;
+    loop {
+        let len = usize::from(*query.get(end)?);
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        end += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    let question = query.get(HEADER_LEN..end + 4)?;
+    let qtype = u16::from_be_bytes([query[end], query[end + 1]]);
+    let qclass = u16::from_be_bytes([query[end + 2], query[end + 3]]);
+    let answers = u16::from(qclass == CLASS_IN && matches!(qtype, TYPE_A | TYPE_ANY));
+
+    let len = HEADER_LEN + question.len() + usize::from(answers) * 16;
+    let out = out.get_mut(..len)?;
+    out[..2].copy_from_slice(&header[..2]);
+    out[2..4].copy_from_slice(&(RESPONSE_FLAGS | flags & FLAG_RD).to_be_bytes());
+    out[4..6].copy_from_slice(&1u16.to_be_bytes());
+    out[6..8].copy_from_slice(&answers.to_be_bytes());
+    out[8..12].fill(0);
+    out[HEADER_LEN..HEADER_LEN + question.len()].copy_from_slice(question);
+    if answers == 1 {
+        let record = &mut out[HEADER_LEN + question.len()..];
+        // Name: pointer to the question name
+        record[..2].copy_from_slice(&[0xC0, HEADER_LEN as u8]);
+        record[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+        record[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        record[6..10].copy_from_slice(&ANSWER_TTL_SECS.to_be_bytes());
+        record[10..12].copy_from_slice(&4u16.to_be_bytes());
+        record[12..16].copy_from_slice(&address);
+    }
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = std::vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn resolves_every_name_to_the_portal() {
+        let request = query("connectivitycheck.gstatic.com", TYPE_A);
+        let mut out = [0; 512];
+        let len = answer(&request, [192, 168, 4, 1], &mut out).unwrap();
+        let response = &out[..len];
+
+        assert_eq!(response[..2], [0x12, 0x34]);
+        assert_eq!(response[2..4], [0x85, 0x00]);
+        assert_eq!(response[6..8], [0, 1]);
+        assert_eq!(response[12..request.len()], request[12..]);
+        let record = &response[request.len()..];
+        assert_eq!(record[..4], [0xC0, 12, 0, 1]);
+        assert_eq!(record[12..], [192, 168, 4, 1]);
+    }
+
+    #[test]
+    fn answers_other_types_without_records() {
+        let request = query("example.com", 28);
+        let mut out = [0; 512];
+        let len = answer(&request, [192, 168, 4, 1], &mut out).unwrap();
+        assert_eq!(len, request.len());
+        assert_eq!(out[6..8], [0, 0]);
+    }
+
+    #[test]
+    fn ignores_invalid_queries() {
+        let mut out = [0; 512];
+        let mut response = query("example.com", TYPE_A);
+        response[2] |= 0x80;
+        assert_eq!(answer(&response, [0; 4], &mut out), None);
+
+        let request = query("example.com", TYPE_A);
+        assert_eq!(
+            answer(&request[..request.len() - 1], [0; 4], &mut out),
+            None
+        );
+        assert_eq!(answer(&request, [0; 4], &mut out[..20]), None);
+    }
+}
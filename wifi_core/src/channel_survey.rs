@@ -0,0 +1,292 @@
+//! 2.4 GHz channel survey and best-channel recommendation.
+//!
+//! [`ChannelSurvey`] turns each [`ScanReport`] into a per-channel sample for
+//! channels 1 to 13 and keeps the last `W` samples. From that rolling window it
+//! ranks the channels, least interfered first.
+//!
+//! # Interference score
+//!
+//! 2.4 GHz channels are 5 MHz apart but 20 MHz wide, so an access point also
+//! disturbs the four channels on either side of its own. For a candidate
+//! channel, every access point contributes its signal strength above
+//! [`NOISE_FLOOR_DBM`], weighted by how much the two channels overlap: 100 %
+//! on the same channel, then 80, 60, 40 and 20 % for one to four channels
+//! apart. Access points using a 40 MHz channel contribute a second time,
+//! centered on their secondary channel. The score of a channel is the sum of
+//! all contributions, averaged over the window.
+
+use core::fmt;
+
+use crate::report::{ScanReport, SecondaryChannel};
+use crate::scan_config::ChannelSet;
+
+/// Number of channels surveyed (1 to 13)
+pub const SURVEY_CHANNELS: usize = 13;
+
+/// Signal strength that contributes nothing to the interference score, in dBm
+pub const NOISE_FLOOR_DBM: i8 = -95;
+
+/// Channel distance at which two 20 MHz channels stop overlapping
+const OVERLAP_SPAN: u8 = 5;
+
+/// Distance of the secondary channel of a 40 MHz access point from its primary
+const SECONDARY_OFFSET: u8 = 4;
+
+/// Occupancy and interference of one channel in one scan
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChannelSample {
+    /// Number of access points using each channel as primary, index 0 is channel 1
+    pub occupancy: [u8; SURVEY_CHANNELS],
+    /// Interference score of each channel, index 0 is channel 1
+    pub interference: [u32; SURVEY_CHANNELS],
+}
+
+impl ChannelSample {
+    /// Computes the sample for one scan
+    pub fn from_report(report: &ScanReport) -> Self {
+        let mut sample = Self::default();
+        for ap in report {
+            if !(1..=SURVEY_CHANNELS as u8).contains(&ap.channel) {
+                continue;
+            }
+            let index = usize::from(ap.channel - 1);
+            sample.occupancy[index] = sample.occupancy[index].saturating_add(1);
+
+            let strength =
+                u32::from(ap.signal_strength.saturating_sub(NOISE_FLOOR_DBM).max(0) as u8);
+            sample.add_interference(ap.channel, strength);
+            match ap.secondary_channel {
+                SecondaryChannel::Above => {
+                    sample.add_interference(ap.channel + SECONDARY_OFFSET, strength)
+                }
+                SecondaryChannel::Below if ap.channel > SECONDARY_OFFSET => {
+                    sample.add_interference(ap.channel - SECONDARY_OFFSET, strength)
+                }
+                _ => {}
+            }
+        }
+        sample
+    }
+
+    /// Spreads `strength` from `center` onto all overlapping channels
+    fn add_interference(&mut self, center: u8, strength: u32) {
+        for channel in 1..=SURVEY_CHANNELS as u8 {
+            let distance = channel.abs_diff(center);
+            if distance < OVERLAP_SPAN {
+                let weight = u32::from(OVERLAP_SPAN - distance);
+                self.interference[usize::from(channel - 1)] +=
+                    strength * weight / u32::from(OVERLAP_SPAN);
+            }
+        }
+    }
+}
+
+/// Averaged survey result for one channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChannelScore {
+    /// Channel number
+    pub channel: u8,
+    /// Average number of access points using this channel as primary, times 10
+    pub occupancy_x10: u16,
+    /// Average interference score, lower is better
+    pub interference: u32,
+}
+
+impl fmt::Display for ChannelScore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Channel {}: {}.{} APs, interference {}",
+            self.channel,
+            self.occupancy_x10 / 10,
+            self.occupancy_x10 % 10,
+            self.interference
+        )
+    }
+}
+
+/// Channels ranked from best to worst
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Recommendation {
+    /// Number of scans the ranking is based on
+    pub scans: u8,
+    /// Candidate channels, least interfered first
+    pub ranked: heapless::Vec<ChannelScore, SURVEY_CHANNELS>,
+}
+
+impl Recommendation {
+    /// The best channel, if any candidate was surveyed
+    pub fn best(&self) -> Option<&ChannelScore> {
+        self.ranked.first()
+    }
+}
+
+/// Rolling survey over the last `W` scans
+#[derive(Clone, Debug, Default)]
+pub struct ChannelSurvey<const W: usize> {
+    samples: heapless::Deque<ChannelSample, W>,
+}
+
+impl<const W: usize> ChannelSurvey<W> {
+    /// Creates an empty survey
+    pub const fn new() -> Self {
+        Self {
+            samples: heapless::Deque::new(),
+        }
+    }
+
+    /// Adds a scan, dropping the oldest one once the window is full.
+    ///
+    /// Scans restricted by SSID, BSSID or channel filters only see part of
+    /// the band and will bias the survey.
+    pub fn add(&mut self, report: &ScanReport) {
+        if self.samples.is_full() {
+            self.samples.pop_front();
+        }
+        // Cannot fail: a slot was freed above
+        let _ = self.samples.push_back(ChannelSample::from_report(report));
+    }
+
+    /// Number of scans in the window
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Returns `true` if no scan has been added
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Forgets all scans
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Average score of one channel over the window
+    pub fn score(&self, channel: u8) -> Option<ChannelScore> {
+        if self.samples.is_empty() || !(1..=SURVEY_CHANNELS as u8).contains(&channel) {
+            return None;
+        }
+        let index = usize::from(channel - 1);
+        let scans = self.samples.len() as u32;
+        let (occupancy, interference) = self.samples.iter().fold((0u32, 0u32), |(o, i), s| {
+            (o + u32::from(s.occupancy[index]), i + s.interference[index])
+        });
+        Some(ChannelScore {
+            channel,
+            occupancy_x10: (occupancy * 10 / scans) as u16,
+            interference: interference / scans,
+        })
+    }
+
+    /// Ranks the `candidates` from least to most interfered.
+    ///
+    /// Ties go to the non-overlapping channels 1, 6 and 11 first, then to the
+    /// lower channel number.
+    pub fn recommend(&self, candidates: ChannelSet) -> Recommendation {
+        let mut ranked: heapless::Vec<ChannelScore, SURVEY_CHANNELS> = candidates
+            .iter()
+            .filter_map(|channel| self.score(channel))
+            .collect();
+        ranked.sort_unstable_by_key(|score| {
+            let preferred = matches!(score.channel, 1 | 6 | 11);
+            (score.interference, !preferred, score.channel)
+        });
+        Recommendation {
+            scans: self.samples.len().min(usize::from(u8::MAX)) as u8,
+            ranked,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::fixtures::{ap, report};
+    use std::format;
+    use std::vec::Vec;
+
+    #[test]
+    fn weights_overlapping_channels() {
+        // 50 dB above the noise floor on channel 6
+        let sample =
+            ChannelSample::from_report(&report(1, &[ap(6).with_channel(6).with_rssi(-45)]));
+        assert_eq!(sample.occupancy[5], 1);
+        assert_eq!(
+            sample.interference,
+            [0, 10, 20, 30, 40, 50, 40, 30, 20, 10, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn counts_secondary_channel() {
+        let sample = ChannelSample::from_report(&report(
+            1,
+            &[ap(1)
+                .with_channel(1)
+                .with_rssi(-45)
+                .with_secondary(SecondaryChannel::Above)],
+        ));
+        assert_eq!(sample.interference[0], 50 + 10);
+        assert_eq!(sample.interference[4], 10 + 50);
+        assert_eq!(sample.occupancy.iter().sum::<u8>(), 1);
+    }
+
+    #[test]
+    fn ignores_signals_below_noise_floor() {
+        let sample =
+            ChannelSample::from_report(&report(1, &[ap(3).with_channel(3).with_rssi(-100)]));
+        assert!(sample.interference.iter().all(|&score| score == 0));
+    }
+
+    #[test]
+    fn recommends_quietest_channel() {
+        let mut survey = ChannelSurvey::<4>::new();
+        survey.add(&report(
+            1,
+            &[ap(1).with_channel(1).with_rssi(-40), ap(6).with_channel(6)],
+        ));
+
+        let recommendation = survey.recommend(ChannelSet::from_channels(&[1, 6, 11]));
+        let order: Vec<_> = recommendation.ranked.iter().map(|s| s.channel).collect();
+        assert_eq!(order, [11, 6, 1]);
+        assert_eq!(recommendation.best().map(|s| s.channel), Some(11));
+    }
+
+    #[test]
+    fn prefers_non_overlapping_channels_on_ties() {
+        let survey = {
+            let mut survey = ChannelSurvey::<4>::new();
+            survey.add(&report(1, &[]));
+            survey
+        };
+        let recommendation = survey.recommend(ChannelSet::ALL);
+        let order: Vec<_> = recommendation
+            .ranked
+            .iter()
+            .take(4)
+            .map(|s| s.channel)
+            .collect();
+        assert_eq!(order, [1, 6, 11, 2]);
+    }
+
+    #[test]
+    fn averages_over_window() {
+        let mut survey = ChannelSurvey::<2>::new();
+        survey.add(&report(1, &[ap(6).with_channel(6).with_rssi(-45)]));
+        survey.add(&report(1, &[]));
+        assert_eq!(survey.score(6).unwrap().interference, 25);
+        assert_eq!(
+            format!("{}", survey.score(6).unwrap()),
+            "Channel 6: 0.5 APs, interference 25"
+        );
+
+        // The first scan falls out of the window
+        survey.add(&report(1, &[]));
+        assert_eq!(survey.len(), 2);
+        assert_eq!(survey.score(6).unwrap().interference, 0);
+    }
+}
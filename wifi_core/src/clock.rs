@@ -0,0 +1,216 @@
+//! Wall-clock time on top of the uptime clock.
+//!
+//! The firmware only has a monotonic uptime clock. A [`WallClock`] turns
+//! uptime into [`UtcTime`] with an offset from the last time
+//! synchronization, corrected for the drift of the local oscillator, which
+//! it estimates from successive synchronizations.
+
+use core::fmt;
+
+/// Microseconds in a second
+const MICROS_PER_SEC: i64 = 1_000_000;
+
+/// Shortest time between two synchronizations that is used to estimate the
+/// drift; over shorter intervals the measurement noise dominates
+pub const MIN_DRIFT_INTERVAL_US: u64 = 60 * MICROS_PER_SEC as u64;
+
+/// Largest drift accepted, in parts per billion; any crystal oscillator
+/// stays well below 500 ppm
+pub const MAX_DRIFT_PPB: i64 = 500_000;
+
+/// A point in time in UTC, with microsecond resolution
+///
+/// Displays as RFC 3339 with milliseconds, e.g. `2024-05-01T12:30:00.250Z`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UtcTime(i64);
+
+impl UtcTime {
+    /// 1970-01-01T00:00:00Z
+    pub const UNIX_EPOCH: Self = Self(0);
+
+    /// Time `micros` microseconds after the Unix epoch
+    pub const fn from_unix_micros(micros: i64) -> Self {
+        Self(micros)
+    }
+
+    /// Microseconds since the Unix epoch
+    pub const fn unix_micros(self) -> i64 {
+        self.0
+    }
+
+    /// Whole seconds since the Unix epoch
+    pub const fn unix_secs(self) -> i64 {
+        self.0.div_euclid(MICROS_PER_SEC)
+    }
+
+    /// Microseconds past the whole second
+    pub const fn subsec_micros(self) -> u32 {
+        self.0.rem_euclid(MICROS_PER_SEC) as u32
+    }
+}
+
+impl fmt::Display for UtcTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.unix_secs();
+        let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+        let secs_of_day = secs.rem_euclid(86_400);
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            self.subsec_micros() / 1000
+        )
+    }
+}
+
+/// Converts days since the Unix epoch to a proleptic Gregorian date.
+///
+/// Howard Hinnant's `civil_from_days`, which counts in 400-year eras that
+/// start on March 1st so leap days fall at the end of each year.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Maps uptime to UTC, from synchronizations with a time server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WallClock {
+    /// UTC minus uptime at the last synchronization, in microseconds
+    offset_us: i64,
+    /// Uptime of the last synchronization, in microseconds
+    synced_at_us: u64,
+    /// Rate at which the offset grows, in parts per billion
+    drift_ppb: i64,
+    /// Whether `drift_ppb` has been measured yet
+    drift_known: bool,
+}
+
+impl WallClock {
+    /// Starts a clock from a first synchronization.
+    ///
+    /// # Arguments
+    ///
+    /// * `uptime_us` - Uptime of the synchronization, in microseconds
+    /// * `offset_us` - UTC minus uptime, in microseconds
+    pub const fn new(uptime_us: u64, offset_us: i64) -> Self {
+        Self {
+            offset_us,
+            synced_at_us: uptime_us,
+            drift_ppb: 0,
+            drift_known: false,
+        }
+    }
+
+    /// Takes a new synchronization into account.
+    ///
+    /// The offset always follows the new measurement. The drift is
+    /// re-estimated from the change in offset since the last synchronization
+    /// if at least [`MIN_DRIFT_INTERVAL_US`] passed; the first estimate is
+    /// taken as is, later ones are averaged in, and all are limited to
+    /// [`MAX_DRIFT_PPB`].
+    pub fn update(&mut self, uptime_us: u64, offset_us: i64) {
+        let elapsed_us = uptime_us.saturating_sub(self.synced_at_us);
+        if elapsed_us >= MIN_DRIFT_INTERVAL_US {
+            let change_us = i128::from(offset_us) - i128::from(self.offset_us);
+            let drift_ppb = (change_us * 1_000_000_000 / i128::from(elapsed_us))
+                .clamp(-i128::from(MAX_DRIFT_PPB), i128::from(MAX_DRIFT_PPB))
+                as i64;
+            if self.drift_known {
+                self.drift_ppb += (drift_ppb - self.drift_ppb) / 4;
+            } else {
+                self.drift_ppb = drift_ppb;
+                self.drift_known = true;
+            }
+        }
+        self.offset_us = offset_us;
+        self.synced_at_us = uptime_us;
+    }
+
+    /// Returns the UTC time at `uptime_us`.
+    pub fn now(&self, uptime_us: u64) -> UtcTime {
+        let elapsed_us = i128::from(uptime_us) - i128::from(self.synced_at_us);
+        let correction_us = elapsed_us * i128::from(self.drift_ppb) / 1_000_000_000;
+        UtcTime(
+            (i128::from(uptime_us) + i128::from(self.offset_us) + correction_us)
+                .clamp(i128::from(i64::MIN), i128::from(i64::MAX)) as i64,
+        )
+    }
+
+    /// Estimated drift of the uptime clock, in parts per billion; positive
+    /// when it runs slow
+    pub const fn drift_ppb(&self) -> i64 {
+        self.drift_ppb
+    }
+
+    /// Uptime of the last synchronization, in microseconds
+    pub const fn synced_at_us(&self) -> u64 {
+        self.synced_at_us
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn displays_rfc3339() {
+        assert_eq!(UtcTime::UNIX_EPOCH.to_string(), "1970-01-01T00:00:00.000Z");
+        // Leap day, and the microseconds are truncated
+        let time = UtcTime::from_unix_micros(1_709_210_096_789_999);
+        assert_eq!(time.to_string(), "2024-02-29T12:34:56.789Z");
+        assert_eq!(time.unix_secs(), 1_709_210_096);
+        assert_eq!(time.subsec_micros(), 789_999);
+        let before_epoch = UtcTime::from_unix_micros(-1);
+        assert_eq!(before_epoch.to_string(), "1969-12-31T23:59:59.999Z");
+        assert_eq!(
+            UtcTime::from_unix_micros(4_102_444_800 * MICROS_PER_SEC).to_string(),
+            "2100-01-01T00:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn corrects_for_drift() {
+        const UTC: i64 = 1_700_000_000 * MICROS_PER_SEC;
+        let mut clock = WallClock::new(1_000_000, UTC);
+        assert_eq!(clock.now(3_000_000).unix_micros(), UTC + 3_000_000);
+
+        // Too soon after the first synchronization to estimate the drift
+        clock.update(2_000_000, UTC + 10);
+        assert_eq!(clock.drift_ppb(), 0);
+
+        // The offset grew by 100 us over 100 s: 1 ppm
+        clock.update(102_000_000, UTC + 110);
+        assert_eq!(clock.drift_ppb(), 1000);
+        assert_eq!(clock.synced_at_us(), 102_000_000);
+        assert_eq!(
+            clock.now(1_102_000_000).unix_micros(),
+            UTC + 1_102_000_000 + 1110
+        );
+
+        // Later estimates are averaged in
+        clock.update(202_000_000, UTC + 610);
+        assert_eq!(clock.drift_ppb(), 2000);
+
+        // A jump of the server time does not count as drift
+        clock.update(302_000_000, UTC + 3_600_000_000);
+        assert_eq!(clock.drift_ppb(), 2000 + (MAX_DRIFT_PPB - 2000) / 4);
+        assert_eq!(clock.now(302_000_000).unix_micros(), UTC + 3_902_000_000);
+    }
+}
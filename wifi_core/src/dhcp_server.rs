@@ -0,0 +1,519 @@
+//! Minimal DHCPv4 server for the provisioning access point.
+//!
+//! [`DhcpServer`] hands out addresses from a small pool to the clients of the
+//! device's own access point (RFC 2131). It answers DISCOVER with an OFFER and
+//! REQUEST with an ACK or NAK, and frees leases on RELEASE. The offer names
+//! the device as router and DNS server, so a captive DNS server on the device
+//! sees every lookup, and carries the portal URL (RFC 8910).
+//!
+//! Replies are meant to be broadcast to port 68: clients without an address
+//! cannot receive unicast before the driver knows their IP.
+
+use crate::report::Bssid;
+
+/// UDP port the server listens on
+pub const SERVER_PORT: u16 = 67;
+
+/// UDP port clients listen on
+pub const CLIENT_PORT: u16 = 68;
+
+/// Fixed part of a DHCP message before the options
+const HEADER_LEN: usize = 236;
+
+/// Marks the start of the options
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// Seconds an offered address is reserved for the client
+const OFFER_HOLD_SECS: u32 = 60;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_CAPTIVE_PORTAL: u8 = 114;
+const OPTION_END: u8 = 255;
+
+/// DHCP message types (option 53)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MessageType {
+    /// Client looks for servers
+    Discover = 1,
+    /// Server offers an address
+    Offer = 2,
+    /// Client asks for the offered or a previous address
+    Request = 3,
+    /// Client found the address in use
+    Decline = 4,
+    /// Server confirms the lease
+    Ack = 5,
+    /// Server refuses the request
+    Nak = 6,
+    /// Client gives the address back
+    Release = 7,
+    /// Client asks for configuration only
+    Inform = 8,
+}
+
+impl MessageType {
+    fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            1 => MessageType::Discover,
+            2 => MessageType::Offer,
+            3 => MessageType::Request,
+            4 => MessageType::Decline,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            7 => MessageType::Release,
+            8 => MessageType::Inform,
+            _ => return None,
+        })
+    }
+}
+
+/// Address plan of the server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DhcpConfig {
+    /// Address of the device, announced as router and DNS server
+    pub server: [u8; 4],
+    /// Subnet mask of the access point network
+    pub netmask: [u8; 4],
+    /// First address of the pool
+    pub pool_start: [u8; 4],
+    /// Number of addresses in the pool
+    pub pool_size: u8,
+    /// Lease duration in seconds
+    pub lease_secs: u32,
+}
+
+impl Default for DhcpConfig {
+    /// 192.168.4.1/24 with 16 addresses from 192.168.4.100, leased for an hour
+    fn default() -> Self {
+        Self {
+            server: [192, 168, 4, 1],
+            netmask: [255, 255, 255, 0],
+            pool_start: [192, 168, 4, 100],
+            pool_size: 16,
+            lease_secs: 3600,
+        }
+    }
+}
+
+/// An address given to a client
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Lease {
+    /// Hardware address of the client
+    pub mac: Bssid,
+    /// Leased address
+    pub ip: [u8; 4],
+    /// Time the lease runs out, in milliseconds on the caller's clock
+    pub expires_ms: u64,
+    /// `false` while the address is only offered
+    pub bound: bool,
+}
+
+/// Parsed client message
+struct ClientMessage<'a> {
+    packet: &'a [u8],
+    message_type: MessageType,
+    mac: Bssid,
+    ciaddr: [u8; 4],
+    requested_ip: Option<[u8; 4]>,
+    server_id: Option<[u8; 4]>,
+}
+
+/// DHCP server with room for `N` leases
+#[derive(Clone, Debug)]
+pub struct DhcpServer<const N: usize> {
+    config: DhcpConfig,
+    portal_url: Option<&'static str>,
+    leases: heapless::Vec<Lease, N>,
+}
+
+impl<const N: usize> DhcpServer<N> {
+    /// Creates a server without leases
+    pub const fn new(config: DhcpConfig) -> Self {
+        Self {
+            config,
+            portal_url: None,
+            leases: heapless::Vec::new(),
+        }
+    }
+
+    /// Announces `url` as captive portal to clients
+    #[must_use]
+    pub const fn with_portal_url(mut self, url: &'static str) -> Self {
+        self.portal_url = Some(url);
+        self
+    }
+
+    /// Current leases, including offers not yet accepted
+    pub fn leases(&self) -> &[Lease] {
+        &self.leases
+    }
+
+    /// Handles one client message received at `now_ms` and writes the reply to `out`.
+    ///
+    /// Returns the length of the reply, or `None` if the message is ignored
+    /// or `out` is too small.
+    pub fn handle(&mut self, packet: &[u8], now_ms: u64, out: &mut [u8]) -> Option<usize> {
+        let message = parse(packet)?;
+        match message.message_type {
+            MessageType::Discover => {
+                let ip = self.allocate(message.mac, message.requested_ip, now_ms)?;
+                self.hold(message.mac, ip, now_ms, false);
+                self.reply(&message, MessageType::Offer, ip, out)
+            }
+            MessageType::Request => {
+                if message
+                    .server_id
+                    .is_some_and(|server| server != self.config.server)
+                {
+                    // The client accepted another server's offer
+                    self.release(message.mac);
+                    return None;
+                }
+                let wanted = message.requested_ip.unwrap_or(message.ciaddr);
+                match self.allocate(message.mac, Some(wanted), now_ms) {
+                    Some(ip) if ip == wanted => {
+                        self.hold(message.mac, ip, now_ms, true);
+                        self.reply(&message, MessageType::Ack, ip, out)
+                    }
+                    _ => self.reply(&message, MessageType::Nak, [0; 4], out),
+                }
+            }
+            MessageType::Release | MessageType::Decline => {
+                self.release(message.mac);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Picks an address for `mac`: its current lease, else `wanted` if it is
+    /// free, else the first free address of the pool.
+    fn allocate(&self, mac: Bssid, wanted: Option<[u8; 4]>, now_ms: u64) -> Option<[u8; 4]> {
+        if let Some(lease) = self.leases.iter().find(|lease| lease.mac == mac) {
+            return Some(lease.ip);
+        }
+        let is_free = |ip: [u8; 4]| {
+            self.in_pool(ip)
+                && !self
+                    .leases
+                    .iter()
+                    .any(|lease| lease.ip == ip && lease.expires_ms > now_ms)
+        };
+        if let Some(ip) = wanted.filter(|&ip| is_free(ip)) {
+            return Some(ip);
+        }
+        let start = u32::from_be_bytes(self.config.pool_start);
+        (0..u32::from(self.config.pool_size))
+            .map(|i| start.wrapping_add(i).to_be_bytes())
+            .find(|&ip| is_free(ip))
+    }
+
+    fn in_pool(&self, ip: [u8; 4]) -> bool {
+        let offset =
+            u32::from_be_bytes(ip).wrapping_sub(u32::from_be_bytes(self.config.pool_start));
+        offset < u32::from(self.config.pool_size)
+    }
+
+    /// Records a lease, replacing expired ones when the table is full
+    fn hold(&mut self, mac: Bssid, ip: [u8; 4], now_ms: u64, bound: bool) {
+        let secs = if bound {
+            self.config.lease_secs
+        } else {
+            OFFER_HOLD_SECS
+        };
+        let lease = Lease {
+            mac,
+            ip,
+            expires_ms: now_ms + u64::from(secs) * 1000,
+            bound,
+        };
+        self.leases
+            .retain(|l| l.mac != mac && l.ip != ip && l.expires_ms > now_ms);
+        if self.leases.push(lease).is_err() {
+            // Table full of live leases: drop the one that runs out first
+            if let Some(oldest) = (0..self.leases.len()).min_by_key(|&i| self.leases[i].expires_ms)
+            {
+                self.leases[oldest] = lease;
+            }
+        }
+    }
+
+    fn release(&mut self, mac: Bssid) {
+        self.leases.retain(|lease| lease.mac != mac);
+    }
+
+    fn reply(
+        &self,
+        message: &ClientMessage<'_>,
+        message_type: MessageType,
+        ip: [u8; 4],
+        out: &mut [u8],
+    ) -> Option<usize> {
+        let mut writer = Writer { out, len: 0 };
+        let request = message.packet;
+        writer.put(&[BOOTREPLY, request[1], request[2], 0])?;
+        // xid, secs, flags
+        writer.put(&request[4..12])?;
+        // ciaddr, yiaddr, siaddr, giaddr
+        writer.put(&[0; 4])?;
+        writer.put(&ip)?;
+        writer.put(&self.config.server)?;
+        writer.put(&request[24..28])?;
+        // chaddr, sname, file
+        writer.put(&request[28..44])?;
+        writer.put(&[0; 192])?;
+        writer.put(&MAGIC_COOKIE)?;
+
+        writer.option(OPTION_MESSAGE_TYPE, &[message_type as u8])?;
+        writer.option(OPTION_SERVER_ID, &self.config.server)?;
+        if message_type != MessageType::Nak {
+            writer.option(OPTION_LEASE_TIME, &self.config.lease_secs.to_be_bytes())?;
+            writer.option(OPTION_SUBNET_MASK, &self.config.netmask)?;
+            writer.option(OPTION_ROUTER, &self.config.server)?;
+            writer.option(OPTION_DNS_SERVER, &self.config.server)?;
+            if let Some(url) = self.portal_url {
+                writer.option(OPTION_CAPTIVE_PORTAL, url.as_bytes())?;
+            }
+        }
+        writer.put(&[OPTION_END])?;
+        Some(writer.len)
+    }
+}
+
+/// Parses a client message, or `None` if it is not a valid Ethernet BOOTREQUEST
+fn parse(packet: &[u8]) -> Option<ClientMessage<'_>> {
+    if packet.len() < HEADER_LEN + MAGIC_COOKIE.len()
+        || packet[0] != BOOTREQUEST
+        || packet[1] != 1
+        || packet[2] != 6
+        || packet[HEADER_LEN..HEADER_LEN + 4] != MAGIC_COOKIE
+    {
+        return None;
+    }
+    let mut message_type = None;
+    let mut requested_ip = None;
+    let mut server_id = None;
+    let mut options = &packet[HEADER_LEN + 4..];
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+        let (&len, rest) = rest.split_first()?;
+        let value = rest.get(..usize::from(len))?;
+        match (code, value) {
+            (OPTION_MESSAGE_TYPE, &[kind]) => message_type = MessageType::from_code(kind),
+            (OPTION_REQUESTED_IP, &[a, b, c, d]) => requested_ip = Some([a, b, c, d]),
+            (OPTION_SERVER_ID, &[a, b, c, d]) => server_id = Some([a, b, c, d]),
+            _ => {}
+        }
+        options = &rest[usize::from(len)..];
+    }
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&packet[28..34]);
+    Some(ClientMessage {
+        packet,
+        message_type: message_type?,
+        mac: Bssid(mac),
+        ciaddr: [packet[12], packet[13], packet[14], packet[15]],
+        requested_ip,
+        server_id,
+    })
+}
+
+/// Bounds-checked output cursor
+struct Writer<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) -> Option<()> {
+        self.out
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    fn option(&mut self, code: u8, value: &[u8]) -> Option<()> {
+        self.put(&[code, u8::try_from(value.len()).ok()?])?;
+        self.put(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x42];
+
+    fn request(mac: [u8; 6], kind: MessageType, options: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut packet = std::vec![0; HEADER_LEN];
+        packet[..4].copy_from_slice(&[BOOTREQUEST, 1, 6, 0]);
+        packet[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        packet[10] = 0x80;
+        packet[28..34].copy_from_slice(&mac);
+        packet.extend_from_slice(&MAGIC_COOKIE);
+        packet.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, kind as u8]);
+        for (code, value) in options {
+            packet.extend_from_slice(&[*code, value.len() as u8]);
+            packet.extend_from_slice(value);
+        }
+        packet.push(OPTION_END);
+        packet
+    }
+
+    /// Message type, yiaddr and options of a reply
+    type Reply = (u8, [u8; 4], Vec<(u8, Vec<u8>)>);
+
+    fn reply_of(reply: &[u8]) -> Reply {
+        assert_eq!(reply[0], BOOTREPLY);
+        assert_eq!(reply[4..8], [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(reply[HEADER_LEN..HEADER_LEN + 4], MAGIC_COOKIE);
+        let mut options = Vec::new();
+        let mut rest = &reply[HEADER_LEN + 4..];
+        while rest[0] != OPTION_END {
+            let len = usize::from(rest[1]);
+            options.push((rest[0], rest[2..2 + len].to_vec()));
+            rest = &rest[2 + len..];
+        }
+        let kind = options[0].1[0];
+        (kind, [reply[16], reply[17], reply[18], reply[19]], options)
+    }
+
+    fn exchange<const N: usize>(
+        server: &mut DhcpServer<N>,
+        packet: &[u8],
+        now_ms: u64,
+    ) -> Option<Reply> {
+        let mut out = [0; 576];
+        let len = server.handle(packet, now_ms, &mut out)?;
+        Some(reply_of(&out[..len]))
+    }
+
+    #[test]
+    fn offers_and_acknowledges_an_address() {
+        let mut server =
+            DhcpServer::<4>::new(DhcpConfig::default()).with_portal_url("http://192.168.4.1/");
+
+        let (kind, ip, options) =
+            exchange(&mut server, &request(MAC, MessageType::Discover, &[]), 0).unwrap();
+        assert_eq!(kind, MessageType::Offer as u8);
+        assert_eq!(ip, [192, 168, 4, 100]);
+        assert!(options.contains(&(OPTION_DNS_SERVER, [192, 168, 4, 1].to_vec())));
+        assert!(options.contains(&(OPTION_CAPTIVE_PORTAL, b"http://192.168.4.1/".to_vec())));
+
+        let (kind, ip, _) = exchange(
+            &mut server,
+            &request(
+                MAC,
+                MessageType::Request,
+                &[
+                    (OPTION_REQUESTED_IP, &ip),
+                    (OPTION_SERVER_ID, &[192, 168, 4, 1]),
+                ],
+            ),
+            100,
+        )
+        .unwrap();
+        assert_eq!(kind, MessageType::Ack as u8);
+        assert_eq!(ip, [192, 168, 4, 100]);
+        assert!(server.leases()[0].bound);
+
+        // A second client gets the next address
+        let (_, ip, _) = exchange(
+            &mut server,
+            &request([2, 0, 0, 0, 0, 7], MessageType::Discover, &[]),
+            200,
+        )
+        .unwrap();
+        assert_eq!(ip, [192, 168, 4, 101]);
+    }
+
+    #[test]
+    fn refuses_addresses_it_cannot_give() {
+        let mut server = DhcpServer::<4>::new(DhcpConfig::default());
+        let (kind, ..) = exchange(
+            &mut server,
+            &request(
+                MAC,
+                MessageType::Request,
+                &[(OPTION_REQUESTED_IP, &[10, 0, 0, 5])],
+            ),
+            0,
+        )
+        .unwrap();
+        assert_eq!(kind, MessageType::Nak as u8);
+
+        // Requests meant for another server are dropped
+        assert!(
+            exchange(
+                &mut server,
+                &request(
+                    MAC,
+                    MessageType::Request,
+                    &[(OPTION_SERVER_ID, &[10, 0, 0, 1])]
+                ),
+                0,
+            )
+            .is_none()
+        );
+        assert!(exchange(&mut server, &[BOOTREQUEST; 100], 0).is_none());
+    }
+
+    #[test]
+    fn reuses_expired_and_released_leases() {
+        let config = DhcpConfig {
+            pool_size: 1,
+            ..DhcpConfig::default()
+        };
+        let mut server = DhcpServer::<2>::new(config);
+        let other = [2, 0, 0, 0, 0, 7];
+
+        exchange(&mut server, &request(MAC, MessageType::Discover, &[]), 0).unwrap();
+        assert!(
+            exchange(
+                &mut server,
+                &request(other, MessageType::Discover, &[]),
+                1000
+            )
+            .is_none()
+        );
+
+        // The offer runs out
+        let later = u64::from(OFFER_HOLD_SECS) * 1000 + 1;
+        let (_, ip, _) = exchange(
+            &mut server,
+            &request(other, MessageType::Discover, &[]),
+            later,
+        )
+        .unwrap();
+        assert_eq!(ip, [192, 168, 4, 100]);
+
+        exchange(
+            &mut server,
+            &request(other, MessageType::Release, &[]),
+            later,
+        );
+        assert!(server.leases().is_empty());
+    }
+}
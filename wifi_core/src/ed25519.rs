@@ -0,0 +1,252 @@
+//! Ed25519 signatures (RFC 8032).
+//!
+//! A [`VerifyingKey`] checks signatures, e.g. on firmware images against a
+//! key built into the firmware. The arithmetic is `ed25519-dalek`'s; signing
+//! is left to it as well, e.g. in a host tool, with its `SigningKey`.
+//!
+//! Signatures are checked strictly: the `S` half must be reduced, so a
+//! signature cannot be altered into another valid one, and keys and `R`
+//! points of small order are rejected.
+
+use core::fmt;
+
+/// Length of an encoded public key
+pub const PUBLIC_KEY_LEN: usize = ed25519_dalek::PUBLIC_KEY_LENGTH;
+
+/// Length of a secret key seed
+pub const SEED_LEN: usize = ed25519_dalek::SECRET_KEY_LENGTH;
+
+/// Length of a signature: the point `R` and the scalar `S`
+pub const SIGNATURE_LEN: usize = ed25519_dalek::SIGNATURE_LENGTH;
+
+/// Reasons why a key or signature was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SignatureError {
+    /// The public key is not the encoding of a curve point
+    InvalidKey,
+    /// The signature does not match the message and key
+    InvalidSignature,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::InvalidKey => f.write_str("invalid public key"),
+            SignatureError::InvalidSignature => f.write_str("invalid signature"),
+        }
+    }
+}
+
+/// Reads a key of [`PUBLIC_KEY_LEN`] bytes from 64 hex digits, e.g. one
+/// given at build time.
+///
+/// Returns `None` if `hex` is not 64 hex digits.
+pub const fn parse_hex_key(hex: &str) -> Option<[u8; PUBLIC_KEY_LEN]> {
+    let hex = hex.as_bytes();
+    if hex.len() != 2 * PUBLIC_KEY_LEN {
+        return None;
+    }
+    let mut key = [0; PUBLIC_KEY_LEN];
+    let mut i = 0;
+    while i < PUBLIC_KEY_LEN {
+        let (Some(high), Some(low)) = (hex_digit(hex[2 * i]), hex_digit(hex[2 * i + 1])) else {
+            return None;
+        };
+        key[i] = high << 4 | low;
+        i += 1;
+    }
+    Some(key)
+}
+
+/// Value of a hex digit
+const fn hex_digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// A public key, which checks signatures
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct VerifyingKey(ed25519_dalek::VerifyingKey);
+
+impl VerifyingKey {
+    /// Reads an encoded public key.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SignatureError::InvalidKey`] if `bytes` do not encode a
+    /// point of the curve.
+    pub fn from_bytes(bytes: &[u8; PUBLIC_KEY_LEN]) -> Result<Self, SignatureError> {
+        ed25519_dalek::VerifyingKey::from_bytes(bytes)
+            .map(Self)
+            .map_err(|_| SignatureError::InvalidKey)
+    }
+
+    /// The encoded key
+    pub fn as_bytes(&self) -> &[u8; PUBLIC_KEY_LEN] {
+        self.0.as_bytes()
+    }
+
+    /// Checks that `signature` was made for `message` with the secret key
+    /// belonging to this key.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SignatureError::InvalidSignature`] if it was not.
+    pub fn verify(
+        &self,
+        message: &[u8],
+        signature: &[u8; SIGNATURE_LEN],
+    ) -> Result<(), SignatureError> {
+        self.0
+            .verify_strict(message, &ed25519_dalek::Signature::from_bytes(signature))
+            .map_err(|_| SignatureError::InvalidSignature)
+    }
+}
+
+impl From<ed25519_dalek::VerifyingKey> for VerifyingKey {
+    fn from(key: ed25519_dalek::VerifyingKey) -> Self {
+        Self(key)
+    }
+}
+
+impl fmt::Debug for VerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VerifyingKey({self})")
+    }
+}
+
+/// Formats as 64 lowercase hex digits, as [`parse_hex_key`] reads them
+impl fmt::Display for VerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_bytes()
+            .iter()
+            .try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for VerifyingKey {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "VerifyingKey({=[u8]:02x})", &self.as_bytes()[..])
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::vec::Vec;
+
+    /// Seed of the key the tests sign with
+    pub(crate) const TEST_SEED: [u8; SEED_LEN] = [7; SEED_LEN];
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn rfc8032_test_vectors() {
+        let vectors = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+                 5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+                 085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+            (
+                "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+                "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+                "af82",
+                "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac\
+                 18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+            ),
+        ];
+        for (seed, public, message, signature) in vectors {
+            let key = SigningKey::from_bytes(&hex(seed).try_into().unwrap());
+            assert_eq!(key.verifying_key().as_bytes().to_vec(), hex(public));
+            let message = hex(message);
+            let made = key.sign(&message).to_bytes();
+            assert_eq!(made.to_vec(), hex(signature));
+
+            let public = VerifyingKey::from_bytes(&hex(public).try_into().unwrap()).unwrap();
+            assert_eq!(public, key.verifying_key().into());
+            assert_eq!(public.verify(&message, &made), Ok(()));
+        }
+    }
+
+    #[test]
+    fn rejects_forgeries() {
+        let key = SigningKey::from_bytes(&TEST_SEED);
+        let public = VerifyingKey::from(key.verifying_key());
+        let signature = key.sign(b"firmware digest").to_bytes();
+        assert_eq!(public.verify(b"firmware digest", &signature), Ok(()));
+
+        assert_eq!(
+            public.verify(b"firmware digesT", &signature),
+            Err(SignatureError::InvalidSignature)
+        );
+        for i in [0, 31, 32, 63] {
+            let mut bad = signature;
+            bad[i] ^= 0x01;
+            assert_eq!(
+                public.verify(b"firmware digest", &bad),
+                Err(SignatureError::InvalidSignature)
+            );
+        }
+        // S + L is the same scalar, but not a reduced one
+        let mut malleated = signature;
+        let mut carry = 0u16;
+        let l = [
+            0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9,
+            0xde, 0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+        ];
+        for (byte, l) in malleated[32..].iter_mut().zip(l) {
+            let sum = u16::from(*byte) + l + carry;
+            *byte = sum as u8;
+            carry = sum >> 8;
+        }
+        assert_eq!(
+            public.verify(b"firmware digest", &malleated),
+            Err(SignatureError::InvalidSignature)
+        );
+
+        let other = VerifyingKey::from(SigningKey::from_bytes(&[8; SEED_LEN]).verifying_key());
+        assert_eq!(
+            other.verify(b"firmware digest", &signature),
+            Err(SignatureError::InvalidSignature)
+        );
+        // y = 2 is not on the curve
+        let mut not_a_point = [0; 32];
+        not_a_point[0] = 2;
+        assert_eq!(
+            VerifyingKey::from_bytes(&not_a_point),
+            Err(SignatureError::InvalidKey)
+        );
+    }
+
+    #[test]
+    fn parses_hex_keys() {
+        let key = VerifyingKey::from(SigningKey::from_bytes(&TEST_SEED).verifying_key());
+        let text = std::format!("{key}");
+        assert_eq!(parse_hex_key(&text), Some(*key.as_bytes()));
+        assert_eq!(parse_hex_key(&text.to_uppercase()), Some(*key.as_bytes()));
+        assert_eq!(parse_hex_key(&text[1..]), None);
+        assert_eq!(parse_hex_key(&std::format!("g{}", &text[1..])), None);
+    }
+}
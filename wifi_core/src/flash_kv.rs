@@ -0,0 +1,1218 @@
+//! Wear-levelled key-value store on NOR flash.
+//!
+//! [`KvStore`] keeps up to [`MAX_KEYS`] small values, each identified by a
+//! one-byte key and tagged with a schema version, in a dedicated flash region.
+//! It is generic over [`NorFlash`], so the firmware runs it on a partition of
+//! the SPI flash (see [`Partition`]) and the tests run it on a RAM mock.
+//!
+//! # Layout
+//!
+//! The region is split into erase sectors used as a ring. Each sector in use
+//! starts with a header holding a magic number, the format version
+//! [`FORMAT_VERSION`] and a sequence number that grows by one every time a
+//! new sector is opened. Records are appended behind it:
+//!
+//! | Bytes | Content                                    |
+//! |-------|--------------------------------------------|
+//! | 2     | Value length, little endian                |
+//! | 1     | Key                                        |
+//! | 1     | Schema version, 0 marks a deleted key      |
+//! | 4     | CRC-32 of the four bytes above and the value |
+//! | n     | Value, padded with `0xFF` to a multiple of 4 |
+//!
+//! Writing a value appends a new record; the newest record of a key wins. The
+//! sector with the highest sequence number is the head and takes all writes,
+//! and the sector after it is always kept erased. When the head is full, the
+//! spare sector becomes the new head, the live records of the oldest sector
+//! are copied into it and the oldest sector is erased to become the next
+//! spare. Every sector is therefore erased in turn, whichever key is written.
+//!
+//! # Power loss
+//!
+//! A record only counts once its CRC matches, so a torn write leaves the
+//! previous value in place. [`KvStore::mount`] finishes or rolls back a
+//! sector change that was cut short and erases half-written sectors. After a
+//! flash error the in-memory state may be stale; mount the store again.
+
+use core::fmt;
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+/// Maximum number of keys in a store
+pub const MAX_KEYS: usize = 32;
+
+/// Maximum length of a value in bytes
+pub const MAX_VALUE_LEN: usize = 1024;
+
+/// On-flash format written by this version of the store
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Magic number at the start of every sector in use
+const MAGIC: [u8; 3] = *b"WKV";
+
+/// Length of the sector header: magic, format version, sequence number, CRC
+const SECTOR_HEADER_LEN: u32 = 12;
+
+/// Length of a record header: length, key, version, CRC
+const RECORD_HEADER_LEN: u32 = 8;
+
+/// Alignment of headers and values
+const WORD: u32 = 4;
+
+/// Size of the buffer used to stream values through
+const CHUNK_LEN: usize = 32;
+
+/// Version of the record that marks a key as deleted
+const TOMBSTONE: u8 = 0;
+
+/// Flash error, as reported by the driver
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FlashError {
+    /// Access not aligned to the flash's read, write or erase size
+    NotAligned,
+    /// Access outside the flash region
+    OutOfBounds,
+    /// Driver specific error
+    Other,
+}
+
+impl From<NorFlashErrorKind> for FlashError {
+    fn from(kind: NorFlashErrorKind) -> Self {
+        match kind {
+            NorFlashErrorKind::NotAligned => FlashError::NotAligned,
+            NorFlashErrorKind::OutOfBounds => FlashError::OutOfBounds,
+            _ => FlashError::Other,
+        }
+    }
+}
+
+/// Key-value store errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StoreError {
+    /// The flash driver failed
+    Flash(FlashError),
+    /// The flash region is too small or has an unsupported geometry
+    UnsupportedFlash,
+    /// The region holds a store written in another format version
+    UnsupportedFormat(u8),
+    /// The value is longer than [`MAX_VALUE_LEN`]
+    ValueTooLarge,
+    /// The store already holds [`MAX_KEYS`] keys
+    TooManyKeys,
+    /// The live values do not fit in the region
+    Full,
+    /// The buffer is too small for the stored value
+    BufferTooSmall,
+    /// Schema version 0 is reserved for deleted keys
+    ReservedVersion,
+    /// The stored value no longer matches its CRC
+    Corrupted,
+    /// The stored value could not be decoded
+    Decode(crate::settings::DecodeError),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Flash(error) => write!(f, "flash error: {error:?}"),
+            StoreError::UnsupportedFlash => f.write_str("unsupported flash geometry"),
+            StoreError::UnsupportedFormat(version) => {
+                write!(f, "unsupported store format version {version}")
+            }
+            StoreError::ValueTooLarge => write!(f, "value longer than {MAX_VALUE_LEN} bytes"),
+            StoreError::TooManyKeys => write!(f, "more than {MAX_KEYS} keys"),
+            StoreError::Full => f.write_str("store is full"),
+            StoreError::BufferTooSmall => f.write_str("buffer too small for value"),
+            StoreError::ReservedVersion => f.write_str("schema version 0 is reserved"),
+            StoreError::Corrupted => f.write_str("stored value is corrupted"),
+            StoreError::Decode(error) => write!(f, "cannot decode value: {error}"),
+        }
+    }
+}
+
+impl<E: NorFlashError> From<E> for StoreError {
+    fn from(error: E) -> Self {
+        StoreError::Flash(error.kind().into())
+    }
+}
+
+/// Location of the newest record of a key
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    key: u8,
+    version: u8,
+    len: u16,
+    /// Address of the record header
+    addr: u32,
+}
+
+/// Parsed record header
+#[derive(Clone, Copy, Debug)]
+struct RecordHeader {
+    len: u16,
+    key: u8,
+    version: u8,
+    crc: u32,
+}
+
+impl RecordHeader {
+    fn new(key: u8, version: u8, value: &[u8]) -> Self {
+        let mut header = Self {
+            len: value.len() as u16,
+            key,
+            version,
+            crc: 0,
+        };
+        let mut crc = Crc32::new();
+        crc.update(&header.prefix());
+        crc.update(value);
+        header.crc = crc.finish();
+        header
+    }
+
+    fn prefix(&self) -> [u8; 4] {
+        let [lo, hi] = self.len.to_le_bytes();
+        [lo, hi, self.key, self.version]
+    }
+
+    fn to_bytes(self) -> [u8; RECORD_HEADER_LEN as usize] {
+        let mut bytes = [0; RECORD_HEADER_LEN as usize];
+        bytes[..4].copy_from_slice(&self.prefix());
+        bytes[4..].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: [u8; RECORD_HEADER_LEN as usize]) -> Self {
+        Self {
+            len: u16::from_le_bytes([bytes[0], bytes[1]]),
+            key: bytes[2],
+            version: bytes[3],
+            crc: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        }
+    }
+
+    /// Length of the whole record on flash
+    fn record_len(&self) -> u32 {
+        RECORD_HEADER_LEN + padded(u32::from(self.len))
+    }
+}
+
+/// State of a sector found while mounting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SectorState {
+    /// Every byte is erased
+    Erased,
+    /// In use, with this sequence number
+    Valid(u32),
+    /// Neither erased nor in use, e.g. a sector whose header or erase was cut short
+    Garbage,
+}
+
+/// Wear-levelled key-value store on a NOR flash region
+pub struct KvStore<F> {
+    flash: F,
+    sector_size: u32,
+    sectors: u32,
+    /// Sector taking the writes
+    head: u32,
+    /// Sequence number of the head sector
+    seq: u32,
+    /// Offset of the next record in the head sector
+    offset: u32,
+    index: heapless::Vec<Entry, MAX_KEYS>,
+}
+
+impl<F: NorFlash> KvStore<F> {
+    /// Wraps the flash region holding the store.
+    ///
+    /// Nothing is read until [`Self::mount`] or [`Self::format`] is called.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::UnsupportedFlash`] if the region has fewer than
+    /// two sectors, sectors too small for a [`MAX_VALUE_LEN`] value, or read
+    /// and write sizes that do not divide 4 bytes.
+    pub fn new(flash: F) -> Result<Self, StoreError> {
+        let sector_size = F::ERASE_SIZE as u32;
+        let sectors = (flash.capacity() / F::ERASE_SIZE) as u32;
+        let geometry_ok = WORD.is_multiple_of(F::READ_SIZE as u32)
+            && WORD.is_multiple_of(F::WRITE_SIZE as u32)
+            && sector_size.is_multiple_of(WORD)
+            && sector_size >= SECTOR_HEADER_LEN + RECORD_HEADER_LEN + MAX_VALUE_LEN as u32
+            && sectors >= 2;
+        if !geometry_ok {
+            return Err(StoreError::UnsupportedFlash);
+        }
+        Ok(Self {
+            flash,
+            sector_size,
+            sectors,
+            head: 0,
+            seq: 0,
+            offset: SECTOR_HEADER_LEN,
+            index: heapless::Vec::new(),
+        })
+    }
+
+    /// Loads the store from flash, formatting the region if it holds no store.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::UnsupportedFormat`] if the region holds a store in
+    /// another format version, which is left untouched; call [`Self::format`]
+    /// to discard it.
+    pub fn mount(&mut self) -> Result<(), StoreError> {
+        loop {
+            let mut head = None;
+            for sector in 0..self.sectors {
+                match self.sector_state(sector)? {
+                    SectorState::Valid(seq) => {
+                        if head.is_none_or(|(_, head_seq)| seq > head_seq) {
+                            head = Some((sector, seq));
+                        }
+                    }
+                    SectorState::Garbage => self.erase(sector)?,
+                    SectorState::Erased => {}
+                }
+            }
+            let Some((head, seq)) = head else {
+                return self.format();
+            };
+            self.head = head;
+            self.seq = seq;
+            let closed = !self.rebuild()?;
+
+            // The sector after the head is not erased: a sector change was cut
+            // short before the oldest sector was reclaimed.
+            let next = self.next(head);
+            if let SectorState::Valid(_) = self.sector_state(next)? {
+                if closed {
+                    // The copy itself was torn, so the head only holds copies
+                    // of records that are still in the oldest sector.
+                    self.erase(head)?;
+                    continue;
+                }
+                self.reclaim(next)?;
+            }
+            return Ok(());
+        }
+    }
+
+    /// Erases the whole region and starts an empty store.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Flash`] if the flash cannot be erased or written.
+    pub fn format(&mut self) -> Result<(), StoreError> {
+        self.index.clear();
+        for sector in 0..self.sectors {
+            self.erase(sector)?;
+        }
+        self.open(0, 0)
+    }
+
+    /// Gives back the flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Number of stored keys
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns `true` if no key is stored
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Iterates over the stored keys
+    pub fn keys(&self) -> impl Iterator<Item = u8> + '_ {
+        self.index.iter().map(|entry| entry.key)
+    }
+
+    /// Schema version of the value stored under `key`
+    pub fn version(&self, key: u8) -> Option<u8> {
+        self.entry(key).map(|entry| entry.version)
+    }
+
+    /// Reads the value stored under `key` into `buf`.
+    ///
+    /// Returns the schema version and length of the value, or `None` if the
+    /// key is not stored.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::BufferTooSmall`] if the value does not fit in
+    /// `buf`, or [`StoreError::Corrupted`] if it no longer matches its CRC.
+    pub fn read(&mut self, key: u8, buf: &mut [u8]) -> Result<Option<(u8, usize)>, StoreError> {
+        let Some(entry) = self.entry(key).copied() else {
+            return Ok(None);
+        };
+        let len = usize::from(entry.len);
+        let buf = buf.get_mut(..len).ok_or(StoreError::BufferTooSmall)?;
+        let header = self.read_header(entry.addr)?;
+        self.read_exact(entry.addr + RECORD_HEADER_LEN, buf)?;
+
+        let mut crc = Crc32::new();
+        crc.update(&header.prefix());
+        crc.update(buf);
+        if crc.finish() != header.crc {
+            return Err(StoreError::Corrupted);
+        }
+        Ok(Some((entry.version, len)))
+    }
+
+    /// Stores `value` under `key` with schema `version`.
+    ///
+    /// Writing the value that is already stored leaves the flash untouched.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::ReservedVersion`] for version 0,
+    /// [`StoreError::ValueTooLarge`], [`StoreError::TooManyKeys`] if `key` is
+    /// new and the store is full of keys, or [`StoreError::Full`] if the live
+    /// values do not fit in the region any more.
+    pub fn write(&mut self, key: u8, version: u8, value: &[u8]) -> Result<(), StoreError> {
+        if version == TOMBSTONE {
+            return Err(StoreError::ReservedVersion);
+        }
+        if value.len() > MAX_VALUE_LEN {
+            return Err(StoreError::ValueTooLarge);
+        }
+        match self.entry(key).copied() {
+            Some(entry) if self.holds(&entry, version, value)? => return Ok(()),
+            None if self.index.is_full() => return Err(StoreError::TooManyKeys),
+            _ => {}
+        }
+        self.append(RecordHeader::new(key, version, value), value)
+    }
+
+    /// Deletes `key`.
+    ///
+    /// Returns `false` if the key was not stored. Deleting always succeeds on
+    /// a full store: the value is left out when sectors are reclaimed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Flash`].
+    pub fn remove(&mut self, key: u8) -> Result<bool, StoreError> {
+        let Some(position) = self.index.iter().position(|entry| entry.key == key) else {
+            return Ok(false);
+        };
+        self.index.swap_remove(position);
+        match self.append(RecordHeader::new(key, TOMBSTONE, &[]), &[]) {
+            // Every sector was reclaimed without the value, so no record of
+            // the key is left to mark as deleted
+            Err(StoreError::Full) => Ok(true),
+            result => result.map(|()| true),
+        }
+    }
+
+    fn entry(&self, key: u8) -> Option<&Entry> {
+        self.index.iter().find(|entry| entry.key == key)
+    }
+
+    /// Returns `true` if `entry` already holds `value` in `version`
+    fn holds(&mut self, entry: &Entry, version: u8, value: &[u8]) -> Result<bool, StoreError> {
+        if entry.version != version || usize::from(entry.len) != value.len() {
+            return Ok(false);
+        }
+        let mut addr = entry.addr + RECORD_HEADER_LEN;
+        for expected in value.chunks(CHUNK_LEN) {
+            let mut chunk = [0; CHUNK_LEN];
+            let chunk = &mut chunk[..expected.len()];
+            self.read_exact(addr, chunk)?;
+            if chunk != expected {
+                return Ok(false);
+            }
+            addr += CHUNK_LEN as u32;
+        }
+        Ok(true)
+    }
+
+    /// Appends a record to the head, moving to the next sector if needed
+    fn append(&mut self, header: RecordHeader, value: &[u8]) -> Result<(), StoreError> {
+        let record_len = header.record_len();
+        let mut rotations = 0;
+        while self.offset + record_len > self.sector_size {
+            if rotations == self.sectors {
+                return Err(StoreError::Full);
+            }
+            self.rotate()?;
+            rotations += 1;
+        }
+
+        let addr = self.sector_start(self.head) + self.offset;
+        self.flash.write(addr, &header.to_bytes())?;
+        let whole = value.len() - value.len() % WORD as usize;
+        if whole > 0 {
+            self.flash
+                .write(addr + RECORD_HEADER_LEN, &value[..whole])?;
+        }
+        if whole < value.len() {
+            let mut last = [0xFF; WORD as usize];
+            last[..value.len() - whole].copy_from_slice(&value[whole..]);
+            self.flash
+                .write(addr + RECORD_HEADER_LEN + whole as u32, &last)?;
+        }
+        self.offset += record_len;
+        self.apply(header, addr)
+    }
+
+    /// Opens the spare sector as the new head and reclaims the oldest sector
+    fn rotate(&mut self) -> Result<(), StoreError> {
+        let spare = self.next(self.head);
+        self.open(spare, self.seq.wrapping_add(1))?;
+        self.reclaim(self.next(spare))
+    }
+
+    /// Writes the header of an erased sector and makes it the head
+    fn open(&mut self, sector: u32, seq: u32) -> Result<(), StoreError> {
+        let mut header = [0; SECTOR_HEADER_LEN as usize];
+        header[..3].copy_from_slice(&MAGIC);
+        header[3] = FORMAT_VERSION;
+        header[4..8].copy_from_slice(&seq.to_le_bytes());
+        let crc = crc32(&header[..8]);
+        header[8..].copy_from_slice(&crc.to_le_bytes());
+        self.flash.write(self.sector_start(sector), &header)?;
+
+        self.head = sector;
+        self.seq = seq;
+        self.offset = SECTOR_HEADER_LEN;
+        Ok(())
+    }
+
+    /// Copies the live records of `sector` to the head, then erases it
+    fn reclaim(&mut self, sector: u32) -> Result<(), StoreError> {
+        let start = self.sector_start(sector);
+        let end = start + self.sector_size;
+        for i in 0..self.index.len() {
+            let entry = self.index[i];
+            if !(start..end).contains(&entry.addr) {
+                continue;
+            }
+            let record_len = RECORD_HEADER_LEN + padded(u32::from(entry.len));
+            if self.offset + record_len > self.sector_size {
+                return Err(StoreError::Full);
+            }
+            let to = self.sector_start(self.head) + self.offset;
+            let mut copied = 0;
+            while copied < record_len {
+                let mut chunk = [0; CHUNK_LEN];
+                let chunk = &mut chunk[..(record_len - copied).min(CHUNK_LEN as u32) as usize];
+                self.flash.read(entry.addr + copied, chunk)?;
+                self.flash.write(to + copied, chunk)?;
+                copied += chunk.len() as u32;
+            }
+            self.offset += record_len;
+            self.index[i].addr = to;
+        }
+        self.erase(sector)
+    }
+
+    /// Rebuilds the index from all sectors, oldest first.
+    ///
+    /// Returns `false` if the head ends in a damaged record and takes no
+    /// more writes.
+    fn rebuild(&mut self) -> Result<bool, StoreError> {
+        self.index.clear();
+        let mut clean = true;
+        for i in 1..=self.sectors {
+            let sector = (self.head + i) % self.sectors;
+            if !matches!(self.sector_state(sector)?, SectorState::Valid(_)) {
+                continue;
+            }
+            let (end, sector_clean) = self.scan(sector)?;
+            if sector == self.head {
+                clean = sector_clean;
+                self.offset = if clean { end } else { self.sector_size };
+            }
+        }
+        Ok(clean)
+    }
+
+    /// Indexes the records of a sector.
+    ///
+    /// Returns the offset after the last record, and whether the rest of the
+    /// sector is erased.
+    fn scan(&mut self, sector: u32) -> Result<(u32, bool), StoreError> {
+        let start = self.sector_start(sector);
+        let mut offset = SECTOR_HEADER_LEN;
+        while offset + RECORD_HEADER_LEN <= self.sector_size {
+            let addr = start + offset;
+            let mut bytes = [0; RECORD_HEADER_LEN as usize];
+            self.flash.read(addr, &mut bytes)?;
+            if bytes[..WORD as usize].iter().all(|&b| b == 0xFF) {
+                let erased = self.is_erased(addr, self.sector_size - offset)?;
+                return Ok((offset, erased));
+            }
+            let header = RecordHeader::from_bytes(bytes);
+            let valid = usize::from(header.len) <= MAX_VALUE_LEN
+                && offset + header.record_len() <= self.sector_size
+                && (header.version != TOMBSTONE || header.len == 0)
+                && self.crc_matches(addr, &header)?;
+            if !valid {
+                return Ok((offset, false));
+            }
+            self.apply(header, addr)?;
+            offset += header.record_len();
+        }
+        Ok((offset, true))
+    }
+
+    /// Points the index at a new record
+    fn apply(&mut self, header: RecordHeader, addr: u32) -> Result<(), StoreError> {
+        let position = self.index.iter().position(|e| e.key == header.key);
+        match (position, header.version) {
+            (Some(i), TOMBSTONE) => {
+                self.index.swap_remove(i);
+            }
+            (None, TOMBSTONE) => {}
+            (Some(i), version) => {
+                self.index[i] = Entry {
+                    key: header.key,
+                    version,
+                    len: header.len,
+                    addr,
+                };
+            }
+            (None, version) => self
+                .index
+                .push(Entry {
+                    key: header.key,
+                    version,
+                    len: header.len,
+                    addr,
+                })
+                .map_err(|_| StoreError::TooManyKeys)?,
+        }
+        Ok(())
+    }
+
+    fn sector_state(&mut self, sector: u32) -> Result<SectorState, StoreError> {
+        let start = self.sector_start(sector);
+        let mut header = [0; SECTOR_HEADER_LEN as usize];
+        self.flash.read(start, &mut header)?;
+        if header.iter().all(|&b| b == 0xFF) {
+            return Ok(if self.is_erased(start, self.sector_size)? {
+                SectorState::Erased
+            } else {
+                SectorState::Garbage
+            });
+        }
+        let crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if header[..3] != MAGIC || crc != crc32(&header[..8]) {
+            return Ok(SectorState::Garbage);
+        }
+        if header[3] != FORMAT_VERSION {
+            return Err(StoreError::UnsupportedFormat(header[3]));
+        }
+        let seq = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        Ok(SectorState::Valid(seq))
+    }
+
+    fn read_header(&mut self, addr: u32) -> Result<RecordHeader, StoreError> {
+        let mut bytes = [0; RECORD_HEADER_LEN as usize];
+        self.flash.read(addr, &mut bytes)?;
+        Ok(RecordHeader::from_bytes(bytes))
+    }
+
+    fn crc_matches(&mut self, addr: u32, header: &RecordHeader) -> Result<bool, StoreError> {
+        let mut crc = Crc32::new();
+        crc.update(&header.prefix());
+        let mut addr = addr + RECORD_HEADER_LEN;
+        let mut remaining = usize::from(header.len);
+        while remaining > 0 {
+            let mut chunk = [0; CHUNK_LEN];
+            let len = remaining.min(CHUNK_LEN);
+            self.read_exact(addr, &mut chunk[..len])?;
+            crc.update(&chunk[..len]);
+            addr += len as u32;
+            remaining -= len;
+        }
+        Ok(crc.finish() == header.crc)
+    }
+
+    fn is_erased(&mut self, addr: u32, len: u32) -> Result<bool, StoreError> {
+        let mut offset = 0;
+        while offset < len {
+            let mut chunk = [0; CHUNK_LEN];
+            let chunk = &mut chunk[..(len - offset).min(CHUNK_LEN as u32) as usize];
+            self.flash.read(addr + offset, chunk)?;
+            if chunk.iter().any(|&b| b != 0xFF) {
+                return Ok(false);
+            }
+            offset += chunk.len() as u32;
+        }
+        Ok(true)
+    }
+
+    /// Reads `buf.len()` bytes from a word-aligned address, whatever the length
+    fn read_exact(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), StoreError> {
+        let whole = buf.len() - buf.len() % WORD as usize;
+        if whole > 0 {
+            self.flash.read(addr, &mut buf[..whole])?;
+        }
+        if whole < buf.len() {
+            let mut last = [0; WORD as usize];
+            self.flash.read(addr + whole as u32, &mut last)?;
+            let rest = buf.len() - whole;
+            buf[whole..].copy_from_slice(&last[..rest]);
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, sector: u32) -> Result<(), StoreError> {
+        let start = self.sector_start(sector);
+        self.flash.erase(start, start + self.sector_size)?;
+        Ok(())
+    }
+
+    fn sector_start(&self, sector: u32) -> u32 {
+        sector * self.sector_size
+    }
+
+    fn next(&self, sector: u32) -> u32 {
+        (sector + 1) % self.sectors
+    }
+}
+
+impl<F> fmt::Debug for KvStore<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KvStore")
+            .field("sectors", &self.sectors)
+            .field("head", &self.head)
+            .field("seq", &self.seq)
+            .field("keys", &self.index.len())
+            .finish()
+    }
+}
+
+/// Part of a larger flash, such as one partition of the SPI flash
+#[derive(Debug)]
+pub struct Partition<F> {
+    flash: F,
+    offset: u32,
+    size: u32,
+}
+
+impl<F: NorFlash> Partition<F> {
+    /// Restricts `flash` to `size` bytes starting at `offset`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FlashError::NotAligned`] if the range does not start and end
+    /// on erase sector boundaries, or [`FlashError::OutOfBounds`] if it does not
+    /// fit in `flash`.
+    pub fn new(flash: F, offset: u32, size: u32) -> Result<Self, FlashError> {
+        let erase_size = F::ERASE_SIZE as u32;
+        if !offset.is_multiple_of(erase_size) || !size.is_multiple_of(erase_size) {
+            return Err(FlashError::NotAligned);
+        }
+        if offset as usize + size as usize > flash.capacity() {
+            return Err(FlashError::OutOfBounds);
+        }
+        Ok(Self {
+            flash,
+            offset,
+            size,
+        })
+    }
+
+    /// Gives back the whole flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    fn check(&self, offset: u32, len: usize) -> Result<u32, NorFlashErrorKind> {
+        if offset as usize + len > self.size as usize {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+        Ok(self.offset + offset)
+    }
+}
+
+impl<F: NorFlash> ErrorType for Partition<F> {
+    type Error = NorFlashErrorKind;
+}
+
+impl<F: NorFlash> ReadNorFlash for Partition<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = self.check(offset, bytes.len())?;
+        self.flash.read(offset, bytes).map_err(|e| e.kind())
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl<F: NorFlash> NorFlash for Partition<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if from > to {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+        let start = self.check(from, (to - from) as usize)?;
+        self.flash
+            .erase(start, start + (to - from))
+            .map_err(|e| e.kind())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = self.check(offset, bytes.len())?;
+        self.flash.write(offset, bytes).map_err(|e| e.kind())
+    }
+}
+
+/// Rounds up to a multiple of the word size
+const fn padded(len: u32) -> u32 {
+    len.div_ceil(WORD) * WORD
+}
+
+/// CRC-32 (IEEE 802.3), as used by zlib
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub(crate) const fn new() -> Self {
+        Self(!0)
+    }
+
+    /// Starts from a cleared register instead, like the ESP ROM's
+    /// `crc32_le` called with an initial value of `u32::MAX`
+    pub(crate) const fn new_cleared() -> Self {
+        Self(0)
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u32::from(byte);
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    pub(crate) const fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Sector size of the mock, the smallest that holds a maximum size value
+    pub(crate) const SECTOR: usize = 2048;
+
+    /// RAM flash with NOR semantics and power cuts.
+    ///
+    /// Writes can only clear bits. Once the operation budget runs out, the
+    /// word write or erase in progress is torn and every later access fails.
+    #[derive(Clone)]
+    pub(crate) struct MockFlash {
+        pub(crate) data: Vec<u8>,
+        pub(crate) erases: Vec<u32>,
+        budget: Option<usize>,
+    }
+
+    impl MockFlash {
+        pub(crate) fn new(sectors: usize) -> Self {
+            Self {
+                data: vec![0xFF; sectors * SECTOR],
+                erases: vec![0; sectors],
+                budget: None,
+            }
+        }
+
+        /// Cuts the power after `ops` word writes and sector erases
+        pub(crate) fn cut_after(&mut self, ops: usize) {
+            self.budget = Some(ops);
+        }
+
+        /// Restores power
+        pub(crate) fn power_on(&mut self) {
+            self.budget = None;
+        }
+
+        pub(crate) fn powered(&self) -> bool {
+            self.budget != Some(0)
+        }
+
+        /// Spends one operation; `false` means it is cut short
+        fn spend(&mut self) -> bool {
+            match &mut self.budget {
+                Some(0) => false,
+                Some(ops) => {
+                    *ops -= 1;
+                    *ops > 0
+                }
+                None => true,
+            }
+        }
+    }
+
+    impl ErrorType for MockFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 4;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            if !self.powered() {
+                return Err(NorFlashErrorKind::Other);
+            }
+            let offset = offset as usize;
+            assert_eq!(offset % 4, 0, "unaligned read");
+            assert_eq!(bytes.len() % 4, 0, "unaligned read");
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            let (from, to) = (from as usize, to as usize);
+            assert!(from % SECTOR == 0 && to % SECTOR == 0 && from <= to);
+            for sector in from / SECTOR..to / SECTOR {
+                if !self.powered() {
+                    return Err(NorFlashErrorKind::Other);
+                }
+                let start = sector * SECTOR;
+                if self.spend() {
+                    self.data[start..start + SECTOR].fill(0xFF);
+                    self.erases[sector] += 1;
+                } else {
+                    // Torn erase: only the second half is cleared
+                    self.data[start + SECTOR / 2..start + SECTOR].fill(0xFF);
+                    return Err(NorFlashErrorKind::Other);
+                }
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            assert_eq!(offset % 4, 0, "unaligned write");
+            assert_eq!(bytes.len() % 4, 0, "unaligned write");
+            for (i, word) in bytes.chunks(4).enumerate() {
+                if !self.powered() {
+                    return Err(NorFlashErrorKind::Other);
+                }
+                let at = offset + i * 4;
+                assert!(
+                    self.data[at..at + 4].iter().all(|&b| b == 0xFF)
+                        || word.iter().all(|&b| b == 0xFF),
+                    "word at {at:#x} written twice"
+                );
+                // A torn write only lands the first half of the word
+                let landed = if self.spend() { 4 } else { 2 };
+                let target = &mut self.data[at..at + landed];
+                target.iter_mut().zip(word).for_each(|(t, w)| *t &= w);
+                if landed < 4 {
+                    return Err(NorFlashErrorKind::Other);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    pub(crate) fn mount(flash: MockFlash) -> Result<KvStore<MockFlash>, StoreError> {
+        let mut store = KvStore::new(flash)?;
+        store.mount()?;
+        Ok(store)
+    }
+
+    fn get(store: &mut KvStore<MockFlash>, key: u8) -> Option<Vec<u8>> {
+        let mut buf = [0; MAX_VALUE_LEN];
+        let (_, len) = store.read(key, &mut buf).unwrap()?;
+        Some(buf[..len].to_vec())
+    }
+
+    fn value(seed: usize, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (seed * 31 + i * 7) as u8).collect()
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn formats_blank_flash_and_reads_back() {
+        let mut store = mount(MockFlash::new(3)).unwrap();
+        assert!(store.is_empty());
+
+        store.write(1, 1, b"sensor-01").unwrap();
+        store.write(2, 3, &value(2, 300)).unwrap();
+        store.write(3, 1, &[]).unwrap();
+        store.write(1, 2, b"sensor-02").unwrap();
+        assert!(store.remove(3).unwrap());
+        assert!(!store.remove(3).unwrap());
+
+        let mut store = mount(store.into_inner()).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.version(1), Some(2));
+        assert_eq!(get(&mut store, 1).as_deref(), Some(&b"sensor-02"[..]));
+        assert_eq!(get(&mut store, 2), Some(value(2, 300)));
+        assert_eq!(get(&mut store, 3), None);
+
+        let mut small = [0; 4];
+        assert_eq!(store.read(1, &mut small), Err(StoreError::BufferTooSmall));
+    }
+
+    #[test]
+    fn rejects_invalid_writes() {
+        let mut store = mount(MockFlash::new(2)).unwrap();
+        assert_eq!(store.write(1, 0, b"x"), Err(StoreError::ReservedVersion));
+        assert_eq!(
+            store.write(1, 1, &[0; MAX_VALUE_LEN + 1]),
+            Err(StoreError::ValueTooLarge)
+        );
+        for key in 0..MAX_KEYS as u8 {
+            store.write(key, 1, &[key]).unwrap();
+        }
+        assert_eq!(store.write(200, 1, b"x"), Err(StoreError::TooManyKeys));
+        store.write(0, 1, b"replaced").unwrap();
+
+        assert_eq!(
+            mount(MockFlash {
+                data: vec![0xFF; SECTOR],
+                erases: vec![0],
+                budget: None
+            })
+            .err(),
+            Some(StoreError::UnsupportedFlash)
+        );
+    }
+
+    #[test]
+    fn skips_unchanged_values() {
+        let mut store = mount(MockFlash::new(2)).unwrap();
+        store.write(1, 1, &value(1, 100)).unwrap();
+        let offset = store.offset;
+        store.write(1, 1, &value(1, 100)).unwrap();
+        assert_eq!(store.offset, offset);
+        store.write(1, 2, &value(1, 100)).unwrap();
+        assert!(store.offset > offset);
+    }
+
+    #[test]
+    fn spreads_erases_over_all_sectors() {
+        let mut store = mount(MockFlash::new(4)).unwrap();
+        store.write(1, 1, &value(0, 900)).unwrap();
+        for i in 0..500 {
+            store.write(2, 1, &value(i, 60)).unwrap();
+        }
+        assert_eq!(get(&mut store, 1), Some(value(0, 900)));
+        assert_eq!(get(&mut store, 2), Some(value(499, 60)));
+
+        let erases = &store.into_inner().erases;
+        let (min, max) = (erases.iter().min().unwrap(), erases.iter().max().unwrap());
+        assert!(*min >= 5, "{erases:?}");
+        assert!(max - min <= 1, "{erases:?}");
+    }
+
+    #[test]
+    fn reports_full_store() {
+        let mut store = mount(MockFlash::new(2)).unwrap();
+        let mut key = 0;
+        let result = loop {
+            if let Err(error) = store.write(key, 1, &value(key.into(), 500)) {
+                break error;
+            }
+            key += 1;
+        };
+        assert_eq!(result, StoreError::Full);
+        assert_eq!(key, 4);
+
+        // Everything written before is still there, and deleting makes room
+        let mut store = mount(store.into_inner()).unwrap();
+        for k in 0..key {
+            assert_eq!(get(&mut store, k), Some(value(k.into(), 500)));
+        }
+        store.remove(0).unwrap();
+        store.write(key, 1, &value(key.into(), 500)).unwrap();
+    }
+
+    #[test]
+    fn refuses_foreign_format() {
+        let mut store = mount(MockFlash::new(2)).unwrap();
+        store.write(1, 1, b"x").unwrap();
+        let mut flash = store.into_inner();
+        // Rewrite the sector header as format version 2
+        let mut header = [0xFF; SECTOR_HEADER_LEN as usize];
+        header[..3].copy_from_slice(&MAGIC);
+        header[3] = 2;
+        header[4..8].copy_from_slice(&0u32.to_le_bytes());
+        let crc = crc32(&header[..8]);
+        header[8..].copy_from_slice(&crc.to_le_bytes());
+        flash.data[..header.len()].copy_from_slice(&header);
+
+        let mut store = KvStore::new(flash).unwrap();
+        assert_eq!(store.mount(), Err(StoreError::UnsupportedFormat(2)));
+        store.format().unwrap();
+        let mut store = mount(store.into_inner()).unwrap();
+        assert!(store.is_empty());
+        assert_eq!(get(&mut store, 1), None);
+    }
+
+    #[test]
+    fn detects_corrupted_value() {
+        let mut store = mount(MockFlash::new(2)).unwrap();
+        store.write(1, 1, b"hello").unwrap();
+        let addr = store.entry(1).unwrap().addr as usize;
+        let mut flash = store.into_inner();
+
+        // Bit rot after mounting is caught on read
+        let mut store = mount(flash.clone()).unwrap();
+        store.flash.data[addr + RECORD_HEADER_LEN as usize] ^= 1;
+        let mut buf = [0; 8];
+        assert_eq!(store.read(1, &mut buf), Err(StoreError::Corrupted));
+
+        // Bit rot before mounting drops the record
+        flash.data[addr + RECORD_HEADER_LEN as usize] ^= 1;
+        let mut store = mount(flash).unwrap();
+        assert_eq!(get(&mut store, 1), None);
+        store.write(1, 1, b"again").unwrap();
+        assert_eq!(get(&mut store, 1).as_deref(), Some(&b"again"[..]));
+    }
+
+    #[test]
+    fn partition_maps_and_bounds_accesses() {
+        let mut partition =
+            Partition::new(MockFlash::new(4), SECTOR as u32, 2 * SECTOR as u32).unwrap();
+        assert_eq!(partition.capacity(), 2 * SECTOR);
+        partition.write(4, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(
+            partition.write(2 * SECTOR as u32, &[0; 4]),
+            Err(NorFlashErrorKind::OutOfBounds)
+        );
+        partition.erase(SECTOR as u32, 2 * SECTOR as u32).unwrap();
+        assert_eq!(
+            partition.erase(SECTOR as u32, 3 * SECTOR as u32),
+            Err(NorFlashErrorKind::OutOfBounds)
+        );
+
+        let flash = partition.into_inner();
+        assert_eq!(flash.data[SECTOR + 4..SECTOR + 8], [1, 2, 3, 4]);
+        assert_eq!(flash.erases, [0, 0, 1, 0]);
+
+        assert_eq!(
+            Partition::new(MockFlash::new(4), 100, SECTOR as u32).err(),
+            Some(FlashError::NotAligned)
+        );
+        assert_eq!(
+            Partition::new(MockFlash::new(4), 0, 5 * SECTOR as u32).err(),
+            Some(FlashError::OutOfBounds)
+        );
+    }
+
+    /// Writes (`Some`) and removals (`None`) that go through several sector changes
+    fn workload() -> Vec<(u8, Option<Vec<u8>>)> {
+        let mut ops = Vec::new();
+        for i in 0..24 {
+            let key = (i % 4) as u8;
+            let op = if i % 7 == 6 {
+                None
+            } else {
+                Some(value(i, 40 + i * 37 % 400))
+            };
+            ops.push((key, op));
+        }
+        ops
+    }
+
+    fn apply_op(store: &mut KvStore<MockFlash>, key: u8, op: &Option<Vec<u8>>) -> bool {
+        match op {
+            Some(value) => store.write(key, 1, value).is_ok(),
+            None => store.remove(key).is_ok(),
+        }
+    }
+
+    #[test]
+    fn survives_power_loss_at_every_step() {
+        let mut base = mount(MockFlash::new(3)).unwrap();
+        let mut initial = [None, None, None, None];
+        for key in 0..4u8 {
+            let v = value(100 + usize::from(key), 200);
+            base.write(key, 1, &v).unwrap();
+            initial[usize::from(key)] = Some(v);
+        }
+        let base = base.into_inner();
+        let ops = workload();
+
+        for cut in 1.. {
+            let mut flash = base.clone();
+            flash.cut_after(cut);
+            let mut expected = initial.clone();
+            let mut torn = None;
+
+            let mut store = mount(flash).unwrap();
+            for (key, op) in &ops {
+                if !apply_op(&mut store, *key, op) {
+                    torn = Some((*key, op.clone()));
+                    break;
+                }
+                expected[usize::from(*key)] = op.clone();
+            }
+            let Some((torn_key, torn_value)) = torn else {
+                // The whole workload ran without reaching the cut
+                assert!(cut > 100, "the workload should need many operations");
+                break;
+            };
+
+            let mut flash = store.into_inner();
+            flash.power_on();
+            let mut store = mount(flash).unwrap();
+            for key in 0..4u8 {
+                let found = get(&mut store, key);
+                let old = &expected[usize::from(key)];
+                if key == torn_key {
+                    assert!(
+                        found == *old || found == torn_value,
+                        "cut {cut}: key {key} holds neither the old nor the new value"
+                    );
+                } else {
+                    assert_eq!(found, *old, "cut {cut}: key {key} changed");
+                }
+            }
+
+            // The store keeps working, through further sector changes
+            for (key, op) in &ops {
+                assert!(apply_op(&mut store, *key, op), "cut {cut}: write failed");
+            }
+            let mut store = mount(store.into_inner()).unwrap();
+            for key in 0..4u8 {
+                let last = ops.iter().rev().find(|(k, _)| *k == key).unwrap();
+                assert_eq!(get(&mut store, key), last.1, "cut {cut}: key {key}");
+            }
+        }
+    }
+}
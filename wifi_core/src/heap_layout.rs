@@ -0,0 +1,179 @@
+//! Heap layout: which memory regions the heap takes, and how much of each.
+//!
+//! A [`HeapConfig`] is built at compile time and handed to
+//! [`init_heap!`](crate::init_heap), which reserves the regions and adds them
+//! to the `esp-alloc` heap:
+//!
+//! ```ignore
+//! use wifi_core::heap_layout::{ESP32_RECLAIMED_RAM, HeapConfig};
+//!
+//! const HEAP: HeapConfig = HeapConfig::new()
+//!     .with_reclaimed(ESP32_RECLAIMED_RAM)
+//!     .with_dram(64 * 1024);
+//! wifi_core::init_heap!(HEAP);
+//!
+//! // On modules with PSRAM, with the `psram` feature of esp-hal
+//! const PSRAM_HEAP: HeapConfig = HEAP.with_psram(true);
+//! wifi_core::init_heap!(PSRAM_HEAP, psram: peripherals.PSRAM);
+//! ```
+//!
+//! The regions are added in the order reclaimed RAM, internal DRAM, PSRAM,
+//! and the allocator tries them in that order, so PSRAM only holds what does
+//! not fit in internal RAM. Reclaimed RAM is the RAM the second-stage
+//! bootloader used, free once the application runs; it costs nothing to use.
+//! DRAM for the heap is taken from the memory of statics and stacks.
+//!
+//! The macro expands to `esp_alloc` and `esp_hal` calls, so the crate that
+//! uses it needs both as dependencies.
+
+/// Size of the RAM reclaimed from the second-stage bootloader on the ESP32
+pub const ESP32_RECLAIMED_RAM: usize = 98_768;
+
+/// Memory regions of the heap and their sizes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HeapConfig {
+    /// Bytes of reclaimed bootloader RAM, or 0 to leave it unused
+    pub reclaimed: usize,
+    /// Bytes of internal DRAM, or 0 for none
+    pub dram: usize,
+    /// Whether all of the external PSRAM is added
+    pub psram: bool,
+}
+
+impl HeapConfig {
+    /// Creates a layout without any region
+    pub const fn new() -> Self {
+        HeapConfig {
+            reclaimed: 0,
+            dram: 0,
+            psram: false,
+        }
+    }
+
+    /// Sets the bytes of reclaimed bootloader RAM to use
+    #[must_use]
+    pub const fn with_reclaimed(mut self, size: usize) -> Self {
+        self.reclaimed = size;
+        self
+    }
+
+    /// Sets the bytes of internal DRAM to use
+    #[must_use]
+    pub const fn with_dram(mut self, size: usize) -> Self {
+        self.dram = size;
+        self
+    }
+
+    /// Sets whether the external PSRAM is used
+    #[must_use]
+    pub const fn with_psram(mut self, enabled: bool) -> Self {
+        self.psram = enabled;
+        self
+    }
+
+    /// Bytes of internal RAM the heap takes
+    pub const fn internal_size(&self) -> usize {
+        self.reclaimed + self.dram
+    }
+
+    /// Number of regions the heap has
+    pub const fn regions(&self) -> usize {
+        (self.reclaimed > 0) as usize + (self.dram > 0) as usize + self.psram as usize
+    }
+
+    /// Returns the layout if it is usable, for use in constants.
+    ///
+    /// # Panics
+    ///
+    /// Panics, at compile time when evaluated in a constant, if the layout
+    /// has no region or a size is not a multiple of 4 bytes.
+    pub const fn check(self) -> Self {
+        assert!(self.regions() > 0, "the heap needs at least one region");
+        assert!(
+            self.reclaimed.is_multiple_of(4) && self.dram.is_multiple_of(4),
+            "heap region sizes must be multiples of 4 bytes"
+        );
+        self
+    }
+}
+
+/// Reserves the regions of a [`HeapConfig`] and adds them to the
+/// `esp-alloc` heap.
+///
+/// Takes the layout as a constant expression, and the PSRAM peripheral if
+/// the layout uses PSRAM. Call it once, early in `main`; see
+/// [`heap_layout`](crate::heap_layout).
+#[macro_export]
+macro_rules! init_heap {
+    ($config:expr) => {{
+        const CONFIG: $crate::heap_layout::HeapConfig = $config.check();
+        const _: () = ::core::assert!(
+            !CONFIG.psram,
+            "a heap with PSRAM needs `init_heap!(config, psram: peripherals.PSRAM)`"
+        );
+        $crate::init_heap!(@internal CONFIG);
+    }};
+    ($config:expr, psram: $psram:expr) => {{
+        const CONFIG: $crate::heap_layout::HeapConfig = $config.check();
+        $crate::init_heap!(@internal CONFIG);
+        if CONFIG.psram {
+            ::esp_alloc::psram_allocator!($psram, ::esp_hal::psram);
+        }
+    }};
+    (@internal $config:ident) => {{
+        #[::esp_hal::ram(reclaimed)]
+        static mut RECLAIMED: ::core::mem::MaybeUninit<[u8; $config.reclaimed]> =
+            ::core::mem::MaybeUninit::uninit();
+        static mut DRAM: ::core::mem::MaybeUninit<[u8; $config.dram]> =
+            ::core::mem::MaybeUninit::uninit();
+
+        // SAFETY: the statics are only reachable here, and the block runs
+        // once per expansion, so the heap has the memory to itself
+        unsafe {
+            if $config.reclaimed > 0 {
+                ::esp_alloc::HEAP.add_region(::esp_alloc::HeapRegion::new(
+                    (&raw mut RECLAIMED).cast::<u8>(),
+                    $config.reclaimed,
+                    ::esp_alloc::MemoryCapability::Internal.into(),
+                ));
+            }
+            if $config.dram > 0 {
+                ::esp_alloc::HEAP.add_region(::esp_alloc::HeapRegion::new(
+                    (&raw mut DRAM).cast::<u8>(),
+                    $config.dram,
+                    ::esp_alloc::MemoryCapability::Internal.into(),
+                ));
+            }
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_layouts() {
+        const LAYOUT: HeapConfig = HeapConfig::new()
+            .with_reclaimed(ESP32_RECLAIMED_RAM)
+            .with_dram(128 * 1024)
+            .check();
+        assert_eq!(LAYOUT.internal_size(), 229_840);
+        assert_eq!(LAYOUT.regions(), 2);
+        assert_eq!(LAYOUT.with_psram(true).regions(), 3);
+        assert_eq!(HeapConfig::new().with_dram(4).regions(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one region")]
+    fn rejects_empty_layouts() {
+        let _ = HeapConfig::new().with_psram(false).check();
+    }
+
+    #[test]
+    #[should_panic(expected = "multiples of 4")]
+    fn rejects_unaligned_sizes() {
+        let _ = HeapConfig::new().with_dram(1001).check();
+    }
+}
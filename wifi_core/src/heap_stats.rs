@@ -0,0 +1,282 @@
+//! Heap usage statistics and low-memory warnings.
+//!
+//! The firmware samples its heap regions now and then; a [`HeapMonitor`]
+//! keeps the high-water mark of each region across the samples and tells
+//! when free memory drops below a threshold, so the heap sizes can be set
+//! from what the device actually used. [`largest_block`] finds the largest
+//! block that could still be allocated, which shows fragmentation that the
+//! free byte count hides.
+//!
+//! The peaks are as high as any sample has seen, so a short burst between
+//! two samples can go unnoticed; the firmware also reports the exact peak of
+//! the whole heap where the allocator tracks it.
+
+use core::fmt;
+
+/// Maximum number of heap regions
+pub const MAX_REGIONS: usize = 3;
+
+/// Use of one heap region
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RegionUsage {
+    /// Size of the region, in bytes
+    pub size: usize,
+    /// Bytes in use
+    pub used: usize,
+    /// Bytes free
+    pub free: usize,
+    /// Most bytes in use in any sample so far
+    pub peak: usize,
+}
+
+/// Use of the whole heap.
+///
+/// Failed allocations are not counted; the allocator the firmware uses,
+/// `esp-alloc`, does not report them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HeapUsage {
+    /// Use of each region, in the order they were added
+    pub regions: heapless::Vec<RegionUsage, MAX_REGIONS>,
+    /// Most bytes in use at any time since boot, if the allocator tracks it;
+    /// otherwise the most seen in any sample
+    pub peak: usize,
+    /// Largest block that can be allocated, in bytes
+    pub largest_free_block: usize,
+}
+
+impl HeapUsage {
+    /// Total size of the regions
+    pub fn size(&self) -> usize {
+        self.regions.iter().map(|region| region.size).sum()
+    }
+
+    /// Bytes in use in all regions
+    pub fn used(&self) -> usize {
+        self.regions.iter().map(|region| region.used).sum()
+    }
+
+    /// Bytes free in all regions
+    pub fn free(&self) -> usize {
+        self.regions.iter().map(|region| region.free).sum()
+    }
+}
+
+impl fmt::Display for HeapUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Heap: {} of {} bytes used, peak {}, {} free, largest free block {}",
+            self.used(),
+            self.size(),
+            self.peak,
+            self.free(),
+            self.largest_free_block
+        )?;
+        for (index, region) in self.regions.iter().enumerate() {
+            let separator = if index == 0 { "; regions " } else { ", " };
+            write!(
+                f,
+                "{}{}/{} (peak {})",
+                separator, region.used, region.size, region.peak
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Change of the low-memory state found by [`HeapMonitor::sample`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LowMemory {
+    /// Free memory dropped below the threshold
+    Entered {
+        /// Bytes free
+        free: usize,
+    },
+    /// Free memory is back above the threshold, with some margin
+    Cleared {
+        /// Bytes free
+        free: usize,
+    },
+}
+
+/// Keeps heap high-water marks and watches for low memory
+#[derive(Clone, Debug, Default)]
+pub struct HeapMonitor {
+    peaks: [usize; MAX_REGIONS],
+    peak: usize,
+    threshold: Option<usize>,
+    low: bool,
+}
+
+impl HeapMonitor {
+    /// Creates a monitor that warns when fewer than `threshold` bytes are
+    /// free, or never if `None`
+    pub const fn new(threshold: Option<usize>) -> Self {
+        HeapMonitor {
+            peaks: [0; MAX_REGIONS],
+            peak: 0,
+            threshold,
+            low: false,
+        }
+    }
+
+    /// Changes the low-memory threshold
+    pub fn set_threshold(&mut self, threshold: Option<usize>) {
+        self.threshold = threshold;
+    }
+
+    /// Records a sample of the regions and fills in the peaks of `usage`.
+    ///
+    /// `exact_peak` is the allocator's own high-water mark of the whole
+    /// heap, if it has one. Returns a change of the low-memory state: memory
+    /// is low once fewer bytes than the threshold are free, and stays low
+    /// until an eighth more than the threshold is free again, so a heap
+    /// hovering around the threshold does not warn on every sample.
+    pub fn sample(
+        &mut self,
+        usage: &mut HeapUsage,
+        exact_peak: Option<usize>,
+    ) -> Option<LowMemory> {
+        for (region, peak) in usage.regions.iter_mut().zip(&mut self.peaks) {
+            *peak = (*peak).max(region.used);
+            region.peak = *peak;
+        }
+        self.peak = self.peak.max(exact_peak.unwrap_or(usage.used()));
+        usage.peak = self.peak;
+
+        let free = usage.free();
+        match self.threshold {
+            Some(threshold) if !self.low && free < threshold => {
+                self.low = true;
+                Some(LowMemory::Entered { free })
+            }
+            Some(threshold) if self.low && free >= threshold + threshold / 8 => {
+                self.low = false;
+                Some(LowMemory::Cleared { free })
+            }
+            None if self.low => {
+                self.low = false;
+                Some(LowMemory::Cleared { free })
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true while memory is low
+    pub fn is_low(&self) -> bool {
+        self.low
+    }
+}
+
+/// Finds the largest block of at most `limit` bytes that can be allocated.
+///
+/// `try_allocate(size)` allocates and frees a block of `size` bytes and
+/// tells whether that worked. The search halves the range of sizes each
+/// time, so it takes about 20 tries for a heap of hundreds of KiB; sizes are
+/// multiples of 4 bytes.
+pub fn largest_block(limit: usize, mut try_allocate: impl FnMut(usize) -> bool) -> usize {
+    // Invariant: blocks of `low` bytes can be allocated, of `high` cannot
+    let mut low = 0;
+    let mut high = limit / 4 + 1;
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if try_allocate(mid * 4) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    low * 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    fn usage(regions: &[(usize, usize)]) -> HeapUsage {
+        HeapUsage {
+            regions: regions
+                .iter()
+                .map(|&(size, used)| RegionUsage {
+                    size,
+                    used,
+                    free: size - used,
+                    peak: 0,
+                })
+                .collect(),
+            ..HeapUsage::default()
+        }
+    }
+
+    #[test]
+    fn keeps_peaks() {
+        let mut monitor = HeapMonitor::new(None);
+        let mut first = usage(&[(1000, 600), (2000, 100)]);
+        assert_eq!(monitor.sample(&mut first, None), None);
+        assert_eq!(first.peak, 700);
+
+        let mut second = usage(&[(1000, 200), (2000, 900)]);
+        monitor.sample(&mut second, None);
+        assert_eq!(
+            second
+                .regions
+                .iter()
+                .map(|r| r.peak)
+                .collect::<heapless::Vec<_, 2>>(),
+            [600, 900]
+        );
+        assert_eq!(second.peak, 1100);
+        // The allocator's own peak wins over the samples
+        monitor.sample(&mut second, Some(1500));
+        assert_eq!(second.peak, 1500);
+
+        assert_eq!(second.used(), 1100);
+        assert_eq!(second.free(), 1900);
+        second.largest_free_block = 1024;
+        assert_eq!(
+            format!("{second}"),
+            "Heap: 1100 of 3000 bytes used, peak 1500, 1900 free, largest free block 1024; \
+             regions 200/1000 (peak 600), 900/2000 (peak 900)"
+        );
+    }
+
+    #[test]
+    fn warns_on_low_memory() {
+        let mut monitor = HeapMonitor::new(Some(800));
+        let mut sample = |used| monitor.sample(&mut usage(&[(1000, used)]), None);
+        assert_eq!(sample(100), None);
+        assert_eq!(sample(300), Some(LowMemory::Entered { free: 700 }));
+        assert_eq!(sample(250), None);
+        // Not yet an eighth above the threshold
+        assert_eq!(sample(150), None);
+        assert_eq!(sample(100), Some(LowMemory::Cleared { free: 900 }));
+        assert_eq!(sample(500), Some(LowMemory::Entered { free: 500 }));
+
+        let mut last = usage(&[(1000, 500)]);
+        assert_eq!(monitor.sample(&mut last, None), None);
+        assert!(monitor.is_low());
+        monitor.set_threshold(None);
+        assert_eq!(
+            monitor.sample(&mut last, None),
+            Some(LowMemory::Cleared { free: 500 })
+        );
+    }
+
+    #[test]
+    fn finds_largest_block() {
+        let mut tries = 0;
+        let found = largest_block(128 * 1024, |size| {
+            tries += 1;
+            size <= 40_001
+        });
+        assert_eq!(found, 40_000);
+        assert!(tries <= 16);
+        assert_eq!(largest_block(1000, |_| true), 1000);
+        assert_eq!(largest_block(1000, |_| false), 0);
+        assert_eq!(largest_block(0, |_| true), 0);
+    }
+}
@@ -0,0 +1,350 @@
+//! Minimal HTTP/1.1 message handling.
+//!
+//! Just enough HTTP for the small servers running on the device: parsing a
+//! request that fits in one buffer, formatting a response head, and decoding
+//! `application/x-www-form-urlencoded` bodies. Responses always close the
+//! connection, so there is no support for keep-alive or chunked bodies.
+
+use core::fmt;
+use core::str;
+
+/// Request method
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Method {
+    /// `GET`
+    Get,
+    /// `HEAD`
+    Head,
+    /// `POST`
+    Post,
+    /// `PUT`
+    Put,
+    /// `DELETE`
+    Delete,
+    /// Any other method
+    Other,
+}
+
+impl Method {
+    fn parse(method: &str) -> Self {
+        match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            _ => Method::Other,
+        }
+    }
+}
+
+/// Reasons why a request could not be parsed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParseError {
+    /// The head or body has not been received completely yet
+    Incomplete,
+    /// The request is not valid HTTP/1.x
+    Malformed,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Incomplete => f.write_str("incomplete request"),
+            ParseError::Malformed => f.write_str("malformed request"),
+        }
+    }
+}
+
+/// A parsed request, borrowing from the receive buffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Request<'a> {
+    /// Request method
+    pub method: Method,
+    /// Path of the request target, without the query
+    pub path: &'a str,
+    /// Query string after `?`, if any
+    pub query: Option<&'a str>,
+    /// Body, as long as the `Content-Length` header says
+    pub body: &'a [u8],
+    headers: &'a str,
+}
+
+impl<'a> Request<'a> {
+    /// Parses a request from the bytes received so far.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ParseError::Incomplete`] until the head and the whole body
+    /// are in `buf`, or [`ParseError::Malformed`] for invalid requests.
+    pub fn parse(buf: &'a [u8]) -> Result<Self, ParseError> {
+        let head_len = find(buf, b"\r\n\r\n").ok_or(ParseError::Incomplete)?;
+        let head = str::from_utf8(&buf[..head_len]).map_err(|_| ParseError::Malformed)?;
+        let (request_line, headers) = head.split_once("\r\n").unwrap_or((head, ""));
+
+        let mut parts = request_line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ParseError::Malformed);
+        };
+        if !version.starts_with("HTTP/1.") || !target.starts_with('/') {
+            return Err(ParseError::Malformed);
+        }
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+
+        let mut request = Request {
+            method: Method::parse(method),
+            path,
+            query,
+            body: &[],
+            headers,
+        };
+        let body_len = match request.header("Content-Length") {
+            Some(len) => len.parse().map_err(|_| ParseError::Malformed)?,
+            None => 0,
+        };
+        let body = &buf[head_len + 4..];
+        request.body = body.get(..body_len).ok_or(ParseError::Incomplete)?;
+        Ok(request)
+    }
+
+    /// Value of the first header called `name`, compared case-insensitively
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        find_header(self.headers, name)
+    }
+}
+
+/// Response status
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Status {
+    /// 200 OK
+    Ok,
+    /// 202 Accepted
+    Accepted,
+    /// 204 No Content
+    NoContent,
+    /// 302 Found
+    Found,
+    /// 400 Bad Request
+    BadRequest,
+    /// 404 Not Found
+    NotFound,
+    /// 405 Method Not Allowed
+    MethodNotAllowed,
+    /// 413 Content Too Large
+    ContentTooLarge,
+    /// 500 Internal Server Error
+    InternalServerError,
+    /// 503 Service Unavailable
+    ServiceUnavailable,
+}
+
+impl Status {
+    /// Numeric status code
+    pub const fn code(&self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::Accepted => 202,
+            Status::NoContent => 204,
+            Status::Found => 302,
+            Status::BadRequest => 400,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::ContentTooLarge => 413,
+            Status::InternalServerError => 500,
+            Status::ServiceUnavailable => 503,
+        }
+    }
+
+    /// Reason phrase
+    pub const fn reason(&self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::Accepted => "Accepted",
+            Status::NoContent => "No Content",
+            Status::Found => "Found",
+            Status::BadRequest => "Bad Request",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::ContentTooLarge => "Content Too Large",
+            Status::InternalServerError => "Internal Server Error",
+            Status::ServiceUnavailable => "Service Unavailable",
+        }
+    }
+}
+
+/// Status line and headers of a response.
+///
+/// Formats with [`Display`](fmt::Display), including the blank line that ends
+/// the head.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResponseHead<'a> {
+    /// Response status
+    pub status: Status,
+    /// Media type of the body
+    pub content_type: &'a str,
+    /// Length of the body in bytes
+    pub content_length: usize,
+    /// Target of a redirect
+    pub location: Option<&'a str>,
+}
+
+impl<'a> ResponseHead<'a> {
+    /// Creates a head for an empty response
+    pub const fn new(status: Status) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            content_length: 0,
+            location: None,
+        }
+    }
+
+    /// Sets the media type and length of the body
+    #[must_use]
+    pub const fn with_content(mut self, content_type: &'a str, content_length: usize) -> Self {
+        self.content_type = content_type;
+        self.content_length = content_length;
+        self
+    }
+
+    /// Sets the redirect target
+    #[must_use]
+    pub const fn with_location(mut self, location: &'a str) -> Self {
+        self.location = Some(location);
+        self
+    }
+}
+
+impl fmt::Display for ResponseHead<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "HTTP/1.1 {} {}\r\n",
+            self.status.code(),
+            self.status.reason()
+        )?;
+        if let Some(location) = self.location {
+            write!(f, "Location: {location}\r\n")?;
+        }
+        write!(
+            f,
+            "Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.content_type, self.content_length
+        )
+    }
+}
+
+/// Iterates over the raw `name=value` pairs of a form-encoded body or query
+pub fn form_fields(form: &str) -> impl Iterator<Item = (&str, &str)> {
+    form.split('&')
+        .filter(|field| !field.is_empty())
+        .map(|field| field.split_once('=').unwrap_or((field, "")))
+}
+
+/// Decodes a form-encoded value: `+` becomes a space and `%XX` the byte XX.
+///
+/// Returns `None` for invalid escapes, non UTF-8 results or values longer
+/// than `N` bytes.
+pub fn form_decode<const N: usize>(raw: &str) -> Option<heapless::String<N>> {
+    let mut bytes = heapless::Vec::<u8, N>::new();
+    let mut input = raw.bytes();
+    while let Some(byte) = input.next() {
+        let decoded = match byte {
+            b'+' => b' ',
+            b'%' => {
+                let high = hex_digit(input.next()?)?;
+                let low = hex_digit(input.next()?)?;
+                high << 4 | low
+            }
+            _ => byte,
+        };
+        bytes.push(decoded).ok()?;
+    }
+    heapless::String::from_utf8(bytes).ok()
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    char::from(digit).to_digit(16).map(|d| d as u8)
+}
+
+/// Value of the first header called `name` in the header lines of a head
+pub(crate) fn find_header<'a>(headers: &'a str, name: &str) -> Option<&'a str> {
+    headers
+        .split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Position of the first occurrence of `needle` in `haystack`
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn parses_request_with_body() {
+        let raw = b"POST /connect?from=portal HTTP/1.1\r\nHost: 192.168.4.1\r\n\
+            content-length: 11\r\n\r\nssid=office";
+        let request = Request::parse(raw).unwrap();
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.path, "/connect");
+        assert_eq!(request.query, Some("from=portal"));
+        assert_eq!(request.header("host"), Some("192.168.4.1"));
+        assert_eq!(request.body, b"ssid=office");
+
+        assert_eq!(
+            Request::parse(&raw[..raw.len() - 1]),
+            Err(ParseError::Incomplete)
+        );
+        assert_eq!(
+            Request::parse(b"GET / HTTP/1.1\r\nHost: x\r\n"),
+            Err(ParseError::Incomplete)
+        );
+        assert_eq!(Request::parse(b"GET /\r\n\r\n"), Err(ParseError::Malformed));
+        assert_eq!(
+            Request::parse(b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n"),
+            Err(ParseError::Malformed)
+        );
+    }
+
+    #[test]
+    fn formats_response_head() {
+        let head = ResponseHead::new(Status::Found).with_location("http://192.168.4.1/");
+        assert_eq!(
+            head.to_string(),
+            "HTTP/1.1 302 Found\r\nLocation: http://192.168.4.1/\r\n\
+            Content-Type: text/plain\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn decodes_form_values() {
+        let fields: std::vec::Vec<_> = form_fields("ssid=My+Net%21&password=&flag").collect();
+        assert_eq!(
+            fields,
+            [("ssid", "My+Net%21"), ("password", ""), ("flag", "")]
+        );
+
+        assert_eq!(form_decode::<16>("My+Net%21").as_deref(), Some("My Net!"));
+        assert_eq!(form_decode::<16>("caf%C3%A9").as_deref(), Some("café"));
+        assert_eq!(form_decode::<16>("bad%2"), None);
+        assert_eq!(form_decode::<16>("bad%zz"), None);
+        assert_eq!(form_decode::<16>("%FF"), None);
+        assert_eq!(form_decode::<4>("toolong"), None);
+    }
+}
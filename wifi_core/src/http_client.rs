@@ -0,0 +1,760 @@
+//! HTTP/1.1 client.
+//!
+//! [`send`] writes a [`Request`] to any connection implementing the
+//! `embedded-io-async` traits, such as an `embassy_net` TCP socket, and reads
+//! the response head into a caller buffer. The body is then streamed from the
+//! returned [`Response`] into caller buffers, whether its length comes from
+//! `Content-Length`, it is chunked, or it ends when the server closes the
+//! connection.
+//!
+//! Requests ask the server to close the connection after the response, so
+//! each connection carries one request. [`Url`] splits the URLs of plain
+//! HTTP resources into what a request needs.
+
+use core::fmt::{self, Write as _};
+use core::str;
+
+use embedded_io_async::{Read, Write};
+
+use crate::http::{find, find_header};
+
+/// Reasons why a request failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClientError<E> {
+    /// The connection failed
+    Io(E),
+    /// The request head or a response line does not fit in the buffer
+    BufferTooSmall,
+    /// The response is not valid HTTP/1.x
+    Malformed,
+    /// The server closed the connection before the response was complete
+    UnexpectedEof,
+}
+
+impl<E: fmt::Debug> fmt::Display for ClientError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "connection error: {:?}", e),
+            ClientError::BufferTooSmall => f.write_str("HTTP message does not fit in the buffer"),
+            ClientError::Malformed => f.write_str("malformed response"),
+            ClientError::UnexpectedEof => f.write_str("connection closed before end of response"),
+        }
+    }
+}
+
+/// A request to send
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Request<'a> {
+    method: &'static str,
+    host: &'a str,
+    path: &'a str,
+    headers: &'a [(&'a str, &'a str)],
+    content_type: Option<&'a str>,
+    body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    /// Creates a request without body
+    const fn new(method: &'static str, host: &'a str, path: &'a str) -> Self {
+        Self {
+            method,
+            host,
+            path,
+            headers: &[],
+            content_type: None,
+            body: &[],
+        }
+    }
+
+    /// Creates a `GET` request.
+    ///
+    /// `host` goes into the `Host` header and needs the port if it is not the
+    /// default one, e.g. `"192.168.1.200:8080"`. `path` is the request target,
+    /// including any query.
+    pub const fn get(host: &'a str, path: &'a str) -> Self {
+        Self::new("GET", host, path)
+    }
+
+    /// Creates a `HEAD` request
+    pub const fn head(host: &'a str, path: &'a str) -> Self {
+        Self::new("HEAD", host, path)
+    }
+
+    /// Creates a `POST` request; set the body with [`Request::with_body`]
+    pub const fn post(host: &'a str, path: &'a str) -> Self {
+        Self::new("POST", host, path)
+    }
+
+    /// Creates a `PUT` request; set the body with [`Request::with_body`]
+    pub const fn put(host: &'a str, path: &'a str) -> Self {
+        Self::new("PUT", host, path)
+    }
+
+    /// Creates a `DELETE` request
+    pub const fn delete(host: &'a str, path: &'a str) -> Self {
+        Self::new("DELETE", host, path)
+    }
+
+    /// Sets extra headers as `(name, value)` pairs
+    #[must_use]
+    pub const fn with_headers(mut self, headers: &'a [(&'a str, &'a str)]) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Sets the media type and content of the body
+    #[must_use]
+    pub const fn with_body(mut self, content_type: &'a str, body: &'a [u8]) -> Self {
+        self.content_type = Some(content_type);
+        self.body = body;
+        self
+    }
+
+    /// Returns `true` if the response to this request never has a body
+    fn is_head(&self) -> bool {
+        self.method == "HEAD"
+    }
+}
+
+impl fmt::Display for Request<'_> {
+    /// Formats the request head, including the blank line that ends it
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} HTTP/1.1\r\nHost: {}\r\n",
+            self.method, self.path, self.host
+        )?;
+        for (name, value) in self.headers {
+            write!(f, "{name}: {value}\r\n")?;
+        }
+        if let Some(content_type) = self.content_type {
+            write!(
+                f,
+                "Content-Type: {}\r\nContent-Length: {}\r\n",
+                content_type,
+                self.body.len()
+            )?;
+        }
+        f.write_str("Connection: close\r\n\r\n")
+    }
+}
+
+/// Where the body of a response ends, and how far reading has got
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Body {
+    /// This many bytes are left
+    Remaining(usize),
+    /// Chunked, before a chunk size line
+    ChunkSize,
+    /// Chunked, with this many bytes left of the current chunk
+    ChunkData(usize),
+    /// Chunked, before the line break after a chunk
+    ChunkEnd,
+    /// Chunked, in the trailer section after the last chunk
+    Trailers,
+    /// Everything up to the end of the connection
+    UntilClose,
+    /// The whole body has been read
+    Done,
+}
+
+/// The parts of an `http://` URL a request needs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Url<'a> {
+    /// Host name or IPv4 address
+    pub host: &'a str,
+    /// TCP port, 80 unless given
+    pub port: u16,
+    /// Host and port as written, for the `Host` header
+    pub authority: &'a str,
+    /// Path and query, `/` if the URL has none
+    pub path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    /// Splits an `http://host[:port][/path]` URL.
+    ///
+    /// Returns `None` for other schemes, user info, an empty host or an
+    /// invalid port.
+    pub fn parse(url: &'a str) -> Option<Self> {
+        let scheme_len = "http://".len();
+        if !url.get(..scheme_len)?.eq_ignore_ascii_case("http://") {
+            return None;
+        }
+        let rest = &url[scheme_len..];
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(at) if rest[at..].starts_with('/') => (&rest[..at], &rest[at..]),
+            Some(_) => return None,
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().ok().filter(|&port| port != 0)?),
+            None => (authority, 80),
+        };
+        if host.is_empty() || host.contains('@') {
+            return None;
+        }
+        Some(Self {
+            host,
+            port,
+            authority,
+            path,
+        })
+    }
+}
+
+/// A response whose head has been received.
+///
+/// The head stays at the start of the buffer passed to [`send`]; the rest of
+/// the buffer holds body bytes received along with the head, and chunk size
+/// lines.
+pub struct Response<'a, C> {
+    connection: &'a mut C,
+    buf: &'a mut [u8],
+    head_len: usize,
+    status: u16,
+    /// Buffered body bytes are `buf[pos..end]`
+    pos: usize,
+    end: usize,
+    body: Body,
+}
+
+impl<C: Read> Response<'_, C> {
+    /// Status code, e.g. 200
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Returns `true` for 2xx status codes
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Reason phrase of the status line, possibly empty
+    pub fn reason(&self) -> &str {
+        let (status_line, _) = self.head().split_once("\r\n").unwrap_or_default();
+        status_line.splitn(3, ' ').nth(2).unwrap_or_default()
+    }
+
+    /// Value of the first header called `name`, compared case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let (_, headers) = self.head().split_once("\r\n")?;
+        find_header(headers, name)
+    }
+
+    /// Status line and headers, checked to be UTF-8 by [`send`]
+    fn head(&self) -> &str {
+        str::from_utf8(&self.buf[..self.head_len]).unwrap_or_default()
+    }
+
+    /// Reads the next part of the body into `out`.
+    ///
+    /// Returns the number of bytes read, which is 0 once the whole body has
+    /// been read.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::UnexpectedEof`] if the connection closes before
+    /// the end of the body, [`ClientError::Malformed`] for invalid chunk
+    /// framing, or [`ClientError::Io`].
+    pub async fn read(&mut self, out: &mut [u8]) -> Result<usize, ClientError<C::Error>> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match self.body {
+                Body::Done | Body::Remaining(0) => {
+                    self.body = Body::Done;
+                    return Ok(0);
+                }
+                Body::Remaining(left) => {
+                    let n = self.read_raw(out, left).await?;
+                    self.body = Body::Remaining(left - n);
+                    return Ok(n);
+                }
+                Body::ChunkData(left) => {
+                    let n = self.read_raw(out, left).await?;
+                    self.body = match left - n {
+                        0 => Body::ChunkEnd,
+                        left => Body::ChunkData(left),
+                    };
+                    return Ok(n);
+                }
+                Body::UntilClose => {
+                    let n = self.read_buffered(out).await?;
+                    if n == 0 {
+                        self.body = Body::Done;
+                    }
+                    return Ok(n);
+                }
+                Body::ChunkSize => {
+                    let line = self.read_line().await?;
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    let size =
+                        usize::from_str_radix(size, 16).map_err(|_| ClientError::Malformed)?;
+                    self.body = match size {
+                        0 => Body::Trailers,
+                        size => Body::ChunkData(size),
+                    };
+                }
+                Body::ChunkEnd => {
+                    if !self.read_line().await?.is_empty() {
+                        return Err(ClientError::Malformed);
+                    }
+                    self.body = Body::ChunkSize;
+                }
+                Body::Trailers => {
+                    if self.read_line().await?.is_empty() {
+                        self.body = Body::Done;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads the rest of the body into `out` and returns its length.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::BufferTooSmall`] if the body is longer than
+    /// `out`, or any error of [`Response::read`].
+    pub async fn read_to_end(&mut self, out: &mut [u8]) -> Result<usize, ClientError<C::Error>> {
+        let mut len = 0;
+        while len < out.len() {
+            match self.read(&mut out[len..]).await? {
+                0 => return Ok(len),
+                n => len += n,
+            }
+        }
+        match self.read(&mut [0]).await? {
+            0 => Ok(len),
+            _ => Err(ClientError::BufferTooSmall),
+        }
+    }
+
+    /// Reads at most `limit` body bytes into `out`, failing at end of stream
+    async fn read_raw(
+        &mut self,
+        out: &mut [u8],
+        limit: usize,
+    ) -> Result<usize, ClientError<C::Error>> {
+        let len = out.len().min(limit);
+        match self.read_buffered(&mut out[..len]).await? {
+            0 => Err(ClientError::UnexpectedEof),
+            n => Ok(n),
+        }
+    }
+
+    /// Reads from the buffer if it holds body bytes, from the connection
+    /// otherwise
+    async fn read_buffered(&mut self, out: &mut [u8]) -> Result<usize, ClientError<C::Error>> {
+        if self.pos < self.end {
+            let n = out.len().min(self.end - self.pos);
+            out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        self.connection.read(out).await.map_err(ClientError::Io)
+    }
+
+    /// Reads a line of the chunk framing, without the line break
+    async fn read_line(&mut self) -> Result<&str, ClientError<C::Error>> {
+        loop {
+            if let Some(len) = find(&self.buf[self.pos..self.end], b"\r\n") {
+                let line = self.pos..self.pos + len;
+                self.pos += len + 2;
+                return str::from_utf8(&self.buf[line]).map_err(|_| ClientError::Malformed);
+            }
+            // Move the partial line to the start of the body area
+            self.buf.copy_within(self.pos..self.end, self.head_len);
+            self.end -= self.pos - self.head_len;
+            self.pos = self.head_len;
+            if self.end == self.buf.len() {
+                return Err(ClientError::BufferTooSmall);
+            }
+            match self.connection.read(&mut self.buf[self.end..]).await {
+                Ok(0) => return Err(ClientError::UnexpectedEof),
+                Ok(n) => self.end += n,
+                Err(e) => return Err(ClientError::Io(e)),
+            }
+        }
+    }
+}
+
+/// Sends `request` over `connection` and receives the response head.
+///
+/// Interim 1xx responses before the final one are read and discarded.
+///
+/// `buf` holds the request head while it is sent and the response head
+/// afterwards, so it must fit both; 1 KiB is plenty for most servers.
+///
+/// # Errors
+///
+/// Returns [`ClientError::BufferTooSmall`] if a head does not fit in `buf`,
+/// [`ClientError::Malformed`] or [`ClientError::UnexpectedEof`] for a bad
+/// response, or [`ClientError::Io`].
+pub async fn send<'a, C: Read + Write>(
+    connection: &'a mut C,
+    request: &Request<'_>,
+    buf: &'a mut [u8],
+) -> Result<Response<'a, C>, ClientError<C::Error>> {
+    let mut writer = SliceWriter { buf, len: 0 };
+    write!(writer, "{}", request).map_err(|_| ClientError::BufferTooSmall)?;
+    let SliceWriter { buf, len } = writer;
+    connection
+        .write_all(&buf[..len])
+        .await
+        .map_err(ClientError::Io)?;
+    connection
+        .write_all(request.body)
+        .await
+        .map_err(ClientError::Io)?;
+    connection.flush().await.map_err(ClientError::Io)?;
+
+    let mut end = 0;
+    let (head_len, status) = loop {
+        let head_len = loop {
+            if let Some(len) = find(&buf[..end], b"\r\n\r\n") {
+                break len + 4;
+            }
+            if end == buf.len() {
+                return Err(ClientError::BufferTooSmall);
+            }
+            match connection.read(&mut buf[end..]).await {
+                Ok(0) => return Err(ClientError::UnexpectedEof),
+                Ok(n) => end += n,
+                Err(e) => return Err(ClientError::Io(e)),
+            }
+        };
+
+        let head = str::from_utf8(&buf[..head_len]).map_err(|_| ClientError::Malformed)?;
+        let (status_line, _) = head.split_once("\r\n").unwrap_or_default();
+        let mut parts = status_line.splitn(3, ' ');
+        let (Some(version), Some(code)) = (parts.next(), parts.next()) else {
+            return Err(ClientError::Malformed);
+        };
+        if !version.starts_with("HTTP/1.") || code.len() != 3 {
+            return Err(ClientError::Malformed);
+        }
+        let status = match code.parse() {
+            Ok(status @ 100..=599) => status,
+            _ => return Err(ClientError::Malformed),
+        };
+        // Interim responses, such as 100 Continue, come before the final
+        // one and are skipped; 101 Switching Protocols is final
+        if status >= 200 || status == 101 {
+            break (head_len, status);
+        }
+        buf.copy_within(head_len..end, 0);
+        end -= head_len;
+    };
+
+    let head = str::from_utf8(&buf[..head_len]).map_err(|_| ClientError::Malformed)?;
+    let (_, headers) = head.split_once("\r\n").unwrap_or_default();
+    let chunked = find_header(headers, "Transfer-Encoding").is_some_and(|codings| {
+        codings
+            .rsplit(',')
+            .next()
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+    });
+    let body = if request.is_head() || status < 200 || status == 204 || status == 304 {
+        Body::Done
+    } else if chunked {
+        Body::ChunkSize
+    } else if let Some(len) = find_header(headers, "Content-Length") {
+        Body::Remaining(len.parse().map_err(|_| ClientError::Malformed)?)
+    } else {
+        Body::UntilClose
+    };
+
+    Ok(Response {
+        connection,
+        buf,
+        head_len,
+        status,
+        pos: head_len,
+        end,
+        body,
+    })
+}
+
+/// Formats into a byte slice
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl fmt::Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{self, Method};
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+    use embedded_io_async::{ErrorKind, ErrorType};
+    use std::io::{Read as _, Write as _};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::{self, JoinHandle};
+    use std::vec::Vec;
+
+    /// A blocking TCP stream, so every future is ready on its first poll
+    struct Stream(TcpStream);
+
+    impl ErrorType for Stream {
+        type Error = ErrorKind;
+    }
+
+    impl Read for Stream {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            self.0.read(buf).map_err(|_| ErrorKind::Other)
+        }
+    }
+
+    impl Write for Stream {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.0.write(buf).map_err(|_| ErrorKind::Other)
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    /// Starts a server on a local port that answers one request with
+    /// `response`, sent in pieces of `piece` bytes, and returns the request.
+    fn serve(response: &'static [u8], piece: usize) -> (Stream, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 256];
+            while let Err(http::ParseError::Incomplete) = http::Request::parse(&request) {
+                match stream.read(&mut buf).unwrap() {
+                    0 => return request,
+                    n => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            for piece in response.chunks(piece) {
+                stream.write_all(piece).unwrap();
+                stream.flush().unwrap();
+            }
+            request
+        });
+        (Stream(TcpStream::connect(address).unwrap()), server)
+    }
+
+    #[test]
+    fn sends_request_and_reads_body() {
+        let (mut stream, server) = serve(
+            b"HTTP/1.1 201 Created\r\nX-Id: 7\r\nContent-Length: 5\r\n\r\nhello",
+            4,
+        );
+        let request = Request::post("192.168.1.200:8080", "/scans?v=1")
+            .with_headers(&[("Authorization", "Bearer abc")])
+            .with_body("application/json", b"{\"n\":3}");
+        let mut buf = [0; 256];
+        let mut body = [0; 16];
+        let len = block_on(async {
+            let mut response = send(&mut stream, &request, &mut buf).await.unwrap();
+            assert_eq!(response.status(), 201);
+            assert!(response.is_success());
+            assert_eq!(response.reason(), "Created");
+            assert_eq!(response.header("x-id"), Some("7"));
+            response.read_to_end(&mut body).await.unwrap()
+        });
+        assert_eq!(&body[..len], b"hello");
+
+        let raw = server.join().unwrap();
+        let received = http::Request::parse(&raw).unwrap();
+        assert_eq!(received.method, Method::Post);
+        assert_eq!(received.path, "/scans");
+        assert_eq!(received.query, Some("v=1"));
+        assert_eq!(received.header("Host"), Some("192.168.1.200:8080"));
+        assert_eq!(received.header("Authorization"), Some("Bearer abc"));
+        assert_eq!(received.header("Content-Type"), Some("application/json"));
+        assert_eq!(received.header("Connection"), Some("close"));
+        assert_eq!(received.body, b"{\"n\":3}");
+    }
+
+    #[test]
+    fn streams_chunked_body_into_small_buffers() {
+        let (mut stream, server) = serve(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            4\r\nWiki\r\n5;name=value\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n\
+            0\r\nExpires: never\r\n\r\n",
+            3,
+        );
+        // Barely more than the head, so chunk lines wrap around the buffer
+        let mut buf = [0; 64];
+        let mut body = Vec::new();
+        block_on(async {
+            let mut response = send(&mut stream, &Request::get("example.com", "/"), &mut buf)
+                .await
+                .unwrap();
+            let mut out = [0; 3];
+            loop {
+                match response.read(&mut out).await.unwrap() {
+                    0 => break,
+                    n => body.extend_from_slice(&out[..n]),
+                }
+            }
+            assert_eq!(response.read(&mut out).await, Ok(0));
+        });
+        assert_eq!(body, b"Wikipedia in\r\n\r\nchunks.");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn skips_interim_responses() {
+        let (mut stream, _) = serve(
+            b"HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+            HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+            7,
+        );
+        let mut buf = [0; 128];
+        let mut body = [0; 16];
+        block_on(async {
+            let mut response = send(&mut stream, &Request::get("h", "/"), &mut buf)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.header("Link"), None);
+            let len = response.read_to_end(&mut body).await.unwrap();
+            assert_eq!(&body[..len], b"hello");
+        });
+
+        let (mut stream, _) = serve(
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n",
+            100,
+        );
+        block_on(async {
+            let mut response = send(&mut stream, &Request::get("h", "/"), &mut buf)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 101);
+            assert_eq!(response.read(&mut body).await, Ok(0));
+        });
+    }
+
+    #[test]
+    fn finds_end_of_body() {
+        let (mut stream, _) = serve(b"HTTP/1.0 200 OK\r\n\r\nuntil close", 100);
+        let mut buf = [0; 128];
+        let mut body = [0; 32];
+        block_on(async {
+            let mut response = send(&mut stream, &Request::get("h", "/"), &mut buf)
+                .await
+                .unwrap();
+            let len = response.read_to_end(&mut body).await.unwrap();
+            assert_eq!(&body[..len], b"until close");
+        });
+
+        let (mut stream, _) = serve(b"HTTP/1.1 204 No Content\r\n\r\n", 100);
+        block_on(async {
+            let mut response = send(&mut stream, &Request::delete("h", "/x"), &mut buf)
+                .await
+                .unwrap();
+            assert_eq!(response.read(&mut body).await, Ok(0));
+        });
+
+        let (mut stream, _) = serve(b"HTTP/1.1 200 OK\r\nContent-Length: 99\r\n\r\n", 100);
+        block_on(async {
+            let mut response = send(&mut stream, &Request::head("h", "/"), &mut buf)
+                .await
+                .unwrap();
+            assert_eq!(response.read(&mut body).await, Ok(0));
+        });
+    }
+
+    #[test]
+    fn rejects_bad_responses() {
+        let mut buf = [0; 128];
+        let mut body = [0; 4];
+        let get = Request::get("h", "/");
+        for response in [
+            &b"HTTP/1.1 OK\r\n\r\n"[..],
+            b"SSH-2.0\r\n\r\n",
+            b"HTTP/1.1 2000 X\r\n\r\n",
+        ] {
+            let (mut stream, _) = serve(response, 100);
+            let result = block_on(send(&mut stream, &get, &mut buf));
+            assert!(matches!(result, Err(ClientError::Malformed)));
+        }
+
+        let (mut stream, _) = serve(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nshort", 100);
+        block_on(async {
+            let mut response = send(&mut stream, &get, &mut buf).await.unwrap();
+            assert_eq!(response.read(&mut body).await, Ok(4));
+            assert_eq!(response.read(&mut body).await, Ok(1));
+            assert_eq!(
+                response.read(&mut body).await,
+                Err(ClientError::UnexpectedEof)
+            );
+        });
+
+        let (mut stream, _) = serve(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello", 100);
+        block_on(async {
+            let mut response = send(&mut stream, &get, &mut buf).await.unwrap();
+            assert_eq!(
+                response.read_to_end(&mut body).await,
+                Err(ClientError::BufferTooSmall)
+            );
+        });
+
+        // The request head fits, the response head does not
+        let (mut stream, _) = serve(
+            b"HTTP/1.1 200 OK\r\nServer: a server name longer than the request\r\n\r\n",
+            100,
+        );
+        let result = block_on(send(&mut stream, &get, &mut buf[..48]));
+        assert!(matches!(result, Err(ClientError::BufferTooSmall)));
+    }
+
+    #[test]
+    fn splits_urls() {
+        assert_eq!(
+            Url::parse("http://192.168.1.10:8000/fw/wifi.bin?v=2"),
+            Some(Url {
+                host: "192.168.1.10",
+                port: 8000,
+                authority: "192.168.1.10:8000",
+                path: "/fw/wifi.bin?v=2",
+            })
+        );
+        let url = Url::parse("HTTP://updates.local").unwrap();
+        assert_eq!((url.host, url.port, url.path), ("updates.local", 80, "/"));
+
+        for invalid in [
+            "https://example.com/",
+            "http://",
+            "http://:80/",
+            "http://host:0/",
+            "http://host:99999/",
+            "http://user@host/",
+            "http://host?query",
+            "ftp://host/",
+        ] {
+            assert_eq!(Url::parse(invalid), None, "{invalid}");
+        }
+    }
+}
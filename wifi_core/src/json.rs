@@ -0,0 +1,420 @@
+//! Minimal JSON support.
+//!
+//! Responses are written with `write!` and [`JsonStr`], which quotes and
+//! escapes strings. Requests are read with [`parse_object`], which walks the
+//! fields of a flat object whose values are scalars or arrays of scalars;
+//! that is all the device accepts, so deeper nesting is rejected.
+
+use core::fmt::{self, Write as _};
+
+/// Reasons why a document could not be read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum JsonError {
+    /// The document is not valid JSON
+    Syntax,
+    /// Objects or arrays are nested deeper than supported
+    TooDeep,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::Syntax => f.write_str("invalid JSON"),
+            JsonError::TooDeep => f.write_str("JSON nested too deeply"),
+        }
+    }
+}
+
+/// Formats a string as a quoted JSON string
+pub struct JsonStr<'a>(pub &'a str);
+
+impl fmt::Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if c < ' ' => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+/// A value read from a document
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value<'a> {
+    /// `null`
+    Null,
+    /// `true` or `false`
+    Bool(bool),
+    /// A number, as written
+    Number(&'a str),
+    /// A string
+    String(JsonString<'a>),
+    /// An array of scalars
+    Array(Array<'a>),
+}
+
+impl Value<'_> {
+    /// The value as an unsigned integer, if it is one in range
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            Value::Number(number) => number.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// The value as a boolean, if it is one
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+/// A string value, still escaped as in the document
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JsonString<'a>(&'a str);
+
+impl JsonString<'_> {
+    /// The string as written between the quotes
+    pub fn raw(&self) -> &str {
+        self.0
+    }
+
+    /// Decodes the escapes.
+    ///
+    /// Returns `None` for invalid `\u` escapes or results longer than `N`
+    /// bytes.
+    pub fn decode<const N: usize>(&self) -> Option<heapless::String<N>> {
+        let mut out = heapless::String::new();
+        let mut chars = self.0.chars();
+        while let Some(c) = chars.next() {
+            let decoded = match c {
+                '\\' => match chars.next()? {
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'u' => {
+                        let high = hex4(&mut chars)?;
+                        if (0xd800..0xdc00).contains(&high) {
+                            if (chars.next()?, chars.next()?) != ('\\', 'u') {
+                                return None;
+                            }
+                            let low = hex4(&mut chars)?;
+                            if !(0xdc00..0xe000).contains(&low) {
+                                return None;
+                            }
+                            char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00))?
+                        } else {
+                            char::from_u32(high)?
+                        }
+                    }
+                    c => c,
+                },
+                c => c,
+            };
+            out.push(decoded).ok()?;
+        }
+        Some(out)
+    }
+}
+
+/// Reads four hex digits
+fn hex4(chars: &mut core::str::Chars<'_>) -> Option<u32> {
+    (0..4).try_fold(0, |value, _| Some(value << 4 | chars.next()?.to_digit(16)?))
+}
+
+/// An array of scalars, checked when the document was read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Array<'a>(&'a str);
+
+impl<'a> Array<'a> {
+    /// Iterates over the elements
+    pub fn iter(&self) -> impl Iterator<Item = Value<'a>> + use<'a> {
+        let mut lexer = Lexer::new(self.0);
+        let mut first = true;
+        core::iter::from_fn(move || {
+            if lexer.at_end() || (!first && !lexer.eat(b',')) {
+                return None;
+            }
+            first = false;
+            lexer.value(false).ok()
+        })
+    }
+}
+
+/// Calls `field` with the name and value of each field of the object in
+/// `json`, in document order.
+///
+/// Names are passed as written, without decoding escapes.
+///
+/// # Errors
+///
+/// Returns [`JsonError`] if `json` is not a flat object, or the first error
+/// returned by `field`.
+pub fn parse_object<'a, E: From<JsonError>>(
+    json: &'a str,
+    mut field: impl FnMut(&'a str, Value<'a>) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut lexer = Lexer::new(json);
+    if !lexer.eat(b'{') {
+        return Err(JsonError::Syntax.into());
+    }
+    if !lexer.eat(b'}') {
+        loop {
+            if !lexer.eat(b'"') {
+                return Err(JsonError::Syntax.into());
+            }
+            let name = lexer.string()?;
+            if !lexer.eat(b':') {
+                return Err(JsonError::Syntax.into());
+            }
+            let value = lexer.value(true)?;
+            field(name, value)?;
+            if lexer.eat(b'}') {
+                break;
+            }
+            if !lexer.eat(b',') {
+                return Err(JsonError::Syntax.into());
+            }
+        }
+    }
+    match lexer.at_end() {
+        true => Ok(()),
+        false => Err(JsonError::Syntax.into()),
+    }
+}
+
+/// Splits a document into values
+struct Lexer<'a> {
+    json: &'a str,
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(json: &'a str) -> Self {
+        Self { json, pos: 0 }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.json.as_bytes().get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_whitespace();
+        self.pos == self.json.len()
+    }
+
+    /// Consumes `byte` if it comes next
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        let found = self.json.as_bytes().get(self.pos) == Some(&byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    /// Reads a value; arrays only if `array` is set
+    fn value(&mut self, array: bool) -> Result<Value<'a>, JsonError> {
+        self.skip_whitespace();
+        let rest = &self.json[self.pos..];
+        let value = match rest.as_bytes().first() {
+            Some(b'"') => {
+                self.pos += 1;
+                Value::String(JsonString(self.string()?))
+            }
+            Some(b'[') if array => {
+                self.pos += 1;
+                let start = self.pos;
+                if !self.eat(b']') {
+                    loop {
+                        self.value(false)?;
+                        if self.eat(b']') {
+                            break;
+                        }
+                        if !self.eat(b',') {
+                            return Err(JsonError::Syntax);
+                        }
+                    }
+                }
+                Value::Array(Array(&self.json[start..self.pos - 1]))
+            }
+            Some(b'[' | b'{') => return Err(JsonError::TooDeep),
+            Some(b'-' | b'0'..=b'9') => Value::Number(self.number()?),
+            _ => {
+                let (value, len) = if rest.starts_with("null") {
+                    (Value::Null, 4)
+                } else if rest.starts_with("true") {
+                    (Value::Bool(true), 4)
+                } else if rest.starts_with("false") {
+                    (Value::Bool(false), 5)
+                } else {
+                    return Err(JsonError::Syntax);
+                };
+                self.pos += len;
+                value
+            }
+        };
+        Ok(value)
+    }
+
+    /// Reads the rest of a string after its opening quote
+    fn string(&mut self) -> Result<&'a str, JsonError> {
+        let start = self.pos;
+        let mut bytes = self.json.as_bytes()[start..].iter().enumerate();
+        while let Some((i, &byte)) = bytes.next() {
+            match byte {
+                b'"' => {
+                    self.pos = start + i + 1;
+                    return Ok(&self.json[start..start + i]);
+                }
+                b'\\' => match bytes.next() {
+                    Some((_, b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't')) => {}
+                    Some((_, b'u')) => {
+                        for _ in 0..4 {
+                            match bytes.next() {
+                                Some((_, digit)) if digit.is_ascii_hexdigit() => {}
+                                _ => return Err(JsonError::Syntax),
+                            }
+                        }
+                    }
+                    _ => return Err(JsonError::Syntax),
+                },
+                0..0x20 => return Err(JsonError::Syntax),
+                _ => {}
+            }
+        }
+        Err(JsonError::Syntax)
+    }
+
+    /// Reads a number: an optional minus, an integer part without leading
+    /// zeros, and optional fraction and exponent
+    fn number(&mut self) -> Result<&'a str, JsonError> {
+        let bytes = self.json.as_bytes();
+        let start = self.pos;
+        let digits = |pos: &mut usize| {
+            let from = *pos;
+            while bytes.get(*pos).is_some_and(u8::is_ascii_digit) {
+                *pos += 1;
+            }
+            *pos > from
+        };
+        let mut pos = start;
+        if bytes.get(pos) == Some(&b'-') {
+            pos += 1;
+        }
+        let int_start = pos;
+        if !digits(&mut pos) || (bytes[int_start] == b'0' && pos - int_start > 1) {
+            return Err(JsonError::Syntax);
+        }
+        if bytes.get(pos) == Some(&b'.') {
+            pos += 1;
+            if !digits(&mut pos) {
+                return Err(JsonError::Syntax);
+            }
+        }
+        if let Some(b'e' | b'E') = bytes.get(pos) {
+            pos += 1;
+            if let Some(b'+' | b'-') = bytes.get(pos) {
+                pos += 1;
+            }
+            if !digits(&mut pos) {
+                return Err(JsonError::Syntax);
+            }
+        }
+        self.pos = pos;
+        Ok(&self.json[start..pos])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(
+            JsonStr("say \"hi\"\\\n\u{1}é").to_string(),
+            "\"say \\\"hi\\\"\\\\\\n\\u0001é\""
+        );
+    }
+
+    #[test]
+    fn reads_flat_objects() {
+        let json = r#" { "a": 10, "b" : [1, 6,11], "c":"x\"y\u00e9\ud83d\ude00",
+            "d": null, "e": true, "f": -1.5e3, "g": [] } "#;
+        let mut fields = Vec::new();
+        parse_object::<JsonError>(json, |name, value| {
+            fields.push((name, value));
+            Ok(())
+        })
+        .unwrap();
+        let names: Vec<_> = fields.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["a", "b", "c", "d", "e", "f", "g"]);
+        assert_eq!(fields[0].1.as_u32(), Some(10));
+        let Value::Array(channels) = fields[1].1 else {
+            panic!("not an array");
+        };
+        let channels: Vec<_> = channels.iter().filter_map(|v| v.as_u32()).collect();
+        assert_eq!(channels, [1, 6, 11]);
+        let Value::String(string) = fields[2].1 else {
+            panic!("not a string");
+        };
+        assert_eq!(string.decode::<16>().as_deref(), Some("x\"yé😀"));
+        assert_eq!(string.decode::<4>(), None);
+        assert_eq!(fields[3].1, Value::Null);
+        assert_eq!(fields[4].1.as_bool(), Some(true));
+        assert_eq!(fields[5].1, Value::Number("-1.5e3"));
+        assert_eq!(fields[5].1.as_u32(), None);
+        let Value::Array(empty) = fields[6].1 else {
+            panic!("not an array");
+        };
+        assert_eq!(empty.iter().count(), 0);
+        assert_eq!(parse_object::<JsonError>("{}", |_, _| Ok(())), Ok(()));
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        let ignore = |_, _| Ok(());
+        for json in [
+            "",
+            "[]",
+            "{",
+            "{\"a\"}",
+            "{\"a\":1,}",
+            "{\"a\":01}",
+            "{\"a\":1.}",
+            "{\"a\":\"\\x\"}",
+            "{\"a\":\"open}",
+            "{\"a\":nul}",
+            "{\"a\":[1,]}",
+            "{\"a\":1} x",
+        ] {
+            assert_eq!(parse_object(json, ignore), Err(JsonError::Syntax), "{json}");
+        }
+        assert_eq!(
+            parse_object("{\"a\":{\"b\":1}}", ignore),
+            Err(JsonError::TooDeep)
+        );
+        assert_eq!(
+            parse_object("{\"a\":[[1]]}", ignore),
+            Err(JsonError::TooDeep)
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Hardware independent core of the ESP32 WiFi scanning library
+//!
+//! This crate holds the data model and the pure logic used by the `wifi` firmware crate.
+//! It has no dependency on `esp-hal` or `esp-radio`, so it builds for the host as well as
+//! for the ESP32, and its unit tests run with a plain `cargo test`.
+//!
+//! ## Features
+//!
+//! - `defmt`: derive `defmt::Format` for the public types
+
+#![no_std]
+#![warn(missing_docs)]
+
+#[cfg(test)]
+extern crate std;
+
+/// Typed WiFi scan results
+pub mod report;
+
+/// Scanner configuration and profiles
+pub mod scan_config;
+
+/// Access point tracking across scans
+pub mod tracker;
+
+/// Channel survey and best-channel recommendation
+pub mod channel_survey;
+
+/// Evil-twin and rogue access point detection
+pub mod rogue;
+
+/// Station connection state machine and reconnect backoff
+pub mod station;
+
+/// Known network profiles and network selection
+pub mod profiles;
+
+/// IPv4 configuration: DHCP or static address
+pub mod net_config;
+
+/// Wear-levelled key-value store on NOR flash
+pub mod flash_kv;
+
+/// Typed settings kept in the flash key-value store
+pub mod settings;
+
+/// Minimal HTTP/1.1 request parsing and response formatting
+pub mod http;
+
+/// HTTP/1.1 client over any async byte stream
+pub mod http_client;
+
+/// Minimal JSON writing and reading
+pub mod json;
+
+/// JSON API of the HTTP server
+pub mod api;
+
+/// MQTT 3.1.1 client over any async byte stream
+pub mod mqtt;
+
+/// Wall-clock time from uptime and time synchronization
+pub mod clock;
+
+/// SNTP request and response packets
+pub mod sntp;
+
+/// mDNS responder and DNS-SD service records
+pub mod mdns;
+
+/// Ed25519 signatures
+pub mod ed25519;
+
+/// Heap regions and sizes
+pub mod heap_layout;
+
+/// Heap usage statistics and low-memory warnings
+pub mod heap_stats;
+
+/// Heartbeat deadlines of supervised tasks and escalation of missed ones
+pub mod task_watchdog;
+
+/// Line editing and command registry of the serial shell
+pub mod shell;
+
+/// ESP-IDF application image checks
+pub mod app_image;
+
+/// A/B firmware slots and rollback through the OTA data partition
+pub mod ota;
+
+/// DHCP server for the provisioning access point
+pub mod dhcp_server;
+
+/// Catch-all DNS responder for the captive portal
+pub mod captive_dns;
+
+/// Captive provisioning portal page and form
+pub mod portal;
+
+/// BLE provisioning service protocol
+pub mod ble_provisioning;
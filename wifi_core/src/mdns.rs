@@ -0,0 +1,629 @@
+//! mDNS responder with DNS-SD service discovery.
+//!
+//! A [`Responder`] answers multicast DNS queries (RFC 6762) for
+//! `<name>.local` with the device's IPv4 address, and advertises its
+//! [`Service`]s with DNS-SD (RFC 6763): a browser asking for
+//! `_http._tcp.local` gets a `PTR` to `<name>._http._tcp.local`, along with
+//! the `SRV` record giving host and port, the `TXT` record with the
+//! service's key/value pairs, and the address.
+//!
+//! The responder only builds packets; receiving queries on
+//! [`MDNS_GROUP`]:[`MDNS_PORT`] and sending the responses is up to the
+//! caller.
+
+use core::str;
+
+/// UDP port of mDNS
+pub const MDNS_PORT: u16 = 5353;
+
+/// IPv4 multicast group of mDNS
+pub const MDNS_GROUP: [u8; 4] = [224, 0, 0, 251];
+
+/// Time-to-live of address and `SRV` records in seconds (RFC 6762 section 10)
+pub const HOST_TTL_SECS: u32 = 120;
+
+/// Time-to-live of `PTR` and `TXT` records in seconds (RFC 6762 section 10)
+pub const SERVICE_TTL_SECS: u32 = 4500;
+
+/// Maximum number of services a responder advertises
+pub const MAX_SERVICES: usize = 8;
+
+/// Largest time-to-live in a legacy unicast response (RFC 6762 section 6.7)
+const LEGACY_TTL_SECS: u32 = 10;
+
+/// Longest domain name
+const MAX_NAME_LEN: usize = 255;
+
+/// Name of the DNS-SD service type enumeration
+const SERVICES_NAME: &str = "_services._dns-sd._udp";
+
+const HEADER_LEN: usize = 12;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+
+/// Top bit of the class: cache-flush in records, unicast-response in
+/// questions
+const CLASS_TOP_BIT: u16 = 0x8000;
+
+/// Flags of a response: QR and AA set, opcode QUERY, no error
+const RESPONSE_FLAGS: u16 = 0x8400;
+
+/// A service advertised with DNS-SD
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Service<'a> {
+    /// Service type and protocol, e.g. `_http._tcp`
+    pub service_type: &'a str,
+    /// Port the service listens on
+    pub port: u16,
+    /// Key/value pairs of the `TXT` record
+    pub txt: &'a [(&'a str, &'a str)],
+}
+
+/// A response to send
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Response {
+    /// Length of the response
+    pub len: usize,
+    /// Whether the response goes to the querier only, rather than to
+    /// [`MDNS_GROUP`]
+    pub unicast: bool,
+}
+
+/// Which records a response holds, with one bit per service in the masks
+#[derive(Clone, Copy, Default)]
+struct Records {
+    host: bool,
+    /// Service type enumeration `PTR`s
+    types: u8,
+    /// Service instance `PTR`s
+    pointers: u8,
+    services: u8,
+    texts: u8,
+}
+
+impl Records {
+    fn is_empty(&self) -> bool {
+        !self.host && self.types | self.pointers | self.services | self.texts == 0
+    }
+
+    fn count(&self) -> u16 {
+        let services = self.types.count_ones()
+            + self.pointers.count_ones()
+            + self.services.count_ones()
+            + self.texts.count_ones();
+        u16::from(self.host) + services as u16
+    }
+
+    /// Records that go along with these ones, without those already here
+    fn additional(&self) -> Records {
+        let services = self.services | self.pointers;
+        Records {
+            host: !self.host && services != 0,
+            types: 0,
+            pointers: 0,
+            services: self.pointers & !self.services,
+            texts: self.pointers & !self.texts,
+        }
+    }
+}
+
+/// Answers mDNS queries for a device and its services
+#[derive(Clone, Copy, Debug)]
+pub struct Responder<'a> {
+    name: &'a str,
+    services: &'a [Service<'a>],
+}
+
+impl<'a> Responder<'a> {
+    /// Creates a responder for `<name>.local`, which is also the instance
+    /// name of each service.
+    ///
+    /// `name` must be a single DNS label: up to 63 bytes, no dots. Only the
+    /// first [`MAX_SERVICES`] services are advertised.
+    pub fn new(name: &'a str, services: &'a [Service<'a>]) -> Self {
+        Self {
+            name,
+            services: &services[..services.len().min(MAX_SERVICES)],
+        }
+    }
+
+    /// Answers `query` for a device at `address` and writes the response to
+    /// `out`.
+    ///
+    /// A `legacy_unicast` query, one that does not come from
+    /// [`MDNS_PORT`], is from a plain DNS resolver: its response repeats the
+    /// query identifier and questions, and goes back to it directly.
+    ///
+    /// Returns `None` if there is nothing to answer, the packet is not a
+    /// query, or `out` is too small.
+    pub fn answer(
+        &self,
+        query: &[u8],
+        address: [u8; 4],
+        legacy_unicast: bool,
+        out: &mut [u8],
+    ) -> Option<Response> {
+        let header = query.get(..HEADER_LEN)?;
+        let flags = u16::from_be_bytes([header[2], header[3]]);
+        // QR must be clear and the opcode QUERY
+        if flags & 0xF800 != 0 {
+            return None;
+        }
+
+        let mut records = Records::default();
+        let mut unicast = legacy_unicast;
+        let mut offset = HEADER_LEN;
+        for _ in 0..u16::from_be_bytes([header[4], header[5]]) {
+            let mut name = heapless::String::<MAX_NAME_LEN>::new();
+            offset = read_name(query, offset, &mut name)?;
+            let fields = query.get(offset..offset + 4)?;
+            offset += 4;
+            let qtype = u16::from_be_bytes([fields[0], fields[1]]);
+            let qclass = u16::from_be_bytes([fields[2], fields[3]]);
+            unicast |= qclass & CLASS_TOP_BIT != 0;
+            if !matches!(qclass & !CLASS_TOP_BIT, CLASS_IN | CLASS_ANY) {
+                continue;
+            }
+            self.select(&name, qtype, &mut records);
+        }
+        if records.is_empty() {
+            return None;
+        }
+
+        let mut writer = Writer { buf: out, len: 0 };
+        if legacy_unicast {
+            writer.bytes(&header[..2])?;
+        } else {
+            writer.u16(0)?;
+        }
+        writer.u16(RESPONSE_FLAGS)?;
+        let additional = records.additional();
+        if legacy_unicast {
+            writer.bytes(&header[4..6])?;
+        } else {
+            writer.u16(0)?;
+        }
+        writer.u16(records.count())?;
+        writer.u16(0)?;
+        writer.u16(additional.count())?;
+        if legacy_unicast {
+            // Compression pointers in the questions stay valid, as they are
+            // at the same offset in the response
+            writer.bytes(&query[HEADER_LEN..offset])?;
+        }
+        self.write_records(&mut writer, &records, address, legacy_unicast)?;
+        self.write_records(&mut writer, &additional, address, legacy_unicast)?;
+        Some(Response {
+            len: writer.len,
+            unicast,
+        })
+    }
+
+    /// Writes an unsolicited response with every record to `out`, to
+    /// announce the device at `address` on the network.
+    ///
+    /// Returns the length of the response, or `None` if `out` is too small.
+    pub fn announce(&self, address: [u8; 4], out: &mut [u8]) -> Option<usize> {
+        let all = self.all_services();
+        let records = Records {
+            host: true,
+            types: all,
+            pointers: all,
+            services: all,
+            texts: all,
+        };
+        let mut writer = Writer { buf: out, len: 0 };
+        writer.u16(0)?;
+        writer.u16(RESPONSE_FLAGS)?;
+        writer.u16(0)?;
+        writer.u16(records.count())?;
+        writer.u16(0)?;
+        writer.u16(0)?;
+        self.write_records(&mut writer, &records, address, false)?;
+        Some(writer.len)
+    }
+
+    /// Mask with the bits of all services set
+    fn all_services(&self) -> u8 {
+        ((1u16 << self.services.len()) - 1) as u8
+    }
+
+    /// Adds the records that answer a question to `records`
+    fn select(&self, name: &str, qtype: u16, records: &mut Records) {
+        let any = qtype == TYPE_ANY;
+        if (any || qtype == TYPE_A) && name_eq(name, &[self.name, "local"]) {
+            records.host = true;
+        }
+        let pointer = any || qtype == TYPE_PTR;
+        if pointer && name_eq(name, &[SERVICES_NAME, "local"]) {
+            records.types = self.all_services();
+        }
+        for (i, service) in self.services.iter().enumerate() {
+            let bit = 1 << i;
+            if pointer && name_eq(name, &[service.service_type, "local"]) {
+                records.pointers |= bit;
+            }
+            if name_eq(name, &[self.name, service.service_type, "local"]) {
+                if any || qtype == TYPE_SRV {
+                    records.services |= bit;
+                }
+                if any || qtype == TYPE_TXT {
+                    records.texts |= bit;
+                }
+            }
+        }
+    }
+
+    /// Writes the records selected in `records`
+    fn write_records(
+        &self,
+        writer: &mut Writer<'_>,
+        records: &Records,
+        address: [u8; 4],
+        legacy_unicast: bool,
+    ) -> Option<()> {
+        let ttl = |ttl: u32| {
+            if legacy_unicast {
+                ttl.min(LEGACY_TTL_SECS)
+            } else {
+                ttl
+            }
+        };
+        // Records only this device has ask caches to drop older copies,
+        // except in legacy unicast responses
+        let unique = !legacy_unicast;
+        let host = [self.name, "local"];
+
+        if records.host {
+            writer.record(&host, TYPE_A, unique, ttl(HOST_TTL_SECS), |w| {
+                w.bytes(&address)
+            })?;
+        }
+        for (i, service) in self.services.iter().enumerate() {
+            let bit = 1 << i;
+            let service_name = [service.service_type, "local"];
+            let instance = [self.name, service.service_type, "local"];
+            if records.types & bit != 0 {
+                let types = [SERVICES_NAME, "local"];
+                writer.record(&types, TYPE_PTR, false, ttl(SERVICE_TTL_SECS), |w| {
+                    w.name(&service_name)
+                })?;
+            }
+            if records.pointers & bit != 0 {
+                writer.record(&service_name, TYPE_PTR, false, ttl(SERVICE_TTL_SECS), |w| {
+                    w.name(&instance)
+                })?;
+            }
+            if records.services & bit != 0 {
+                writer.record(&instance, TYPE_SRV, unique, ttl(HOST_TTL_SECS), |w| {
+                    // Priority and weight
+                    w.u16(0)?;
+                    w.u16(0)?;
+                    w.u16(service.port)?;
+                    w.name(&host)
+                })?;
+            }
+            if records.texts & bit != 0 {
+                writer.record(&instance, TYPE_TXT, unique, ttl(SERVICE_TTL_SECS), |w| {
+                    if service.txt.is_empty() {
+                        // A TXT record holds at least one string
+                        return w.bytes(&[0]);
+                    }
+                    for (key, value) in service.txt {
+                        let len = u8::try_from(key.len() + 1 + value.len()).ok()?;
+                        w.bytes(&[len])?;
+                        w.bytes(key.as_bytes())?;
+                        w.bytes(b"=")?;
+                        w.bytes(value.as_bytes())?;
+                    }
+                    Some(())
+                })?;
+            }
+        }
+        Some(())
+    }
+}
+
+/// Checks whether the dotted `name` is `parts` joined with dots, ignoring
+/// ASCII case
+fn name_eq(name: &str, parts: &[&str]) -> bool {
+    let mut rest = name.as_bytes();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            match rest.split_first() {
+                Some((b'.', tail)) => rest = tail,
+                _ => return false,
+            }
+        }
+        match rest.split_at_checked(part.len()) {
+            Some((head, tail)) if head.eq_ignore_ascii_case(part.as_bytes()) => rest = tail,
+            _ => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+/// Reads the name at `offset` of `packet` into `out` as dotted labels,
+/// following compression pointers.
+///
+/// Returns the offset after the name, or `None` if the name is invalid or
+/// longer than `out`.
+fn read_name(
+    packet: &[u8],
+    mut offset: usize,
+    out: &mut heapless::String<MAX_NAME_LEN>,
+) -> Option<usize> {
+    let mut end = None;
+    // Each pointer must go back, so a loop of pointers ends
+    let mut limit = offset;
+    loop {
+        let len = *packet.get(offset)?;
+        match len & 0xC0 {
+            0x00 if len == 0 => return Some(end.unwrap_or(offset + 1)),
+            0x00 => {
+                let label = packet.get(offset + 1..offset + 1 + usize::from(len))?;
+                if !out.is_empty() {
+                    out.push('.').ok()?;
+                }
+                out.push_str(str::from_utf8(label).ok()?).ok()?;
+                offset += 1 + usize::from(len);
+            }
+            0xC0 => {
+                let target = usize::from(len & 0x3F) << 8 | usize::from(*packet.get(offset + 1)?);
+                if target >= limit {
+                    return None;
+                }
+                end.get_or_insert(offset + 2);
+                limit = target;
+                offset = target;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Appends to a response
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        let end = self.len + bytes.len();
+        self.buf.get_mut(self.len..end)?.copy_from_slice(bytes);
+        self.len = end;
+        Some(())
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// Writes the name made of the dotted `parts`, without compression
+    fn name(&mut self, parts: &[&str]) -> Option<()> {
+        for label in parts.iter().flat_map(|part| part.split('.')) {
+            let len = u8::try_from(label.len()).ok().filter(|&len| len < 64)?;
+            self.bytes(&[len])?;
+            self.bytes(label.as_bytes())?;
+        }
+        self.bytes(&[0])
+    }
+
+    /// Writes a resource record whose data is written by `data`
+    fn record(
+        &mut self,
+        name: &[&str],
+        rtype: u16,
+        unique: bool,
+        ttl: u32,
+        data: impl FnOnce(&mut Self) -> Option<()>,
+    ) -> Option<()> {
+        self.name(name)?;
+        self.u16(rtype)?;
+        self.u16(if unique {
+            CLASS_IN | CLASS_TOP_BIT
+        } else {
+            CLASS_IN
+        })?;
+        self.bytes(&ttl.to_be_bytes())?;
+        let length_at = self.len;
+        self.u16(0)?;
+        data(self)?;
+        let data_len = u16::try_from(self.len - length_at - 2).ok()?;
+        self.buf[length_at..length_at + 2].copy_from_slice(&data_len.to_be_bytes());
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use std::vec::Vec;
+
+    const ADDRESS: [u8; 4] = [192, 168, 1, 50];
+
+    const SERVICES: [Service<'static>; 2] = [
+        Service {
+            service_type: "_http._tcp",
+            port: 80,
+            txt: &[("path", "/api/status")],
+        },
+        Service {
+            service_type: "_espscan._tcp",
+            port: 80,
+            txt: &[("version", "0.1.0"), ("chip", "esp32")],
+        },
+    ];
+
+    /// A record of a response: name, type, class, TTL and data
+    type Record = (String, u16, u16, u32, Vec<u8>);
+
+    fn query(id: u16, questions: &[(&str, u16, u16)]) -> Vec<u8> {
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&[0, 0, 0, questions.len() as u8, 0, 0, 0, 0, 0, 0]);
+        for (name, qtype, qclass) in questions {
+            for label in name.split('.') {
+                packet.push(label.len() as u8);
+                packet.extend_from_slice(label.as_bytes());
+            }
+            packet.push(0);
+            packet.extend_from_slice(&qtype.to_be_bytes());
+            packet.extend_from_slice(&qclass.to_be_bytes());
+        }
+        packet
+    }
+
+    /// Splits a response into its header counts and records
+    fn parse(packet: &[u8]) -> ([u16; 4], Vec<Record>) {
+        let field = |at: usize| u16::from_be_bytes([packet[at], packet[at + 1]]);
+        let counts = [field(4), field(6), field(8), field(10)];
+        let mut offset = HEADER_LEN;
+        for _ in 0..counts[0] {
+            offset = read_name(packet, offset, &mut heapless::String::new()).unwrap() + 4;
+        }
+        let mut records = Vec::new();
+        for _ in 0..counts[1] + counts[3] {
+            let mut name = heapless::String::new();
+            offset = read_name(packet, offset, &mut name).unwrap();
+            let ttl = u32::from_be_bytes(packet[offset + 4..offset + 8].try_into().unwrap());
+            let len = usize::from(field(offset + 8));
+            let data = packet[offset + 10..offset + 10 + len].to_vec();
+            records.push((
+                name.as_str().into(),
+                field(offset),
+                field(offset + 2),
+                ttl,
+                data,
+            ));
+            offset += 10 + len;
+        }
+        assert_eq!(offset, packet.len());
+        (counts, records)
+    }
+
+    #[test]
+    fn answers_host_and_service_queries() {
+        let responder = Responder::new("espscan-a1b2c3", &SERVICES);
+        let mut out = [0; 1024];
+
+        let request = query(0, &[("ESPSCAN-A1B2C3.local", TYPE_A, CLASS_IN)]);
+        let response = responder
+            .answer(&request, ADDRESS, false, &mut out)
+            .unwrap();
+        assert!(!response.unicast);
+        assert_eq!(out[..4], [0, 0, 0x84, 0]);
+        let (counts, records) = parse(&out[..response.len]);
+        assert_eq!(counts, [0, 1, 0, 0]);
+        assert_eq!(
+            records[0],
+            (
+                "espscan-a1b2c3.local".into(),
+                TYPE_A,
+                0x8001,
+                HOST_TTL_SECS,
+                ADDRESS.to_vec()
+            )
+        );
+
+        // Browsing a service type brings the instance's SRV, TXT and address
+        let request = query(0, &[("_espscan._tcp.local", TYPE_PTR, CLASS_IN)]);
+        let response = responder
+            .answer(&request, ADDRESS, false, &mut out)
+            .unwrap();
+        let (counts, records) = parse(&out[..response.len]);
+        assert_eq!(counts, [0, 1, 0, 3]);
+        let instance = "espscan-a1b2c3._espscan._tcp.local";
+        assert_eq!(records[0].0, "_espscan._tcp.local");
+        assert_eq!(records[0].2, CLASS_IN);
+        let mut target = heapless::String::new();
+        read_name(&records[0].4, 0, &mut target).unwrap();
+        assert_eq!(target, instance);
+        assert_eq!(records[1].0, "espscan-a1b2c3.local");
+        assert_eq!(records[2].0, instance);
+        assert_eq!(records[2].1, TYPE_SRV);
+        assert_eq!(records[2].4[..6], [0, 0, 0, 0, 0, 80]);
+        assert_eq!(records[3].1, TYPE_TXT);
+        assert_eq!(records[3].4, b"\x0dversion=0.1.0\x0achip=esp32");
+
+        // Service type enumeration lists both types
+        let request = query(0, &[("_services._dns-sd._udp.local", TYPE_PTR, CLASS_IN)]);
+        let response = responder
+            .answer(&request, ADDRESS, false, &mut out)
+            .unwrap();
+        let (counts, _) = parse(&out[..response.len]);
+        assert_eq!(counts, [0, 2, 0, 0]);
+
+        let len = responder.announce(ADDRESS, &mut out).unwrap();
+        let (counts, records) = parse(&out[..len]);
+        assert_eq!(counts, [0, 9, 0, 0]);
+        assert_eq!(records[0].1, TYPE_A);
+    }
+
+    #[test]
+    fn answers_unicast_questions() {
+        let responder = Responder::new("espscan-a1b2c3", &SERVICES);
+        let mut out = [0; 1024];
+
+        // The unicast-response bit of the question
+        let request = query(0, &[("espscan-a1b2c3.local", TYPE_ANY, 0x8001)]);
+        let response = responder
+            .answer(&request, ADDRESS, false, &mut out)
+            .unwrap();
+        assert!(response.unicast);
+
+        // A resolver querying from another port gets its ID and question back
+        let request = query(
+            0x1234,
+            &[("espscan-a1b2c3._http._tcp.local", TYPE_SRV, CLASS_IN)],
+        );
+        let response = responder.answer(&request, ADDRESS, true, &mut out).unwrap();
+        assert!(response.unicast);
+        assert_eq!(out[..2], [0x12, 0x34]);
+        assert_eq!(out[12..request.len()], request[12..]);
+        let (counts, records) = parse(&out[..response.len]);
+        assert_eq!(counts, [1, 1, 0, 1]);
+        assert_eq!(records[0].1, TYPE_SRV);
+        assert_eq!(records[0].2, CLASS_IN);
+        assert_eq!(records[0].3, LEGACY_TTL_SECS);
+        assert_eq!(records[1].1, TYPE_A);
+    }
+
+    #[test]
+    fn ignores_other_names_and_packets() {
+        let responder = Responder::new("espscan-a1b2c3", &SERVICES);
+        let mut out = [0; 1024];
+
+        let other = query(0, &[("printer.local", TYPE_A, CLASS_IN)]);
+        assert_eq!(responder.answer(&other, ADDRESS, false, &mut out), None);
+        let txt_for_host = query(0, &[("espscan-a1b2c3.local", TYPE_TXT, CLASS_IN)]);
+        assert_eq!(
+            responder.answer(&txt_for_host, ADDRESS, false, &mut out),
+            None
+        );
+
+        let mut response = query(0, &[("espscan-a1b2c3.local", TYPE_A, CLASS_IN)]);
+        response[2] = 0x84;
+        assert_eq!(responder.answer(&response, ADDRESS, false, &mut out), None);
+
+        // A pointer to itself
+        let mut looped = query(0, &[]);
+        looped[5] = 1;
+        looped.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+        assert_eq!(responder.answer(&looped, ADDRESS, false, &mut out), None);
+
+        let request = query(0, &[("espscan-a1b2c3.local", TYPE_A, CLASS_IN)]);
+        assert_eq!(
+            responder.answer(&request, ADDRESS, false, &mut out[..20]),
+            None
+        );
+    }
+}
@@ -0,0 +1,878 @@
+//! MQTT 3.1.1 client.
+//!
+//! A [`Client`] speaks MQTT over any connection implementing the
+//! `embedded-io-async` traits, such as an `embassy_net` TCP socket. It
+//! supports QoS 0 and 1 in both directions, subscriptions and keepalive
+//! pings; QoS 2 is not supported.
+//!
+//! The client never waits for the broker on its own: [`Client::publish`],
+//! [`Client::subscribe`] and [`Client::ping`] only send, and everything the
+//! broker sends back, acknowledgements included, comes out of
+//! [`Client::poll`] as an [`Event`]. Incoming QoS 1 messages are acknowledged
+//! by the next call that talks to the broker, [`Client::poll`] included.
+//!
+//! [`Client::poll`] keeps partially received packets and the acknowledgement
+//! still to send in the client, and only waits for the connection while
+//! reading, so it can be raced against other futures and cancelled without
+//! losing data.
+
+use core::fmt;
+use core::ops::Range;
+use core::str;
+
+use embedded_io_async::{Read, Write};
+
+/// Default port of unencrypted MQTT
+pub const DEFAULT_PORT: u16 = 1883;
+
+/// Control packet types, in the high nibble of the first byte
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// Longest fixed header: type byte and four bytes of remaining length
+const MAX_HEADER_LEN: usize = 5;
+
+/// Quality of service of a message
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum QoS {
+    /// Delivered at most once, without acknowledgement
+    #[default]
+    AtMostOnce,
+    /// Delivered at least once; the receiver acknowledges it
+    AtLeastOnce,
+}
+
+/// Reasons why an MQTT operation failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MqttError<E> {
+    /// The connection failed
+    Io(E),
+    /// A packet does not fit in the client's buffers
+    BufferTooSmall,
+    /// The broker sent an invalid packet
+    Malformed,
+    /// The broker sent a packet the client does not expect, e.g. a QoS 2
+    /// message
+    Protocol,
+    /// The broker closed the connection
+    UnexpectedEof,
+    /// The broker refused the connection with this return code
+    ConnectionRefused(u8),
+}
+
+impl<E: fmt::Debug> fmt::Display for MqttError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::Io(e) => write!(f, "connection error: {:?}", e),
+            MqttError::BufferTooSmall => f.write_str("MQTT packet does not fit in the buffer"),
+            MqttError::Malformed => f.write_str("malformed MQTT packet"),
+            MqttError::Protocol => f.write_str("unexpected MQTT packet"),
+            MqttError::UnexpectedEof => f.write_str("broker closed the connection"),
+            MqttError::ConnectionRefused(code) => {
+                let reason = match code {
+                    1 => "unacceptable protocol version",
+                    2 => "client identifier rejected",
+                    3 => "server unavailable",
+                    4 => "bad user name or password",
+                    5 => "not authorized",
+                    _ => "unknown reason",
+                };
+                write!(f, "connection refused: {} ({})", reason, code)
+            }
+        }
+    }
+}
+
+/// Options of the connection to the broker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectOptions<'a> {
+    /// Identifier of the client, unique per broker
+    pub client_id: &'a str,
+    /// Longest time between two packets from the client, in seconds; 0
+    /// disables keepalive
+    pub keep_alive_secs: u16,
+    /// User name and password
+    pub credentials: Option<(&'a str, &'a [u8])>,
+    /// Discard any session the broker keeps for this client
+    pub clean_session: bool,
+}
+
+impl<'a> ConnectOptions<'a> {
+    /// Options for a clean session with a keepalive of 60 seconds
+    pub const fn new(client_id: &'a str) -> Self {
+        Self {
+            client_id,
+            keep_alive_secs: 60,
+            credentials: None,
+            clean_session: true,
+        }
+    }
+
+    /// Sets the keepalive interval in seconds
+    #[must_use]
+    pub const fn with_keep_alive_secs(mut self, keep_alive_secs: u16) -> Self {
+        self.keep_alive_secs = keep_alive_secs;
+        self
+    }
+
+    /// Sets the user name and password
+    #[must_use]
+    pub const fn with_credentials(mut self, username: &'a str, password: &'a [u8]) -> Self {
+        self.credentials = Some((username, password));
+        self
+    }
+}
+
+/// A message received on a subscription
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Message<'a> {
+    /// Topic the message was published to
+    pub topic: &'a str,
+    /// Content of the message
+    pub payload: &'a [u8],
+    /// Quality of service the broker delivered it with
+    pub qos: QoS,
+    /// Whether the broker kept the message for new subscribers
+    pub retain: bool,
+}
+
+/// Something the broker sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// A message on a subscribed topic
+    Message(Message<'a>),
+    /// The broker received the QoS 1 message with this packet identifier
+    PubAck(u16),
+    /// The broker accepted a subscription with this quality of service, or
+    /// rejected it (`None`)
+    SubAck {
+        /// Packet identifier returned by [`Client::subscribe`]
+        packet_id: u16,
+        /// Quality of service granted
+        granted: Option<QoS>,
+    },
+    /// The broker answered a ping
+    PingResp,
+}
+
+/// An MQTT session over a connection
+pub struct Client<'b, C> {
+    connection: C,
+    rx: &'b mut [u8],
+    /// Bytes received are `rx[..rx_len]`
+    rx_len: usize,
+    /// Length of the packet returned by the last poll, removed by the next
+    consumed: usize,
+    tx: &'b mut [u8],
+    /// Packet identifier of a received QoS 1 message not acknowledged yet
+    pending_ack: Option<u16>,
+    next_packet_id: u16,
+    ping_outstanding: bool,
+}
+
+impl<'b, C: Read + Write> Client<'b, C> {
+    /// Opens a session: sends CONNECT and waits for the broker's CONNACK.
+    ///
+    /// `rx` must hold the largest packet the broker sends, and `tx` the
+    /// largest one the client sends, including the topic.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MqttError::ConnectionRefused`] if the broker refuses the
+    /// session, or any other [`MqttError`] if the exchange fails.
+    pub async fn connect(
+        connection: C,
+        options: &ConnectOptions<'_>,
+        rx: &'b mut [u8],
+        tx: &'b mut [u8],
+    ) -> Result<Self, MqttError<C::Error>> {
+        let mut client = Self {
+            connection,
+            rx,
+            rx_len: 0,
+            consumed: 0,
+            tx,
+            pending_ack: None,
+            next_packet_id: 1,
+            ping_outstanding: false,
+        };
+
+        let mut flags = 0;
+        if options.clean_session {
+            flags |= 0x02;
+        }
+        if options.credentials.is_some() {
+            flags |= 0xc0;
+        }
+        client
+            .send(CONNECT << 4, |body| {
+                body.str("MQTT")?;
+                body.u8(4)?;
+                body.u8(flags)?;
+                body.u16(options.keep_alive_secs)?;
+                body.str(options.client_id)?;
+                if let Some((username, password)) = options.credentials {
+                    body.str(username)?;
+                    body.u16(u16::try_from(password.len()).ok()?)?;
+                    body.bytes(password)?;
+                }
+                Some(())
+            })
+            .await?;
+
+        let (header, body) = client.next_packet().await?;
+        let body = &client.rx[body];
+        if header >> 4 != CONNACK || body.len() != 2 {
+            return Err(MqttError::Protocol);
+        }
+        match body[1] {
+            0 => Ok(client),
+            code => Err(MqttError::ConnectionRefused(code)),
+        }
+    }
+
+    /// Publishes `payload` to `topic`.
+    ///
+    /// Returns the packet identifier of a QoS 1 message; [`Client::poll`]
+    /// returns an [`Event::PubAck`] with it once the broker has the message.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MqttError::BufferTooSmall`] if the message does not fit in
+    /// the transmit buffer, or [`MqttError::Io`].
+    pub async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> Result<Option<u16>, MqttError<C::Error>> {
+        self.send_pending_ack().await?;
+        let packet_id = match qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce => Some(self.next_packet_id()),
+        };
+        let mut header = PUBLISH << 4 | u8::from(retain);
+        if qos == QoS::AtLeastOnce {
+            header |= 0x02;
+        }
+        self.send(header, |body| {
+            body.str(topic)?;
+            if let Some(packet_id) = packet_id {
+                body.u16(packet_id)?;
+            }
+            body.bytes(payload)
+        })
+        .await?;
+        Ok(packet_id)
+    }
+
+    /// Subscribes to the topics matching `filter`, receiving messages with at
+    /// most the quality of service `qos`.
+    ///
+    /// Returns the packet identifier the broker's [`Event::SubAck`] refers to.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MqttError::BufferTooSmall`] or [`MqttError::Io`].
+    pub async fn subscribe(&mut self, filter: &str, qos: QoS) -> Result<u16, MqttError<C::Error>> {
+        self.send_pending_ack().await?;
+        let packet_id = self.next_packet_id();
+        self.send(SUBSCRIBE << 4 | 0x02, |body| {
+            body.u16(packet_id)?;
+            body.str(filter)?;
+            body.u8(qos as u8)
+        })
+        .await?;
+        Ok(packet_id)
+    }
+
+    /// Sends a ping, which keeps the session alive and checks the broker.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MqttError::Io`].
+    pub async fn ping(&mut self) -> Result<(), MqttError<C::Error>> {
+        self.send_pending_ack().await?;
+        self.send(PINGREQ << 4, |_| Some(())).await?;
+        self.ping_outstanding = true;
+        Ok(())
+    }
+
+    /// Returns `true` if the broker has not answered the last ping yet.
+    ///
+    /// A ping still outstanding after the keepalive interval means the
+    /// connection is dead.
+    pub fn ping_outstanding(&self) -> bool {
+        self.ping_outstanding
+    }
+
+    /// Ends the session cleanly and returns the connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MqttError::Io`].
+    pub async fn disconnect(mut self) -> Result<C, MqttError<C::Error>> {
+        self.send_pending_ack().await?;
+        self.send(DISCONNECT << 4, |_| Some(())).await?;
+        Ok(self.connection)
+    }
+
+    /// Waits for the next packet from the broker.
+    ///
+    /// A QoS 1 message is acknowledged by the next call that talks to the
+    /// broker, this one included, which sends the acknowledgement before
+    /// waiting. Cancelling the returned future loses neither a partially
+    /// received packet nor an acknowledgement.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MqttError::UnexpectedEof`] if the broker closed the
+    /// connection, [`MqttError::Malformed`] or [`MqttError::Protocol`] for bad
+    /// packets, [`MqttError::BufferTooSmall`] for packets larger than the
+    /// receive buffer, or [`MqttError::Io`].
+    pub async fn poll(&mut self) -> Result<Event<'_>, MqttError<C::Error>> {
+        self.send_pending_ack().await?;
+        let (header, body) = self.next_packet().await?;
+        let flags = header & 0x0f;
+        match header >> 4 {
+            PUBLISH => {
+                let mut reader = Reader(&self.rx[body.clone()]);
+                let topic_len = reader.str()?.len();
+                let qos = match flags >> 1 & 0x03 {
+                    0 => QoS::AtMostOnce,
+                    1 => QoS::AtLeastOnce,
+                    _ => return Err(MqttError::Protocol),
+                };
+                if qos == QoS::AtLeastOnce {
+                    self.pending_ack = Some(reader.u16()?);
+                }
+                let payload_len = reader.0.len();
+                let body = &self.rx[body];
+                let topic =
+                    str::from_utf8(&body[2..2 + topic_len]).map_err(|_| MqttError::Malformed)?;
+                Ok(Event::Message(Message {
+                    topic,
+                    payload: &body[body.len() - payload_len..],
+                    qos,
+                    retain: flags & 0x01 != 0,
+                }))
+            }
+            PUBACK => Ok(Event::PubAck(Reader(&self.rx[body]).u16()?)),
+            SUBACK => {
+                let mut reader = Reader(&self.rx[body]);
+                let packet_id = reader.u16()?;
+                let granted = match reader.u8()? {
+                    0 => Some(QoS::AtMostOnce),
+                    // QoS 2 is granted only if asked for, which the client never does
+                    1 | 2 => Some(QoS::AtLeastOnce),
+                    _ => None,
+                };
+                Ok(Event::SubAck { packet_id, granted })
+            }
+            PINGRESP => {
+                self.ping_outstanding = false;
+                Ok(Event::PingResp)
+            }
+            _ => Err(MqttError::Protocol),
+        }
+    }
+
+    /// Receives the next complete packet, returning its first byte and the
+    /// position of its body in the receive buffer
+    async fn next_packet(&mut self) -> Result<(u8, Range<usize>), MqttError<C::Error>> {
+        if self.consumed > 0 {
+            self.rx.copy_within(self.consumed..self.rx_len, 0);
+            self.rx_len -= self.consumed;
+            self.consumed = 0;
+        }
+        loop {
+            if let Some((header, body)) =
+                decode(&self.rx[..self.rx_len]).map_err(|_| MqttError::Malformed)?
+            {
+                self.consumed = body.end;
+                return Ok((header, body));
+            }
+            if self.rx_len == self.rx.len() {
+                return Err(MqttError::BufferTooSmall);
+            }
+            match self.connection.read(&mut self.rx[self.rx_len..]).await {
+                Ok(0) => return Err(MqttError::UnexpectedEof),
+                Ok(n) => self.rx_len += n,
+                Err(e) => return Err(MqttError::Io(e)),
+            }
+        }
+    }
+
+    /// Acknowledges the QoS 1 message [`Client::poll`] returned last, if it
+    /// has not been yet
+    async fn send_pending_ack(&mut self) -> Result<(), MqttError<C::Error>> {
+        if let Some(packet_id) = self.pending_ack {
+            self.send(PUBACK << 4, |body| body.u16(packet_id)).await?;
+            self.pending_ack = None;
+        }
+        Ok(())
+    }
+
+    /// Sends a packet whose body is written by `body`
+    async fn send(
+        &mut self,
+        header: u8,
+        body: impl FnOnce(&mut Writer<'_>) -> Option<()>,
+    ) -> Result<(), MqttError<C::Error>> {
+        let packet = encode(self.tx, header, body).ok_or(MqttError::BufferTooSmall)?;
+        self.connection
+            .write_all(&self.tx[packet])
+            .await
+            .map_err(MqttError::Io)?;
+        self.connection.flush().await.map_err(MqttError::Io)
+    }
+
+    /// Next packet identifier; identifiers are never 0
+    fn next_packet_id(&mut self) -> u16 {
+        let packet_id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        packet_id
+    }
+}
+
+/// Writes a packet into `buf` and returns where it is.
+///
+/// The body is written first, after room for the longest fixed header, which
+/// then goes right in front of it.
+fn encode(
+    buf: &mut [u8],
+    header: u8,
+    body: impl FnOnce(&mut Writer<'_>) -> Option<()>,
+) -> Option<Range<usize>> {
+    let (head, rest) = buf.split_at_mut_checked(MAX_HEADER_LEN)?;
+    let mut writer = Writer { buf: rest, len: 0 };
+    body(&mut writer)?;
+    let body_len = writer.len;
+
+    let mut length = [0; 4];
+    let mut length_len = 0;
+    let mut remaining = body_len;
+    loop {
+        let mut byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+        *length.get_mut(length_len)? = byte;
+        length_len += 1;
+        if remaining == 0 {
+            break;
+        }
+    }
+
+    let start = MAX_HEADER_LEN - 1 - length_len;
+    head[start] = header;
+    head[start + 1..].copy_from_slice(&length[..length_len]);
+    Some(start..MAX_HEADER_LEN + body_len)
+}
+
+/// Finds the first complete packet in `buf`.
+///
+/// Returns its first byte and the position of its body, or `None` if more
+/// bytes are needed.
+fn decode(buf: &[u8]) -> Result<Option<(u8, Range<usize>)>, ()> {
+    let Some(&header) = buf.first() else {
+        return Ok(None);
+    };
+    let mut body_len = 0;
+    for i in 0..4 {
+        let Some(&byte) = buf.get(1 + i) else {
+            return Ok(None);
+        };
+        body_len |= usize::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            let start = 2 + i;
+            let end = start + body_len;
+            return Ok((buf.len() >= end).then_some((header, start..end)));
+        }
+    }
+    Err(())
+}
+
+/// Appends the fields of a packet body
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        let end = self.len + bytes.len();
+        self.buf.get_mut(self.len..end)?.copy_from_slice(bytes);
+        self.len = end;
+        Some(())
+    }
+
+    fn u8(&mut self, value: u8) -> Option<()> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// A string with its length in front
+    fn str(&mut self, value: &str) -> Option<()> {
+        self.u16(u16::try_from(value.len()).ok()?)?;
+        self.bytes(value.as_bytes())
+    }
+}
+
+/// Takes the fields of a packet body from the front
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes<E>(&mut self, len: usize) -> Result<&'a [u8], MqttError<E>> {
+        let (bytes, rest) = self.0.split_at_checked(len).ok_or(MqttError::Malformed)?;
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8<E>(&mut self) -> Result<u8, MqttError<E>> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16<E>(&mut self) -> Result<u16, MqttError<E>> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A string with its length in front
+    fn str<E>(&mut self) -> Result<&'a [u8], MqttError<E>> {
+        let len = self.u16()?;
+        self.bytes(usize::from(len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use core::future::poll_fn;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+    use embedded_io_async::{ErrorKind, ErrorType};
+    use std::collections::VecDeque;
+    use std::io::{Read as _, Write as _};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::{self, JoinHandle};
+    use std::vec::Vec;
+
+    /// A blocking TCP stream, so every future is ready on its first poll
+    struct Stream(TcpStream);
+
+    impl ErrorType for Stream {
+        type Error = ErrorKind;
+    }
+
+    impl Read for Stream {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            self.0.read(buf).map_err(|_| ErrorKind::Other)
+        }
+    }
+
+    impl Write for Stream {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.0.write(buf).map_err(|_| ErrorKind::Other)
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    /// What an in-memory [`Scripted`] connection receives and sends
+    #[derive(Default)]
+    struct Script {
+        incoming: VecDeque<u8>,
+        outgoing: Vec<u8>,
+        writable: bool,
+    }
+
+    /// An in-memory connection whose reads wait until the script has
+    /// incoming bytes, and whose writes wait while it is not writable
+    struct Scripted<'a>(&'a RefCell<Script>);
+
+    impl ErrorType for Scripted<'_> {
+        type Error = ErrorKind;
+    }
+
+    impl Read for Scripted<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            poll_fn(|_| {
+                let mut script = self.0.borrow_mut();
+                if script.incoming.is_empty() {
+                    return Poll::Pending;
+                }
+                let n = buf.len().min(script.incoming.len());
+                for (byte, received) in buf.iter_mut().zip(script.incoming.drain(..n)) {
+                    *byte = received;
+                }
+                Poll::Ready(Ok(n))
+            })
+            .await
+        }
+    }
+
+    impl Write for Scripted<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            poll_fn(|_| {
+                let mut script = self.0.borrow_mut();
+                if !script.writable {
+                    return Poll::Pending;
+                }
+                script.outgoing.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            })
+            .await
+        }
+    }
+
+    /// First byte and body of a packet the broker received
+    type Packet = (u8, Vec<u8>);
+
+    /// Writes a packet the way a broker would
+    fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+        let mut buf = [0; 256];
+        let range = encode(&mut buf, header, |w| w.bytes(body)).unwrap();
+        buf[range].to_vec()
+    }
+
+    /// Starts a broker stand-in on a local port for one client.
+    ///
+    /// It answers CONNECT with `connack_code`, acknowledges QoS 1 messages,
+    /// grants subscriptions and sends one retained QoS 1 message on each,
+    /// and answers pings, until DISCONNECT. Returns the packets received.
+    fn broker(connack_code: u8) -> (Stream, JoinHandle<Vec<Packet>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            let mut buf = Vec::new();
+            loop {
+                let (header, body) = match decode(&buf).unwrap() {
+                    Some((header, body)) => (header, body),
+                    None => {
+                        let mut chunk = [0; 256];
+                        match stream.read(&mut chunk).unwrap() {
+                            0 => return received,
+                            n => buf.extend_from_slice(&chunk[..n]),
+                        }
+                        continue;
+                    }
+                };
+                let data = buf[body.clone()].to_vec();
+                buf.drain(..body.end);
+                let reply = match header >> 4 {
+                    CONNECT => packet(CONNACK << 4, &[0, connack_code]),
+                    PUBLISH if header & 0x06 == 0x02 => {
+                        let topic_len = usize::from(u16::from_be_bytes([data[0], data[1]]));
+                        packet(PUBACK << 4, &data[2 + topic_len..4 + topic_len])
+                    }
+                    SUBSCRIBE => {
+                        let filter_len = usize::from(u16::from_be_bytes([data[2], data[3]]));
+                        let filter = &data[4..4 + filter_len];
+                        let mut reply = packet(SUBACK << 4, &[data[0], data[1], 1]);
+                        let mut message = Vec::new();
+                        message.extend_from_slice(&data[2..4 + filter_len]);
+                        message.extend_from_slice(&[0, 7]);
+                        message.extend_from_slice(b"{\"interval_secs\":30}");
+                        assert_eq!(filter, b"devices/esp/config");
+                        reply.extend(packet(PUBLISH << 4 | 0x03, &message));
+                        reply
+                    }
+                    PINGREQ => packet(PINGRESP << 4, &[]),
+                    _ => Vec::new(),
+                };
+                stream.write_all(&reply).unwrap();
+                received.push((header, data));
+                if header >> 4 == DISCONNECT {
+                    return received;
+                }
+            }
+        });
+        (Stream(TcpStream::connect(address).unwrap()), server)
+    }
+
+    #[test]
+    fn exchanges_messages_with_broker() {
+        let (stream, broker) = broker(0);
+        let (mut rx, mut tx) = ([0; 128], [0; 128]);
+        let options = ConnectOptions::new("esp-scanner")
+            .with_keep_alive_secs(30)
+            .with_credentials("device", b"secret");
+        block_on(async {
+            let mut client = Client::connect(stream, &options, &mut rx, &mut tx)
+                .await
+                .unwrap();
+            let qos0 = client.publish("scans", b"{}", QoS::AtMostOnce, false);
+            assert_eq!(qos0.await, Ok(None));
+            let id = client
+                .publish("scans", b"[1]", QoS::AtLeastOnce, true)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(client.poll().await, Ok(Event::PubAck(id)));
+
+            let packet_id = client
+                .subscribe("devices/esp/config", QoS::AtLeastOnce)
+                .await
+                .unwrap();
+            assert_eq!(
+                client.poll().await,
+                Ok(Event::SubAck {
+                    packet_id,
+                    granted: Some(QoS::AtLeastOnce)
+                })
+            );
+            assert_eq!(
+                client.poll().await,
+                Ok(Event::Message(Message {
+                    topic: "devices/esp/config",
+                    payload: b"{\"interval_secs\":30}",
+                    qos: QoS::AtLeastOnce,
+                    retain: true,
+                }))
+            );
+
+            client.ping().await.unwrap();
+            assert!(client.ping_outstanding());
+            assert_eq!(client.poll().await, Ok(Event::PingResp));
+            assert!(!client.ping_outstanding());
+            client.disconnect().await.unwrap();
+        });
+
+        let received = broker.join().unwrap();
+        let types: Vec<_> = received.iter().map(|(header, _)| *header).collect();
+        assert_eq!(
+            types,
+            [0x10, 0x30, 0x33, 0x82, 0x40, 0xc0, 0xe0],
+            "CONNECT, PUBLISH, PUBLISH QoS 1 retained, SUBSCRIBE, PUBACK, PINGREQ, DISCONNECT"
+        );
+        assert_eq!(
+            received[0].1,
+            b"\0\x04MQTT\x04\xc2\0\x1e\0\x0besp-scanner\0\x06device\0\x06secret"
+        );
+        assert_eq!(received[1].1, b"\0\x05scans{}");
+        assert_eq!(received[2].1, b"\0\x05scans\0\x01[1]");
+        // The broker's message was acknowledged
+        assert_eq!(received[4].1, [0, 7]);
+    }
+
+    #[test]
+    fn survives_cancelled_poll() {
+        let script = RefCell::new(Script {
+            writable: true,
+            ..Script::default()
+        });
+        script
+            .borrow_mut()
+            .incoming
+            .extend(packet(CONNACK << 4, &[0, 0]));
+        let (mut rx, mut tx) = ([0; 64], [0; 64]);
+        let mut client = block_on(Client::connect(
+            Scripted(&script),
+            &ConnectOptions::new("esp"),
+            &mut rx,
+            &mut tx,
+        ))
+        .unwrap();
+        script.borrow_mut().outgoing.clear();
+
+        // Half a QoS 1 message arrives and the poll waiting for the rest is
+        // dropped, as when another branch of a select wins
+        let message = packet(PUBLISH << 4 | 0x02, b"\0\x03cmd\0\x09scan");
+        let (first, rest) = message.split_at(6);
+        script.borrow_mut().incoming.extend(first);
+        script.borrow_mut().writable = false;
+        {
+            let mut poll = pin!(client.poll());
+            let mut cx = Context::from_waker(Waker::noop());
+            assert!(poll.as_mut().poll(&mut cx).is_pending());
+        }
+
+        // The next poll completes the message without writing anything
+        script.borrow_mut().incoming.extend(rest);
+        assert_eq!(
+            block_on(client.poll()),
+            Ok(Event::Message(Message {
+                topic: "cmd",
+                payload: b"scan",
+                qos: QoS::AtLeastOnce,
+                retain: false,
+            }))
+        );
+        assert!(script.borrow().outgoing.is_empty());
+
+        // and the acknowledgement goes out with the next packet
+        script.borrow_mut().writable = true;
+        block_on(client.ping()).unwrap();
+        assert_eq!(
+            script.borrow().outgoing,
+            [0x40, 0x02, 0x00, 0x09, 0xc0, 0x00]
+        );
+        block_on(client.ping()).unwrap();
+        assert_eq!(script.borrow().outgoing[6..], [0xc0, 0x00]);
+    }
+
+    #[test]
+    fn reports_refused_connection() {
+        let (stream, _) = broker(5);
+        let (mut rx, mut tx) = ([0; 64], [0; 64]);
+        let result = block_on(Client::connect(
+            stream,
+            &ConnectOptions::new("esp"),
+            &mut rx,
+            &mut tx,
+        ));
+        assert!(matches!(result, Err(MqttError::ConnectionRefused(5))));
+    }
+
+    #[test]
+    fn frames_packets() {
+        for (body_len, length) in [
+            (0, &[0x00][..]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (16383, &[0xff, 0x7f]),
+            (16384, &[0x80, 0x80, 0x01]),
+        ] {
+            let mut buf = std::vec![0; MAX_HEADER_LEN + body_len];
+            let range = encode(&mut buf, 0x30, |w| w.bytes(&std::vec![0xaa; body_len])).unwrap();
+            let packet = &buf[range];
+            assert_eq!(&packet[1..1 + length.len()], length);
+            assert_eq!(
+                decode(packet),
+                Ok(Some((0x30, 1 + length.len()..packet.len())))
+            );
+            assert_eq!(decode(&packet[..packet.len() - 1]), Ok(None));
+        }
+        assert_eq!(decode(&[0x30, 0x80, 0x80]), Ok(None));
+        assert_eq!(decode(&[0x30, 0x80, 0x80, 0x80, 0x80, 0x01]), Err(()));
+
+        let mut small = [0; 8];
+        assert_eq!(encode(&mut small, 0x30, |w| w.str("topic")), None);
+    }
+}
@@ -0,0 +1,257 @@
+//! IPv4 configuration of a network interface.
+//!
+//! [`Ipv4Config`] says whether an interface gets its address by DHCP or
+//! uses a [`StaticIpv4`] configuration. Static configurations are checked
+//! when they are built, so the firmware can hand them to the network stack
+//! as they are.
+
+use core::fmt;
+
+/// Maximum number of DNS servers in a static configuration
+pub const MAX_DNS_SERVERS: usize = 3;
+
+/// Reasons why a static configuration was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NetConfigError {
+    /// The prefix length is not between 1 and 30
+    InvalidPrefix,
+    /// The address is unspecified, multicast, or the network or broadcast
+    /// address of its subnet
+    InvalidAddress,
+    /// The gateway is not a host of the subnet
+    GatewayOutsideSubnet,
+    /// More than [`MAX_DNS_SERVERS`] DNS servers were given
+    TooManyDnsServers,
+}
+
+impl fmt::Display for NetConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetConfigError::InvalidPrefix => f.write_str("prefix length must be 1 to 30"),
+            NetConfigError::InvalidAddress => f.write_str("address is not a valid host address"),
+            NetConfigError::GatewayOutsideSubnet => f.write_str("gateway is outside the subnet"),
+            NetConfigError::TooManyDnsServers => {
+                write!(f, "at most {} DNS servers are supported", MAX_DNS_SERVERS)
+            }
+        }
+    }
+}
+
+/// Static IPv4 configuration
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StaticIpv4 {
+    address: [u8; 4],
+    prefix_len: u8,
+    gateway: Option<[u8; 4]>,
+    dns_servers: heapless::Vec<[u8; 4], MAX_DNS_SERVERS>,
+}
+
+impl StaticIpv4 {
+    /// Creates a configuration for `address` in a subnet of `prefix_len` bits,
+    /// without gateway and DNS servers.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`NetConfigError::InvalidPrefix`] or
+    /// [`NetConfigError::InvalidAddress`].
+    pub fn new(address: [u8; 4], prefix_len: u8) -> Result<Self, NetConfigError> {
+        if !(1..=30).contains(&prefix_len) {
+            return Err(NetConfigError::InvalidPrefix);
+        }
+        let config = Self {
+            address,
+            prefix_len,
+            gateway: None,
+            dns_servers: heapless::Vec::new(),
+        };
+        if !config.is_host(address) {
+            return Err(NetConfigError::InvalidAddress);
+        }
+        Ok(config)
+    }
+
+    /// Sets the default gateway.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`NetConfigError::GatewayOutsideSubnet`] unless the gateway is
+    /// another host of the subnet.
+    pub fn with_gateway(mut self, gateway: [u8; 4]) -> Result<Self, NetConfigError> {
+        if gateway == self.address || !self.is_host(gateway) {
+            return Err(NetConfigError::GatewayOutsideSubnet);
+        }
+        self.gateway = Some(gateway);
+        Ok(self)
+    }
+
+    /// Adds a DNS server.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`NetConfigError::TooManyDnsServers`] if the list is full.
+    pub fn with_dns_server(mut self, server: [u8; 4]) -> Result<Self, NetConfigError> {
+        self.dns_servers
+            .push(server)
+            .map_err(|_| NetConfigError::TooManyDnsServers)?;
+        Ok(self)
+    }
+
+    /// Address of the interface
+    pub fn address(&self) -> [u8; 4] {
+        self.address
+    }
+
+    /// Length of the subnet prefix in bits
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Subnet mask, e.g. `255.255.255.0` for a prefix of 24 bits
+    pub fn netmask(&self) -> [u8; 4] {
+        mask(self.prefix_len).to_be_bytes()
+    }
+
+    /// Default gateway, if any
+    pub fn gateway(&self) -> Option<[u8; 4]> {
+        self.gateway
+    }
+
+    /// DNS servers, in order of preference
+    pub fn dns_servers(&self) -> &[[u8; 4]] {
+        &self.dns_servers
+    }
+
+    /// Returns `true` if `ip` is a host address of the subnet
+    fn is_host(&self, ip: [u8; 4]) -> bool {
+        let mask = mask(self.prefix_len);
+        let host = u32::from_be_bytes(ip) & !mask;
+        let first = ip[0];
+        (u32::from_be_bytes(ip) ^ u32::from_be_bytes(self.address)) & mask == 0
+            && host != 0
+            && host != !mask
+            && first != 0
+            && !(224..=239).contains(&first)
+    }
+}
+
+impl fmt::Display for StaticIpv4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", Ip(self.address), self.prefix_len)?;
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", Ip(gateway))?;
+        }
+        for (i, server) in self.dns_servers.iter().enumerate() {
+            let separator = if i == 0 { ", DNS " } else { " " };
+            write!(f, "{}{}", separator, Ip(*server))?;
+        }
+        Ok(())
+    }
+}
+
+/// How an interface gets its IPv4 address
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Ipv4Config {
+    /// Lease an address from a DHCP server
+    #[default]
+    Dhcp,
+    /// Use a fixed address
+    Static(StaticIpv4),
+}
+
+impl fmt::Display for Ipv4Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ipv4Config::Dhcp => f.write_str("DHCP"),
+            Ipv4Config::Static(config) => write!(f, "static {}", config),
+        }
+    }
+}
+
+/// Network mask of a prefix of `prefix_len` bits
+const fn mask(prefix_len: u8) -> u32 {
+    match prefix_len {
+        0 => 0,
+        len => u32::MAX << (32 - len as u32),
+    }
+}
+
+/// Formats an address in dotted decimal notation
+pub(crate) struct Ip(pub(crate) [u8; 4]);
+
+impl fmt::Display for Ip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn builds_static_configuration() {
+        let config = StaticIpv4::new([192, 168, 1, 50], 24)
+            .and_then(|c| c.with_gateway([192, 168, 1, 1]))
+            .and_then(|c| c.with_dns_server([1, 1, 1, 1]))
+            .and_then(|c| c.with_dns_server([192, 168, 1, 1]))
+            .unwrap();
+        assert_eq!(config.netmask(), [255, 255, 255, 0]);
+        assert_eq!(config.dns_servers().len(), 2);
+        assert_eq!(
+            config.to_string(),
+            "192.168.1.50/24 via 192.168.1.1, DNS 1.1.1.1 192.168.1.1"
+        );
+        assert_eq!(
+            Ipv4Config::Static(config).to_string(),
+            "static 192.168.1.50/24 via 192.168.1.1, DNS 1.1.1.1 192.168.1.1"
+        );
+        assert_eq!(
+            StaticIpv4::new([10, 0, 0, 5], 8).unwrap().netmask(),
+            [255, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        assert_eq!(
+            StaticIpv4::new([192, 168, 1, 50], 31),
+            Err(NetConfigError::InvalidPrefix)
+        );
+        assert_eq!(
+            StaticIpv4::new([192, 168, 1, 50], 0),
+            Err(NetConfigError::InvalidPrefix)
+        );
+        for address in [
+            [192, 168, 1, 0],
+            [192, 168, 1, 255],
+            [0, 0, 0, 1],
+            [224, 0, 0, 1],
+        ] {
+            assert_eq!(
+                StaticIpv4::new(address, 24),
+                Err(NetConfigError::InvalidAddress)
+            );
+        }
+
+        let config = StaticIpv4::new([192, 168, 1, 50], 24).unwrap();
+        for gateway in [[192, 168, 2, 1], [192, 168, 1, 50], [192, 168, 1, 255]] {
+            assert_eq!(
+                config.clone().with_gateway(gateway),
+                Err(NetConfigError::GatewayOutsideSubnet)
+            );
+        }
+
+        let full = (0..MAX_DNS_SERVERS as u8)
+            .try_fold(config, |c, i| c.with_dns_server([9, 9, 9, i]))
+            .unwrap();
+        assert_eq!(
+            full.with_dns_server([8, 8, 8, 8]),
+            Err(NetConfigError::TooManyDnsServers)
+        );
+    }
+}
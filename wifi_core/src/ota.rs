@@ -0,0 +1,947 @@
+//! A/B firmware updates on the ESP-IDF partition layout.
+//!
+//! The flash holds two application slots, `ota_0` and `ota_1`, and an OTA
+//! data partition that tells the bootloader which of them to boot.
+//! [`PartitionLayout`] finds them among the entries of the partition table,
+//! [`SlotWriter`] streams a new signed image into the slot that is not
+//! running while a [`SignedImageVerifier`] checks it, and [`OtaData`]
+//! switches the bootloader over to it. Everything is generic over [`NorFlash`], so the tests run on a
+//! RAM mock.
+//!
+//! # OTA data
+//!
+//! The OTA data partition has two 4 KiB sectors, each starting with a select
+//! entry:
+//!
+//! | Bytes | Content                                    |
+//! |-------|--------------------------------------------|
+//! | 4     | Sequence number, little endian             |
+//! | 20    | Label, ignored by the bootloader           |
+//! | 4     | [`ImageState`], little endian              |
+//! | 4     | CRC-32 of the sequence number              |
+//!
+//! The bootloader takes the entry with the highest sequence number among
+//! those with a matching CRC whose image is neither invalid nor aborted, and
+//! boots slot `(seq - 1) % 2`. Without such an entry it boots the first
+//! application partition.
+//!
+//! # Rollback
+//!
+//! [`OtaData::select`] writes the new entry into the sector that does not
+//! hold the current one, so a power cut during the switch leaves the old
+//! selection in place. The new image then boots on trial:
+//! [`OtaData::check_boot`] counts its boots in the label, and the image
+//! must call [`OtaData::confirm`] once it finds itself healthy. An image that
+//! boots [`MAX_TRIAL_BOOTS`] times without confirming, or gives up with
+//! [`OtaData::roll_back`], has its entry marked invalid, and the bootloader
+//! goes back to the previous slot.
+//!
+//! This works with any ESP-IDF bootloader. One built with rollback support
+//! turns the state from new into pending-verify on the first boot; that
+//! counts as a trial boot all the same.
+
+use core::fmt;
+
+use embedded_storage::nor_flash::{NorFlash, NorFlashError};
+
+use crate::app_image::{ImageError, ImageInfo, ImageVerifier, SignedImageVerifier};
+use crate::ed25519::VerifyingKey;
+use crate::flash_kv::{Crc32, FlashError};
+
+/// Size of the OTA data partition
+pub const OTADATA_LEN: u32 = 0x2000;
+
+/// Boots an image gets to confirm itself before it is rolled back
+pub const MAX_TRIAL_BOOTS: u8 = 3;
+
+/// Alignment the bootloader requires of application partitions
+const APP_ALIGN: u32 = 0x1_0000;
+
+/// Partition type of applications
+const TYPE_APP: u8 = 0x00;
+
+/// Partition type of data partitions
+const TYPE_DATA: u8 = 0x01;
+
+/// Application subtype of the first OTA slot; the second one follows it
+const SUBTYPE_OTA_0: u8 = 0x10;
+
+/// Data subtype of the OTA data partition
+const SUBTYPE_OTADATA: u8 = 0x00;
+
+/// Distance between the two select entries
+const ENTRY_SECTOR_LEN: u32 = 0x1000;
+
+/// Length of a select entry
+const ENTRY_LEN: usize = 32;
+
+/// Sequence number of an erased entry
+const BLANK_SEQ: u32 = u32::MAX;
+
+/// Size of the chunks images are written and read back in
+const CHUNK_LEN: usize = 256;
+
+/// OTA update errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OtaError {
+    /// The flash driver failed
+    Flash(FlashError),
+    /// The partition table has no partition of this name
+    MissingPartition(&'static str),
+    /// The OTA partitions have the wrong size or alignment, or overlap
+    InvalidLayout,
+    /// The flash geometry does not suit the partition
+    UnsupportedFlash,
+    /// The image was rejected
+    Image(ImageError),
+    /// The image read back from flash differs from the one written
+    ReadBack,
+}
+
+impl fmt::Display for OtaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtaError::Flash(error) => write!(f, "flash error: {error:?}"),
+            OtaError::MissingPartition(name) => write!(f, "no {name} partition"),
+            OtaError::InvalidLayout => f.write_str("invalid OTA partition layout"),
+            OtaError::UnsupportedFlash => f.write_str("unsupported flash geometry"),
+            OtaError::Image(error) => write!(f, "{error}"),
+            OtaError::ReadBack => f.write_str("image read back from flash differs"),
+        }
+    }
+}
+
+impl<E: NorFlashError> From<E> for OtaError {
+    fn from(error: E) -> Self {
+        OtaError::Flash(error.kind().into())
+    }
+}
+
+impl From<ImageError> for OtaError {
+    fn from(error: ImageError) -> Self {
+        OtaError::Image(error)
+    }
+}
+
+/// One of the two application slots
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Slot {
+    /// The `ota_0` partition
+    Ota0,
+    /// The `ota_1` partition
+    Ota1,
+}
+
+impl Slot {
+    /// The slot that is not this one
+    pub const fn other(self) -> Self {
+        match self {
+            Slot::Ota0 => Slot::Ota1,
+            Slot::Ota1 => Slot::Ota0,
+        }
+    }
+
+    /// Number of the slot, 0 or 1
+    pub const fn index(self) -> usize {
+        match self {
+            Slot::Ota0 => 0,
+            Slot::Ota1 => 1,
+        }
+    }
+
+    /// The slot the bootloader boots for sequence number `seq`
+    const fn from_seq(seq: u32) -> Self {
+        if seq.is_multiple_of(2) {
+            Slot::Ota1
+        } else {
+            Slot::Ota0
+        }
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ota_{}", self.index())
+    }
+}
+
+/// State of the image a select entry points to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImageState {
+    /// Selected and not booted yet
+    New,
+    /// Booted once by a bootloader with rollback support, not confirmed yet
+    PendingVerify,
+    /// Confirmed to work
+    Valid,
+    /// Found not to work; the bootloader skips it
+    Invalid,
+    /// Not confirmed before the next boot; the bootloader skips it
+    Aborted,
+    /// No state recorded, e.g. written by a flasher
+    Undefined,
+}
+
+impl ImageState {
+    const fn from_raw(raw: u32) -> Self {
+        match raw {
+            0 => ImageState::New,
+            1 => ImageState::PendingVerify,
+            2 => ImageState::Valid,
+            3 => ImageState::Invalid,
+            4 => ImageState::Aborted,
+            _ => ImageState::Undefined,
+        }
+    }
+
+    const fn raw(self) -> u32 {
+        match self {
+            ImageState::New => 0,
+            ImageState::PendingVerify => 1,
+            ImageState::Valid => 2,
+            ImageState::Invalid => 3,
+            ImageState::Aborted => 4,
+            ImageState::Undefined => u32::MAX,
+        }
+    }
+
+    /// Returns `true` if the bootloader may boot an image in this state
+    const fn is_bootable(self) -> bool {
+        !matches!(self, ImageState::Invalid | ImageState::Aborted)
+    }
+
+    /// Returns `true` while the image still has to confirm itself
+    const fn is_trial(self) -> bool {
+        matches!(self, ImageState::New | ImageState::PendingVerify)
+    }
+}
+
+/// A partition's place in flash
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Region {
+    /// Offset from the start of the flash
+    pub offset: u32,
+    /// Size in bytes
+    pub size: u32,
+}
+
+impl Region {
+    /// Offset of the first byte after the region
+    const fn end(&self) -> u32 {
+        self.offset + self.size
+    }
+
+    /// Returns `true` if the two regions share a byte
+    const fn overlaps(&self, other: &Region) -> bool {
+        self.offset < other.end() && other.offset < self.end()
+    }
+}
+
+/// An entry of the partition table, with its raw type and subtype
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PartitionInfo {
+    /// Partition type: 0 for applications, 1 for data
+    pub kind: u8,
+    /// Partition subtype, e.g. 0x10 for `ota_0`
+    pub subtype: u8,
+    /// Place of the partition in flash
+    pub region: Region,
+}
+
+/// Where the OTA partitions are
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PartitionLayout {
+    /// The OTA data partition
+    pub otadata: Region,
+    /// The `ota_0` and `ota_1` application partitions
+    pub slots: [Region; 2],
+}
+
+impl PartitionLayout {
+    /// Finds the OTA partitions among the entries of a partition table.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OtaError::MissingPartition`] if the OTA data partition or
+    /// one of the two slots is missing, or [`OtaError::InvalidLayout`] if the
+    /// OTA data partition is not [`OTADATA_LEN`] long, a slot is not aligned
+    /// to 64 KiB, or the partitions overlap.
+    pub fn find(partitions: impl IntoIterator<Item = PartitionInfo>) -> Result<Self, OtaError> {
+        let mut otadata = None;
+        let mut slots = [None; 2];
+        for partition in partitions {
+            match (partition.kind, partition.subtype) {
+                (TYPE_DATA, SUBTYPE_OTADATA) => otadata = Some(partition.region),
+                (TYPE_APP, subtype) if subtype.wrapping_sub(SUBTYPE_OTA_0) < 2 => {
+                    slots[usize::from(subtype - SUBTYPE_OTA_0)] = Some(partition.region)
+                }
+                _ => {}
+            }
+        }
+        let layout = Self {
+            otadata: otadata.ok_or(OtaError::MissingPartition("otadata"))?,
+            slots: [
+                slots[0].ok_or(OtaError::MissingPartition("ota_0"))?,
+                slots[1].ok_or(OtaError::MissingPartition("ota_1"))?,
+            ],
+        };
+        let [ota_0, ota_1] = &layout.slots;
+        let valid = layout.otadata.size == OTADATA_LEN
+            && layout.slots.iter().all(|slot| {
+                slot.offset.is_multiple_of(APP_ALIGN) && !slot.overlaps(&layout.otadata)
+            })
+            && !ota_0.overlaps(ota_1);
+        if !valid {
+            return Err(OtaError::InvalidLayout);
+        }
+        Ok(layout)
+    }
+
+    /// Place of `slot` in flash
+    pub const fn slot(&self, slot: Slot) -> Region {
+        self.slots[slot.index()]
+    }
+
+    /// The slot starting at `offset`, e.g. the one the running image was
+    /// booted from
+    pub fn slot_at(&self, offset: u32) -> Option<Slot> {
+        [Slot::Ota0, Slot::Ota1]
+            .into_iter()
+            .find(|&slot| self.slot(slot).offset == offset)
+    }
+}
+
+/// The select entry the bootloader follows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Selection {
+    /// Slot booted
+    pub slot: Slot,
+    /// State of its image
+    pub state: ImageState,
+    /// Sequence number of the entry
+    pub seq: u32,
+    /// Boots of the image on trial so far
+    pub trial_boots: u8,
+    /// Sector holding the entry
+    sector: u32,
+}
+
+/// What the running image has to do after [`OtaData::check_boot`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BootCheck {
+    /// Nothing: the image is confirmed or was not installed by an update
+    Settled,
+    /// Confirm the image with [`OtaData::confirm`] once it is healthy
+    Trial {
+        /// Number of this boot, from 1 to [`MAX_TRIAL_BOOTS`]
+        boot: u8,
+    },
+    /// Restart: the image used up its trial boots and was marked invalid
+    RolledBack,
+}
+
+/// A select entry as stored in flash
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    seq: u32,
+    label: [u8; 20],
+    state: ImageState,
+    crc: u32,
+}
+
+impl Entry {
+    fn new(seq: u32, state: ImageState) -> Self {
+        Self {
+            seq,
+            label: [0xFF; 20],
+            state,
+            crc: seq_crc(seq),
+        }
+    }
+
+    fn from_bytes(bytes: &[u8; ENTRY_LEN]) -> Self {
+        let word = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        let mut label = [0; 20];
+        label.copy_from_slice(&bytes[4..24]);
+        Self {
+            seq: word(0),
+            label,
+            state: ImageState::from_raw(word(24)),
+            crc: word(28),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; ENTRY_LEN] {
+        let mut bytes = [0; ENTRY_LEN];
+        bytes[..4].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[4..24].copy_from_slice(&self.label);
+        bytes[24..28].copy_from_slice(&self.state.raw().to_le_bytes());
+        bytes[28..].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    /// Returns `true` if the entry holds a sequence number
+    fn is_written(&self) -> bool {
+        self.seq != BLANK_SEQ && self.crc == seq_crc(self.seq)
+    }
+
+    /// Trial boots, kept complemented in the first label byte so that an
+    /// erased label counts none
+    fn trial_boots(&self) -> u8 {
+        !self.label[0]
+    }
+
+    fn set_trial_boots(&mut self, boots: u8) {
+        self.label[0] = !boots;
+    }
+}
+
+/// CRC of a sequence number, as the bootloader computes it
+fn seq_crc(seq: u32) -> u32 {
+    let mut crc = Crc32::new_cleared();
+    crc.update(&seq.to_le_bytes());
+    crc.finish()
+}
+
+/// The OTA data partition
+pub struct OtaData<F> {
+    flash: F,
+}
+
+impl<F: NorFlash> OtaData<F> {
+    /// Wraps the OTA data partition.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OtaError::UnsupportedFlash`] if the partition is not
+    /// [`OTADATA_LEN`] long or the flash cannot erase and write the entries
+    /// on their own.
+    pub fn new(flash: F) -> Result<Self, OtaError> {
+        let geometry_ok = flash.capacity() == OTADATA_LEN as usize
+            && ENTRY_SECTOR_LEN.is_multiple_of(F::ERASE_SIZE as u32)
+            && ENTRY_LEN.is_multiple_of(F::WRITE_SIZE)
+            && ENTRY_LEN.is_multiple_of(F::READ_SIZE);
+        if !geometry_ok {
+            return Err(OtaError::UnsupportedFlash);
+        }
+        Ok(Self { flash })
+    }
+
+    /// Gives back the flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Returns the entry the bootloader follows, or `None` if it boots the
+    /// first application partition.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OtaError::Flash`] if the partition cannot be read.
+    pub fn selection(&mut self) -> Result<Option<Selection>, OtaError> {
+        let mut selection: Option<Selection> = None;
+        for sector in 0..2 {
+            let entry = self.read_entry(sector)?;
+            if entry.is_written()
+                && entry.state.is_bootable()
+                && selection.is_none_or(|selection| entry.seq > selection.seq)
+            {
+                selection = Some(Selection {
+                    slot: Slot::from_seq(entry.seq),
+                    state: entry.state,
+                    seq: entry.seq,
+                    trial_boots: entry.trial_boots(),
+                    sector,
+                });
+            }
+        }
+        Ok(selection)
+    }
+
+    /// Makes the bootloader boot `slot` from the next restart on, with the
+    /// image on trial.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OtaError::Flash`] if the partition cannot be written.
+    pub fn select(&mut self, slot: Slot) -> Result<(), OtaError> {
+        let selection = self.selection()?;
+        let mut seq = 0;
+        for sector in 0..2 {
+            let entry = self.read_entry(sector)?;
+            if entry.is_written() {
+                seq = seq.max(entry.seq);
+            }
+        }
+        // The next sequence number that maps to the slot
+        seq += 1;
+        if Slot::from_seq(seq) != slot {
+            seq += 1;
+        }
+        let sector = selection.map_or(0, |selection| 1 - selection.sector);
+        self.write_entry(sector, Entry::new(seq, ImageState::New))
+    }
+
+    /// Counts a boot of the image in `booted` and decides whether it is on
+    /// trial.
+    ///
+    /// Call this early in every boot. An image past [`MAX_TRIAL_BOOTS`] is
+    /// marked invalid right away, and the caller must restart.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OtaError::Flash`] if the partition cannot be read or written.
+    pub fn check_boot(&mut self, booted: Slot) -> Result<BootCheck, OtaError> {
+        let Some(selection) = self.selection()? else {
+            return Ok(BootCheck::Settled);
+        };
+        // The bootloader fell back to another image on its own
+        if selection.slot != booted || !selection.state.is_trial() {
+            return Ok(BootCheck::Settled);
+        }
+        let boot = selection.trial_boots.saturating_add(1);
+        if boot > MAX_TRIAL_BOOTS {
+            self.set_state(&selection, ImageState::Invalid)?;
+            return Ok(BootCheck::RolledBack);
+        }
+        let mut entry = self.read_entry(selection.sector)?;
+        entry.state = ImageState::PendingVerify;
+        entry.set_trial_boots(boot);
+        self.write_entry(selection.sector, entry)?;
+        Ok(BootCheck::Trial { boot })
+    }
+
+    /// Confirms the selected image after a trial boot.
+    ///
+    /// Returns `false` if the image was not on trial.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OtaError::Flash`] if the partition cannot be read or written.
+    pub fn confirm(&mut self) -> Result<bool, OtaError> {
+        match self.selection()? {
+            Some(selection) if selection.state.is_trial() => {
+                self.set_state(&selection, ImageState::Valid)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Marks the selected image invalid, so the bootloader goes back to the
+    /// previous one on the next restart.
+    ///
+    /// Returns the slot booted next, or `None` if that is the first
+    /// application partition.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OtaError::Flash`] if the partition cannot be read or written.
+    pub fn roll_back(&mut self) -> Result<Option<Slot>, OtaError> {
+        if let Some(selection) = self.selection()? {
+            self.set_state(&selection, ImageState::Invalid)?;
+        }
+        Ok(self.selection()?.map(|selection| selection.slot))
+    }
+
+    /// Rewrites the entry of `selection` with a new state.
+    ///
+    /// The entry is erased first; a power cut in between leaves the other
+    /// entry selected, as after a rollback.
+    fn set_state(&mut self, selection: &Selection, state: ImageState) -> Result<(), OtaError> {
+        let mut entry = self.read_entry(selection.sector)?;
+        entry.state = state;
+        entry.label = [0xFF; 20];
+        self.write_entry(selection.sector, entry)
+    }
+
+    fn read_entry(&mut self, sector: u32) -> Result<Entry, OtaError> {
+        let mut bytes = [0; ENTRY_LEN];
+        self.flash.read(sector * ENTRY_SECTOR_LEN, &mut bytes)?;
+        Ok(Entry::from_bytes(&bytes))
+    }
+
+    fn write_entry(&mut self, sector: u32, entry: Entry) -> Result<(), OtaError> {
+        let start = sector * ENTRY_SECTOR_LEN;
+        self.flash.erase(start, start + ENTRY_SECTOR_LEN)?;
+        self.flash.write(start, &entry.to_bytes())?;
+        Ok(())
+    }
+}
+
+impl<F> fmt::Debug for OtaData<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OtaData").finish_non_exhaustive()
+    }
+}
+
+/// Writes a signed image into an application slot as it streams in.
+///
+/// Sectors are erased just before they are written, and every byte is
+/// checked by a [`SignedImageVerifier`] on the way; the signature block is
+/// checked but not written. [`SlotWriter::finish`] only succeeds for an image
+/// signed with the expected key, and reads the image back from flash to
+/// check it once more.
+pub struct SlotWriter<F> {
+    flash: F,
+    chip_id: u16,
+    verifier: SignedImageVerifier,
+    /// Bytes written to flash
+    written: u32,
+    /// Offset up to which the slot is erased
+    erased: u32,
+    buf: [u8; CHUNK_LEN],
+    filled: usize,
+}
+
+impl<F: NorFlash> SlotWriter<F> {
+    /// Starts writing an image for the chip `chip_id` into the slot `flash`,
+    /// which must be signed with the secret key of `key`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OtaError::UnsupportedFlash`] if the flash's read and write
+    /// sizes do not divide the chunks the image is written in.
+    pub fn new(flash: F, chip_id: u16, key: VerifyingKey) -> Result<Self, OtaError> {
+        if !CHUNK_LEN.is_multiple_of(F::WRITE_SIZE) || !CHUNK_LEN.is_multiple_of(F::READ_SIZE) {
+            return Err(OtaError::UnsupportedFlash);
+        }
+        let max_len = flash.capacity() as u32;
+        Ok(Self {
+            flash,
+            chip_id,
+            verifier: SignedImageVerifier::new(chip_id, max_len, key),
+            written: 0,
+            erased: 0,
+            buf: [0; CHUNK_LEN],
+            filled: 0,
+        })
+    }
+
+    /// Number of bytes taken so far, including the signature block
+    pub fn len(&self) -> u32 {
+        self.verifier.len()
+    }
+
+    /// Returns `true` before the first byte
+    pub fn is_empty(&self) -> bool {
+        self.verifier.is_empty()
+    }
+
+    /// Takes the next part of the image.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OtaError::Image`] as soon as the data cannot be a valid
+    /// image, or [`OtaError::Flash`].
+    pub fn write(&mut self, data: &[u8]) -> Result<(), OtaError> {
+        let image_len = self.verifier.update(data)?;
+        let mut data = &data[..image_len];
+        while !data.is_empty() {
+            let take = data.len().min(CHUNK_LEN - self.filled);
+            self.buf[self.filled..self.filled + take].copy_from_slice(&data[..take]);
+            self.filled += take;
+            data = &data[take..];
+            if self.filled == CHUNK_LEN {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the rest of the image and checks it and its signature.
+    ///
+    /// Returns what the image holds and gives back the flash.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OtaError::Image`] if the image is incomplete or not signed
+    /// with the expected key, [`OtaError::ReadBack`] if the flash does not
+    /// hold it afterwards, or [`OtaError::Flash`].
+    pub fn finish(mut self) -> Result<(ImageInfo, F), OtaError> {
+        if self.filled > 0 {
+            let padded = self.filled.next_multiple_of(F::WRITE_SIZE);
+            self.buf[self.filled..padded].fill(0xFF);
+            self.filled = padded;
+            self.flush()?;
+        }
+        let Self {
+            mut flash,
+            chip_id,
+            verifier,
+            mut buf,
+            ..
+        } = self;
+        let info = verifier.finish()?;
+
+        // The digest in `info` covers every byte, so a match proves the
+        // flash holds the signed image
+        let mut verifier = ImageVerifier::new(chip_id, info.len);
+        let mut offset = 0;
+        while offset < info.len {
+            let len = CHUNK_LEN.min((info.len - offset) as usize);
+            let padded = len.next_multiple_of(F::READ_SIZE);
+            flash.read(offset, &mut buf[..padded])?;
+            verifier
+                .update(&buf[..len])
+                .map_err(|_| OtaError::ReadBack)?;
+            offset += len as u32;
+        }
+        if verifier.finish().ok().as_ref() != Some(&info) {
+            return Err(OtaError::ReadBack);
+        }
+        Ok((info, flash))
+    }
+
+    /// Writes the buffered chunk, erasing the sectors it reaches into first
+    fn flush(&mut self) -> Result<(), OtaError> {
+        let end = self.written + self.filled as u32;
+        if end > self.erased {
+            let erase_to = end.next_multiple_of(F::ERASE_SIZE as u32);
+            self.flash.erase(self.erased, erase_to)?;
+            self.erased = erase_to;
+        }
+        self.flash.write(self.written, &self.buf[..self.filled])?;
+        self.written = end;
+        self.filled = 0;
+        Ok(())
+    }
+}
+
+impl<F> fmt::Debug for SlotWriter<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SlotWriter")
+            .field("verifier", &self.verifier)
+            .field("written", &self.written)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_image::tests::{image, signed_image};
+    use crate::app_image::{CHIP_ID_ESP32, SIGNATURE_BLOCK_LEN};
+    use crate::ed25519::tests::TEST_SEED;
+    use crate::flash_kv::tests::{MockFlash, SECTOR};
+    use ed25519_dalek::SigningKey;
+
+    /// The layout of `partitions.csv`
+    fn partitions() -> [PartitionInfo; 6] {
+        let entry = |kind, subtype, offset, size| PartitionInfo {
+            kind,
+            subtype,
+            region: Region { offset, size },
+        };
+        [
+            entry(TYPE_DATA, 0x02, 0x9000, 0x4000),
+            entry(TYPE_DATA, SUBTYPE_OTADATA, 0xD000, 0x2000),
+            entry(TYPE_DATA, 0x01, 0xF000, 0x1000),
+            entry(TYPE_APP, 0x10, 0x1_0000, 0x1E_0000),
+            entry(TYPE_APP, 0x11, 0x1F_0000, 0x1E_0000),
+            entry(TYPE_DATA, 0x06, 0x3D_0000, 0x4000),
+        ]
+    }
+
+    fn otadata() -> OtaData<MockFlash> {
+        OtaData::new(MockFlash::new(OTADATA_LEN as usize / SECTOR)).unwrap()
+    }
+
+    fn entry_bytes(otadata: &OtaData<MockFlash>, sector: usize) -> &[u8] {
+        let start = sector * ENTRY_SECTOR_LEN as usize;
+        &otadata.flash.data[start..start + ENTRY_LEN]
+    }
+
+    #[test]
+    fn finds_ota_partitions() {
+        let layout = PartitionLayout::find(partitions()).unwrap();
+        assert_eq!(
+            layout.otadata,
+            Region {
+                offset: 0xD000,
+                size: 0x2000
+            }
+        );
+        assert_eq!(layout.slot(Slot::Ota1).offset, 0x1F_0000);
+        assert_eq!(layout.slot_at(0x1_0000), Some(Slot::Ota0));
+        assert_eq!(layout.slot_at(0x9000), None);
+
+        let without = |subtype| {
+            partitions()
+                .into_iter()
+                .filter(move |p| p.subtype != subtype)
+        };
+        assert_eq!(
+            PartitionLayout::find(without(0x11)),
+            Err(OtaError::MissingPartition("ota_1"))
+        );
+        let mut overlapping = partitions();
+        overlapping[4].region.offset = 0x1E_0000;
+        assert_eq!(
+            PartitionLayout::find(overlapping),
+            Err(OtaError::InvalidLayout)
+        );
+        let mut short = partitions();
+        short[1].region.size = 0x1000;
+        assert_eq!(PartitionLayout::find(short), Err(OtaError::InvalidLayout));
+    }
+
+    #[test]
+    fn selects_slots_like_the_bootloader() {
+        let mut otadata = otadata();
+        assert_eq!(otadata.selection(), Ok(None));
+        assert_eq!(otadata.check_boot(Slot::Ota0), Ok(BootCheck::Settled));
+
+        otadata.select(Slot::Ota1).unwrap();
+        // The entry written by ESP-IDF for the same selection
+        let mut expected = [0xFF; ENTRY_LEN];
+        expected[..4].copy_from_slice(&[2, 0, 0, 0]);
+        expected[24..].copy_from_slice(&[0, 0, 0, 0, 116, 55, 246, 85]);
+        assert_eq!(entry_bytes(&otadata, 0), expected);
+        let selection = otadata.selection().unwrap().unwrap();
+        assert_eq!(
+            (selection.slot, selection.state, selection.seq),
+            (Slot::Ota1, ImageState::New, 2)
+        );
+
+        // The next selection goes into the other sector
+        otadata.select(Slot::Ota0).unwrap();
+        assert_eq!(entry_bytes(&otadata, 0), expected);
+        let selection = otadata.selection().unwrap().unwrap();
+        assert_eq!((selection.slot, selection.seq), (Slot::Ota0, 3));
+        otadata.select(Slot::Ota0).unwrap();
+        assert_eq!(otadata.selection().unwrap().unwrap().seq, 5);
+    }
+
+    #[test]
+    fn confirms_or_rolls_back_trial_images() {
+        let mut otadata = otadata();
+        otadata.select(Slot::Ota0).unwrap();
+        otadata.confirm().unwrap();
+        assert_eq!(otadata.check_boot(Slot::Ota0), Ok(BootCheck::Settled));
+
+        // Confirmed on the second boot
+        otadata.select(Slot::Ota1).unwrap();
+        assert_eq!(
+            otadata.check_boot(Slot::Ota1),
+            Ok(BootCheck::Trial { boot: 1 })
+        );
+        assert_eq!(
+            otadata.check_boot(Slot::Ota1),
+            Ok(BootCheck::Trial { boot: 2 })
+        );
+        assert_eq!(otadata.confirm(), Ok(true));
+        assert_eq!(otadata.confirm(), Ok(false));
+        assert_eq!(otadata.check_boot(Slot::Ota1), Ok(BootCheck::Settled));
+        assert_eq!(
+            otadata.selection().unwrap().unwrap().state,
+            ImageState::Valid
+        );
+
+        // Never confirmed
+        otadata.select(Slot::Ota0).unwrap();
+        for boot in 1..=MAX_TRIAL_BOOTS {
+            assert_eq!(
+                otadata.check_boot(Slot::Ota0),
+                Ok(BootCheck::Trial { boot })
+            );
+        }
+        assert_eq!(otadata.check_boot(Slot::Ota0), Ok(BootCheck::RolledBack));
+        let selection = otadata.selection().unwrap().unwrap();
+        assert_eq!(
+            (selection.slot, selection.state),
+            (Slot::Ota1, ImageState::Valid)
+        );
+
+        // Given up on, after the bootloader started the trial
+        otadata.select(Slot::Ota0).unwrap();
+        let sector = otadata.selection().unwrap().unwrap().sector;
+        let mut entry = otadata.read_entry(sector).unwrap();
+        entry.state = ImageState::PendingVerify;
+        otadata.write_entry(sector, entry).unwrap();
+        assert_eq!(
+            otadata.check_boot(Slot::Ota0),
+            Ok(BootCheck::Trial { boot: 1 })
+        );
+        assert_eq!(otadata.roll_back(), Ok(Some(Slot::Ota1)));
+        assert_eq!(otadata.check_boot(Slot::Ota1), Ok(BootCheck::Settled));
+    }
+
+    #[test]
+    fn keeps_a_selection_through_power_loss() {
+        let mut base = otadata();
+        base.select(Slot::Ota0).unwrap();
+        base.confirm().unwrap();
+
+        for ops in 1.. {
+            let mut otadata = OtaData::new(base.flash.clone()).unwrap();
+            otadata.flash.cut_after(ops);
+            let done = otadata.select(Slot::Ota1).is_ok();
+            otadata.flash.power_on();
+            let slot = otadata.selection().unwrap().map(|selection| selection.slot);
+            if done {
+                assert_eq!(slot, Some(Slot::Ota1));
+                break;
+            }
+            assert!(slot.is_some(), "no selection after a cut at {ops}");
+        }
+    }
+
+    #[test]
+    fn writes_and_verifies_signed_images() {
+        let key = VerifyingKey::from(SigningKey::from_bytes(&TEST_SEED).verifying_key());
+        let new_writer = || SlotWriter::new(MockFlash::new(2), CHIP_ID_ESP32, key).unwrap();
+        let image = image("2.0.0", true);
+        let signed = signed_image(image.clone(), &TEST_SEED);
+        for chunk in [1, 100, CHUNK_LEN, signed.len()] {
+            let mut writer = new_writer();
+            for part in signed.chunks(chunk) {
+                writer.write(part).unwrap();
+            }
+            assert_eq!(writer.len() as usize, image.len() + SIGNATURE_BLOCK_LEN);
+            let (info, flash) = writer.finish().unwrap();
+            assert_eq!(info.version, "2.0.0");
+            // Only the image goes to flash; the padding of the last word is
+            // left erased
+            assert_eq!(&flash.data[..image.len()], &image[..]);
+            assert!(flash.data[image.len()..].iter().all(|&b| b == 0xFF));
+            assert_eq!(flash.erases, [1, 0]);
+        }
+
+        let mut writer = new_writer();
+        writer.write(&signed[..100]).unwrap();
+        assert_eq!(
+            writer.finish().err(),
+            Some(OtaError::Image(ImageError::Truncated))
+        );
+        let mut writer = new_writer();
+        assert_eq!(
+            writer.write(&[0; 64]),
+            Err(OtaError::Image(ImageError::BadMagic))
+        );
+        let mut writer = new_writer();
+        writer.write(&image).unwrap();
+        assert_eq!(
+            writer.finish().err(),
+            Some(OtaError::Image(ImageError::Unsigned))
+        );
+        let mut writer = new_writer();
+        writer
+            .write(&signed_image(image.clone(), &[8; 32]))
+            .unwrap();
+        assert_eq!(
+            writer.finish().err(),
+            Some(OtaError::Image(ImageError::BadSignature))
+        );
+    }
+}
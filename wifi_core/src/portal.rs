@@ -0,0 +1,283 @@
+//! Captive provisioning portal: page, routes and form.
+//!
+//! While the device has no credentials, it serves a small page on its own
+//! access point that lists the networks from the latest scan and asks for an
+//! SSID and password. [`route`] decides what to answer to each request,
+//! [`render_page`] writes the page and [`parse_form`] turns the submitted
+//! form into [`Credentials`].
+//!
+//! Any other path is redirected to the page, which makes phones and laptops
+//! that probe for Internet access open the portal on their own.
+
+use core::fmt;
+
+use crate::http::{Method, Request, form_decode, form_fields};
+use crate::report::{AccessPointRecord, AuthMethod, Ssid, distinct_networks};
+use crate::station::{Credentials, CredentialsError, MAX_PASSWORD_LEN, Password};
+
+/// Path of the portal page and of the form submission
+pub const PORTAL_PATH: &str = "/";
+
+/// Reasons why a submitted form was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FormError {
+    /// The body is not a valid form
+    Malformed,
+    /// The SSID field is missing
+    MissingSsid,
+    /// The credentials are invalid
+    Credentials(CredentialsError),
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormError::Malformed => f.write_str("the form could not be read"),
+            FormError::MissingSsid => f.write_str("no network name was given"),
+            FormError::Credentials(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// What to answer to a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortalAction {
+    /// Serve the portal page
+    Page,
+    /// Redirect to the portal page
+    Redirect,
+    /// Credentials were submitted
+    Submitted(Credentials),
+    /// The submitted form was rejected; serve the page with the error
+    Rejected(FormError),
+    /// The method is not supported
+    MethodNotAllowed,
+}
+
+/// Decides what to answer to `request`
+pub fn route(request: &Request<'_>) -> PortalAction {
+    match (request.method, request.path == PORTAL_PATH) {
+        (Method::Get | Method::Head, true) => PortalAction::Page,
+        (Method::Get | Method::Head, false) => PortalAction::Redirect,
+        (Method::Post, true) => match parse_form(request.body) {
+            Ok(credentials) => PortalAction::Submitted(credentials),
+            Err(e) => PortalAction::Rejected(e),
+        },
+        _ => PortalAction::MethodNotAllowed,
+    }
+}
+
+/// Reads the `ssid` and `password` fields of a submitted form.
+///
+/// # Errors
+///
+/// Returns a [`FormError`] if the body is not a form, the SSID is missing or
+/// the credentials are invalid.
+pub fn parse_form(body: &[u8]) -> Result<Credentials, FormError> {
+    let body = core::str::from_utf8(body).map_err(|_| FormError::Malformed)?;
+    let mut ssid = None;
+    let mut password = Password::new();
+    for (name, value) in form_fields(body) {
+        match name {
+            // One byte of slack so that overlong values fail in Credentials::new
+            "ssid" => ssid = Some(form_decode::<33>(value).ok_or(FormError::Malformed)?),
+            "password" => {
+                let value =
+                    form_decode::<{ MAX_PASSWORD_LEN + 1 }>(value).ok_or(FormError::Malformed)?;
+                password = Password::try_from(value.as_str())
+                    .map_err(|_| FormError::Credentials(CredentialsError::InvalidPasswordLength))?;
+            }
+            _ => {}
+        }
+    }
+    let ssid = ssid.ok_or(FormError::MissingSsid)?;
+    Credentials::new(&ssid, &password).map_err(FormError::Credentials)
+}
+
+/// Outcome shown at the top of the page
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Notice<'a> {
+    /// The credentials were saved and the device is joining the network
+    Saved(&'a str),
+    /// The form was rejected
+    Error(FormError),
+}
+
+/// Writes the portal page listing `networks`, strongest first.
+///
+/// Networks are listed as by [`distinct_networks`].
+///
+/// # Errors
+///
+/// Returns an error if `out` fails.
+pub fn render_page<'a>(
+    out: &mut impl fmt::Write,
+    networks: impl IntoIterator<Item = &'a AccessPointRecord>,
+    notice: Option<Notice<'_>>,
+) -> fmt::Result {
+    out.write_str(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+        <meta name=\"viewport\" content=\"width=device-width,initial-scale=1\">\
+        <title>WiFi setup</title><style>\
+        body{font-family:sans-serif;max-width:26em;margin:auto;padding:1em}\
+        input,button{width:100%;padding:.5em;margin:.3em 0;box-sizing:border-box}\
+        li{list-style:none;padding:.2em 0}.e{color:#b00}\
+        </style></head><body><h1>WiFi setup</h1>",
+    )?;
+    match notice {
+        Some(Notice::Saved(ssid)) => {
+            return write!(
+                out,
+                "<p>Saved. Joining <b>{}</b>; this access point will now close.</p></body></html>",
+                Escaped(ssid)
+            );
+        }
+        Some(Notice::Error(e)) => write!(out, "<p class=\"e\">{}</p>", Escaped(&e))?,
+        None => {}
+    }
+
+    let list = distinct_networks(networks);
+
+    write!(out, "<form method=\"post\" action=\"{PORTAL_PATH}\"><ul>")?;
+    for ap in &list {
+        let lock = match ap.auth_method {
+            Some(AuthMethod::Open) => "",
+            _ => " &#128274;",
+        };
+        write!(
+            out,
+            "<li><label><input type=\"radio\" name=\"ssid\" value=\"{ssid}\" style=\"width:auto\" \
+            onclick=\"document.getElementById('s').value=this.value\"> {ssid} ({rssi} dBm){lock}\
+            </label></li>",
+            ssid = Escaped(&ap.ssid),
+            rssi = ap.signal_strength,
+        )?;
+    }
+    out.write_str(
+        "</ul><input id=\"s\" name=\"ssid\" placeholder=\"Network name\" maxlength=\"32\" required>\
+        <input name=\"password\" type=\"password\" placeholder=\"Password\" maxlength=\"64\">\
+        <button>Connect</button></form></body></html>",
+    )
+}
+
+/// Writes a value with the HTML special characters escaped
+struct Escaped<'a, T: ?Sized>(&'a T);
+
+impl<T: fmt::Display + ?Sized> fmt::Display for Escaped<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Escaper<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+        impl fmt::Write for Escaper<'_, '_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                for c in s.chars() {
+                    match c {
+                        '<' => self.0.write_str("&lt;")?,
+                        '>' => self.0.write_str("&gt;")?,
+                        '&' => self.0.write_str("&amp;")?,
+                        '"' => self.0.write_str("&quot;")?,
+                        '\'' => self.0.write_str("&#39;")?,
+                        _ => fmt::Write::write_char(self.0, c)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        fmt::write(&mut Escaper(f), format_args!("{}", self.0))
+    }
+}
+
+/// SSID of the provisioning access point for a device with the given MAC address
+pub fn access_point_ssid(prefix: &str, mac: [u8; 6]) -> Ssid {
+    let mut ssid = crate::report::truncated_ssid(prefix);
+    let _ = fmt::Write::write_fmt(&mut ssid, format_args!("{:02X}{:02X}", mac[4], mac[5]));
+    ssid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::fixtures::ap;
+    use std::string::String;
+
+    fn request(raw: &[u8]) -> PortalAction {
+        route(&Request::parse(raw).unwrap())
+    }
+
+    #[test]
+    fn routes_requests() {
+        assert_eq!(request(b"GET / HTTP/1.1\r\n\r\n"), PortalAction::Page);
+        assert_eq!(
+            request(b"GET /generate_204 HTTP/1.1\r\n\r\n"),
+            PortalAction::Redirect
+        );
+        assert_eq!(
+            request(b"DELETE / HTTP/1.1\r\n\r\n"),
+            PortalAction::MethodNotAllowed
+        );
+        assert_eq!(
+            request(
+                b"POST / HTTP/1.1\r\nContent-Length: 34\r\n\r\nssid=My+Net&password=p%40ssw0rd%21"
+            ),
+            PortalAction::Submitted(Credentials::new("My Net", "p@ssw0rd!").unwrap())
+        );
+        assert_eq!(
+            request(b"POST / HTTP/1.1\r\nContent-Length: 20\r\n\r\nssid=x&password=1234"),
+            PortalAction::Rejected(FormError::Credentials(
+                CredentialsError::InvalidPasswordLength
+            ))
+        );
+    }
+
+    #[test]
+    fn parses_form() {
+        assert_eq!(
+            parse_form(b"ssid=cafe&password="),
+            Ok(Credentials::new("cafe", "").unwrap())
+        );
+        assert_eq!(
+            parse_form(b"password=secret123"),
+            Err(FormError::MissingSsid)
+        );
+        assert_eq!(parse_form(b"ssid=%E2%28"), Err(FormError::Malformed));
+        assert_eq!(
+            parse_form(b"ssid=aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"),
+            Err(FormError::Credentials(CredentialsError::SsidTooLong))
+        );
+    }
+
+    #[test]
+    fn renders_escaped_deduplicated_networks() {
+        let networks = [
+            ap(1).with_ssid("office").with_rssi(-70),
+            ap(2)
+                .with_ssid("<script>")
+                .with_rssi(-40)
+                .with_auth(AuthMethod::Open),
+            ap(3).with_ssid("office"),
+            ap(4).with_ssid("").with_rssi(-30),
+        ];
+        let mut page = String::new();
+        render_page(&mut page, &networks, None).unwrap();
+
+        assert!(page.contains("&lt;script&gt; (-40 dBm)</label>"));
+        assert!(!page.contains("<script>"));
+        assert_eq!(page.matches("value=\"office\"").count(), 1);
+        assert!(page.contains("office (-50 dBm) &#128274;"));
+        assert!(page.find("&lt;script&gt;") < page.find("office"));
+        assert_eq!(page.matches("type=\"radio\"").count(), 2);
+
+        let mut page = String::new();
+        render_page(&mut page, &[], Some(Notice::Saved("a&b"))).unwrap();
+        assert!(page.contains("Joining <b>a&amp;b</b>"));
+    }
+
+    #[test]
+    fn derives_access_point_ssid() {
+        assert_eq!(
+            access_point_ssid("esp-setup-", [0, 1, 2, 3, 0xAB, 0x0C]).as_str(),
+            "esp-setup-AB0C"
+        );
+    }
+}
@@ -0,0 +1,288 @@
+//! Known network profiles and network selection.
+//!
+//! A [`ProfileStore`] holds the credentials of up to [`MAX_PROFILES`]
+//! networks, each with a priority and an optional BSSID pin. Given a
+//! [`ScanReport`], [`ProfileStore::select`] lists the known networks in range
+//! as [`Candidate`]s, best first, so the station can fall back to the next
+//! one when a connection fails.
+//!
+//! # Selection
+//!
+//! An access point matches a profile if its SSID is the profile's SSID, its
+//! BSSID is the pinned one (if any), and its security fits the credentials:
+//! an open profile only matches open networks and a profile with a password
+//! never matches an open network, which could be an evil twin. Access points
+//! of unknown security are accepted.
+//!
+//! Each profile yields at most one candidate, its strongest matching access
+//! point. Candidates are ordered by priority, highest first, then by signal
+//! strength.
+
+use core::cmp::Reverse;
+
+use crate::report::{AccessPointRecord, AuthMethod, Bssid, ScanReport};
+use crate::station::Credentials;
+
+/// Maximum number of stored network profiles
+pub const MAX_PROFILES: usize = 8;
+
+/// A known network
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NetworkProfile {
+    /// SSID and password of the network
+    pub credentials: Credentials,
+    /// Preference over other networks in range; higher is preferred
+    pub priority: u8,
+    /// Only connect to this access point
+    pub bssid: Option<Bssid>,
+}
+
+impl NetworkProfile {
+    /// Creates a profile with priority 0 and no BSSID pin
+    pub const fn new(credentials: Credentials) -> Self {
+        Self {
+            credentials,
+            priority: 0,
+            bssid: None,
+        }
+    }
+
+    /// Sets the priority; higher is preferred
+    #[must_use]
+    pub const fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Pins the profile to one access point
+    #[must_use]
+    pub fn with_bssid(mut self, bssid: impl Into<Bssid>) -> Self {
+        self.bssid = Some(bssid.into());
+        self
+    }
+
+    /// Returns `true` if `ap` belongs to this network
+    pub fn matches(&self, ap: &AccessPointRecord) -> bool {
+        let security_fits = match ap.auth_method {
+            Some(AuthMethod::Open) => self.credentials.is_open(),
+            Some(_) => !self.credentials.is_open(),
+            None => true,
+        };
+        ap.ssid == self.credentials.ssid
+            && self.bssid.is_none_or(|bssid| bssid == ap.bssid)
+            && security_fits
+    }
+}
+
+/// A known network in range, as picked by [`ProfileStore::select`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Candidate {
+    /// SSID and password of the network
+    pub credentials: Credentials,
+    /// Priority of the profile
+    pub priority: u8,
+    /// Strongest matching access point
+    pub bssid: Bssid,
+    /// Channel of that access point
+    pub channel: u8,
+    /// Signal strength of that access point in dBm
+    pub rssi: i8,
+}
+
+/// Stored network profiles
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ProfileStore {
+    profiles: heapless::Vec<NetworkProfile, MAX_PROFILES>,
+}
+
+impl ProfileStore {
+    /// Creates an empty store
+    pub const fn new() -> Self {
+        Self {
+            profiles: heapless::Vec::new(),
+        }
+    }
+
+    /// Adds a profile, replacing any profile for the same SSID.
+    ///
+    /// # Errors
+    ///
+    /// Returns the profile back if the store is full.
+    pub fn insert(&mut self, profile: NetworkProfile) -> Result<(), NetworkProfile> {
+        let ssid = &profile.credentials.ssid;
+        if let Some(existing) = self
+            .profiles
+            .iter_mut()
+            .find(|p| p.credentials.ssid == *ssid)
+        {
+            *existing = profile;
+            return Ok(());
+        }
+        self.profiles.push(profile)
+    }
+
+    /// Removes the profile for an SSID
+    pub fn remove(&mut self, ssid: &str) -> Option<NetworkProfile> {
+        let index = self
+            .profiles
+            .iter()
+            .position(|p| p.credentials.ssid.as_str() == ssid)?;
+        Some(self.profiles.remove(index))
+    }
+
+    /// Looks up the profile for an SSID
+    pub fn get(&self, ssid: &str) -> Option<&NetworkProfile> {
+        self.profiles
+            .iter()
+            .find(|p| p.credentials.ssid.as_str() == ssid)
+    }
+
+    /// Iterates over the profiles
+    pub fn iter(&self) -> core::slice::Iter<'_, NetworkProfile> {
+        self.profiles.iter()
+    }
+
+    /// Number of stored profiles
+    pub fn len(&self) -> usize {
+        self.profiles.len()
+    }
+
+    /// Returns `true` if no profile is stored
+    pub fn is_empty(&self) -> bool {
+        self.profiles.is_empty()
+    }
+
+    /// Lists the known networks in `report`, best first.
+    pub fn select(&self, report: &ScanReport) -> heapless::Vec<Candidate, MAX_PROFILES> {
+        let mut candidates: heapless::Vec<Candidate, MAX_PROFILES> = self
+            .profiles
+            .iter()
+            .filter_map(|profile| {
+                let ap = report
+                    .iter()
+                    .filter(|ap| profile.matches(ap))
+                    .max_by_key(|ap| ap.signal_strength)?;
+                Some(Candidate {
+                    credentials: profile.credentials.clone(),
+                    priority: profile.priority,
+                    bssid: ap.bssid,
+                    channel: ap.channel,
+                    rssi: ap.signal_strength,
+                })
+            })
+            .collect();
+        candidates.sort_unstable_by_key(|c| (Reverse(c.priority), Reverse(c.rssi)));
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::fixtures::{ap, report};
+    use std::vec::Vec;
+
+    fn profile(ssid: &str, password: &str, priority: u8) -> NetworkProfile {
+        NetworkProfile::new(Credentials::new(ssid, password).unwrap()).with_priority(priority)
+    }
+
+    fn order(candidates: &[Candidate]) -> Vec<(&str, u8)> {
+        candidates
+            .iter()
+            .map(|c| (c.credentials.ssid.as_str(), c.bssid.0[5]))
+            .collect()
+    }
+
+    #[test]
+    fn orders_by_priority_then_signal() {
+        let mut store = ProfileStore::new();
+        store.insert(profile("office", "password1", 1)).unwrap();
+        store.insert(profile("lab", "password2", 1)).unwrap();
+        store.insert(profile("home", "password3", 5)).unwrap();
+        store.insert(profile("away", "password4", 9)).unwrap();
+
+        let scan = report(
+            1,
+            &[
+                ap(1).with_ssid("office").with_rssi(-70),
+                ap(2).with_ssid("office").with_rssi(-40),
+                ap(3).with_ssid("lab"),
+                ap(4)
+                    .with_ssid("home")
+                    .with_rssi(-85)
+                    .with_auth(AuthMethod::Wpa3Personal),
+                ap(5).with_ssid("stranger").with_rssi(-30),
+            ],
+        );
+        assert_eq!(
+            order(&store.select(&scan)),
+            [("home", 4), ("office", 2), ("lab", 3)]
+        );
+    }
+
+    #[test]
+    fn honors_bssid_pin() {
+        let mut store = ProfileStore::new();
+        store
+            .insert(profile("office", "password1", 0).with_bssid([0, 0, 0, 0, 0, 1]))
+            .unwrap();
+
+        let scan = report(
+            1,
+            &[
+                ap(1).with_ssid("office").with_rssi(-70),
+                ap(2).with_ssid("office").with_rssi(-40),
+            ],
+        );
+        assert_eq!(order(&store.select(&scan)), [("office", 1)]);
+
+        let scan = report(1, &[ap(2).with_ssid("office").with_rssi(-40)]);
+        assert!(store.select(&scan).is_empty());
+    }
+
+    #[test]
+    fn skips_access_points_with_mismatched_security() {
+        let mut store = ProfileStore::new();
+        store.insert(profile("office", "password1", 0)).unwrap();
+        store.insert(profile("cafe", "", 0)).unwrap();
+
+        let scan = report(
+            1,
+            &[
+                ap(1)
+                    .with_ssid("office")
+                    .with_rssi(-40)
+                    .with_auth(AuthMethod::Open),
+                ap(2).with_ssid("cafe").with_rssi(-40),
+                ap(3)
+                    .with_ssid("cafe")
+                    .with_rssi(-80)
+                    .with_auth(AuthMethod::Open),
+            ],
+        );
+        assert_eq!(order(&store.select(&scan)), [("cafe", 3)]);
+    }
+
+    #[test]
+    fn replaces_and_removes_profiles() {
+        let mut store = ProfileStore::new();
+        store.insert(profile("office", "password1", 0)).unwrap();
+        store.insert(profile("office", "password2", 3)).unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.get("office").map(|p| p.priority), Some(3));
+
+        for i in 1..MAX_PROFILES {
+            store
+                .insert(profile(&std::format!("net{i}"), "", 0))
+                .unwrap();
+        }
+        assert!(store.insert(profile("extra", "", 0)).is_err());
+
+        assert!(store.remove("office").is_some());
+        assert!(store.remove("office").is_none());
+        assert!(store.insert(profile("extra", "", 0)).is_ok());
+    }
+}
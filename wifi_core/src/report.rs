@@ -0,0 +1,533 @@
+//! Typed WiFi scan results.
+//!
+//! A [`ScanReport`] is produced for every completed scan. It holds one
+//! [`AccessPointRecord`] per discovered network, together with the scan sequence
+//! number and the time the scan finished. All storage is fixed-capacity, so reports
+//! can be copied between tasks without a heap.
+
+use core::cmp::Ordering;
+use core::fmt;
+
+use crate::clock::UtcTime;
+
+/// Maximum SSID length in bytes, as defined by IEEE 802.11
+pub const MAX_SSID_LEN: usize = 32;
+
+/// Maximum number of access points kept in a single report
+pub const MAX_ACCESS_POINTS: usize = 32;
+
+/// SSID of an access point
+pub type Ssid = heapless::String<MAX_SSID_LEN>;
+
+/// BSSID (MAC address) of an access point.
+///
+/// Formats as the usual colon separated hex string, e.g. `aa:bb:cc:00:11:22`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Bssid(pub [u8; 6]);
+
+impl fmt::Display for Bssid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl Bssid {
+    /// Parses the colon separated form, in either case.
+    ///
+    /// Returns `None` unless `text` is six pairs of hex digits.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut bytes = [0; 6];
+        let mut parts = text.split(':');
+        for byte in &mut bytes {
+            let part = parts.next()?;
+            if part.len() != 2 {
+                return None;
+            }
+            *byte = u8::from_str_radix(part, 16).ok()?;
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(Self(bytes)),
+        }
+    }
+}
+
+impl From<[u8; 6]> for Bssid {
+    fn from(bytes: [u8; 6]) -> Self {
+        Self(bytes)
+    }
+}
+
+/// Position of the secondary (40 MHz) channel relative to the primary channel
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SecondaryChannel {
+    /// No secondary channel (20 MHz operation)
+    #[default]
+    None,
+    /// Secondary channel is above the primary channel
+    Above,
+    /// Secondary channel is below the primary channel
+    Below,
+}
+
+/// Authentication method advertised by an access point
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AuthMethod {
+    /// Open network, no authentication
+    Open,
+    /// Wired Equivalent Privacy
+    Wep,
+    /// WPA Personal
+    Wpa,
+    /// WPA2 Personal
+    Wpa2Personal,
+    /// WPA/WPA2 Personal mixed mode
+    WpaWpa2Personal,
+    /// WPA2 Enterprise
+    Wpa2Enterprise,
+    /// WPA3 Personal
+    Wpa3Personal,
+    /// WPA2/WPA3 Personal mixed mode
+    Wpa2Wpa3Personal,
+    /// WLAN Authentication and Privacy Infrastructure
+    WapiPersonal,
+}
+
+impl AuthMethod {
+    /// Every authentication method, weakest first
+    pub const ALL: [AuthMethod; 9] = [
+        AuthMethod::Open,
+        AuthMethod::Wep,
+        AuthMethod::Wpa,
+        AuthMethod::WpaWpa2Personal,
+        AuthMethod::Wpa2Personal,
+        AuthMethod::Wpa2Wpa3Personal,
+        AuthMethod::WapiPersonal,
+        AuthMethod::Wpa2Enterprise,
+        AuthMethod::Wpa3Personal,
+    ];
+
+    /// Relative strength of the authentication method, higher is more secure.
+    ///
+    /// Mixed modes rank with their weaker member, since clients may use it.
+    pub const fn security_level(&self) -> u8 {
+        match self {
+            AuthMethod::Open => 0,
+            AuthMethod::Wep => 1,
+            AuthMethod::Wpa | AuthMethod::WpaWpa2Personal => 2,
+            AuthMethod::Wpa2Personal | AuthMethod::Wpa2Wpa3Personal | AuthMethod::WapiPersonal => 3,
+            AuthMethod::Wpa2Enterprise | AuthMethod::Wpa3Personal => 4,
+        }
+    }
+
+    /// Short human readable name of the authentication method
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            AuthMethod::Open => "open",
+            AuthMethod::Wep => "WEP",
+            AuthMethod::Wpa => "WPA",
+            AuthMethod::Wpa2Personal => "WPA2",
+            AuthMethod::WpaWpa2Personal => "WPA/WPA2",
+            AuthMethod::Wpa2Enterprise => "WPA2-Enterprise",
+            AuthMethod::Wpa3Personal => "WPA3",
+            AuthMethod::Wpa2Wpa3Personal => "WPA2/WPA3",
+            AuthMethod::WapiPersonal => "WAPI",
+        }
+    }
+
+    /// Parses the name returned by [`AuthMethod::as_str`], in either case
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|auth| auth.as_str().eq_ignore_ascii_case(name))
+    }
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A single access point seen during a scan
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AccessPointRecord {
+    /// Network name; empty for hidden networks
+    pub ssid: Ssid,
+    /// MAC address of the access point
+    pub bssid: Bssid,
+    /// Primary channel
+    pub channel: u8,
+    /// Secondary channel configuration
+    pub secondary_channel: SecondaryChannel,
+    /// Received signal strength in dBm
+    pub signal_strength: i8,
+    /// Authentication method, if the driver reported one
+    pub auth_method: Option<AuthMethod>,
+}
+
+impl AccessPointRecord {
+    /// Creates a record, truncating `ssid` to [`MAX_SSID_LEN`] bytes on a character boundary.
+    pub fn new(
+        ssid: &str,
+        bssid: impl Into<Bssid>,
+        channel: u8,
+        secondary_channel: SecondaryChannel,
+        signal_strength: i8,
+        auth_method: Option<AuthMethod>,
+    ) -> Self {
+        Self {
+            ssid: truncated_ssid(ssid),
+            bssid: bssid.into(),
+            channel,
+            secondary_channel,
+            signal_strength,
+            auth_method,
+        }
+    }
+
+    /// Returns `true` if the access point does not broadcast its SSID
+    pub fn is_hidden(&self) -> bool {
+        self.ssid.is_empty()
+    }
+}
+
+impl fmt::Display for AccessPointRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_hidden() {
+            f.write_str("SSID: <hidden>")?;
+        } else {
+            write!(f, "SSID: {}", self.ssid)?;
+        }
+        write!(
+            f,
+            ", BSSID: {}, Channel: {}, RSSI: {}, Auth: ",
+            self.bssid, self.channel, self.signal_strength
+        )?;
+        match self.auth_method {
+            Some(auth) => write!(f, "{auth}"),
+            None => f.write_str("unknown"),
+        }
+    }
+}
+
+/// Access points and reports for the unit tests of this crate.
+///
+/// [`ap`](fixtures::ap) builds a WPA2 network called `net` on channel 6 at
+/// -50 dBm, and the `with_` methods change what a test cares about.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+
+    /// Access point whose BSSID is `00:00:00:00:00:<last>`
+    pub(crate) fn ap(last: u8) -> AccessPointRecord {
+        AccessPointRecord::new(
+            "net",
+            [0, 0, 0, 0, 0, last],
+            6,
+            SecondaryChannel::None,
+            -50,
+            Some(AuthMethod::Wpa2Personal),
+        )
+    }
+
+    /// Report of scan number `sequence`, completed `sequence` seconds after
+    /// boot, holding `aps`
+    pub(crate) fn report(sequence: u32, aps: &[AccessPointRecord]) -> ScanReport {
+        let mut report = ScanReport::new(sequence, u64::from(sequence) * 1000);
+        for record in aps {
+            report.push(record.clone());
+        }
+        report
+    }
+
+    impl AccessPointRecord {
+        /// Sets the SSID
+        pub(crate) fn with_ssid(mut self, ssid: &str) -> Self {
+            self.ssid = truncated_ssid(ssid);
+            self
+        }
+
+        /// Sets the primary channel
+        pub(crate) fn with_channel(mut self, channel: u8) -> Self {
+            self.channel = channel;
+            self
+        }
+
+        /// Sets the secondary channel
+        pub(crate) fn with_secondary(mut self, secondary: SecondaryChannel) -> Self {
+            self.secondary_channel = secondary;
+            self
+        }
+
+        /// Sets the signal strength, in dBm
+        pub(crate) fn with_rssi(mut self, rssi: i8) -> Self {
+            self.signal_strength = rssi;
+            self
+        }
+
+        /// Sets the authentication method
+        pub(crate) fn with_auth(mut self, auth: AuthMethod) -> Self {
+            self.auth_method = Some(auth);
+            self
+        }
+    }
+}
+
+/// Copies at most [`MAX_SSID_LEN`] bytes of `ssid`, never splitting a UTF-8 character
+pub(crate) fn truncated_ssid(ssid: &str) -> Ssid {
+    let mut end = ssid.len().min(MAX_SSID_LEN);
+    while !ssid.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut out = Ssid::new();
+    // Cannot fail: `end` is bounded by the capacity
+    let _ = out.push_str(&ssid[..end]);
+    out
+}
+
+/// Visible networks among `records`, one per SSID, strongest first.
+///
+/// Hidden networks are left out; of several access points sharing an SSID,
+/// the one with the strongest signal is kept.
+pub fn distinct_networks<'a>(
+    records: impl IntoIterator<Item = &'a AccessPointRecord>,
+) -> heapless::Vec<&'a AccessPointRecord, MAX_ACCESS_POINTS> {
+    let mut list = heapless::Vec::<&AccessPointRecord, MAX_ACCESS_POINTS>::new();
+    for ap in records {
+        if ap.is_hidden() {
+            continue;
+        }
+        match list.iter_mut().find(|listed| listed.ssid == ap.ssid) {
+            Some(listed) if listed.signal_strength < ap.signal_strength => *listed = ap,
+            Some(_) => {}
+            None => {
+                let _ = list.push(ap);
+            }
+        }
+    }
+    list.sort_unstable_by_key(|ap| core::cmp::Reverse(ap.signal_strength));
+    list
+}
+
+/// Results of one completed scan
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScanReport {
+    /// Sequence number of the scan, incremented by one for every scan
+    pub sequence: u32,
+    /// Time the scan completed, in milliseconds since boot
+    pub timestamp_ms: u64,
+    /// Wall-clock time the scan completed, if the clock was synchronized
+    pub utc: Option<UtcTime>,
+    /// Access points found, in driver order unless sorted
+    pub access_points: heapless::Vec<AccessPointRecord, MAX_ACCESS_POINTS>,
+    /// Number of access points that did not fit into the report
+    pub dropped: u16,
+}
+
+impl ScanReport {
+    /// Creates an empty report for scan number `sequence` completed at `timestamp_ms`
+    pub fn new(sequence: u32, timestamp_ms: u64) -> Self {
+        Self {
+            sequence,
+            timestamp_ms,
+            utc: None,
+            access_points: heapless::Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Adds a record to the report.
+    ///
+    /// Returns `false` and counts the record in [`ScanReport::dropped`] if the report is full.
+    pub fn push(&mut self, record: AccessPointRecord) -> bool {
+        match self.access_points.push(record) {
+            Ok(()) => true,
+            Err(_) => {
+                self.dropped = self.dropped.saturating_add(1);
+                false
+            }
+        }
+    }
+
+    /// Number of access points in the report
+    pub fn len(&self) -> usize {
+        self.access_points.len()
+    }
+
+    /// Returns `true` if no access point was found
+    pub fn is_empty(&self) -> bool {
+        self.access_points.is_empty()
+    }
+
+    /// Iterates over the access points in the report
+    pub fn iter(&self) -> core::slice::Iter<'_, AccessPointRecord> {
+        self.access_points.iter()
+    }
+
+    /// Sorts the access points by signal strength, strongest first.
+    ///
+    /// Ties are broken by BSSID so the order is deterministic.
+    pub fn sort_by_signal(&mut self) {
+        self.access_points.sort_unstable_by(|a, b| {
+            b.signal_strength
+                .cmp(&a.signal_strength)
+                .then_with(|| a.bssid.cmp(&b.bssid))
+        });
+    }
+
+    /// Sorts the access points by SSID, then strongest signal first.
+    ///
+    /// Hidden networks sort last.
+    pub fn sort_by_ssid(&mut self) {
+        self.access_points.sort_unstable_by(|a, b| {
+            match (a.is_hidden(), b.is_hidden()) {
+                (false, true) => Ordering::Less,
+                (true, false) => Ordering::Greater,
+                _ => a.ssid.cmp(&b.ssid),
+            }
+            .then_with(|| b.signal_strength.cmp(&a.signal_strength))
+            .then_with(|| a.bssid.cmp(&b.bssid))
+        });
+    }
+
+    /// Access point with the strongest signal, if any
+    pub fn strongest(&self) -> Option<&AccessPointRecord> {
+        self.access_points
+            .iter()
+            .max_by_key(|ap| ap.signal_strength)
+    }
+
+    /// Looks up an access point by BSSID
+    pub fn find(&self, bssid: Bssid) -> Option<&AccessPointRecord> {
+        self.access_points.iter().find(|ap| ap.bssid == bssid)
+    }
+}
+
+impl fmt::Display for ScanReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Scan #{} at {} ms", self.sequence, self.timestamp_ms)?;
+        if let Some(utc) = self.utc {
+            write!(f, " ({})", utc)?;
+        }
+        write!(f, ": found {} networks", self.len())?;
+        if self.dropped > 0 {
+            write!(f, " ({} dropped)", self.dropped)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> IntoIterator for &'a ScanReport {
+    type Item = &'a AccessPointRecord;
+    type IntoIter = core::slice::Iter<'a, AccessPointRecord>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::{ap, report};
+    use super::*;
+    use std::format;
+    use std::vec::Vec;
+
+    #[test]
+    fn formats_record() {
+        let record = ap(0xab).with_ssid("office").with_rssi(-42);
+        assert_eq!(
+            format!("{record}"),
+            "SSID: office, BSSID: 00:00:00:00:00:ab, Channel: 6, RSSI: -42, Auth: WPA2"
+        );
+
+        let mut hidden = ap(1).with_ssid("").with_rssi(-80);
+        hidden.auth_method = None;
+        assert_eq!(
+            format!("{hidden}"),
+            "SSID: <hidden>, BSSID: 00:00:00:00:00:01, Channel: 6, RSSI: -80, Auth: unknown"
+        );
+    }
+
+    #[test]
+    fn parses_bssid() {
+        let bssid = Bssid([0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22]);
+        assert_eq!(Bssid::parse("aa:bb:cc:00:11:22"), Some(bssid));
+        assert_eq!(Bssid::parse("AA:BB:CC:00:11:22"), Some(bssid));
+        assert_eq!(Bssid::parse(&format!("{bssid}")), Some(bssid));
+        for text in [
+            "aa:bb:cc:00:11",
+            "aa:bb:cc:00:11:22:33",
+            "aa:bb:cc:0:011:22",
+            "aa:bb:cc:00:11:zz",
+        ] {
+            assert_eq!(Bssid::parse(text), None, "{text}");
+        }
+    }
+
+    #[test]
+    fn truncates_long_ssid_on_char_boundary() {
+        // 31 ASCII bytes followed by a 2-byte character
+        let long = "abcdefghijklmnopqrstuvwxyz01234é";
+        let record = ap(1).with_ssid(long);
+        assert_eq!(record.ssid.as_str(), "abcdefghijklmnopqrstuvwxyz01234");
+    }
+
+    #[test]
+    fn counts_dropped_records() {
+        let mut report = ScanReport::new(1, 0);
+        for i in 0..MAX_ACCESS_POINTS + 3 {
+            report.push(ap(i as u8).with_rssi(-60));
+        }
+        assert_eq!(report.len(), MAX_ACCESS_POINTS);
+        assert_eq!(report.dropped, 3);
+        assert_eq!(
+            format!("{report}"),
+            "Scan #1 at 0 ms: found 32 networks (3 dropped)"
+        );
+        report.utc = Some(UtcTime::from_unix_micros(1_714_564_800_000_000));
+        assert!(format!("{report}").starts_with("Scan #1 at 0 ms (2024-05-01T12:00:00.000Z): "));
+    }
+
+    #[test]
+    fn sorts_by_signal() {
+        let mut report = report(
+            7,
+            &[
+                ap(1).with_ssid("a").with_rssi(-70),
+                ap(2).with_ssid("b").with_rssi(-40),
+                ap(3).with_ssid("c").with_rssi(-90),
+                ap(0).with_ssid("d").with_rssi(-70),
+            ],
+        );
+        report.sort_by_signal();
+
+        let order: Vec<_> = report.iter().map(|r| r.bssid.0[5]).collect();
+        assert_eq!(order, [2, 0, 1, 3]);
+        assert_eq!(report.strongest().map(|r| r.ssid.as_str()), Some("b"));
+    }
+
+    #[test]
+    fn sorts_by_ssid_with_hidden_last() {
+        let mut report = report(
+            1,
+            &[
+                ap(1).with_ssid("").with_rssi(-30),
+                ap(2).with_ssid("beta"),
+                ap(3).with_ssid("alpha").with_rssi(-60),
+                ap(4).with_ssid("alpha").with_rssi(-40),
+            ],
+        );
+        report.sort_by_ssid();
+
+        let order: Vec<_> = report.iter().map(|r| r.bssid.0[5]).collect();
+        assert_eq!(order, [4, 3, 2, 1]);
+    }
+}
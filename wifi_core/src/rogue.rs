@@ -0,0 +1,459 @@
+//! Evil-twin and rogue access point detection.
+//!
+//! [`RogueDetector`] checks every [`ScanReport`] against an [`Allowlist`] of
+//! expected networks and raises a [`RogueAlert`] when an access point looks
+//! suspicious. Each alert carries an [`AlertReason`] with a stable numeric
+//! code for reporting to a backend.
+//!
+//! The checks are:
+//!
+//! - **Downgraded twin**: the same SSID is seen from several BSSIDs with
+//!   different authentication methods. The BSSIDs using a weaker method than
+//!   the strongest one seen are flagged, since an evil twin usually offers
+//!   weaker security to lure clients. This check needs no allowlist.
+//! - **Known SSID open**: an allowlisted SSID is advertised as an open network.
+//! - **Unexpected auth**: an allowlisted SSID uses a different authentication
+//!   method than expected.
+//! - **Unknown BSSID**: an allowlisted SSID with pinned BSSIDs is advertised
+//!   by a BSSID that is not pinned.
+//!
+//! An alert is raised once per BSSID and reason. The detector remembers the
+//! last [`MAX_REMEMBERED_ALERTS`] alerts; older ones may be raised again.
+
+use core::fmt;
+
+use crate::report::{AccessPointRecord, AuthMethod, Bssid, ScanReport, Ssid};
+
+/// Maximum number of BSSIDs pinned to one allowlisted SSID
+pub const MAX_PINNED_BSSIDS: usize = 8;
+
+/// Maximum number of allowlisted networks
+pub const MAX_ALLOWLIST_ENTRIES: usize = 8;
+
+/// Number of raised alerts remembered to avoid repeating them every scan
+pub const MAX_REMEMBERED_ALERTS: usize = 32;
+
+/// A network that is expected in the area
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AllowlistEntry {
+    /// SSID of the network
+    pub ssid: Ssid,
+    /// Authentication method all of its access points must use
+    pub auth_method: AuthMethod,
+    /// Access points allowed to advertise the SSID; empty means any
+    pub bssids: heapless::Vec<Bssid, MAX_PINNED_BSSIDS>,
+}
+
+impl AllowlistEntry {
+    /// Creates an entry for `ssid` that accepts any BSSID
+    pub fn new(ssid: &str, auth_method: AuthMethod) -> Self {
+        Self {
+            ssid: crate::report::truncated_ssid(ssid),
+            auth_method,
+            bssids: heapless::Vec::new(),
+        }
+    }
+
+    /// Pins a BSSID to the SSID. Once any BSSID is pinned, all others are flagged.
+    ///
+    /// BSSIDs beyond [`MAX_PINNED_BSSIDS`] are ignored.
+    #[must_use]
+    pub fn with_bssid(mut self, bssid: impl Into<Bssid>) -> Self {
+        let _ = self.bssids.push(bssid.into());
+        self
+    }
+
+    /// Returns `true` if the SSID is restricted to pinned BSSIDs
+    pub fn is_pinned(&self) -> bool {
+        !self.bssids.is_empty()
+    }
+}
+
+/// Set of expected networks
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Allowlist {
+    entries: heapless::Vec<AllowlistEntry, MAX_ALLOWLIST_ENTRIES>,
+}
+
+impl Allowlist {
+    /// Creates an empty allowlist
+    pub const fn new() -> Self {
+        Self {
+            entries: heapless::Vec::new(),
+        }
+    }
+
+    /// Adds an entry, replacing any entry for the same SSID.
+    ///
+    /// # Errors
+    ///
+    /// Returns the entry back if the allowlist is full.
+    pub fn insert(&mut self, entry: AllowlistEntry) -> Result<(), AllowlistEntry> {
+        if let Some(existing) = self.entries.iter_mut().find(|e| e.ssid == entry.ssid) {
+            *existing = entry;
+            return Ok(());
+        }
+        self.entries.push(entry)
+    }
+
+    /// Looks up the entry for an SSID
+    pub fn get(&self, ssid: &str) -> Option<&AllowlistEntry> {
+        self.entries.iter().find(|e| e.ssid.as_str() == ssid)
+    }
+
+    /// Iterates over the entries
+    pub fn iter(&self) -> core::slice::Iter<'_, AllowlistEntry> {
+        self.entries.iter()
+    }
+
+    /// Number of entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if no network is expected
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Parses a comma separated list of `ssid=auth` entries, each optionally
+    /// followed by `+bssid` pins, such as
+    /// `corp=WPA2-Enterprise+aa:bb:cc:00:11:22,guest=WPA2`.
+    ///
+    /// Authentication methods are named as by [`AuthMethod::as_str`]. Returns
+    /// `None` if an entry is malformed or there are more than
+    /// [`MAX_ALLOWLIST_ENTRIES`] entries or [`MAX_PINNED_BSSIDS`] pins.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut allowlist = Self::new();
+        for entry in text.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let (ssid, rest) = entry.trim().rsplit_once('=')?;
+            let mut parts = rest.split('+');
+            let auth_method = AuthMethod::parse(parts.next()?)?;
+            let mut entry = AllowlistEntry::new(ssid, auth_method);
+            for bssid in parts {
+                entry.bssids.push(Bssid::parse(bssid)?).ok()?;
+            }
+            allowlist.insert(entry).ok()?;
+        }
+        Some(allowlist)
+    }
+}
+
+/// Why an access point was flagged
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum AlertReason {
+    /// Same SSID seen with a stronger authentication method from another BSSID
+    DowngradedTwin = 1,
+    /// Allowlisted SSID advertised as an open network
+    KnownSsidOpen = 2,
+    /// Allowlisted SSID advertised with an unexpected authentication method
+    UnexpectedAuth = 3,
+    /// Pinned SSID advertised by a BSSID that is not pinned
+    UnknownBssid = 4,
+}
+
+impl AlertReason {
+    /// Stable numeric code of the reason
+    pub const fn code(self) -> u8 {
+        self as u8
+    }
+
+    /// Short machine friendly name of the reason
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            AlertReason::DowngradedTwin => "downgraded_twin",
+            AlertReason::KnownSsidOpen => "known_ssid_open",
+            AlertReason::UnexpectedAuth => "unexpected_auth",
+            AlertReason::UnknownBssid => "unknown_bssid",
+        }
+    }
+}
+
+/// A suspicious access point
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RogueAlert {
+    /// Why the access point was flagged
+    pub reason: AlertReason,
+    /// SSID it advertised
+    pub ssid: Ssid,
+    /// Its BSSID
+    pub bssid: Bssid,
+    /// Channel it was seen on
+    pub channel: u8,
+    /// Signal strength in dBm
+    pub rssi: i8,
+    /// Authentication method it advertised
+    pub auth_method: Option<AuthMethod>,
+    /// Sequence number of the scan it was seen in
+    pub scan_sequence: u32,
+}
+
+impl fmt::Display for RogueAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Rogue AP alert {} ({}): SSID {} BSSID {} on channel {} at {} dBm",
+            self.reason.code(),
+            self.reason.as_str(),
+            self.ssid,
+            self.bssid,
+            self.channel,
+            self.rssi
+        )
+    }
+}
+
+/// Checks scans for suspicious access points
+#[derive(Clone, Debug, Default)]
+pub struct RogueDetector {
+    allowlist: Allowlist,
+    raised: heapless::Deque<(Bssid, AlertReason), MAX_REMEMBERED_ALERTS>,
+}
+
+impl RogueDetector {
+    /// Creates a detector for the given expected networks
+    pub const fn new(allowlist: Allowlist) -> Self {
+        Self {
+            allowlist,
+            raised: heapless::Deque::new(),
+        }
+    }
+
+    /// The expected networks
+    pub fn allowlist(&self) -> &Allowlist {
+        &self.allowlist
+    }
+
+    /// Replaces the expected networks and forgets raised alerts
+    pub fn set_allowlist(&mut self, allowlist: Allowlist) {
+        self.allowlist = allowlist;
+        self.raised.clear();
+    }
+
+    /// Checks one scan, calling `emit` for every new alert.
+    pub fn check(&mut self, report: &ScanReport, mut emit: impl FnMut(RogueAlert)) {
+        for ap in report {
+            if ap.is_hidden() {
+                continue;
+            }
+            if let Some(reason) = self.classify(report, ap)
+                && self.remember(ap.bssid, reason)
+            {
+                emit(RogueAlert {
+                    reason,
+                    ssid: ap.ssid.clone(),
+                    bssid: ap.bssid,
+                    channel: ap.channel,
+                    rssi: ap.signal_strength,
+                    auth_method: ap.auth_method,
+                    scan_sequence: report.sequence,
+                });
+            }
+        }
+    }
+
+    /// Returns the most specific reason to flag `ap`, if any
+    fn classify(&self, report: &ScanReport, ap: &AccessPointRecord) -> Option<AlertReason> {
+        if let Some(expected) = self.allowlist.get(&ap.ssid) {
+            if expected.is_pinned() && !expected.bssids.contains(&ap.bssid) {
+                return Some(AlertReason::UnknownBssid);
+            }
+            match ap.auth_method {
+                Some(AuthMethod::Open) if expected.auth_method != AuthMethod::Open => {
+                    return Some(AlertReason::KnownSsidOpen);
+                }
+                Some(auth) if auth != expected.auth_method => {
+                    return Some(AlertReason::UnexpectedAuth);
+                }
+                _ => {}
+            }
+        }
+
+        let level = ap.auth_method?.security_level();
+        let stronger_twin = report.iter().any(|other| {
+            other.ssid == ap.ssid
+                && other.bssid != ap.bssid
+                && other
+                    .auth_method
+                    .is_some_and(|auth| auth.security_level() > level)
+        });
+        stronger_twin.then_some(AlertReason::DowngradedTwin)
+    }
+
+    /// Records an alert; returns `false` if it has been raised before
+    fn remember(&mut self, bssid: Bssid, reason: AlertReason) -> bool {
+        if self.raised.iter().any(|&raised| raised == (bssid, reason)) {
+            return false;
+        }
+        if self.raised.is_full() {
+            self.raised.pop_front();
+        }
+        // Cannot fail: a slot was freed above
+        let _ = self.raised.push_back((bssid, reason));
+        true
+    }
+
+    /// Forgets raised alerts so they can be raised again
+    pub fn reset(&mut self) {
+        self.raised.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::fixtures::{ap, report};
+    use std::format;
+    use std::vec::Vec;
+
+    fn check(detector: &mut RogueDetector, report: &ScanReport) -> Vec<(u8, AlertReason)> {
+        let mut alerts = Vec::new();
+        detector.check(report, |alert| {
+            alerts.push((alert.bssid.0[5], alert.reason))
+        });
+        alerts
+    }
+
+    fn corporate() -> Allowlist {
+        let mut allowlist = Allowlist::new();
+        allowlist
+            .insert(
+                AllowlistEntry::new("corp", AuthMethod::Wpa2Enterprise)
+                    .with_bssid([0, 0, 0, 0, 0, 1]),
+            )
+            .unwrap();
+        allowlist
+            .insert(AllowlistEntry::new("guest", AuthMethod::Wpa2Personal))
+            .unwrap();
+        allowlist
+    }
+
+    #[test]
+    fn flags_downgraded_twin_without_allowlist() {
+        let mut detector = RogueDetector::default();
+        let scan = report(
+            1,
+            &[
+                ap(1).with_ssid("cafe"),
+                ap(2).with_ssid("cafe").with_auth(AuthMethod::Open),
+                ap(3).with_ssid("cafe"),
+            ],
+        );
+        assert_eq!(
+            check(&mut detector, &scan),
+            [(2, AlertReason::DowngradedTwin)]
+        );
+    }
+
+    #[test]
+    fn flags_known_ssid_open() {
+        let mut detector = RogueDetector::new(corporate());
+        let scan = report(1, &[ap(2).with_ssid("guest").with_auth(AuthMethod::Open)]);
+        assert_eq!(
+            check(&mut detector, &scan),
+            [(2, AlertReason::KnownSsidOpen)]
+        );
+    }
+
+    #[test]
+    fn flags_unexpected_auth() {
+        let mut detector = RogueDetector::new(corporate());
+        let scan = report(
+            1,
+            &[ap(2).with_ssid("guest").with_auth(AuthMethod::Wpa3Personal)],
+        );
+        assert_eq!(
+            check(&mut detector, &scan),
+            [(2, AlertReason::UnexpectedAuth)]
+        );
+    }
+
+    #[test]
+    fn flags_unknown_bssid_for_pinned_ssid() {
+        let mut detector = RogueDetector::new(corporate());
+        let scan = report(
+            1,
+            &[
+                ap(1)
+                    .with_ssid("corp")
+                    .with_auth(AuthMethod::Wpa2Enterprise),
+                ap(9)
+                    .with_ssid("corp")
+                    .with_auth(AuthMethod::Wpa2Enterprise),
+            ],
+        );
+        assert_eq!(
+            check(&mut detector, &scan),
+            [(9, AlertReason::UnknownBssid)]
+        );
+    }
+
+    #[test]
+    fn raises_each_alert_once() {
+        let mut detector = RogueDetector::new(corporate());
+        let scan = report(1, &[ap(2).with_ssid("guest").with_auth(AuthMethod::Open)]);
+        assert_eq!(check(&mut detector, &scan).len(), 1);
+        assert!(check(&mut detector, &scan).is_empty());
+
+        detector.reset();
+        assert_eq!(check(&mut detector, &scan).len(), 1);
+    }
+
+    #[test]
+    fn accepts_expected_networks() {
+        let mut detector = RogueDetector::new(corporate());
+        let scan = report(
+            1,
+            &[
+                ap(1)
+                    .with_ssid("corp")
+                    .with_auth(AuthMethod::Wpa2Enterprise),
+                ap(2).with_ssid("guest"),
+                ap(3).with_ssid("guest"),
+                ap(4).with_ssid("").with_auth(AuthMethod::Open),
+            ],
+        );
+        assert!(check(&mut detector, &scan).is_empty());
+    }
+
+    #[test]
+    fn parses_allowlist() {
+        let allowlist =
+            Allowlist::parse("corp=wpa2-enterprise+00:00:00:00:00:01, guest=WPA2,").unwrap();
+        assert_eq!(allowlist, corporate());
+        assert_eq!(Allowlist::parse(""), Some(Allowlist::new()));
+        assert_eq!(
+            Allowlist::parse("a=b=WPA/WPA2").unwrap().get("a=b"),
+            Some(&AllowlistEntry::new("a=b", AuthMethod::WpaWpa2Personal))
+        );
+        for text in [
+            "corp",
+            "corp=WPA4",
+            "corp=WPA2+00:00:00:00:01",
+            "corp=WPA2+",
+        ] {
+            assert_eq!(Allowlist::parse(text), None, "{text}");
+        }
+    }
+
+    #[test]
+    fn formats_alert_with_reason_code() {
+        let mut detector = RogueDetector::new(corporate());
+        let mut text = None;
+        detector.check(
+            &report(1, &[ap(2).with_ssid("guest").with_auth(AuthMethod::Open)]),
+            |alert| text = Some(format!("{alert}")),
+        );
+        assert_eq!(
+            text.as_deref(),
+            Some(
+                "Rogue AP alert 2 (known_ssid_open): SSID guest BSSID 00:00:00:00:00:02 on channel 6 at -50 dBm"
+            )
+        );
+    }
+}
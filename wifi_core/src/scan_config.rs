@@ -0,0 +1,337 @@
+//! Scanner configuration.
+//!
+//! [`ScannerConfig`] describes how and how often the scan task scans. It is a
+//! plain value type: the firmware translates it into driver scan settings and
+//! can replace it at runtime.
+
+use core::fmt;
+
+use crate::report::{Bssid, Ssid};
+
+/// Highest 2.4 GHz channel number
+pub const MAX_CHANNEL: u8 = 14;
+
+/// Longest per-channel dwell time accepted for passive scans.
+///
+/// Longer passive dwells may cause a connected station to lose its AP.
+pub const MAX_PASSIVE_DWELL_MS: u32 = 1500;
+
+/// Active or passive scanning
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ScanType {
+    /// Send probe requests on each channel and wait for responses
+    #[default]
+    Active,
+    /// Only listen for beacons on each channel
+    Passive,
+}
+
+/// Set of 2.4 GHz channels (1 to [`MAX_CHANNEL`])
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChannelSet(u16);
+
+impl ChannelSet {
+    /// Every channel
+    pub const ALL: ChannelSet = ChannelSet(((1 << MAX_CHANNEL) - 1) << 1);
+
+    /// No channel
+    pub const EMPTY: ChannelSet = ChannelSet(0);
+
+    /// Builds a set from a list of channels, ignoring invalid channel numbers
+    pub fn from_channels(channels: &[u8]) -> Self {
+        let mut set = Self::EMPTY;
+        for &channel in channels {
+            set.insert(channel);
+        }
+        set
+    }
+
+    /// Adds a channel. Returns `false` if the channel number is out of range.
+    pub fn insert(&mut self, channel: u8) -> bool {
+        if !(1..=MAX_CHANNEL).contains(&channel) {
+            return false;
+        }
+        self.0 |= 1 << channel;
+        true
+    }
+
+    /// Removes a channel
+    pub fn remove(&mut self, channel: u8) {
+        if (1..=MAX_CHANNEL).contains(&channel) {
+            self.0 &= !(1 << channel);
+        }
+    }
+
+    /// Returns `true` if the channel is in the set
+    pub fn contains(&self, channel: u8) -> bool {
+        (1..=MAX_CHANNEL).contains(&channel) && self.0 & (1 << channel) != 0
+    }
+
+    /// Returns `true` if every channel is in the set
+    pub fn is_all(&self) -> bool {
+        *self == Self::ALL
+    }
+
+    /// Returns `true` if no channel is in the set
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Number of channels in the set
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Iterates over the channels in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (1..=MAX_CHANNEL).filter(|&channel| self.contains(channel))
+    }
+
+    /// Raw bitmask, bit `n` set for channel `n`
+    pub const fn bits(&self) -> u16 {
+        self.0
+    }
+
+    /// Builds a set from a raw bitmask, dropping bits that are not valid channels
+    pub const fn from_bits(bits: u16) -> Self {
+        Self(bits & Self::ALL.0)
+    }
+}
+
+impl Default for ChannelSet {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl fmt::Debug for ChannelSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// Reason a [`ScannerConfig`] was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigError {
+    /// The scan interval is zero
+    ZeroInterval,
+    /// The minimum dwell time is larger than the maximum, or the maximum is zero
+    InvalidDwell,
+    /// A passive dwell time above [`MAX_PASSIVE_DWELL_MS`]
+    DwellTooLong,
+    /// The channel set is empty
+    NoChannels,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::ZeroInterval => f.write_str("scan interval must be at least one second"),
+            ConfigError::InvalidDwell => f.write_str("invalid per-channel dwell time range"),
+            ConfigError::DwellTooLong => write!(
+                f,
+                "passive dwell time must not exceed {} ms",
+                MAX_PASSIVE_DWELL_MS
+            ),
+            ConfigError::NoChannels => f.write_str("no channel selected"),
+        }
+    }
+}
+
+/// How and how often the scan task scans
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScannerConfig {
+    /// Time between the end of one scan and the start of the next, in seconds
+    pub interval_secs: u32,
+    /// Active or passive scanning
+    pub scan_type: ScanType,
+    /// Minimum time spent on each channel in active scans, in milliseconds
+    pub dwell_min_ms: u32,
+    /// Maximum time spent on each channel, in milliseconds.
+    ///
+    /// Passive scans listen for exactly this long on each channel.
+    pub dwell_max_ms: u32,
+    /// Channels to scan
+    pub channels: ChannelSet,
+    /// Whether to report networks that hide their SSID
+    pub show_hidden: bool,
+    /// Only report networks with this SSID
+    pub ssid_filter: Option<Ssid>,
+    /// Only report the access point with this BSSID
+    pub bssid_filter: Option<Bssid>,
+}
+
+impl Default for ScannerConfig {
+    /// Active scan of all channels every 10 seconds, with the driver's default dwell times
+    fn default() -> Self {
+        Self {
+            interval_secs: 10,
+            scan_type: ScanType::Active,
+            dwell_min_ms: 10,
+            dwell_max_ms: 20,
+            channels: ChannelSet::ALL,
+            show_hidden: false,
+            ssid_filter: None,
+            bssid_filter: None,
+        }
+    }
+}
+
+impl ScannerConfig {
+    /// Profile for site surveys: frequent, thorough passive scans including hidden networks
+    pub fn survey() -> Self {
+        Self {
+            interval_secs: 5,
+            scan_type: ScanType::Passive,
+            dwell_min_ms: 0,
+            dwell_max_ms: 360,
+            show_hidden: true,
+            ..Self::default()
+        }
+    }
+
+    /// Profile for idle monitoring: short active scans every five minutes
+    pub fn idle_monitor() -> Self {
+        Self {
+            interval_secs: 300,
+            ..Self::default()
+        }
+    }
+
+    /// Sets the scan interval in seconds
+    #[must_use]
+    pub fn with_interval_secs(mut self, interval_secs: u32) -> Self {
+        self.interval_secs = interval_secs;
+        self
+    }
+
+    /// Selects active scanning with the given per-channel dwell range
+    #[must_use]
+    pub fn with_active(mut self, dwell_min_ms: u32, dwell_max_ms: u32) -> Self {
+        self.scan_type = ScanType::Active;
+        self.dwell_min_ms = dwell_min_ms;
+        self.dwell_max_ms = dwell_max_ms;
+        self
+    }
+
+    /// Selects passive scanning with the given per-channel dwell time
+    #[must_use]
+    pub fn with_passive(mut self, dwell_ms: u32) -> Self {
+        self.scan_type = ScanType::Passive;
+        self.dwell_min_ms = 0;
+        self.dwell_max_ms = dwell_ms;
+        self
+    }
+
+    /// Restricts scanning to the given channels
+    #[must_use]
+    pub fn with_channels(mut self, channels: ChannelSet) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    /// Sets whether hidden networks are reported
+    #[must_use]
+    pub fn with_show_hidden(mut self, show_hidden: bool) -> Self {
+        self.show_hidden = show_hidden;
+        self
+    }
+
+    /// Only report networks with this SSID. SSIDs longer than 32 bytes are truncated.
+    #[must_use]
+    pub fn with_ssid_filter(mut self, ssid: &str) -> Self {
+        self.ssid_filter = Some(crate::report::truncated_ssid(ssid));
+        self
+    }
+
+    /// Only report the access point with this BSSID
+    #[must_use]
+    pub fn with_bssid_filter(mut self, bssid: impl Into<Bssid>) -> Self {
+        self.bssid_filter = Some(bssid.into());
+        self
+    }
+
+    /// Checks that the configuration can be handed to the driver.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first problem found, see [`ConfigError`].
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.interval_secs == 0 {
+            return Err(ConfigError::ZeroInterval);
+        }
+        if self.dwell_max_ms == 0 || self.dwell_min_ms > self.dwell_max_ms {
+            return Err(ConfigError::InvalidDwell);
+        }
+        if self.scan_type == ScanType::Passive && self.dwell_max_ms > MAX_PASSIVE_DWELL_MS {
+            return Err(ConfigError::DwellTooLong);
+        }
+        if self.channels.is_empty() {
+            return Err(ConfigError::NoChannels);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+    use std::vec::Vec;
+
+    #[test]
+    fn channel_set_bounds() {
+        let mut set = ChannelSet::EMPTY;
+        assert!(set.insert(1));
+        assert!(set.insert(14));
+        assert!(!set.insert(0));
+        assert!(!set.insert(15));
+        assert_eq!(set.iter().collect::<Vec<_>>(), [1, 14]);
+        assert_eq!(format!("{set:?}"), "{1, 14}");
+
+        assert_eq!(ChannelSet::ALL.len(), 14);
+        assert!(ChannelSet::from_bits(0xffff).is_all());
+        assert_eq!(ChannelSet::from_channels(&[1, 6, 11, 99]).len(), 3);
+    }
+
+    #[test]
+    fn profiles_are_valid() {
+        assert_eq!(ScannerConfig::default().validate(), Ok(()));
+        assert_eq!(ScannerConfig::survey().validate(), Ok(()));
+        assert_eq!(ScannerConfig::idle_monitor().validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_bad_configs() {
+        let config = ScannerConfig::default();
+        assert_eq!(
+            config.clone().with_interval_secs(0).validate(),
+            Err(ConfigError::ZeroInterval)
+        );
+        assert_eq!(
+            config.clone().with_active(50, 20).validate(),
+            Err(ConfigError::InvalidDwell)
+        );
+        assert_eq!(
+            config.clone().with_passive(2000).validate(),
+            Err(ConfigError::DwellTooLong)
+        );
+        assert_eq!(
+            config.with_channels(ChannelSet::EMPTY).validate(),
+            Err(ConfigError::NoChannels)
+        );
+    }
+
+    #[test]
+    fn filters_are_stored() {
+        let config = ScannerConfig::default()
+            .with_ssid_filter("corp")
+            .with_bssid_filter([1, 2, 3, 4, 5, 6]);
+        assert_eq!(config.ssid_filter.as_deref(), Some("corp"));
+        assert_eq!(config.bssid_filter, Some(Bssid([1, 2, 3, 4, 5, 6])));
+    }
+}
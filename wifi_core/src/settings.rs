@@ -0,0 +1,662 @@
+//! Typed settings on top of the flash key-value store.
+//!
+//! A [`Setting`] is a value with a fixed key in the [`KvStore`] and a binary
+//! encoding tagged with a schema version. [`KvStore::load`] decodes values
+//! written by older firmware through [`Setting::decode`] and rewrites them in
+//! the current version; values written by newer firmware are rejected.
+//!
+//! | Key | Type              | Version |
+//! |-----|-------------------|---------|
+//! | 1   | [`DeviceName`]    | 1       |
+//! | 2   | [`ScannerConfig`] | 1       |
+//! | 3   | [`ProfileStore`]  | 2       |
+//! | 4   | [`Ipv4Config`]    | 1       |
+//! | 5   | [`Allowlist`]     | 1       |
+//!
+//! Network credentials are stored as part of the [`ProfileStore`]. Version 1
+//! of the profiles held only SSIDs and passwords; version 2 added the
+//! priority and BSSID pin of each profile.
+//!
+//! Encodings are little endian. Strings are prefixed with their length in
+//! bytes, optional values with a presence byte.
+
+use core::fmt;
+
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::flash_kv::{KvStore, MAX_VALUE_LEN, StoreError};
+use crate::net_config::{Ipv4Config, StaticIpv4};
+use crate::profiles::{NetworkProfile, ProfileStore};
+use crate::report::{AuthMethod, Bssid, MAX_SSID_LEN, Ssid};
+use crate::rogue::{Allowlist, AllowlistEntry};
+use crate::scan_config::{ChannelSet, ScanType, ScannerConfig};
+use crate::station::{Credentials, Password};
+
+/// Maximum length of a device name in bytes
+pub const MAX_DEVICE_NAME_LEN: usize = 32;
+
+/// Reasons why a stored value could not be decoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeError {
+    /// The value ended early
+    UnexpectedEnd,
+    /// A field holds an impossible value, or bytes are left over
+    Invalid,
+    /// The value was written in a schema version this firmware cannot read
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => f.write_str("value ends early"),
+            DecodeError::Invalid => f.write_str("invalid value"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported schema version {version}")
+            }
+        }
+    }
+}
+
+impl From<DecodeError> for StoreError {
+    fn from(error: DecodeError) -> Self {
+        StoreError::Decode(error)
+    }
+}
+
+/// A value kept in the [`KvStore`]
+pub trait Setting: Sized {
+    /// Key of the value in the store
+    const KEY: u8;
+    /// Current schema version, 1 or higher
+    const VERSION: u8;
+
+    /// Encodes the value in the current schema version
+    fn encode(&self, encoder: &mut Encoder<'_>);
+
+    /// Decodes a value written in schema `version`, which is at most [`Self::VERSION`].
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`DecodeError`] if the value is malformed or `version` is not
+    /// supported any more.
+    fn decode(version: u8, decoder: &mut Decoder<'_>) -> Result<Self, DecodeError>;
+}
+
+/// Writes a value into a buffer
+#[derive(Debug)]
+pub struct Encoder<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    overflow: bool,
+}
+
+impl<'a> Encoder<'a> {
+    /// Creates an encoder writing to the start of `buf`
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Appends raw bytes
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        match self.buf.get_mut(self.len..self.len + bytes.len()) {
+            Some(dest) if !self.overflow => {
+                dest.copy_from_slice(bytes);
+                self.len += bytes.len();
+            }
+            _ => self.overflow = true,
+        }
+    }
+
+    /// Appends a byte
+    pub fn u8(&mut self, value: u8) {
+        self.bytes(&[value]);
+    }
+
+    /// Appends a 16-bit integer
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// Appends a 32-bit integer
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// Appends a boolean as one byte
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value.into());
+    }
+
+    /// Appends a string of at most 255 bytes, prefixed with its length
+    pub fn str(&mut self, value: &str) {
+        match u8::try_from(value.len()) {
+            Ok(len) => {
+                self.u8(len);
+                self.bytes(value.as_bytes());
+            }
+            Err(_) => self.overflow = true,
+        }
+    }
+
+    /// Appends a presence byte, and the value if there is one
+    pub fn option<T>(&mut self, value: Option<&T>, encode: impl FnOnce(&mut Self, &T)) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            encode(self, value);
+        }
+    }
+
+    /// Returns the encoded bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::ValueTooLarge`] if the value did not fit in the buffer.
+    pub fn finish(self) -> Result<&'a [u8], StoreError> {
+        if self.overflow {
+            return Err(StoreError::ValueTooLarge);
+        }
+        Ok(&self.buf[..self.len])
+    }
+}
+
+/// Reads a value from a buffer
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    /// Creates a decoder reading `buf` from the start
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    /// Takes the next `len` raw bytes
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DecodeError::UnexpectedEnd`] if fewer bytes are left.
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if len > self.buf.len() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    /// Takes a byte
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DecodeError::UnexpectedEnd`] at the end of the value.
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    /// Takes a 16-bit integer
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DecodeError::UnexpectedEnd`] if fewer than 2 bytes are left.
+    pub fn u16(&mut self) -> Result<u16, DecodeError> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    /// Takes a 32-bit integer
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DecodeError::UnexpectedEnd`] if fewer than 4 bytes are left.
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    /// Takes a boolean
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DecodeError::Invalid`] for bytes other than 0 and 1.
+    pub fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+
+    /// Takes a length-prefixed string of at most `N` bytes
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DecodeError::Invalid`] if the string is longer than `N` bytes
+    /// or not UTF-8.
+    pub fn str<const N: usize>(&mut self) -> Result<heapless::String<N>, DecodeError> {
+        let len = self.u8()?;
+        let bytes = self.bytes(len.into())?;
+        let value = core::str::from_utf8(bytes).map_err(|_| DecodeError::Invalid)?;
+        heapless::String::try_from(value).map_err(|_| DecodeError::Invalid)
+    }
+
+    /// Takes a presence byte, and the value if there is one
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `decode`, or [`DecodeError::Invalid`] for a bad
+    /// presence byte.
+    pub fn option<T>(
+        &mut self,
+        decode: impl FnOnce(&mut Self) -> Result<T, DecodeError>,
+    ) -> Result<Option<T>, DecodeError> {
+        if self.bool()? {
+            decode(self).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Checks that the whole value was read
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DecodeError::Invalid`] if bytes are left over.
+    pub fn finish(self) -> Result<(), DecodeError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError::Invalid)
+        }
+    }
+}
+
+impl<F: NorFlash> KvStore<F> {
+    /// Loads a setting, or `None` if it was never saved.
+    ///
+    /// Values saved in an older schema version are migrated and saved again.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StoreError::Decode`] if the stored value cannot be decoded,
+    /// or the error of [`KvStore::read`].
+    pub fn load<T: Setting>(&mut self) -> Result<Option<T>, StoreError> {
+        let mut buf = [0; MAX_VALUE_LEN];
+        let Some((version, len)) = self.read(T::KEY, &mut buf)? else {
+            return Ok(None);
+        };
+        if version > T::VERSION {
+            return Err(DecodeError::UnsupportedVersion(version).into());
+        }
+        let mut decoder = Decoder::new(&buf[..len]);
+        let value = T::decode(version, &mut decoder)?;
+        decoder.finish()?;
+        if version < T::VERSION {
+            self.save(&value)?;
+        }
+        Ok(Some(value))
+    }
+
+    /// Saves a setting in its current schema version.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of [`KvStore::write`].
+    pub fn save<T: Setting>(&mut self, value: &T) -> Result<(), StoreError> {
+        let mut buf = [0; MAX_VALUE_LEN];
+        let mut encoder = Encoder::new(&mut buf);
+        value.encode(&mut encoder);
+        let bytes = encoder.finish()?;
+        self.write(T::KEY, T::VERSION, bytes)
+    }
+
+    /// Deletes a setting.
+    ///
+    /// Returns `false` if it was never saved.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of [`KvStore::remove`].
+    pub fn forget<T: Setting>(&mut self) -> Result<bool, StoreError> {
+        self.remove(T::KEY)
+    }
+}
+
+/// Name the device announces itself with
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceName(pub heapless::String<MAX_DEVICE_NAME_LEN>);
+
+impl DeviceName {
+    /// Creates a device name, or `None` if it is empty or too long
+    pub fn new(name: &str) -> Option<Self> {
+        if name.is_empty() {
+            return None;
+        }
+        heapless::String::try_from(name).ok().map(Self)
+    }
+
+    /// The name as a string slice
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Setting for DeviceName {
+    const KEY: u8 = 1;
+    const VERSION: u8 = 1;
+
+    fn encode(&self, encoder: &mut Encoder<'_>) {
+        encoder.str(&self.0);
+    }
+
+    fn decode(_version: u8, decoder: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        let name = decoder.str()?;
+        if name.is_empty() {
+            return Err(DecodeError::Invalid);
+        }
+        Ok(Self(name))
+    }
+}
+
+impl Setting for ScannerConfig {
+    const KEY: u8 = 2;
+    const VERSION: u8 = 1;
+
+    fn encode(&self, encoder: &mut Encoder<'_>) {
+        encoder.u32(self.interval_secs);
+        encoder.u8(match self.scan_type {
+            ScanType::Active => 0,
+            ScanType::Passive => 1,
+        });
+        encoder.u32(self.dwell_min_ms);
+        encoder.u32(self.dwell_max_ms);
+        encoder.u16(self.channels.bits());
+        encoder.bool(self.show_hidden);
+        encoder.option(self.ssid_filter.as_ref(), |e, ssid| e.str(ssid));
+        encoder.option(self.bssid_filter.as_ref(), |e, bssid| e.bytes(&bssid.0));
+    }
+
+    fn decode(_version: u8, decoder: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        let config = ScannerConfig {
+            interval_secs: decoder.u32()?,
+            scan_type: match decoder.u8()? {
+                0 => ScanType::Active,
+                1 => ScanType::Passive,
+                _ => return Err(DecodeError::Invalid),
+            },
+            dwell_min_ms: decoder.u32()?,
+            dwell_max_ms: decoder.u32()?,
+            channels: ChannelSet::from_bits(decoder.u16()?),
+            show_hidden: decoder.bool()?,
+            ssid_filter: decoder.option(|d| d.str::<MAX_SSID_LEN>())?,
+            bssid_filter: decoder.option(|d| d.array().map(Bssid))?,
+        };
+        config.validate().map_err(|_| DecodeError::Invalid)?;
+        Ok(config)
+    }
+}
+
+impl Setting for ProfileStore {
+    const KEY: u8 = 3;
+    const VERSION: u8 = 2;
+
+    fn encode(&self, encoder: &mut Encoder<'_>) {
+        encoder.u8(self.len() as u8);
+        for profile in self.iter() {
+            encoder.str(&profile.credentials.ssid);
+            encoder.str(&profile.credentials.password);
+            encoder.u8(profile.priority);
+            encoder.option(profile.bssid.as_ref(), |e, bssid| e.bytes(&bssid.0));
+        }
+    }
+
+    fn decode(version: u8, decoder: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        let mut store = ProfileStore::new();
+        for _ in 0..decoder.u8()? {
+            let ssid: Ssid = decoder.str()?;
+            let password: Password = decoder.str()?;
+            let credentials =
+                Credentials::new(&ssid, &password).map_err(|_| DecodeError::Invalid)?;
+            let mut profile = NetworkProfile::new(credentials);
+            match version {
+                1 => {}
+                2 => {
+                    profile.priority = decoder.u8()?;
+                    profile.bssid = decoder.option(|d| d.array().map(Bssid))?;
+                }
+                _ => return Err(DecodeError::UnsupportedVersion(version)),
+            }
+            store.insert(profile).map_err(|_| DecodeError::Invalid)?;
+        }
+        Ok(store)
+    }
+}
+
+impl Setting for Ipv4Config {
+    const KEY: u8 = 4;
+    const VERSION: u8 = 1;
+
+    fn encode(&self, encoder: &mut Encoder<'_>) {
+        match self {
+            Ipv4Config::Dhcp => encoder.u8(0),
+            Ipv4Config::Static(config) => {
+                encoder.u8(1);
+                encoder.bytes(&config.address());
+                encoder.u8(config.prefix_len());
+                encoder.option(config.gateway().as_ref(), |e, gateway| e.bytes(gateway));
+                encoder.u8(config.dns_servers().len() as u8);
+                for server in config.dns_servers() {
+                    encoder.bytes(server);
+                }
+            }
+        }
+    }
+
+    fn decode(_version: u8, decoder: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        match decoder.u8()? {
+            0 => Ok(Ipv4Config::Dhcp),
+            1 => {
+                let invalid = |_| DecodeError::Invalid;
+                let mut config =
+                    StaticIpv4::new(decoder.array()?, decoder.u8()?).map_err(invalid)?;
+                if let Some(gateway) = decoder.option(|d| d.array())? {
+                    config = config.with_gateway(gateway).map_err(invalid)?;
+                }
+                for _ in 0..decoder.u8()? {
+                    config = config.with_dns_server(decoder.array()?).map_err(invalid)?;
+                }
+                Ok(Ipv4Config::Static(config))
+            }
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+}
+
+impl Setting for Allowlist {
+    const KEY: u8 = 5;
+    const VERSION: u8 = 1;
+
+    fn encode(&self, encoder: &mut Encoder<'_>) {
+        encoder.u8(self.len() as u8);
+        for entry in self.iter() {
+            encoder.str(&entry.ssid);
+            encoder.u8(match entry.auth_method {
+                AuthMethod::Open => 0,
+                AuthMethod::Wep => 1,
+                AuthMethod::Wpa => 2,
+                AuthMethod::Wpa2Personal => 3,
+                AuthMethod::WpaWpa2Personal => 4,
+                AuthMethod::Wpa2Enterprise => 5,
+                AuthMethod::Wpa3Personal => 6,
+                AuthMethod::Wpa2Wpa3Personal => 7,
+                AuthMethod::WapiPersonal => 8,
+            });
+            encoder.u8(entry.bssids.len() as u8);
+            for bssid in &entry.bssids {
+                encoder.bytes(&bssid.0);
+            }
+        }
+    }
+
+    fn decode(_version: u8, decoder: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        let mut allowlist = Allowlist::new();
+        for _ in 0..decoder.u8()? {
+            let ssid: Ssid = decoder.str()?;
+            let auth_method = match decoder.u8()? {
+                0 => AuthMethod::Open,
+                1 => AuthMethod::Wep,
+                2 => AuthMethod::Wpa,
+                3 => AuthMethod::Wpa2Personal,
+                4 => AuthMethod::WpaWpa2Personal,
+                5 => AuthMethod::Wpa2Enterprise,
+                6 => AuthMethod::Wpa3Personal,
+                7 => AuthMethod::Wpa2Wpa3Personal,
+                8 => AuthMethod::WapiPersonal,
+                _ => return Err(DecodeError::Invalid),
+            };
+            let mut entry = AllowlistEntry::new(&ssid, auth_method);
+            for _ in 0..decoder.u8()? {
+                let bssid = Bssid(decoder.array()?);
+                entry.bssids.push(bssid).map_err(|_| DecodeError::Invalid)?;
+            }
+            allowlist.insert(entry).map_err(|_| DecodeError::Invalid)?;
+        }
+        Ok(allowlist)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash_kv::tests::{MockFlash, mount};
+
+    fn store() -> KvStore<MockFlash> {
+        mount(MockFlash::new(2)).unwrap()
+    }
+
+    fn profile(ssid: &str, password: &str) -> NetworkProfile {
+        NetworkProfile::new(Credentials::new(ssid, password).unwrap())
+    }
+
+    #[test]
+    fn round_trips_settings() {
+        let mut kv = store();
+        assert_eq!(kv.load::<DeviceName>(), Ok(None));
+
+        let name = DeviceName::new("scanner-kitchen").unwrap();
+        let config = ScannerConfig::survey()
+            .with_ssid_filter("office")
+            .with_bssid_filter([1, 2, 3, 4, 5, 6]);
+        let mut profiles = ProfileStore::new();
+        profiles
+            .insert(profile("office", "password1").with_priority(3))
+            .unwrap();
+        profiles
+            .insert(profile("cafe", "").with_bssid([6, 5, 4, 3, 2, 1]))
+            .unwrap();
+
+        let ipv4 = StaticIpv4::new([10, 0, 0, 7], 16)
+            .and_then(|c| c.with_gateway([10, 0, 0, 1]))
+            .and_then(|c| c.with_dns_server([10, 0, 0, 1]))
+            .map(Ipv4Config::Static)
+            .unwrap();
+
+        let mut allowlist = Allowlist::new();
+        allowlist
+            .insert(
+                AllowlistEntry::new("corp", AuthMethod::Wpa2Enterprise)
+                    .with_bssid([0xaa, 0xbb, 0xcc, 0, 0, 1])
+                    .with_bssid([0xaa, 0xbb, 0xcc, 0, 0, 2]),
+            )
+            .unwrap();
+        allowlist
+            .insert(AllowlistEntry::new("guest", AuthMethod::Open))
+            .unwrap();
+
+        kv.save(&name).unwrap();
+        kv.save(&config).unwrap();
+        kv.save(&profiles).unwrap();
+        kv.save(&ipv4).unwrap();
+        kv.save(&allowlist).unwrap();
+
+        let mut kv = mount(kv.into_inner()).unwrap();
+        assert_eq!(kv.load(), Ok(Some(name)));
+        assert_eq!(kv.load(), Ok(Some(config)));
+        assert_eq!(kv.load(), Ok(Some(profiles)));
+        assert_eq!(kv.load(), Ok(Some(ipv4)));
+        assert_eq!(kv.load(), Ok(Some(allowlist)));
+
+        kv.save(&Ipv4Config::Dhcp).unwrap();
+        assert_eq!(kv.load(), Ok(Some(Ipv4Config::Dhcp)));
+
+        assert_eq!(kv.forget::<DeviceName>(), Ok(true));
+        assert_eq!(kv.load::<DeviceName>(), Ok(None));
+    }
+
+    #[test]
+    fn migrates_version_1_profiles() {
+        let mut kv = store();
+        let mut buf = [0; 64];
+        let mut encoder = Encoder::new(&mut buf);
+        encoder.u8(2);
+        for (ssid, password) in [("office", "password1"), ("cafe", "")] {
+            encoder.str(ssid);
+            encoder.str(password);
+        }
+        kv.write(ProfileStore::KEY, 1, encoder.finish().unwrap())
+            .unwrap();
+
+        let profiles: ProfileStore = kv.load().unwrap().unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(
+            profiles.get("office"),
+            Some(&profile("office", "password1"))
+        );
+        assert_eq!(profiles.get("cafe"), Some(&profile("cafe", "")));
+
+        // Loading rewrote the value in the current version
+        assert_eq!(kv.version(ProfileStore::KEY), Some(2));
+        assert_eq!(kv.load(), Ok(Some(profiles)));
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        let mut kv = store();
+        kv.write(DeviceName::KEY, 2, b"\x03abc").unwrap();
+        assert_eq!(
+            kv.load::<DeviceName>(),
+            Err(StoreError::Decode(DecodeError::UnsupportedVersion(2)))
+        );
+
+        kv.write(DeviceName::KEY, 1, b"\x03abcd").unwrap();
+        assert_eq!(
+            kv.load::<DeviceName>(),
+            Err(StoreError::Decode(DecodeError::Invalid))
+        );
+
+        kv.write(DeviceName::KEY, 1, b"\x05abc").unwrap();
+        assert_eq!(
+            kv.load::<DeviceName>(),
+            Err(StoreError::Decode(DecodeError::UnexpectedEnd))
+        );
+
+        // A scanner configuration that fails validation
+        kv.save(&ScannerConfig::default().with_interval_secs(0))
+            .unwrap();
+        assert_eq!(
+            kv.load::<ScannerConfig>(),
+            Err(StoreError::Decode(DecodeError::Invalid))
+        );
+    }
+}
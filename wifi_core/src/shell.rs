@@ -0,0 +1,743 @@
+//! Line-oriented command shell: line editing, argument splitting and a
+//! command registry.
+//!
+//! A [`LineEditor`] takes the bytes typed on a terminal one at a time,
+//! echoes them, and hands back complete lines. It understands backspace,
+//! the arrow keys, Home and End, Ctrl-A, Ctrl-E, Ctrl-U, Ctrl-W and Ctrl-C,
+//! and keeps a history of the last lines entered for the up and down keys.
+//! Lines are limited to printable ASCII.
+//!
+//! A [`Registry`] maps lines to [`Command`]s. A command name may have
+//! several words, such as `scan interval`; the command whose name matches
+//! most of the leading words of a line is taken, so `scan interval 30` runs
+//! `scan interval` with the argument `30` even if `scan` is registered as
+//! well. The action a command carries is up to the caller: the firmware
+//! stores an enum of its built-in commands and function pointers for the
+//! application's own.
+//!
+//! ```
+//! use wifi_core::shell::{Command, Registry};
+//!
+//! let mut registry = Registry::<u8, 4>::new();
+//! registry.register(Command::new("scan", "Scan now", 1)).unwrap();
+//! let interval = Command::new("scan interval", "Set the scan interval", 2);
+//! registry.register(interval.with_args("<secs>", 1, 1)).unwrap();
+//!
+//! let invocation = registry.parse("scan interval 30").unwrap().unwrap();
+//! assert_eq!(invocation.command.action, 2);
+//! assert_eq!(invocation.args.parse::<u32>(0, "secs"), Ok(30));
+//! ```
+
+use core::fmt;
+use core::str::FromStr;
+
+use crate::json::JsonStr;
+
+/// Maximum length of a line, in bytes
+pub const MAX_LINE_LEN: usize = 128;
+
+/// Maximum number of words on a line, command name included
+pub const MAX_ARGS: usize = 8;
+
+/// Line terminator the shell writes
+pub const NEWLINE: &str = "\r\n";
+
+/// Reasons why a line could not be run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ShellError {
+    /// A quoted word is missing its closing quote
+    UnterminatedQuote,
+    /// The line has more than [`MAX_ARGS`] words
+    TooManyArguments,
+    /// No command has the name the line starts with
+    UnknownCommand,
+    /// The command was given too few or too many arguments
+    Usage,
+    /// An argument could not be understood
+    InvalidArgument(&'static str),
+    /// The command ran but failed
+    Failed(&'static str),
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShellError::UnterminatedQuote => f.write_str("missing closing quote"),
+            ShellError::TooManyArguments => write!(f, "more than {MAX_ARGS} words"),
+            ShellError::UnknownCommand => f.write_str("unknown command, try `help`"),
+            ShellError::Usage => f.write_str("wrong number of arguments"),
+            ShellError::InvalidArgument(name) => write!(f, "invalid {name}"),
+            ShellError::Failed(reason) => f.write_str(reason),
+        }
+    }
+}
+
+/// Words of a line, split at spaces.
+///
+/// A word in double or single quotes may hold spaces; the quotes are not
+/// part of it. There are no escapes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Args<'a> {
+    words: heapless::Vec<&'a str, MAX_ARGS>,
+}
+
+impl<'a> Args<'a> {
+    /// Splits `line` into words.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ShellError::UnterminatedQuote`] if a quote is not closed, or
+    /// [`ShellError::TooManyArguments`] if the line has more than
+    /// [`MAX_ARGS`] words.
+    pub fn split(line: &'a str) -> Result<Self, ShellError> {
+        let mut words = heapless::Vec::new();
+        let mut rest = line.trim_start();
+        while !rest.is_empty() {
+            let (word, after) = match rest.as_bytes()[0] {
+                quote @ (b'"' | b'\'') => {
+                    let end = rest[1..]
+                        .find(quote as char)
+                        .ok_or(ShellError::UnterminatedQuote)?;
+                    (&rest[1..1 + end], &rest[end + 2..])
+                }
+                _ => rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len())),
+            };
+            words.push(word).map_err(|_| ShellError::TooManyArguments)?;
+            rest = after.trim_start();
+        }
+        Ok(Args { words })
+    }
+
+    /// Returns the number of words
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    /// Returns true if there are no words
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Returns word `index`, if there is one
+    pub fn get(&self, index: usize) -> Option<&'a str> {
+        self.words.get(index).copied()
+    }
+
+    /// Iterates over the words
+    pub fn iter(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.words.iter().copied()
+    }
+
+    /// Parses word `index`, which the error calls `name`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ShellError::InvalidArgument`] if the word is missing or does
+    /// not parse.
+    pub fn parse<T: FromStr>(&self, index: usize, name: &'static str) -> Result<T, ShellError> {
+        self.get(index)
+            .and_then(|word| word.parse().ok())
+            .ok_or(ShellError::InvalidArgument(name))
+    }
+
+    /// Returns the words from `index` on
+    fn skip(&self, index: usize) -> Args<'a> {
+        Args {
+            words: self.words.iter().skip(index).copied().collect(),
+        }
+    }
+}
+
+/// A command the shell can run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Command<T> {
+    /// Name the line starts with; may have several words
+    pub name: &'static str,
+    /// Arguments as shown in the help, e.g. `<ssid> [password]`
+    pub usage: &'static str,
+    /// One-line description
+    pub help: &'static str,
+    /// Fewest arguments the command takes
+    pub min_args: usize,
+    /// Most arguments the command takes
+    pub max_args: usize,
+    /// What to do, as understood by the caller
+    pub action: T,
+}
+
+impl<T> Command<T> {
+    /// Creates a command without arguments
+    pub const fn new(name: &'static str, help: &'static str, action: T) -> Self {
+        Command {
+            name,
+            usage: "",
+            help,
+            min_args: 0,
+            max_args: 0,
+            action,
+        }
+    }
+
+    /// Sets the arguments the command takes
+    #[must_use]
+    pub const fn with_args(
+        mut self,
+        usage: &'static str,
+        min_args: usize,
+        max_args: usize,
+    ) -> Self {
+        self.usage = usage;
+        self.min_args = min_args;
+        self.max_args = max_args;
+        self
+    }
+
+    /// Returns the number of words in the name
+    fn name_words(&self) -> usize {
+        self.name.split_whitespace().count()
+    }
+
+    /// Returns true if `args` starts with the name
+    fn matches(&self, args: &Args<'_>) -> bool {
+        let mut words = args.iter();
+        self.name
+            .split_whitespace()
+            .all(|name| words.next() == Some(name))
+    }
+}
+
+/// A line matched to a command
+#[derive(Debug, PartialEq, Eq)]
+pub struct Invocation<'r, 'a, T> {
+    /// The command to run
+    pub command: &'r Command<T>,
+    /// The words after the command name
+    pub args: Args<'a>,
+}
+
+/// Up to `N` commands, looked up by name
+#[derive(Clone, Debug)]
+pub struct Registry<T, const N: usize> {
+    commands: heapless::Vec<Command<T>, N>,
+}
+
+impl<T, const N: usize> Default for Registry<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Registry<T, N> {
+    /// Creates an empty registry
+    pub const fn new() -> Self {
+        Registry {
+            commands: heapless::Vec::new(),
+        }
+    }
+
+    /// Adds a command, replacing any command of the same name.
+    ///
+    /// # Errors
+    ///
+    /// Returns the command back if the registry is full.
+    pub fn register(&mut self, command: Command<T>) -> Result<(), Command<T>> {
+        match self.commands.iter_mut().find(|c| c.name == command.name) {
+            Some(existing) => {
+                *existing = command;
+                Ok(())
+            }
+            None => {
+                self.commands.push(command)?;
+                self.commands.sort_unstable_by_key(|c| c.name);
+                Ok(())
+            }
+        }
+    }
+
+    /// Iterates over the commands, by name
+    pub fn iter(&self) -> core::slice::Iter<'_, Command<T>> {
+        self.commands.iter()
+    }
+
+    /// Finds the command `line` is for.
+    ///
+    /// Returns `None` for a blank line.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ShellError`] if the line cannot be split into words, no
+    /// command matches, or the command does not take that many arguments.
+    pub fn parse<'r, 'a>(
+        &'r self,
+        line: &'a str,
+    ) -> Result<Option<Invocation<'r, 'a, T>>, ShellError> {
+        let words = Args::split(line)?;
+        if words.is_empty() {
+            return Ok(None);
+        }
+        let command = self.lookup(&words).ok_or(ShellError::UnknownCommand)?;
+        let args = words.skip(command.name_words());
+        if !(command.min_args..=command.max_args).contains(&args.len()) {
+            return Err(ShellError::Usage);
+        }
+        Ok(Some(Invocation { command, args }))
+    }
+
+    /// Finds the command with the longest name `words` start with
+    pub fn lookup(&self, words: &Args<'_>) -> Option<&Command<T>> {
+        self.commands
+            .iter()
+            .filter(|c| c.matches(words))
+            .max_by_key(|c| c.name_words())
+    }
+
+    /// Writes one line per command with its usage and description
+    pub fn write_help(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        let width = self
+            .commands
+            .iter()
+            .map(|c| c.name.len() + 1 + c.usage.len())
+            .max()
+            .unwrap_or(0);
+        for command in &self.commands {
+            let len = command.name.len() + 1 + command.usage.len();
+            write!(
+                out,
+                "  {} {}{:pad$}  {}{NEWLINE}",
+                command.name,
+                command.usage,
+                "",
+                command.help,
+                pad = width - len
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes how `command` is used
+pub fn write_usage<T>(out: &mut impl fmt::Write, command: &Command<T>) -> fmt::Result {
+    write!(out, "usage: {} {}{NEWLINE}", command.name, command.usage)
+}
+
+/// Writes a JSON object setting configuration field `key` to `value`, for
+/// [`crate::api::update_config`].
+///
+/// A value that reads as JSON, such as `30`, `true`, `null` or `[1,6,11]`,
+/// is taken as it is; anything else is taken as a string.
+pub fn write_setting(out: &mut impl fmt::Write, key: &str, value: &str) -> fmt::Result {
+    let is_json = matches!(value, "true" | "false" | "null")
+        || value.starts_with(['[', '"'])
+        || value.starts_with(|c: char| c == '-' || c.is_ascii_digit());
+    if is_json {
+        write!(out, "{{{}:{}}}", JsonStr(key), value)
+    } else {
+        write!(out, "{{{}:{}}}", JsonStr(key), JsonStr(value))
+    }
+}
+
+/// What a byte fed to a [`LineEditor`] completed
+#[derive(Debug, PartialEq, Eq)]
+pub enum Edit<'a> {
+    /// The line is still being typed
+    Pending,
+    /// Enter was pressed on this line
+    Line(&'a str),
+    /// Ctrl-C threw the line away
+    Cancelled,
+}
+
+/// Where an escape sequence is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Escape {
+    /// Not in a sequence
+    None,
+    /// After ESC
+    Start,
+    /// After ESC [ or ESC O, with the numeric parameter so far
+    Sequence(u8),
+}
+
+/// Editor for a line of up to `LEN` bytes, remembering `HISTORY` lines
+#[derive(Clone, Debug)]
+pub struct LineEditor<const LEN: usize = MAX_LINE_LEN, const HISTORY: usize = 8> {
+    prompt: &'static str,
+    line: heapless::Vec<u8, LEN>,
+    cursor: usize,
+    entered: heapless::Vec<u8, LEN>,
+    history: heapless::Deque<heapless::Vec<u8, LEN>, HISTORY>,
+    browsing: Option<usize>,
+    escape: Escape,
+    after_cr: bool,
+}
+
+impl<const LEN: usize, const HISTORY: usize> LineEditor<LEN, HISTORY> {
+    /// Creates an editor that shows `prompt` before each line
+    pub const fn new(prompt: &'static str) -> Self {
+        LineEditor {
+            prompt,
+            line: heapless::Vec::new(),
+            cursor: 0,
+            entered: heapless::Vec::new(),
+            history: heapless::Deque::new(),
+            browsing: None,
+            escape: Escape::None,
+            after_cr: false,
+        }
+    }
+
+    /// Returns the line typed so far
+    pub fn line(&self) -> &str {
+        ascii(&self.line)
+    }
+
+    /// Iterates over the remembered lines, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(|line| ascii(line))
+    }
+
+    /// Writes the prompt, to start a line
+    pub fn write_prompt(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        out.write_str(self.prompt)
+    }
+
+    /// Handles one byte typed on the terminal, echoing its effect to `out`.
+    ///
+    /// Output errors are ignored; the echo only helps the user.
+    pub fn feed(&mut self, byte: u8, out: &mut impl fmt::Write) -> Edit<'_> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match (self.escape, byte) {
+            (Escape::None, 0x1b) => self.escape = Escape::Start,
+            (Escape::Start, b'[' | b'O') => self.escape = Escape::Sequence(0),
+            (Escape::Start, _) => self.escape = Escape::None,
+            (Escape::Sequence(param), b'0'..=b'9') => {
+                self.escape = Escape::Sequence(param.saturating_mul(10).saturating_add(byte - b'0'))
+            }
+            (Escape::Sequence(param), _) => {
+                self.escape = Escape::None;
+                self.key_sequence(param, byte, out);
+            }
+            (Escape::None, b'\n') if after_cr => {}
+            (Escape::None, b'\r' | b'\n') => return self.enter(out),
+            (Escape::None, 0x03) => {
+                let _ = write!(out, "^C{NEWLINE}");
+                self.line.clear();
+                self.cursor = 0;
+                self.browsing = None;
+                return Edit::Cancelled;
+            }
+            (Escape::None, 0x08 | 0x7f) => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.line.remove(self.cursor);
+                    if self.cursor == self.line.len() {
+                        let _ = out.write_str("\x08 \x08");
+                    } else {
+                        self.redraw(out);
+                    }
+                }
+            }
+            (Escape::None, 0x01) => self.move_to(0, out),
+            (Escape::None, 0x05) => self.move_to(self.line.len(), out),
+            (Escape::None, 0x15) => {
+                self.line.clear();
+                self.cursor = 0;
+                self.redraw(out);
+            }
+            (Escape::None, 0x17) => {
+                let before = ascii(&self.line[..self.cursor]).trim_end();
+                let start = before.rfind(' ').map_or(0, |i| i + 1);
+                for _ in start..self.cursor {
+                    self.line.remove(start);
+                }
+                self.cursor = start;
+                self.redraw(out);
+            }
+            (Escape::None, 0x20..=0x7e) => {
+                if self.line.insert(self.cursor, byte).is_err() {
+                    // Bell: the line is full
+                    let _ = out.write_char('\x07');
+                } else {
+                    self.cursor += 1;
+                    if self.cursor == self.line.len() {
+                        let _ = out.write_char(byte as char);
+                    } else {
+                        self.redraw(out);
+                    }
+                }
+            }
+            (Escape::None, _) => {}
+        }
+        Edit::Pending
+    }
+
+    /// Handles the final byte of an escape sequence
+    fn key_sequence(&mut self, param: u8, byte: u8, out: &mut impl fmt::Write) {
+        match (param, byte) {
+            (_, b'A') => self.browse_older(out),
+            (_, b'B') => self.browse_newer(out),
+            (_, b'C') => self.move_to((self.cursor + 1).min(self.line.len()), out),
+            (_, b'D') => self.move_to(self.cursor.saturating_sub(1), out),
+            (_, b'H') | (1 | 7, b'~') => self.move_to(0, out),
+            (_, b'F') | (4 | 8, b'~') => self.move_to(self.line.len(), out),
+            (3, b'~') if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+                self.redraw(out);
+            }
+            _ => {}
+        }
+    }
+
+    /// Finishes the line and remembers it
+    fn enter(&mut self, out: &mut impl fmt::Write) -> Edit<'_> {
+        let _ = out.write_str(NEWLINE);
+        self.entered = core::mem::take(&mut self.line);
+        self.cursor = 0;
+        self.browsing = None;
+
+        let line = ascii(&self.entered).trim();
+        if !line.is_empty() && self.history.back().map(|last| ascii(last)) != Some(line) {
+            if self.history.is_full() {
+                self.history.pop_front();
+            }
+            let mut remembered = heapless::Vec::new();
+            // Cannot fail: the line fit in an equally large buffer
+            let _ = remembered.extend_from_slice(line.as_bytes());
+            let _ = self.history.push_back(remembered);
+        }
+        Edit::Line(ascii(&self.entered))
+    }
+
+    /// Replaces the line with the next older remembered one
+    fn browse_older(&mut self, out: &mut impl fmt::Write) {
+        let next = self.browsing.map_or(0, |back| back + 1);
+        if next < self.history.len() {
+            self.browsing = Some(next);
+            self.recall(out);
+        }
+    }
+
+    /// Replaces the line with the next newer remembered one, or clears it
+    /// after the newest
+    fn browse_newer(&mut self, out: &mut impl fmt::Write) {
+        match self.browsing {
+            None => {}
+            Some(0) => {
+                self.browsing = None;
+                self.line.clear();
+                self.cursor = 0;
+                self.redraw(out);
+            }
+            Some(back) => {
+                self.browsing = Some(back - 1);
+                self.recall(out);
+            }
+        }
+    }
+
+    /// Shows the remembered line being browsed
+    fn recall(&mut self, out: &mut impl fmt::Write) {
+        if let Some(back) = self.browsing
+            && let Some(line) = self.history.iter().rev().nth(back)
+        {
+            self.line.clone_from(line);
+            self.cursor = self.line.len();
+            self.redraw(out);
+        }
+    }
+
+    /// Moves the cursor to `position`
+    fn move_to(&mut self, position: usize, out: &mut impl fmt::Write) {
+        if position != self.cursor {
+            self.cursor = position;
+            self.redraw(out);
+        }
+    }
+
+    /// Writes the prompt and line again, and puts the cursor in place
+    fn redraw(&self, out: &mut impl fmt::Write) {
+        let _ = write!(out, "\r\x1b[K{}{}", self.prompt, self.line());
+        let back = self.line.len() - self.cursor;
+        if back > 0 {
+            let _ = write!(out, "\x1b[{back}D");
+        }
+    }
+}
+
+/// Reads a buffer that only ever holds printable ASCII
+fn ascii(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api;
+    use crate::scan_config::ScannerConfig;
+    use std::string::String;
+
+    fn type_in<'e>(editor: &'e mut LineEditor<16, 3>, keys: &[u8], echo: &mut String) -> Edit<'e> {
+        let (last, keys) = keys.split_last().unwrap();
+        for &key in keys {
+            assert_eq!(editor.feed(key, echo), Edit::Pending);
+        }
+        editor.feed(*last, echo)
+    }
+
+    #[test]
+    fn splits_words() {
+        let args = Args::split("  connect \"My Net\" 'pa ss'  x ").unwrap();
+        assert_eq!(
+            args.iter().collect::<heapless::Vec<_, 4>>(),
+            ["connect", "My Net", "pa ss", "x"]
+        );
+        assert!(Args::split("   ").unwrap().is_empty());
+        assert_eq!(Args::split("a \"b"), Err(ShellError::UnterminatedQuote));
+        assert_eq!(
+            Args::split("a b c d e f g h i"),
+            Err(ShellError::TooManyArguments)
+        );
+        assert_eq!(Args::split("a 12").unwrap().parse::<u32>(1, "n"), Ok(12));
+        assert_eq!(
+            Args::split("a x").unwrap().parse::<u32>(1, "n"),
+            Err(ShellError::InvalidArgument("n"))
+        );
+    }
+
+    #[test]
+    fn finds_commands() {
+        let mut registry = Registry::<u8, 3>::new();
+        registry
+            .register(Command::new("scan", "Scan now", 1))
+            .unwrap();
+        registry
+            .register(Command::new("scan interval", "Set interval", 2).with_args("<secs>", 1, 1))
+            .unwrap();
+        registry
+            .register(Command::new("connect", "Join", 3).with_args("<ssid> [password]", 1, 2))
+            .unwrap();
+        assert!(registry.register(Command::new("heap", "Heap", 4)).is_err());
+        // Same name replaces
+        registry
+            .register(Command::new("scan", "Scan again", 5))
+            .unwrap();
+
+        let run = |line| {
+            registry
+                .parse(line)
+                .map(|i| i.map(|i| (i.command.action, i.args)))
+        };
+        assert_eq!(run(""), Ok(None));
+        assert_eq!(run(" scan "), Ok(Some((5, Args::default()))));
+        assert_eq!(
+            run("scan interval 30"),
+            Ok(Some((2, Args::split("30").unwrap())))
+        );
+        assert_eq!(run("scan interval"), Err(ShellError::Usage));
+        assert_eq!(run("scan now"), Err(ShellError::Usage));
+        assert_eq!(
+            run("connect 'My Net'"),
+            Ok(Some((3, Args::split("'My Net'").unwrap())))
+        );
+        assert_eq!(run("connect a b c"), Err(ShellError::Usage));
+        assert_eq!(run("reboot"), Err(ShellError::UnknownCommand));
+
+        let mut help = String::new();
+        registry.write_help(&mut help).unwrap();
+        assert_eq!(
+            help,
+            "  connect <ssid> [password]  Join\r\n  \
+             scan                       Scan again\r\n  \
+             scan interval <secs>       Set interval\r\n"
+        );
+    }
+
+    #[test]
+    fn edits_lines() {
+        let mut editor = LineEditor::<16, 3>::new("> ");
+        let mut echo = String::new();
+        assert_eq!(
+            type_in(&mut editor, b"sca\x7fan\r", &mut echo),
+            Edit::Line("scan")
+        );
+        assert_eq!(echo, "sca\x08 \x08an\r\n");
+        // LF after CR is the same Enter
+        assert_eq!(editor.feed(b'\n', &mut echo), Edit::Pending);
+        assert_eq!(editor.line(), "");
+
+        // Left arrow, insert, Home, Delete
+        echo.clear();
+        type_in(&mut editor, b"hep\x1b[Dal\x1b[H\x1b[3~", &mut echo);
+        assert_eq!(editor.line(), "ealp");
+        assert!(echo.ends_with("\r\x1b[K> ealp\x1b[4D"));
+        // Ctrl-E, Ctrl-W, Ctrl-U
+        type_in(&mut editor, b"\x05 one two\x17", &mut echo);
+        assert_eq!(editor.line(), "ealp one ");
+        type_in(&mut editor, b"\x15", &mut echo);
+        assert_eq!(editor.line(), "");
+
+        // Ctrl-C drops the line
+        assert_eq!(type_in(&mut editor, b"abc\x03", &mut echo), Edit::Cancelled);
+        assert_eq!(editor.line(), "");
+
+        // Full lines ring the bell
+        echo.clear();
+        type_in(&mut editor, b"0123456789abcdefXY", &mut echo);
+        assert_eq!(editor.line(), "0123456789abcdef");
+        assert!(echo.ends_with("\x07\x07"));
+    }
+
+    #[test]
+    fn remembers_history() {
+        let mut editor = LineEditor::<16, 3>::new("> ");
+        let mut echo = String::new();
+        for line in ["a", "b", "b", " ", "c", "d"] {
+            type_in(&mut editor, line.as_bytes(), &mut echo);
+            editor.feed(b'\r', &mut echo);
+        }
+        // Blank lines and repeats are skipped; the oldest goes
+        assert_eq!(
+            editor.history().collect::<heapless::Vec<_, 3>>(),
+            ["b", "c", "d"]
+        );
+
+        type_in(&mut editor, b"\x1b[A\x1b[A", &mut echo);
+        assert_eq!(editor.line(), "c");
+        type_in(&mut editor, b"\x1b[A\x1b[A", &mut echo);
+        assert_eq!(editor.line(), "b");
+        type_in(&mut editor, b"\x1bOB", &mut echo);
+        assert_eq!(editor.line(), "c");
+        type_in(&mut editor, b"\x1b[B\x1b[B", &mut echo);
+        assert_eq!(editor.line(), "");
+        assert_eq!(
+            type_in(&mut editor, b"\x1b[Ax\r", &mut echo),
+            Edit::Line("dx")
+        );
+    }
+
+    #[test]
+    fn writes_settings() {
+        let mut body = String::new();
+        let mut update = |key, value| {
+            body.clear();
+            write_setting(&mut body, key, value).unwrap();
+            api::update_config(&ScannerConfig::default(), body.as_bytes())
+        };
+        assert_eq!(update("interval_secs", "30").unwrap().interval_secs, 30);
+        assert_eq!(
+            update("show_hidden", "true").map(|c| c.show_hidden),
+            Ok(true)
+        );
+        assert_eq!(
+            update("ssid_filter", "My \"Net\"")
+                .unwrap()
+                .ssid_filter
+                .unwrap(),
+            "My \"Net\""
+        );
+        assert!(update("ssid_filter", "null").unwrap().ssid_filter.is_none());
+        assert!(update("channels", "[1,6,11]").unwrap().channels.contains(6));
+        assert!(update("interval_secs", "soon").is_err());
+    }
+}
@@ -0,0 +1,259 @@
+//! SNTP client packets (RFC 4330).
+//!
+//! [`write_request`] builds the request a client sends to port [`NTP_PORT`]
+//! of a time server, and [`parse_response`] checks the answer and extracts
+//! the server's timestamps. [`SntpResponse::measure`] then combines them
+//! with the local send and receive times into the offset of the local clock
+//! and the round-trip delay, ready for a [`WallClock`](crate::clock::WallClock).
+
+use core::fmt;
+
+use crate::clock::UtcTime;
+
+/// UDP port of NTP servers
+pub const NTP_PORT: u16 = 123;
+
+/// Length of an SNTP packet without extensions
+pub const PACKET_LEN: usize = 48;
+
+/// Seconds from the NTP epoch, 1900-01-01, to the Unix epoch
+const NTP_UNIX_OFFSET_SECS: i64 = 2_208_988_800;
+
+/// Version 4, client mode
+const CLIENT_HEADER: u8 = 4 << 3 | 3;
+
+/// Server mode
+const MODE_SERVER: u8 = 4;
+
+/// Leap indicator of a server whose clock is not synchronized
+const LEAP_ALARM: u8 = 3;
+
+/// Reasons why a response was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SntpError {
+    /// The packet is too short or not a server response
+    Malformed,
+    /// The response does not answer the last request
+    OriginMismatch,
+    /// The server's own clock is not synchronized
+    Unsynchronized,
+    /// The server refused to answer, with this kiss code, e.g. `RATE`
+    KissOfDeath([u8; 4]),
+}
+
+impl fmt::Display for SntpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SntpError::Malformed => f.write_str("malformed SNTP response"),
+            SntpError::OriginMismatch => f.write_str("SNTP response does not match the request"),
+            SntpError::Unsynchronized => f.write_str("time server is not synchronized"),
+            SntpError::KissOfDeath(code) => match core::str::from_utf8(code) {
+                Ok(code) => write!(f, "time server refused with {}", code),
+                Err(_) => f.write_str("time server refused"),
+            },
+        }
+    }
+}
+
+/// An NTP timestamp: seconds since 1900 in the upper 32 bits, and the
+/// fraction of a second in the lower 32
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NtpTimestamp(pub u64);
+
+impl NtpTimestamp {
+    /// Converts to UTC.
+    ///
+    /// Timestamps with the top bit clear are taken to be in the era that
+    /// starts in 2036, so the result lies between 1968 and 2104.
+    pub fn to_utc(self) -> UtcTime {
+        let mut secs = (self.0 >> 32) as i64;
+        if secs < 1 << 31 {
+            secs += 1 << 32;
+        }
+        let micros = ((self.0 & 0xffff_ffff) * 1_000_000) >> 32;
+        UtcTime::from_unix_micros((secs - NTP_UNIX_OFFSET_SECS) * 1_000_000 + micros as i64)
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        let mut value = [0; 8];
+        value.copy_from_slice(&bytes[..8]);
+        Self(u64::from_be_bytes(value))
+    }
+}
+
+/// Writes a request into `packet`.
+///
+/// `transmit` may be any value that identifies the request, such as the
+/// uptime; the server echoes it and [`parse_response`] checks it.
+pub fn write_request(packet: &mut [u8; PACKET_LEN], transmit: NtpTimestamp) {
+    packet.fill(0);
+    packet[0] = CLIENT_HEADER;
+    packet[40..].copy_from_slice(&transmit.0.to_be_bytes());
+}
+
+/// The timestamps of a server response
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SntpResponse {
+    /// Distance of the server from a reference clock; 1 is a primary server
+    pub stratum: u8,
+    /// Time the server received the request
+    pub receive: UtcTime,
+    /// Time the server sent the response
+    pub transmit: UtcTime,
+}
+
+/// Offset and round trip of one exchange with a time server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Measurement {
+    /// UTC minus local time, in microseconds
+    pub offset_us: i64,
+    /// Time the packets spent in the network, in microseconds
+    pub delay_us: i64,
+}
+
+impl SntpResponse {
+    /// Computes the offset of the local clock from the server's.
+    ///
+    /// # Arguments
+    ///
+    /// * `sent_us` - Local time the request was sent, in microseconds
+    /// * `received_us` - Local time the response arrived, in microseconds
+    pub fn measure(&self, sent_us: u64, received_us: u64) -> Measurement {
+        let t1 = sent_us as i64;
+        let t2 = self.receive.unix_micros();
+        let t3 = self.transmit.unix_micros();
+        let t4 = received_us as i64;
+        Measurement {
+            offset_us: ((t2 - t1) + (t3 - t4)) / 2,
+            delay_us: (t4 - t1) - (t3 - t2),
+        }
+    }
+}
+
+/// Checks a response to the request sent with `origin` and returns the
+/// server's timestamps.
+///
+/// # Errors
+///
+/// Returns an [`SntpError`] if the packet is not a valid answer from a
+/// synchronized server to that request.
+pub fn parse_response(packet: &[u8], origin: NtpTimestamp) -> Result<SntpResponse, SntpError> {
+    if packet.len() < PACKET_LEN || packet[0] & 0x07 != MODE_SERVER {
+        return Err(SntpError::Malformed);
+    }
+    let stratum = packet[1];
+    if stratum == 0 {
+        let mut code = [0; 4];
+        code.copy_from_slice(&packet[12..16]);
+        return Err(SntpError::KissOfDeath(code));
+    }
+    if packet[0] >> 6 == LEAP_ALARM {
+        return Err(SntpError::Unsynchronized);
+    }
+    if NtpTimestamp::read(&packet[24..]) != origin {
+        return Err(SntpError::OriginMismatch);
+    }
+    let receive = NtpTimestamp::read(&packet[32..]);
+    let transmit = NtpTimestamp::read(&packet[40..]);
+    if receive.0 == 0 || transmit.0 == 0 {
+        return Err(SntpError::Malformed);
+    }
+    Ok(SntpResponse {
+        stratum,
+        receive: receive.to_utc(),
+        transmit: transmit.to_utc(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-05-01T12:00:00Z in NTP seconds
+    const NTP_SECS: u64 = 3_923_553_600;
+
+    fn response(origin: NtpTimestamp) -> [u8; PACKET_LEN] {
+        let mut packet = [0; PACKET_LEN];
+        packet[0] = 4 << 3 | MODE_SERVER;
+        packet[1] = 2;
+        packet[24..32].copy_from_slice(&origin.0.to_be_bytes());
+        // Received at 12:00:00.5, sent 1 ms later
+        packet[32..40].copy_from_slice(&(NTP_SECS << 32 | 1 << 31).to_be_bytes());
+        let transmit = NTP_SECS << 32 | ((501u64 << 32) / 1000 + 1);
+        packet[40..48].copy_from_slice(&transmit.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn measures_offset() {
+        let origin = NtpTimestamp(0x1234_5678);
+        let mut request = [0xff; PACKET_LEN];
+        write_request(&mut request, origin);
+        assert_eq!(request[0], 0x23);
+        assert!(request[1..40].iter().all(|&b| b == 0));
+        assert_eq!(&request[40..], &origin.0.to_be_bytes());
+
+        let response = parse_response(&response(origin), origin).unwrap();
+        assert_eq!(response.stratum, 2);
+        let noon = 1_714_564_800_000_000;
+        assert_eq!(response.receive.unix_micros(), noon + 500_000);
+        assert_eq!(response.transmit.unix_micros(), noon + 501_000);
+
+        // Sent at 10 s uptime, answered 21 ms later
+        let measurement = response.measure(10_000_000, 10_021_000);
+        assert_eq!(
+            measurement,
+            Measurement {
+                offset_us: noon + 500_500 - 10_010_500,
+                delay_us: 20_000,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_responses() {
+        let origin = NtpTimestamp(42);
+        let packet = response(origin);
+        assert_eq!(
+            parse_response(&packet[..40], origin),
+            Err(SntpError::Malformed)
+        );
+        assert_eq!(
+            parse_response(&packet, NtpTimestamp(43)),
+            Err(SntpError::OriginMismatch)
+        );
+
+        let mut kiss = packet;
+        kiss[1] = 0;
+        kiss[12..16].copy_from_slice(b"RATE");
+        assert_eq!(
+            parse_response(&kiss, origin),
+            Err(SntpError::KissOfDeath(*b"RATE"))
+        );
+
+        let mut unsynchronized = packet;
+        unsynchronized[0] |= LEAP_ALARM << 6;
+        assert_eq!(
+            parse_response(&unsynchronized, origin),
+            Err(SntpError::Unsynchronized)
+        );
+
+        let mut request = [0; PACKET_LEN];
+        write_request(&mut request, origin);
+        assert_eq!(parse_response(&request, origin), Err(SntpError::Malformed));
+    }
+
+    #[test]
+    fn converts_next_era() {
+        // 2036-02-07T06:28:16Z wraps the seconds to 0
+        assert_eq!(NtpTimestamp(0).to_utc().unix_secs(), 2_085_978_496);
+        assert_eq!(
+            NtpTimestamp(0xffff_ffff << 32).to_utc().unix_secs(),
+            2_085_978_495
+        );
+    }
+}
@@ -0,0 +1,596 @@
+//! Station connection state machine.
+//!
+//! [`StationMachine`] decides what the station does next. The firmware task
+//! reports driver results to it, e.g. a failed connection attempt or a lost
+//! link, and then acts on its [`ConnectionState`]:
+//!
+//! | State          | Call                | Next state                          |
+//! |----------------|---------------------|-------------------------------------|
+//! | `Idle`         | `start`             | `Connecting`                        |
+//! | `Connecting`   | `connected`         | `Connected`                         |
+//! | `Connecting`   | `failed`            | `Disconnected`                      |
+//! | `Connected`    | `link_lost`         | `Disconnected`                      |
+//! | `Disconnected` | `retry`             | `Connecting` after a lost link, otherwise `Backoff` |
+//! | `Backoff`      | `backoff_elapsed`   | `Connecting`                        |
+//! | any            | `stop`              | `Idle`                              |
+//!
+//! After a lost link the first reconnect is attempted right away. Every
+//! failed attempt after that waits in [`ConnectionState::Backoff`] for an
+//! exponentially growing, jittered delay (see [`BackoffConfig::delay_ms`]).
+//! Once [`BackoffConfig::max_attempts`] attempts have failed in a row, the
+//! machine stays [`ConnectionState::Disconnected`] until it is started again.
+
+use core::fmt;
+
+use crate::report::{MAX_SSID_LEN, Ssid};
+
+/// Maximum length of a WPA passphrase or hex key
+pub const MAX_PASSWORD_LEN: usize = 64;
+
+/// Minimum length of a WPA passphrase
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Password of a station configuration
+pub type Password = heapless::String<MAX_PASSWORD_LEN>;
+
+/// Reasons why [`Credentials`] were rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CredentialsError {
+    /// The SSID is empty
+    EmptySsid,
+    /// The SSID is longer than 32 bytes
+    SsidTooLong,
+    /// The password is neither empty nor 8 to 64 bytes long
+    InvalidPasswordLength,
+}
+
+impl fmt::Display for CredentialsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialsError::EmptySsid => f.write_str("SSID is empty"),
+            CredentialsError::SsidTooLong => {
+                write!(f, "SSID is longer than {} bytes", MAX_SSID_LEN)
+            }
+            CredentialsError::InvalidPasswordLength => write!(
+                f,
+                "password must be empty or {} to {} bytes long",
+                MIN_PASSWORD_LEN, MAX_PASSWORD_LEN
+            ),
+        }
+    }
+}
+
+/// SSID and password of the network to join
+///
+/// The password is left out of the `Debug` and `defmt` output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    /// SSID of the network
+    pub ssid: Ssid,
+    /// Password of the network; empty for open networks
+    pub password: Password,
+}
+
+impl Credentials {
+    /// Creates credentials for a network.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`CredentialsError`] if the SSID is empty or too long, or
+    /// the password is neither empty nor a valid WPA passphrase length.
+    pub fn new(ssid: &str, password: &str) -> Result<Self, CredentialsError> {
+        if ssid.is_empty() {
+            return Err(CredentialsError::EmptySsid);
+        }
+        if !password.is_empty() && !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&password.len())
+        {
+            return Err(CredentialsError::InvalidPasswordLength);
+        }
+        Ok(Self {
+            ssid: ssid.try_into().map_err(|_| CredentialsError::SsidTooLong)?,
+            password: password
+                .try_into()
+                .map_err(|_| CredentialsError::InvalidPasswordLength)?,
+        })
+    }
+
+    /// Returns `true` if the network has no password
+    pub fn is_open(&self) -> bool {
+        self.password.is_empty()
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("ssid", &self.ssid)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Credentials {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "Credentials {{ ssid: {}, .. }}", self.ssid.as_str())
+    }
+}
+
+/// Why the station lost or could not establish its connection
+///
+/// Decoded from the reason code of the driver's disconnect event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DisconnectReason {
+    /// No reason given
+    Unspecified,
+    /// The authentication is no longer valid
+    AuthExpired,
+    /// The access point deauthenticated the station
+    Deauthenticated,
+    /// The access point dropped the station for inactivity
+    Inactivity,
+    /// The access point cannot handle more stations
+    ApFull,
+    /// The station left the network
+    Left,
+    /// The key handshake timed out, usually because of a wrong password
+    HandshakeTimeout,
+    /// The access point stopped sending beacons
+    BeaconTimeout,
+    /// No access point with a matching SSID and security was found
+    NoApFound,
+    /// Authentication was rejected
+    AuthFailed,
+    /// Association was rejected
+    AssocFailed,
+    /// The connection failed for another reason
+    ConnectionFailed,
+    /// Any other reason code
+    Other(u8),
+}
+
+impl DisconnectReason {
+    /// Decodes a reason code reported by the driver
+    pub const fn from_code(code: u8) -> Self {
+        match code {
+            1 => DisconnectReason::Unspecified,
+            2 => DisconnectReason::AuthExpired,
+            3 => DisconnectReason::Deauthenticated,
+            4 => DisconnectReason::Inactivity,
+            5 => DisconnectReason::ApFull,
+            8 => DisconnectReason::Left,
+            15 | 204 => DisconnectReason::HandshakeTimeout,
+            200 => DisconnectReason::BeaconTimeout,
+            201 | 210..=212 => DisconnectReason::NoApFound,
+            202 => DisconnectReason::AuthFailed,
+            203 => DisconnectReason::AssocFailed,
+            205 => DisconnectReason::ConnectionFailed,
+            code => DisconnectReason::Other(code),
+        }
+    }
+
+    /// Reason code of the disconnect; [`from_code`](Self::from_code) maps it back
+    pub const fn code(self) -> u8 {
+        match self {
+            DisconnectReason::Unspecified => 1,
+            DisconnectReason::AuthExpired => 2,
+            DisconnectReason::Deauthenticated => 3,
+            DisconnectReason::Inactivity => 4,
+            DisconnectReason::ApFull => 5,
+            DisconnectReason::Left => 8,
+            DisconnectReason::HandshakeTimeout => 15,
+            DisconnectReason::BeaconTimeout => 200,
+            DisconnectReason::NoApFound => 201,
+            DisconnectReason::AuthFailed => 202,
+            DisconnectReason::AssocFailed => 203,
+            DisconnectReason::ConnectionFailed => 205,
+            DisconnectReason::Other(code) => code,
+        }
+    }
+
+    /// Returns `true` if the reason points at wrong credentials
+    pub const fn is_auth_failure(self) -> bool {
+        matches!(
+            self,
+            DisconnectReason::HandshakeTimeout | DisconnectReason::AuthFailed
+        )
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::Unspecified => f.write_str("unspecified"),
+            DisconnectReason::AuthExpired => f.write_str("authentication expired"),
+            DisconnectReason::Deauthenticated => f.write_str("deauthenticated by access point"),
+            DisconnectReason::Inactivity => f.write_str("inactivity"),
+            DisconnectReason::ApFull => f.write_str("access point full"),
+            DisconnectReason::Left => f.write_str("left network"),
+            DisconnectReason::HandshakeTimeout => f.write_str("handshake timeout"),
+            DisconnectReason::BeaconTimeout => f.write_str("beacon timeout"),
+            DisconnectReason::NoApFound => f.write_str("no access point found"),
+            DisconnectReason::AuthFailed => f.write_str("authentication failed"),
+            DisconnectReason::AssocFailed => f.write_str("association failed"),
+            DisconnectReason::ConnectionFailed => f.write_str("connection failed"),
+            DisconnectReason::Other(code) => write!(f, "reason {}", code),
+        }
+    }
+}
+
+/// Connection state of the station
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConnectionState {
+    /// Not trying to connect
+    Idle,
+    /// A connection attempt is in progress
+    Connecting {
+        /// Number of the attempt, starting at 1
+        attempt: u32,
+    },
+    /// Connected to the access point
+    Connected,
+    /// The connection was lost or an attempt failed
+    Disconnected {
+        /// Why the connection was lost
+        reason: DisconnectReason,
+    },
+    /// Waiting before the next attempt
+    Backoff {
+        /// Number of the attempt that follows the wait
+        attempt: u32,
+        /// Length of the wait in milliseconds
+        delay_ms: u32,
+    },
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Idle => f.write_str("idle"),
+            ConnectionState::Connecting { attempt } => {
+                write!(f, "connecting (attempt {})", attempt)
+            }
+            ConnectionState::Connected => f.write_str("connected"),
+            ConnectionState::Disconnected { reason } => write!(f, "disconnected: {}", reason),
+            ConnectionState::Backoff { attempt, delay_ms } => {
+                write!(f, "retrying in {} ms (attempt {})", delay_ms, attempt)
+            }
+        }
+    }
+}
+
+/// Reconnect timing of a [`StationMachine`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BackoffConfig {
+    /// Wait after the first failed attempt, in milliseconds
+    pub initial_delay_ms: u32,
+    /// Upper bound of the wait before jitter, in milliseconds
+    pub max_delay_ms: u32,
+    /// Random variation of each wait, in percent of it (0 to 100)
+    pub jitter_percent: u8,
+    /// Consecutive failed attempts before giving up; `None` retries forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 1_000,
+            max_delay_ms: 60_000,
+            jitter_percent: 20,
+            max_attempts: None,
+        }
+    }
+}
+
+impl BackoffConfig {
+    /// Computes the wait after `failures` consecutive failed attempts.
+    ///
+    /// The wait doubles with every failure, starting at
+    /// [`initial_delay_ms`](Self::initial_delay_ms) and capped at
+    /// [`max_delay_ms`](Self::max_delay_ms). It is then moved by up to
+    /// [`jitter_percent`](Self::jitter_percent) in either direction, picked
+    /// by `entropy`, so that devices losing the same access point do not
+    /// all retry at once.
+    pub fn delay_ms(&self, failures: u32, entropy: u32) -> u32 {
+        let doublings = failures.saturating_sub(1).min(31);
+        let base = self
+            .initial_delay_ms
+            .saturating_mul(1 << doublings)
+            .min(self.max_delay_ms);
+
+        let jitter = u64::from(self.jitter_percent.min(100));
+        let spread = u64::from(base) * jitter / 100;
+        let offset = u64::from(entropy) % (2 * spread + 1);
+        (u64::from(base) - spread + offset).min(u64::from(u32::MAX)) as u32
+    }
+}
+
+/// Connection state machine of the station
+#[derive(Clone, Debug)]
+pub struct StationMachine {
+    config: BackoffConfig,
+    state: ConnectionState,
+    failures: u32,
+    last_reason: Option<DisconnectReason>,
+}
+
+impl StationMachine {
+    /// Creates an idle state machine
+    pub const fn new(config: BackoffConfig) -> Self {
+        Self {
+            config,
+            state: ConnectionState::Idle,
+            failures: 0,
+            last_reason: None,
+        }
+    }
+
+    /// The current state
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// The reconnect timing
+    pub fn config(&self) -> &BackoffConfig {
+        &self.config
+    }
+
+    /// Why the connection was last lost, if it ever was
+    pub fn last_reason(&self) -> Option<DisconnectReason> {
+        self.last_reason
+    }
+
+    /// Number of consecutive failed attempts
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Starts connecting.
+    ///
+    /// Has an effect when idle, or after giving up; a pending backoff is cut
+    /// short. The failure count starts over.
+    pub fn start(&mut self) {
+        match self.state {
+            ConnectionState::Idle
+            | ConnectionState::Disconnected { .. }
+            | ConnectionState::Backoff { .. } => {
+                self.failures = 0;
+                self.state = ConnectionState::Connecting { attempt: 1 };
+            }
+            ConnectionState::Connecting { .. } | ConnectionState::Connected => {}
+        }
+    }
+
+    /// Stops connecting; the caller disconnects the driver.
+    pub fn stop(&mut self) {
+        self.failures = 0;
+        self.state = ConnectionState::Idle;
+    }
+
+    /// Reports that the connection attempt succeeded.
+    pub fn connected(&mut self) {
+        if let ConnectionState::Connecting { .. } = self.state {
+            self.failures = 0;
+            self.state = ConnectionState::Connected;
+        }
+    }
+
+    /// Reports that the connection attempt failed.
+    pub fn failed(&mut self, reason: DisconnectReason) {
+        if let ConnectionState::Connecting { .. } = self.state {
+            self.failures = self.failures.saturating_add(1);
+            self.disconnect(reason);
+        }
+    }
+
+    /// Reports that the established connection was lost.
+    pub fn link_lost(&mut self, reason: DisconnectReason) {
+        if self.state == ConnectionState::Connected {
+            self.failures = 0;
+            self.disconnect(reason);
+        }
+    }
+
+    fn disconnect(&mut self, reason: DisconnectReason) {
+        self.last_reason = Some(reason);
+        self.state = ConnectionState::Disconnected { reason };
+    }
+
+    /// Leaves [`ConnectionState::Disconnected`] for the next attempt.
+    ///
+    /// Right after a lost link this goes straight to
+    /// [`ConnectionState::Connecting`], otherwise to
+    /// [`ConnectionState::Backoff`] with a delay jittered by `entropy`.
+    ///
+    /// Returns `false`, leaving the state unchanged, if the machine is not
+    /// disconnected or has used up [`BackoffConfig::max_attempts`].
+    pub fn retry(&mut self, entropy: u32) -> bool {
+        if !matches!(self.state, ConnectionState::Disconnected { .. }) || self.gave_up() {
+            return false;
+        }
+        let attempt = self.failures.saturating_add(1);
+        self.state = if self.failures == 0 {
+            ConnectionState::Connecting { attempt }
+        } else {
+            ConnectionState::Backoff {
+                attempt,
+                delay_ms: self.config.delay_ms(self.failures, entropy),
+            }
+        };
+        true
+    }
+
+    /// Returns `true` if the last [`BackoffConfig::max_attempts`] attempts all failed
+    pub fn gave_up(&self) -> bool {
+        self.config
+            .max_attempts
+            .is_some_and(|max| self.failures >= max)
+    }
+
+    /// Reports that the backoff delay is over; moves on to the next attempt.
+    pub fn backoff_elapsed(&mut self) {
+        if let ConnectionState::Backoff { attempt, .. } = self.state {
+            self.state = ConnectionState::Connecting { attempt };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    fn no_jitter() -> BackoffConfig {
+        BackoffConfig {
+            jitter_percent: 0,
+            ..BackoffConfig::default()
+        }
+    }
+
+    #[test]
+    fn connects_and_reconnects_at_once_after_link_loss() {
+        let mut machine = StationMachine::new(no_jitter());
+        assert_eq!(machine.state(), ConnectionState::Idle);
+
+        machine.start();
+        assert_eq!(machine.state(), ConnectionState::Connecting { attempt: 1 });
+        machine.connected();
+        assert_eq!(machine.state(), ConnectionState::Connected);
+
+        machine.link_lost(DisconnectReason::BeaconTimeout);
+        assert_eq!(
+            machine.state(),
+            ConnectionState::Disconnected {
+                reason: DisconnectReason::BeaconTimeout
+            }
+        );
+        assert!(machine.retry(0));
+        assert_eq!(machine.state(), ConnectionState::Connecting { attempt: 1 });
+        assert_eq!(machine.last_reason(), Some(DisconnectReason::BeaconTimeout));
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_cap() {
+        let mut machine = StationMachine::new(BackoffConfig {
+            max_delay_ms: 5_000,
+            ..no_jitter()
+        });
+        machine.start();
+
+        let mut delays = std::vec::Vec::new();
+        for _ in 0..5 {
+            machine.failed(DisconnectReason::NoApFound);
+            assert!(machine.retry(0));
+            let ConnectionState::Backoff { attempt, delay_ms } = machine.state() else {
+                panic!("expected backoff, got {:?}", machine.state());
+            };
+            delays.push(delay_ms);
+            machine.backoff_elapsed();
+            assert_eq!(machine.state(), ConnectionState::Connecting { attempt });
+        }
+        assert_eq!(delays, [1_000, 2_000, 4_000, 5_000, 5_000]);
+    }
+
+    #[test]
+    fn keeps_jitter_within_bounds() {
+        let config = BackoffConfig::default();
+        let delays = (0..1000).map(|entropy| config.delay_ms(2, entropy * 7919));
+        assert!(delays.clone().all(|delay| (1_600..=2_400).contains(&delay)));
+        assert!(delays.clone().any(|delay| delay < 1_700));
+        assert!(delays.clone().any(|delay| delay > 2_300));
+        assert!(config.delay_ms(u32::MAX, u32::MAX) <= 72_000);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts_until_restarted() {
+        let mut machine = StationMachine::new(BackoffConfig {
+            max_attempts: Some(2),
+            ..no_jitter()
+        });
+        machine.start();
+        machine.failed(DisconnectReason::AuthFailed);
+        assert!(machine.retry(0));
+        machine.backoff_elapsed();
+        machine.failed(DisconnectReason::AuthFailed);
+
+        assert!(machine.gave_up());
+        assert!(!machine.retry(0));
+        assert!(matches!(
+            machine.state(),
+            ConnectionState::Disconnected { .. }
+        ));
+
+        machine.start();
+        assert_eq!(machine.state(), ConnectionState::Connecting { attempt: 1 });
+        assert!(!machine.gave_up());
+    }
+
+    #[test]
+    fn ignores_reports_that_do_not_fit_the_state() {
+        let mut machine = StationMachine::new(no_jitter());
+        machine.connected();
+        machine.link_lost(DisconnectReason::Unspecified);
+        machine.backoff_elapsed();
+        assert!(!machine.retry(0));
+        assert_eq!(machine.state(), ConnectionState::Idle);
+
+        machine.start();
+        machine.connected();
+        machine.start();
+        assert_eq!(machine.state(), ConnectionState::Connected);
+        machine.stop();
+        assert_eq!(machine.state(), ConnectionState::Idle);
+    }
+
+    #[test]
+    fn decodes_reason_codes() {
+        assert_eq!(
+            DisconnectReason::from_code(15),
+            DisconnectReason::HandshakeTimeout
+        );
+        assert_eq!(
+            DisconnectReason::from_code(211),
+            DisconnectReason::NoApFound
+        );
+        assert_eq!(DisconnectReason::from_code(99), DisconnectReason::Other(99));
+        for code in 0..=u8::MAX {
+            let reason = DisconnectReason::from_code(code);
+            assert_eq!(DisconnectReason::from_code(reason.code()), reason);
+        }
+        assert!(DisconnectReason::from_code(202).is_auth_failure());
+        assert_eq!(
+            format!(
+                "{}",
+                ConnectionState::Disconnected {
+                    reason: DisconnectReason::from_code(200)
+                }
+            ),
+            "disconnected: beacon timeout"
+        );
+    }
+
+    #[test]
+    fn validates_credentials_and_hides_password() {
+        let credentials = Credentials::new("home", "correct horse").unwrap();
+        assert!(!credentials.is_open());
+        assert!(!format!("{:?}", credentials).contains("horse"));
+        assert!(Credentials::new("cafe", "").unwrap().is_open());
+
+        assert_eq!(
+            Credentials::new("", "password"),
+            Err(CredentialsError::EmptySsid)
+        );
+        assert_eq!(
+            Credentials::new("home", "short"),
+            Err(CredentialsError::InvalidPasswordLength)
+        );
+        assert_eq!(
+            Credentials::new(&"x".repeat(33), ""),
+            Err(CredentialsError::SsidTooLong)
+        );
+    }
+}
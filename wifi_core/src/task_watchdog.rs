@@ -0,0 +1,376 @@
+//! Heartbeat deadlines of long-running tasks and escalation of missed ones.
+//!
+//! Each supervised task registers with a [`TaskWatchdog`] and checks in with
+//! a heartbeat at least once per deadline while it works. A task that waits
+//! for something that may legitimately take forever, such as a command,
+//! marks itself idle instead and has no deadline until its next heartbeat.
+//!
+//! The firmware calls [`TaskWatchdog::check`] now and then. Each deadline a
+//! task misses in a row is reported and, as set by the [`Escalation`], can
+//! lead to an [`Action`]: restarting the radio, entering safe mode or
+//! resetting the chip. Times are milliseconds since boot.
+
+use core::fmt;
+
+/// Maximum number of supervised tasks
+pub const MAX_TASKS: usize = 16;
+
+/// Recovery action for missed deadlines, from the mildest to the most severe
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Action {
+    /// Stop and start the WiFi radio
+    RestartRadio,
+    /// Reset the chip
+    ResetChip,
+    /// Reset the chip into safe mode, without the radio
+    SafeMode,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::RestartRadio => "restarting the radio",
+            Action::ResetChip => "resetting the chip",
+            Action::SafeMode => "entering safe mode",
+        })
+    }
+}
+
+/// Number of deadlines a task must miss in a row before each [`Action`]
+///
+/// `None` disables an action. When several actions are due at the same
+/// count, the most severe one is taken. A reset starts all counts over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Escalation {
+    /// Misses in a row before the radio is restarted
+    pub restart_radio_after: Option<u32>,
+    /// Misses in a row before the chip is reset
+    pub reset_after: Option<u32>,
+    /// Misses in a row before the chip is reset into safe mode
+    pub safe_mode_after: Option<u32>,
+}
+
+impl Escalation {
+    /// Only reports missed deadlines
+    pub const LOG_ONLY: Escalation = Escalation {
+        restart_radio_after: None,
+        reset_after: None,
+        safe_mode_after: None,
+    };
+
+    /// Returns the action due after `misses` deadlines missed in a row
+    pub fn action(&self, misses: u32) -> Option<Action> {
+        [
+            (self.safe_mode_after, Action::SafeMode),
+            (self.reset_after, Action::ResetChip),
+            (self.restart_radio_after, Action::RestartRadio),
+        ]
+        .into_iter()
+        .find(|&(after, _)| after == Some(misses))
+        .map(|(_, action)| action)
+    }
+}
+
+impl Default for Escalation {
+    /// Restarts the radio on the first miss and resets the chip on the third
+    fn default() -> Self {
+        Escalation {
+            restart_radio_after: Some(1),
+            reset_after: Some(3),
+            safe_mode_after: None,
+        }
+    }
+}
+
+/// Why a task could not be registered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum WatchdogError {
+    /// [`MAX_TASKS`] tasks are already registered
+    Full,
+}
+
+impl fmt::Display for WatchdogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchdogError::Full => write!(f, "more than {MAX_TASKS} supervised tasks"),
+        }
+    }
+}
+
+/// Registered task, as returned by [`TaskWatchdog::register`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TaskId(u8);
+
+/// Supervision state of one task
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TaskStatus {
+    /// Name the task registered with
+    pub name: &'static str,
+    /// Longest time allowed between two heartbeats, in milliseconds
+    pub deadline_ms: u32,
+    /// Time of the last heartbeat, or of the registration before the first
+    pub last_seen_ms: u64,
+    /// Time by which the next heartbeat is due, or `None` while idle
+    pub due_ms: Option<u64>,
+    /// Deadlines missed since the last heartbeat
+    pub misses: u32,
+    /// Deadlines missed since boot
+    pub total_misses: u32,
+}
+
+impl fmt::Display for TaskStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.name)?;
+        match self.due_ms {
+            Some(_) if self.misses > 0 => write!(f, "late, {} missed", self.misses)?,
+            Some(_) => f.write_str("running")?,
+            None => f.write_str("idle")?,
+        }
+        write!(
+            f,
+            ", deadline {} ms, {} missed since boot",
+            self.deadline_ms, self.total_misses
+        )
+    }
+}
+
+/// Missed deadline found by [`TaskWatchdog::check`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Miss {
+    /// Task that missed its deadline
+    pub task: TaskId,
+    /// Name of the task
+    pub name: &'static str,
+    /// Time since the last heartbeat or registration, in milliseconds
+    pub silent_ms: u64,
+    /// Deadlines missed in a row, including this one
+    pub misses: u32,
+    /// Action the [`Escalation`] asks for after this miss, if any
+    pub action: Option<Action>,
+}
+
+impl fmt::Display for Miss {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Task {} missed its deadline: no heartbeat for {} ms, {} in a row",
+            self.name, self.silent_ms, self.misses
+        )?;
+        if let Some(action) = self.action {
+            write!(f, ", {}", action)?;
+        }
+        Ok(())
+    }
+}
+
+/// Deadlines and missed heartbeats of the supervised tasks
+#[derive(Clone, Debug)]
+pub struct TaskWatchdog<const N: usize = MAX_TASKS> {
+    tasks: heapless::Vec<TaskStatus, N>,
+    escalation: Escalation,
+}
+
+impl<const N: usize> TaskWatchdog<N> {
+    /// Creates a watchdog without tasks
+    pub const fn new(escalation: Escalation) -> Self {
+        TaskWatchdog {
+            tasks: heapless::Vec::new(),
+            escalation,
+        }
+    }
+
+    /// Changes what missed deadlines lead to
+    pub fn set_escalation(&mut self, escalation: Escalation) {
+        self.escalation = escalation;
+    }
+
+    /// Adds a task whose first heartbeat is due `deadline_ms` after `now_ms`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`WatchdogError::Full`] if `N` tasks are already registered.
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        deadline_ms: u32,
+        now_ms: u64,
+    ) -> Result<TaskId, WatchdogError> {
+        let id = TaskId(self.tasks.len() as u8);
+        self.tasks
+            .push(TaskStatus {
+                name,
+                deadline_ms,
+                last_seen_ms: now_ms,
+                due_ms: Some(now_ms + u64::from(deadline_ms)),
+                misses: 0,
+                total_misses: 0,
+            })
+            .map_err(|_| WatchdogError::Full)?;
+        Ok(id)
+    }
+
+    /// Records a heartbeat: the task is working, and its next heartbeat is
+    /// due within its deadline
+    pub fn heartbeat(&mut self, task: TaskId, now_ms: u64) {
+        if let Some(status) = self.tasks.get_mut(usize::from(task.0)) {
+            status.last_seen_ms = now_ms;
+            status.due_ms = Some(now_ms + u64::from(status.deadline_ms));
+            status.misses = 0;
+        }
+    }
+
+    /// Marks the task idle: it waits for something that may take any time
+    /// and has no deadline until its next heartbeat
+    pub fn idle(&mut self, task: TaskId, now_ms: u64) {
+        if let Some(status) = self.tasks.get_mut(usize::from(task.0)) {
+            status.last_seen_ms = now_ms;
+            status.due_ms = None;
+            status.misses = 0;
+        }
+    }
+
+    /// Finds the tasks whose heartbeat is overdue at `now_ms`.
+    ///
+    /// Calls `on_miss` for each of them and gives the task another deadline,
+    /// so a task that stays silent misses one deadline per period. Returns
+    /// the most severe action any of the misses asks for.
+    pub fn check(&mut self, now_ms: u64, mut on_miss: impl FnMut(&Miss)) -> Option<Action> {
+        let mut worst = None;
+        for (index, status) in self.tasks.iter_mut().enumerate() {
+            let Some(due_ms) = status.due_ms else {
+                continue;
+            };
+            if now_ms < due_ms {
+                continue;
+            }
+            status.misses = status.misses.saturating_add(1);
+            status.total_misses = status.total_misses.saturating_add(1);
+            status.due_ms = Some(now_ms + u64::from(status.deadline_ms));
+
+            let action = self.escalation.action(status.misses);
+            on_miss(&Miss {
+                task: TaskId(index as u8),
+                name: status.name,
+                silent_ms: now_ms.saturating_sub(status.last_seen_ms),
+                misses: status.misses,
+                action,
+            });
+            worst = worst.max(action);
+        }
+        worst
+    }
+
+    /// Returns the state of each registered task
+    pub fn tasks(&self) -> impl Iterator<Item = &TaskStatus> {
+        self.tasks.iter()
+    }
+
+    /// Returns the number of deadlines missed by all tasks since boot
+    pub fn total_misses(&self) -> u32 {
+        self.tasks
+            .iter()
+            .fold(0, |total, status| total.saturating_add(status.total_misses))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+    use std::vec::Vec;
+
+    #[test]
+    fn picks_the_action_for_each_count() {
+        let escalation = Escalation::default();
+        assert_eq!(escalation.action(1), Some(Action::RestartRadio));
+        assert_eq!(escalation.action(2), None);
+        assert_eq!(escalation.action(3), Some(Action::ResetChip));
+        assert_eq!(escalation.action(4), None);
+
+        let same = Escalation {
+            restart_radio_after: Some(2),
+            reset_after: Some(2),
+            safe_mode_after: Some(2),
+        };
+        assert_eq!(same.action(2), Some(Action::SafeMode));
+        assert_eq!(Escalation::LOG_ONLY.action(1), None);
+    }
+
+    #[test]
+    fn reports_missed_heartbeats() {
+        let mut watchdog = TaskWatchdog::<2>::new(Escalation::default());
+        let scanner = watchdog.register("scanner", 1000, 0).unwrap();
+        let station = watchdog.register("station", 5000, 0).unwrap();
+        assert_eq!(watchdog.register("extra", 1, 0), Err(WatchdogError::Full));
+
+        let check = |watchdog: &mut TaskWatchdog<2>, now| {
+            let mut misses = Vec::new();
+            let action = watchdog.check(now, |miss| misses.push(*miss));
+            (action, misses)
+        };
+        assert_eq!(check(&mut watchdog, 999), (None, Vec::new()));
+
+        watchdog.heartbeat(scanner, 900);
+        watchdog.idle(station, 900);
+        let (action, misses) = check(&mut watchdog, 2000);
+        assert_eq!(action, Some(Action::RestartRadio));
+        assert_eq!(
+            misses,
+            [Miss {
+                task: scanner,
+                name: "scanner",
+                silent_ms: 1100,
+                misses: 1,
+                action: Some(Action::RestartRadio),
+            }]
+        );
+        assert_eq!(
+            format!("{}", misses[0]),
+            "Task scanner missed its deadline: no heartbeat for 1100 ms, 1 in a row, \
+             restarting the radio"
+        );
+
+        // One miss per deadline while the task stays silent
+        assert_eq!(check(&mut watchdog, 2500).1.len(), 0);
+        let (action, misses) = check(&mut watchdog, 3000);
+        assert_eq!(action, None);
+        assert_eq!(misses[0].misses, 2);
+        let (action, misses) = check(&mut watchdog, 4000);
+        assert_eq!(action, Some(Action::ResetChip));
+        assert_eq!(misses[0].misses, 3);
+        assert_eq!(misses[0].silent_ms, 3100);
+
+        // A heartbeat starts the count over
+        watchdog.heartbeat(scanner, 4100);
+        let status = watchdog.tasks().next().unwrap();
+        assert_eq!((status.misses, status.total_misses), (0, 3));
+        assert_eq!(
+            format!("{status}"),
+            "scanner: running, deadline 1000 ms, 3 missed since boot"
+        );
+        assert_eq!(check(&mut watchdog, 5200).0, Some(Action::RestartRadio));
+        assert_eq!(watchdog.total_misses(), 4);
+        assert_eq!(
+            format!("{}", watchdog.tasks().nth(1).unwrap()),
+            "station: idle, deadline 5000 ms, 0 missed since boot"
+        );
+    }
+
+    #[test]
+    fn catches_tasks_that_never_check_in() {
+        let mut watchdog = TaskWatchdog::<4>::new(Escalation::LOG_ONLY);
+        watchdog.register("mqtt", 2000, 500).unwrap();
+        let mut silent = None;
+        assert_eq!(
+            watchdog.check(2500, |miss| silent = Some(miss.silent_ms)),
+            None
+        );
+        assert_eq!(silent, Some(2000));
+    }
+}
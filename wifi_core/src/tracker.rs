@@ -0,0 +1,506 @@
+//! Access point tracking across scans.
+//!
+//! [`ApTracker`] follows access points from one [`ScanReport`] to the next,
+//! keyed by BSSID. For each access point it keeps first/last seen times, how
+//! often it was seen, and the minimum, maximum and exponentially weighted
+//! moving average (EWMA) of its signal strength. Feeding it a report yields
+//! [`TrackerEvent`]s when networks appear, disappear, or change signal.
+//!
+//! # Capacity and eviction
+//!
+//! The tracker holds at most `N` access points. When a new access point is
+//! seen and the table is full, the entry that was seen least recently is
+//! evicted, and among equally old entries the one with the weakest average
+//! signal. Access points present in the report being processed are never
+//! evicted to make room for each other; if every entry was seen in the
+//! current report, the new access point is not tracked and is counted in
+//! [`ApTracker::untracked`]. Evicted entries are reported as
+//! [`TrackerEvent::Disappeared`] with [`DisappearReason::Evicted`].
+
+use core::fmt;
+
+use crate::report::{AccessPointRecord, AuthMethod, Bssid, ScanReport, Ssid};
+
+/// Default number of access points tracked by the firmware
+pub const DEFAULT_TRACKER_CAPACITY: usize = 64;
+
+/// Fixed-point scale of the stored EWMA
+const EWMA_SCALE: i32 = 16;
+
+/// Tuning of an [`ApTracker`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TrackerConfig {
+    /// Consecutive scans an access point may be missing before it is reported gone
+    pub missed_scans_before_disappear: u8,
+    /// Change of the average signal, in dB, that triggers [`TrackerEvent::SignalChanged`]
+    pub signal_change_threshold_db: u8,
+    /// Weight of a new sample in the signal average, in percent (1 to 100)
+    pub ewma_weight_percent: u8,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            missed_scans_before_disappear: 3,
+            signal_change_threshold_db: 10,
+            ewma_weight_percent: 25,
+        }
+    }
+}
+
+/// Why an access point left the tracking table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DisappearReason {
+    /// It was missing from too many consecutive scans
+    Missed,
+    /// It was evicted to make room for a new access point
+    Evicted,
+}
+
+/// Change reported by [`ApTracker::update`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TrackerEvent {
+    /// An access point was seen for the first time
+    Appeared {
+        /// BSSID of the access point
+        bssid: Bssid,
+        /// SSID of the access point
+        ssid: Ssid,
+        /// Channel it was seen on
+        channel: u8,
+        /// Signal strength in dBm
+        rssi: i8,
+    },
+    /// An access point is no longer tracked
+    Disappeared {
+        /// BSSID of the access point
+        bssid: Bssid,
+        /// SSID of the access point
+        ssid: Ssid,
+        /// Time it was last seen, in milliseconds since boot
+        last_seen_ms: u64,
+        /// Why it left the table
+        reason: DisappearReason,
+    },
+    /// The average signal of an access point moved by at least the configured threshold
+    SignalChanged {
+        /// BSSID of the access point
+        bssid: Bssid,
+        /// Average signal strength at the previous event, in dBm
+        previous: i8,
+        /// Current average signal strength, in dBm
+        current: i8,
+    },
+}
+
+impl fmt::Display for TrackerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackerEvent::Appeared {
+                bssid,
+                ssid,
+                channel,
+                rssi,
+            } => write!(
+                f,
+                "Appeared: {} ({}) on channel {} at {} dBm",
+                ssid, bssid, channel, rssi
+            ),
+            TrackerEvent::Disappeared {
+                bssid,
+                ssid,
+                last_seen_ms,
+                reason,
+            } => {
+                write!(
+                    f,
+                    "Disappeared: {} ({}), last seen at {} ms",
+                    ssid, bssid, last_seen_ms
+                )?;
+                if *reason == DisappearReason::Evicted {
+                    f.write_str(" (evicted)")?;
+                }
+                Ok(())
+            }
+            TrackerEvent::SignalChanged {
+                bssid,
+                previous,
+                current,
+            } => write!(
+                f,
+                "Signal changed: {} from {} dBm to {} dBm",
+                bssid, previous, current
+            ),
+        }
+    }
+}
+
+/// Tracking state of one access point
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TrackedAp {
+    /// BSSID of the access point
+    pub bssid: Bssid,
+    /// Most recently seen SSID
+    pub ssid: Ssid,
+    /// Most recently seen channel
+    pub channel: u8,
+    /// Most recently seen authentication method
+    pub auth_method: Option<AuthMethod>,
+    /// Time of the first sighting, in milliseconds since boot
+    pub first_seen_ms: u64,
+    /// Time of the latest sighting, in milliseconds since boot
+    pub last_seen_ms: u64,
+    /// Number of scans the access point was seen in
+    pub seen_count: u32,
+    /// Consecutive scans the access point has been missing from
+    pub missed_scans: u8,
+    /// Weakest signal seen, in dBm
+    pub rssi_min: i8,
+    /// Strongest signal seen, in dBm
+    pub rssi_max: i8,
+    /// Signal average scaled by `EWMA_SCALE`
+    ewma_scaled: i32,
+    /// Average signal at the last event emitted for this access point
+    reported_rssi: i8,
+    /// Update that last saw this access point
+    last_generation: u32,
+}
+
+impl TrackedAp {
+    fn new(record: &AccessPointRecord, timestamp_ms: u64, generation: u32) -> Self {
+        Self {
+            bssid: record.bssid,
+            ssid: record.ssid.clone(),
+            channel: record.channel,
+            auth_method: record.auth_method,
+            first_seen_ms: timestamp_ms,
+            last_seen_ms: timestamp_ms,
+            seen_count: 1,
+            missed_scans: 0,
+            rssi_min: record.signal_strength,
+            rssi_max: record.signal_strength,
+            ewma_scaled: i32::from(record.signal_strength) * EWMA_SCALE,
+            reported_rssi: record.signal_strength,
+            last_generation: generation,
+        }
+    }
+
+    /// Exponentially weighted moving average of the signal strength, in dBm
+    pub fn rssi_ewma(&self) -> i8 {
+        // Round to nearest; the result always lies between the min and max samples
+        ((self.ewma_scaled + EWMA_SCALE / 2).div_euclid(EWMA_SCALE)) as i8
+    }
+
+    fn record_sighting(
+        &mut self,
+        record: &AccessPointRecord,
+        timestamp_ms: u64,
+        generation: u32,
+        weight_percent: u8,
+    ) {
+        let sample = i32::from(record.signal_strength) * EWMA_SCALE;
+        let weight = i32::from(weight_percent.clamp(1, 100));
+        self.ewma_scaled += (sample - self.ewma_scaled) * weight / 100;
+
+        self.ssid = record.ssid.clone();
+        self.channel = record.channel;
+        self.auth_method = record.auth_method;
+        self.last_seen_ms = timestamp_ms;
+        self.seen_count = self.seen_count.saturating_add(1);
+        self.missed_scans = 0;
+        self.rssi_min = self.rssi_min.min(record.signal_strength);
+        self.rssi_max = self.rssi_max.max(record.signal_strength);
+        self.last_generation = generation;
+    }
+}
+
+/// Tracks up to `N` access points across scans
+#[derive(Clone, Debug)]
+pub struct ApTracker<const N: usize> {
+    config: TrackerConfig,
+    aps: heapless::Vec<TrackedAp, N>,
+    generation: u32,
+    untracked: u32,
+}
+
+impl<const N: usize> ApTracker<N> {
+    /// Creates an empty tracker
+    pub const fn new(config: TrackerConfig) -> Self {
+        Self {
+            config,
+            aps: heapless::Vec::new(),
+            generation: 0,
+            untracked: 0,
+        }
+    }
+
+    /// Processes one scan, calling `emit` for every resulting event.
+    ///
+    /// Access points that went missing are reported first, then appearances,
+    /// evictions and signal changes in report order.
+    pub fn update(&mut self, report: &ScanReport, mut emit: impl FnMut(TrackerEvent)) {
+        self.generation = self.generation.wrapping_add(1);
+        let generation = self.generation;
+
+        // Age everything missing from this scan and drop what has been gone too long
+        let limit = self.config.missed_scans_before_disappear.max(1);
+        let mut i = 0;
+        while i < self.aps.len() {
+            let ap = &mut self.aps[i];
+            if report.find(ap.bssid).is_some() {
+                i += 1;
+                continue;
+            }
+            ap.missed_scans = ap.missed_scans.saturating_add(1);
+            if ap.missed_scans >= limit {
+                let gone = self.aps.swap_remove(i);
+                emit(disappeared(gone, DisappearReason::Missed));
+            } else {
+                i += 1;
+            }
+        }
+
+        for record in report {
+            if let Some(ap) = self.aps.iter_mut().find(|ap| ap.bssid == record.bssid) {
+                ap.record_sighting(
+                    record,
+                    report.timestamp_ms,
+                    generation,
+                    self.config.ewma_weight_percent,
+                );
+                let current = ap.rssi_ewma();
+                let delta = (i16::from(current) - i16::from(ap.reported_rssi)).unsigned_abs();
+                if delta >= u16::from(self.config.signal_change_threshold_db.max(1)) {
+                    emit(TrackerEvent::SignalChanged {
+                        bssid: ap.bssid,
+                        previous: ap.reported_rssi,
+                        current,
+                    });
+                    ap.reported_rssi = current;
+                }
+                continue;
+            }
+
+            if self.aps.is_full() {
+                match self.eviction_candidate(generation) {
+                    Some(index) => {
+                        let evicted = self.aps.swap_remove(index);
+                        emit(disappeared(evicted, DisappearReason::Evicted));
+                    }
+                    None => {
+                        self.untracked = self.untracked.saturating_add(1);
+                        continue;
+                    }
+                }
+            }
+
+            let ap = TrackedAp::new(record, report.timestamp_ms, generation);
+            emit(TrackerEvent::Appeared {
+                bssid: ap.bssid,
+                ssid: ap.ssid.clone(),
+                channel: ap.channel,
+                rssi: record.signal_strength,
+            });
+            // Cannot fail: a slot was freed above if the table was full
+            let _ = self.aps.push(ap);
+        }
+    }
+
+    /// Index of the entry to evict, or `None` if every entry was seen in this update
+    fn eviction_candidate(&self, generation: u32) -> Option<usize> {
+        self.aps
+            .iter()
+            .enumerate()
+            .filter(|(_, ap)| ap.last_generation != generation)
+            .min_by_key(|(_, ap)| (ap.last_seen_ms, ap.ewma_scaled))
+            .map(|(index, _)| index)
+    }
+
+    /// Looks up a tracked access point by BSSID
+    pub fn get(&self, bssid: Bssid) -> Option<&TrackedAp> {
+        self.aps.iter().find(|ap| ap.bssid == bssid)
+    }
+
+    /// Iterates over the tracked access points in no particular order
+    pub fn iter(&self) -> core::slice::Iter<'_, TrackedAp> {
+        self.aps.iter()
+    }
+
+    /// Number of tracked access points
+    pub fn len(&self) -> usize {
+        self.aps.len()
+    }
+
+    /// Returns `true` if no access point is tracked
+    pub fn is_empty(&self) -> bool {
+        self.aps.is_empty()
+    }
+
+    /// Number of new access points that could not be tracked because the table was full
+    pub fn untracked(&self) -> u32 {
+        self.untracked
+    }
+
+    /// Forgets all tracked access points without emitting events
+    pub fn clear(&mut self) {
+        self.aps.clear();
+    }
+}
+
+fn disappeared(ap: TrackedAp, reason: DisappearReason) -> TrackerEvent {
+    TrackerEvent::Disappeared {
+        bssid: ap.bssid,
+        ssid: ap.ssid,
+        last_seen_ms: ap.last_seen_ms,
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::fixtures::{ap, report};
+    use std::format;
+    use std::vec::Vec;
+
+    fn update<const N: usize>(
+        tracker: &mut ApTracker<N>,
+        report: &ScanReport,
+    ) -> Vec<TrackerEvent> {
+        let mut events = Vec::new();
+        tracker.update(report, |event| events.push(event));
+        events
+    }
+
+    #[test]
+    fn appears_and_keeps_statistics() {
+        let mut tracker = ApTracker::<8>::new(TrackerConfig::default());
+
+        let events = update(&mut tracker, &report(1, &[ap(1).with_rssi(-60)]));
+        assert!(matches!(
+            events[..],
+            [TrackerEvent::Appeared { rssi: -60, .. }]
+        ));
+
+        assert!(update(&mut tracker, &report(2, &[ap(1)])).is_empty());
+        assert!(update(&mut tracker, &report(3, &[ap(1).with_rssi(-70)])).is_empty());
+
+        let tracked = tracker.get(Bssid([0, 0, 0, 0, 0, 1])).unwrap();
+        assert_eq!(tracked.seen_count, 3);
+        assert_eq!(tracked.first_seen_ms, 1000);
+        assert_eq!(tracked.last_seen_ms, 3000);
+        assert_eq!((tracked.rssi_min, tracked.rssi_max), (-70, -50));
+        // -60 -> -57.5 -> -60.625 with a 25 % weight
+        assert_eq!(tracked.rssi_ewma(), -61);
+    }
+
+    #[test]
+    fn disappears_after_missed_scans() {
+        let config = TrackerConfig {
+            missed_scans_before_disappear: 2,
+            ..TrackerConfig::default()
+        };
+        let mut tracker = ApTracker::<8>::new(config);
+        update(
+            &mut tracker,
+            &report(1, &[ap(1).with_rssi(-60), ap(2).with_rssi(-60)]),
+        );
+
+        assert!(update(&mut tracker, &report(2, &[ap(2).with_rssi(-60)])).is_empty());
+        let events = update(&mut tracker, &report(3, &[ap(2).with_rssi(-60)]));
+        assert_eq!(
+            events,
+            [TrackerEvent::Disappeared {
+                bssid: Bssid([0, 0, 0, 0, 0, 1]),
+                ssid: "net".try_into().unwrap(),
+                last_seen_ms: 1000,
+                reason: DisappearReason::Missed,
+            }]
+        );
+        assert_eq!(tracker.len(), 1);
+        assert_eq!(
+            format!("{}", events[0]),
+            "Disappeared: net (00:00:00:00:00:01), last seen at 1000 ms"
+        );
+    }
+
+    #[test]
+    fn reappearing_resets_missed_count() {
+        let mut tracker = ApTracker::<8>::new(TrackerConfig::default());
+        update(&mut tracker, &report(1, &[ap(1).with_rssi(-60)]));
+        update(&mut tracker, &report(2, &[]));
+        update(&mut tracker, &report(3, &[]));
+        update(&mut tracker, &report(4, &[ap(1).with_rssi(-60)]));
+        assert!(update(&mut tracker, &report(5, &[])).is_empty());
+        assert_eq!(
+            tracker.get(Bssid([0, 0, 0, 0, 0, 1])).unwrap().missed_scans,
+            1
+        );
+    }
+
+    #[test]
+    fn reports_signal_changes() {
+        let config = TrackerConfig {
+            ewma_weight_percent: 100,
+            signal_change_threshold_db: 5,
+            ..TrackerConfig::default()
+        };
+        let mut tracker = ApTracker::<8>::new(config);
+        update(&mut tracker, &report(1, &[ap(1).with_rssi(-60)]));
+
+        assert!(update(&mut tracker, &report(2, &[ap(1).with_rssi(-63)])).is_empty());
+        let events = update(&mut tracker, &report(3, &[ap(1).with_rssi(-66)]));
+        assert_eq!(
+            events,
+            [TrackerEvent::SignalChanged {
+                bssid: Bssid([0, 0, 0, 0, 0, 1]),
+                previous: -60,
+                current: -66,
+            }]
+        );
+    }
+
+    #[test]
+    fn evicts_least_recently_seen() {
+        let mut tracker = ApTracker::<2>::new(TrackerConfig::default());
+        update(&mut tracker, &report(1, &[ap(1).with_rssi(-60)]));
+        update(&mut tracker, &report(2, &[ap(2).with_rssi(-60)]));
+
+        let events = update(
+            &mut tracker,
+            &report(3, &[ap(2).with_rssi(-60), ap(3).with_rssi(-60)]),
+        );
+        assert!(matches!(
+            events[0],
+            TrackerEvent::Disappeared {
+                bssid: Bssid([0, 0, 0, 0, 0, 1]),
+                reason: DisappearReason::Evicted,
+                ..
+            }
+        ));
+        assert!(matches!(events[1], TrackerEvent::Appeared { .. }));
+        assert!(tracker.get(Bssid([0, 0, 0, 0, 0, 3])).is_some());
+    }
+
+    #[test]
+    fn never_evicts_entries_from_the_same_scan() {
+        let mut tracker = ApTracker::<2>::new(TrackerConfig::default());
+        let events = update(
+            &mut tracker,
+            &report(
+                1,
+                &[
+                    ap(1).with_rssi(-60),
+                    ap(2).with_rssi(-60),
+                    ap(3).with_rssi(-60),
+                ],
+            ),
+        );
+        assert_eq!(events.len(), 2);
+        assert_eq!(tracker.untracked(), 1);
+    }
+}